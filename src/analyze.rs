@@ -0,0 +1,267 @@
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+
+use crate::{
+    export::{
+        format_timestamp_as,
+        get_room_index_by_identifier,
+        get_room_indices_by_pattern,
+        glob_to_regex,
+        is_glob,
+        EventPager,
+        room_indices_to_resolution,
+        EventSource,
+        ExportEventRange,
+        ExportTimezone,
+        PaginationOptions,
+        RoomIndexRetrievalError,
+        RoomResolution,
+    },
+    get_rooms_info,
+    Result,
+    RoomWithCachedInfo,
+};
+
+use matrix_sdk::{
+    deserialized_responses::TimelineEventKind,
+    ruma::{
+        events::{
+            room::message::{
+                MessageType,
+                Relation,
+            },
+            AnySyncMessageLikeEvent,
+            AnySyncTimelineEvent,
+            SyncMessageLikeEvent,
+        },
+        OwnedRoomId,
+    },
+    Client,
+};
+use serde::Serialize;
+
+///////////////
+//   Types   //
+///////////////
+
+// Undecryptable events sharing a megolm session, all of which become decryptable once its key turns up.
+#[derive(Serialize)]
+pub struct MissingSession {
+    pub session_id: String,
+    pub event_count: usize,
+    pub first_timestamp_millis: Option<i64>,
+    pub last_timestamp_millis: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct UndecryptableEventStats {
+    pub room_id: OwnedRoomId,
+    pub room_name: Option<String>,
+    pub event_count: usize, // Every event paginated through, encrypted or not
+    pub encrypted_event_count: usize,
+    pub undecryptable_event_count: usize,
+    pub first_timestamp_millis: Option<i64>, // Of the earliest undecryptable event
+    pub last_timestamp_millis: Option<i64>, // Of the latest undecryptable event
+    pub missing_sessions: Vec<MissingSession>, // Oldest first
+}
+
+pub struct UndecryptableEventReport {
+    pub room_resolutions: Vec<RoomResolution>, // As for export, minus the DM partners and regexes
+    pub room_stats: Vec<UndecryptableEventStats>,
+}
+
+#[derive(Serialize)]
+pub struct SenderMessageCount {
+    pub sender: String,
+    pub message_count: usize,
+}
+
+#[derive(Serialize)]
+pub struct RoomActivityStats {
+    pub room_id: OwnedRoomId,
+    pub room_name: Option<String>,
+    pub event_count: usize, // Every event paginated through, messages or not
+    pub message_count: usize, // m.room.message and m.sticker events, redacted ones aside
+    pub media_count: usize, // Messages with an image, video, audio clip, or file attached, stickers included
+    pub undecryptable_event_count: usize, // There's no telling whether these are messages, so they count towards neither messages nor media
+    pub first_timestamp_millis: Option<i64>, // Of the earliest message
+    pub last_timestamp_millis: Option<i64>, // Of the latest message
+    pub senders: Vec<SenderMessageCount>, // Most messages first
+    pub messages_by_month: BTreeMap<String, usize>, // Keyed like 2020-01, in whichever time zone was asked for
+    pub messages_by_day: BTreeMap<String, usize>, // Keyed like 2020-01-31, likewise
+    pub messages_by_hour: BTreeMap<String, usize>, // Keyed by hour of the day, from 00 to 23, totalled across every day
+}
+
+pub struct RoomActivityReport {
+    pub room_resolutions: Vec<RoomResolution>, // As for undecryptable_event_stats
+    pub room_stats: Vec<RoomActivityStats>,
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+// Resolves room identifiers as export does, minus peeking, into indices into accessible_rooms_info, sorted and deduplicated.
+pub(crate) fn resolve_joined_rooms(client: &Client, accessible_rooms_info: &[RoomWithCachedInfo], rooms: Vec<String>) -> (Vec<usize>, Vec<RoomResolution>) {
+    let mut room_indices = Vec::new();
+    let mut room_resolutions = Vec::new();
+    for room_identifier in rooms {
+        let identifier_room_indices = match get_room_index_by_identifier(accessible_rooms_info, &room_identifier) {
+            Ok(index) => vec![index],
+            Err(RoomIndexRetrievalError::NoRoomsWithSpecifiedName) if is_glob(&room_identifier) => get_room_indices_by_pattern(accessible_rooms_info, &glob_to_regex(&room_identifier)),
+            Err(e) => {
+                room_resolutions.push(RoomResolution {
+                    result: Err(e.into_error(client, &room_identifier)),
+                    identifier: room_identifier,
+                });
+                continue
+            }
+        };
+        room_resolutions.push(room_indices_to_resolution(client, accessible_rooms_info, room_identifier, &identifier_room_indices));
+        room_indices.extend(identifier_room_indices);
+    }
+    room_indices.sort_unstable();
+    room_indices.dedup();
+
+    (room_indices, room_resolutions)
+}
+
+fn widen_time_range(first: &mut Option<i64>, last: &mut Option<i64>, timestamp_millis: Option<i64>) {
+    if let Some(timestamp_millis) = timestamp_millis {
+        *first = Some(first.map_or(timestamp_millis, |first| first.min(timestamp_millis)));
+        *last = Some(last.map_or(timestamp_millis, |last| last.max(timestamp_millis)));
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Paginates through each room's history the way export does, but only tallies up which events couldn't be decrypted, without writing anything. Rooms are identified as for export, minus peeking.
+pub async fn undecryptable_event_stats(client: &Client, rooms: Vec<String>, pagination_options: PaginationOptions) -> Result<UndecryptableEventReport> {
+    let accessible_rooms_info = get_rooms_info(client).await?;
+    let (room_indices, room_resolutions) = resolve_joined_rooms(client, &accessible_rooms_info, rooms);
+
+    let mut all_stats = Vec::new();
+    for room_index in room_indices {
+        let room_info = &accessible_rooms_info[room_index];
+        let mut stats = UndecryptableEventStats {
+            room_id: room_info.id.clone(),
+            room_name: room_info.name.clone(),
+            event_count: 0,
+            encrypted_event_count: 0,
+            undecryptable_event_count: 0,
+            first_timestamp_millis: None,
+            last_timestamp_millis: None,
+            missing_sessions: Vec::new(),
+        };
+        let mut missing_sessions: HashMap<String, MissingSession> = HashMap::new();
+        let mut event_pager = EventPager::new(EventSource::Joined(&room_info.room), &ExportEventRange::default(), &pagination_options);
+        while let Some(page) = event_pager.next_page().await? {
+            for event in page {
+                stats.event_count += 1;
+                let utd_info = match &event.kind {
+                    TimelineEventKind::PlainText { .. } => continue,
+                    TimelineEventKind::Decrypted(_) => {
+                        stats.encrypted_event_count += 1;
+                        continue
+                    }
+                    TimelineEventKind::UnableToDecrypt { utd_info, .. } => utd_info,
+                };
+                stats.encrypted_event_count += 1;
+                stats.undecryptable_event_count += 1;
+                let timestamp_millis = event.raw().get_field::<i64>("origin_server_ts").ok().flatten();
+                widen_time_range(&mut stats.first_timestamp_millis, &mut stats.last_timestamp_millis, timestamp_millis);
+                if let Some(session_id) = &utd_info.session_id {
+                    let missing_session = missing_sessions.entry(session_id.clone()).or_insert_with(|| MissingSession {
+                        session_id: session_id.clone(),
+                        event_count: 0,
+                        first_timestamp_millis: None,
+                        last_timestamp_millis: None,
+                    });
+                    missing_session.event_count += 1;
+                    widen_time_range(&mut missing_session.first_timestamp_millis, &mut missing_session.last_timestamp_millis, timestamp_millis);
+                }
+            }
+        }
+        event_pager.ensure_no_fetch_failures()?;
+        stats.missing_sessions = missing_sessions.into_values().collect();
+        stats.missing_sessions.sort_by_key(|missing_session| missing_session.first_timestamp_millis);
+        all_stats.push(stats);
+    }
+
+    Ok(UndecryptableEventReport {
+        room_resolutions,
+        room_stats: all_stats,
+    })
+}
+
+// Tallies up who said how much and when across each room's history, as paginated the way export does. Rooms are identified as for undecryptable_event_stats, and time-based breakdowns use the given time zone.
+pub async fn room_activity_stats(client: &Client, rooms: Vec<String>, pagination_options: PaginationOptions, timezone: &ExportTimezone) -> Result<RoomActivityReport> {
+    let accessible_rooms_info = get_rooms_info(client).await?;
+    let (room_indices, room_resolutions) = resolve_joined_rooms(client, &accessible_rooms_info, rooms);
+
+    let mut all_stats = Vec::new();
+    for room_index in room_indices {
+        let room_info = &accessible_rooms_info[room_index];
+        let mut stats = RoomActivityStats {
+            room_id: room_info.id.clone(),
+            room_name: room_info.name.clone(),
+            event_count: 0,
+            message_count: 0,
+            media_count: 0,
+            undecryptable_event_count: 0,
+            first_timestamp_millis: None,
+            last_timestamp_millis: None,
+            senders: Vec::new(),
+            messages_by_month: BTreeMap::new(),
+            messages_by_day: BTreeMap::new(),
+            messages_by_hour: BTreeMap::new(),
+        };
+        let mut message_counts_by_sender: HashMap<String, usize> = HashMap::new();
+        let mut event_pager = EventPager::new(EventSource::Joined(&room_info.room), &ExportEventRange::default(), &pagination_options);
+        while let Some(page) = event_pager.next_page().await? {
+            for event in page {
+                stats.event_count += 1;
+                if let TimelineEventKind::UnableToDecrypt { .. } = event.kind {
+                    stats.undecryptable_event_count += 1;
+                    continue
+                }
+                let Ok(AnySyncTimelineEvent::MessageLike(message)) = event.raw().deserialize() else {
+                    continue
+                };
+                // Edits only change messages already counted, so they don't count as messages of their own
+                let has_media = match &message {
+                    AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(message)) if matches!(message.content.relates_to, Some(Relation::Replacement(_))) => continue,
+                    AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(message)) => matches!(message.content.msgtype, MessageType::Image(_) | MessageType::Video(_) | MessageType::Audio(_) | MessageType::File(_)),
+                    AnySyncMessageLikeEvent::Sticker(SyncMessageLikeEvent::Original(_)) => true,
+                    _ => continue,
+                };
+                stats.message_count += 1;
+                if has_media {
+                    stats.media_count += 1;
+                }
+                *message_counts_by_sender.entry(message.sender().to_string()).or_default() += 1;
+                let timestamp_millis: i64 = message.origin_server_ts().0.into();
+                widen_time_range(&mut stats.first_timestamp_millis, &mut stats.last_timestamp_millis, Some(timestamp_millis));
+                *stats.messages_by_month.entry(format_timestamp_as(timestamp_millis, timezone, Some("%Y-%m"))).or_default() += 1;
+                *stats.messages_by_day.entry(format_timestamp_as(timestamp_millis, timezone, Some("%Y-%m-%d"))).or_default() += 1;
+                *stats.messages_by_hour.entry(format_timestamp_as(timestamp_millis, timezone, Some("%H"))).or_default() += 1;
+            }
+        }
+        event_pager.ensure_no_fetch_failures()?;
+        stats.senders = message_counts_by_sender.into_iter().map(|(sender, message_count)| SenderMessageCount {
+            sender,
+            message_count,
+        }).collect();
+        stats.senders.sort_by(|sender_1, sender_2| sender_2.message_count.cmp(&sender_1.message_count).then_with(|| sender_1.sender.cmp(&sender_2.sender)));
+        all_stats.push(stats);
+    }
+
+    Ok(RoomActivityReport {
+        room_resolutions,
+        room_stats: all_stats,
+    })
+}
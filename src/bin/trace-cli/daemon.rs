@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{
+    create_dir_all,
+    read_to_string,
+    File,
+};
+use std::io::Write;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use trace::{
+    checkpoint::CheckpointsFile,
+    hooks::{
+        run_export_hook,
+        ExportHook,
+        ExportSummary,
+    },
+    object_storage::ObjectStorage,
+    profiles::ProfileCacheFile,
+    schedule::Schedule,
+    CancellationToken,
+    ExportDestination,
+    ExportOptions,
+    ExportOutputFormat,
+    ExportProgress,
+    ExportReport,
+    ExportTimezone,
+    RoomExportStatus,
+    SessionsFile,
+    nonfirst_login,
+    user_id_to_crypto_store_path,
+};
+
+use chrono::{
+    DateTime,
+    Local,
+    SecondsFormat,
+    Utc,
+};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{
+    parse_timezone,
+    resolve_session,
+    DaemonCommand,
+    InvalidArguments,
+};
+
+///////////////
+//   Types   //
+///////////////
+
+// Read from daemon.toml in the platform's usual config directory, or wherever --config points. For example:
+//
+//     on_failure = "notify-send Trace \"Export job $TRACE_JOB failed: $TRACE_ERROR\""
+//     webhook_url = "https://monitoring.example.com/hooks/trace"
+//
+//     [[job]]
+//     name = "work"
+//     account = "@alice:example.com"
+//     rooms = ["#general:example.com"]
+//     spaces = ["#engineering:example.com"]
+//     formats = ["json", "txt"]
+//     output = "/home/alice/exports/work"
+//     schedule = "0 3 * * *"
+#[derive(Deserialize)]
+struct DaemonConfig {
+    log_dir: Option<PathBuf>, // Where each run's log goes; defaults to daemon-logs in the data directory
+    timezone: Option<String>, // What the schedules are in, as for export's --timezone; defaults to local time, as with cron
+    on_failure: Option<String>, // Shell command to run whenever a job fails, or fails to export some of its rooms, with TRACE_JOB, TRACE_ERROR, and TRACE_RUN_LOG set in its environment
+    webhook_url: Option<String>, // URL to POST a JSON summary of every run to, as with export's --webhook-url, with the job's name included
+    hook_command: Option<String>, // Shell command to run after every run, with the same summary on its stdin
+    #[serde(rename = "job")]
+    jobs: Vec<DaemonJob>,
+}
+
+#[derive(Deserialize)]
+struct DaemonJob {
+    name: String, // Also goes into the job's run log and checkpoint filenames, so it's limited to letters, numbers, '-', and '_'
+    account: String, // User ID or session alias
+    #[serde(default)]
+    rooms: Vec<String>, // As for export
+    #[serde(default)]
+    spaces: Vec<String>, // Every room in each of these gets exported, as of each run, so rooms added to them later get picked up too
+    #[serde(default)]
+    formats: Vec<String>, // Defaults to JSON alone
+    output: PathBuf, // With s3_bucket set, the directory to stage each room in before it's uploaded
+    s3_bucket: Option<String>, // As for export's --s3-bucket, with credentials likewise from the daemon's environment
+    s3_endpoint: Option<String>,
+    s3_prefix: Option<String>,
+    schedule: String, // Cron-style, as in schedule::Schedule
+    #[serde(default)]
+    avatars: bool,
+    #[serde(default)]
+    media: bool,
+}
+
+// Everyone to tell how each run went.
+struct RunNotifications {
+    on_failure: Option<String>,
+    export_hooks: Vec<ExportHook>,
+}
+
+// A job from the config, with its schedule and formats checked and parsed up front, so that mistakes in them come out when the daemon starts rather than whenever the job first comes round.
+struct ScheduledJob {
+    job: DaemonJob,
+    schedule: Schedule,
+    formats: HashSet<ExportOutputFormat>,
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn scheduled_job(job: DaemonJob) -> anyhow::Result<ScheduledJob> {
+    if job.name.is_empty() || !job.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        anyhow::bail!(InvalidArguments(format!("Received invalid job name {} in daemon config. Job names can contain only letters, numbers, '-', and '_'.", job.name)))
+    }
+    let mut formats = HashSet::new();
+    for format in &job.formats {
+        match format.to_lowercase().as_ref() {
+            "json" | ".json" => formats.insert(ExportOutputFormat::Json),
+            "txt" | ".txt" => formats.insert(ExportOutputFormat::Txt),
+            _ => anyhow::bail!(InvalidArguments(format!("Received invalid format specifier {} for job {} in daemon config. Valid options are 'json' and 'txt'.", format, job.name))),
+        };
+    }
+    if formats.is_empty() {
+        formats.insert(ExportOutputFormat::Json);
+    }
+
+    Ok(ScheduledJob {
+        schedule: Schedule::parse(&job.schedule)?,
+        formats,
+        job,
+    })
+}
+
+// Schedules are matched in the configured time zone, but kept track of in UTC.
+fn next_run(schedule: &Schedule, timezone: &ExportTimezone, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match timezone {
+        ExportTimezone::Utc => schedule.next_after(&after),
+        ExportTimezone::Local => schedule.next_after(&after.with_timezone(&Local)).map(|next_run| next_run.with_timezone(&Utc)),
+        ExportTimezone::Named(timezone) => schedule.next_after(&after.with_timezone(timezone)).map(|next_run| next_run.with_timezone(&Utc)),
+    }
+}
+
+// A run's log failing to be written to isn't worth failing the run itself over.
+fn log_line(run_log: &RefCell<File>, line: &str) {
+    if let Err(e) = writeln!(run_log.borrow_mut(), "{} {}", Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true), line) {
+        eprintln!("Couldn't write to run log due to error '{}'.", e);
+    }
+}
+
+fn progress_line(progress: ExportProgress) -> Option<String> {
+    match progress {
+        ExportProgress::RoomStarted { room_id } => Some(format!("Started exporting {}.", room_id)),
+        ExportProgress::RoomFinished { room_id } => Some(format!("Finished exporting {}.", room_id)),
+        ExportProgress::RoomFailed { room_id, description } => Some(format!("Couldn't export {} due to error '{}'.", room_id, description)),
+        ExportProgress::GapFound { room_id, description } => Some(format!("Export of {} is incomplete. {}.", room_id, description)),
+        ExportProgress::DecryptionRetried { room_id, undecryptable_count, decrypted_count } => Some(format!("Decrypted {} of {} undecryptable events in {} with keys from other devices.", decrypted_count, undecryptable_count, room_id)),
+        ExportProgress::MediaSkipped { description, .. } => Some(format!("{}. Continuing without it.", description)),
+        ExportProgress::UpgradeChainTruncated { room_id, inaccessible_room_id } => Some(format!("Couldn't access room {}, which {} was upgraded from. Exporting only the later part of its upgrade chain.", inaccessible_room_id, room_id)),
+        ExportProgress::PageFetched { .. } | ExportProgress::EventsProcessed { .. } | ExportProgress::BytesWritten { .. } | ExportProgress::MediaDownloaded { .. } | ExportProgress::FollowStarted { .. } | ExportProgress::EventAppended { .. } => None,
+    }
+}
+
+// Each run logs in afresh, so that the daemon doesn't hold onto a client (and its store) between runs which could be days apart.
+async fn run_job(scheduled_job: &ScheduledJob, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path, run_log: &RefCell<File>, cancellation: &CancellationToken) -> anyhow::Result<ExportReport> {
+    let job = &scheduled_job.job;
+    let (user_id, profile) = resolve_session(sessions_file, Some(&job.account), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = nonfirst_login(&user_id, profile, sessions_file, &store_path).await?;
+    let token_rejection = trace::watch_for_token_rejection(&client, &user_id, profile);
+    trace::light_sync(&client).await?;
+
+    let mut rooms = job.rooms.clone();
+    for space in &job.spaces {
+        let child_ids = trace::get_space_child_ids(&client, space).await?;
+        log_line(run_log, &format!("Found {} rooms in space {}.", child_ids.len(), space));
+        rooms.extend(child_ids.into_iter().map(|room_id| room_id.to_string()));
+    }
+
+    let log_progress = |progress: ExportProgress| {
+        if let Some(line) = progress_line(progress) {
+            log_line(run_log, &line);
+        }
+    };
+    // Checkpoints are kept per job rather than shared with export --incremental, so that jobs exporting the same rooms to different places don't skip over each other's events
+    let destination = match &job.s3_bucket {
+        Some(s3_bucket) => ExportDestination::ObjectStorage(ObjectStorage::from_env(job.s3_endpoint.as_deref(), s3_bucket, job.s3_prefix.as_deref())?, Some(job.output.clone())),
+        None => ExportDestination::Directory(Some(job.output.clone())),
+    };
+    let export_options = ExportOptions::new()
+        .rooms(rooms)
+        .destination(destination)
+        .formats(scheduled_job.formats.clone())
+        .download_avatars(job.avatars)
+        .download_media(job.media)
+        .incremental_checkpoints(CheckpointsFile::open(store_path.join(format!("daemon-{}-checkpoints.json", job.name)))?)
+        .profile_cache(ProfileCacheFile::open(store_path.join("profiles.json"), chrono::Duration::hours(24))?)
+        .resume_dir(store_path.join("resume"))
+        .progress(&log_progress)
+        .cancellation(cancellation);
+
+    // A token rejected partway through gets reported as such, rather than as whichever request it happened to fail
+    match trace::export(&client, export_options).await {
+        Ok(export_report) => Ok(export_report),
+        Err(e) => Err(token_rejection.take().map(trace::Error::SessionExpired).unwrap_or(e).into()),
+    }
+}
+
+// Failing to notify only gets reported, since there's nowhere better to send word of it.
+async fn notify_failure(on_failure: &str, job_name: &str, error: &str, run_log_path: &Path) {
+    let (shell, shell_flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let status = Command::new(shell)
+        .arg(shell_flag)
+        .arg(on_failure)
+        .env("TRACE_JOB", job_name)
+        .env("TRACE_ERROR", error)
+        .env("TRACE_RUN_LOG", run_log_path)
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!("Failure notification command for job {} exited with {}.", job_name, status),
+        Err(e) => eprintln!("Couldn't run failure notification command for job {} due to error '{}'.", job_name, e),
+    }
+}
+
+// Runs a job once, writing how it went to a log of its own and notifying of any failure. Nothing here counts as an error, not even failing to create the log, since one job's failures shouldn't stop the rest from running.
+async fn run_and_log(scheduled_job: &ScheduledJob, run_notifications: &RunNotifications, log_dir: &Path, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path, cancellation: &CancellationToken) {
+    let job_name = &scheduled_job.job.name;
+    let run_log_path = log_dir.join(format!("{}-{}.log", job_name, Utc::now().format("%Y%m%dT%H%M%SZ")));
+    let run_log = match File::create(&run_log_path) {
+        Ok(run_log_file) => RefCell::new(run_log_file),
+        Err(e) => {
+            // The job doesn't get run without a log to show for it, e.g. when the disk's full, but later runs still get their chance
+            let failure = format!("Couldn't create run log {} due to error '{}'.", run_log_path.display(), e);
+            eprintln!("Job {} failed: {}", job_name, failure);
+            if let Some(on_failure) = &run_notifications.on_failure {
+                notify_failure(on_failure, job_name, &failure, &run_log_path).await;
+            }
+            return
+        }
+    };
+    println!("Running job {}.", job_name);
+    log_line(&run_log, &format!("Starting run of job {}.", job_name));
+
+    let export_result = run_job(scheduled_job, profile, sessions_file, data_dir, &run_log, cancellation).await;
+    // Stopping the daemon mid-run isn't worth telling anyone about
+    if let Err(e) = &export_result {
+        if let Some(trace::Error::ExportCancelled) = e.downcast_ref::<trace::Error>() {
+            log_line(&run_log, "Run cancelled, as the daemon was stopped. The next run will pick up from where this one left off.");
+            println!("Job {} cancelled.", job_name);
+            return
+        }
+    }
+    let mut export_summary = match &export_result {
+        Ok(export_report) => ExportSummary::from_report(export_report),
+        Err(e) => ExportSummary::from_error(format!("{:#}", e)),
+    };
+    export_summary.job = Some(job_name.clone());
+
+    let failure = match export_result {
+        Ok(export_report) => {
+            let mut failed_room_count = 0;
+            for room_resolution in export_report.room_resolutions {
+                if let Err(e) = room_resolution.result {
+                    log_line(&run_log, &format!("{} | Not found | {}", room_resolution.identifier, e));
+                    failed_room_count += 1;
+                }
+            }
+            for room_outcome in export_report.room_outcomes {
+                let room = match room_outcome.room_name {
+                    Some(name) => format!("{} [{}]", name, room_outcome.room_id),
+                    None => room_outcome.room_id.to_string(),
+                };
+                match room_outcome.status {
+                    RoomExportStatus::Exported { byte_count, event_count, .. } => log_line(&run_log, &format!("{} | Exported | {} events, {} bytes written", room, event_count, byte_count)),
+                    RoomExportStatus::Skipped => log_line(&run_log, &format!("{} | Skipped | Nothing new since the last run", room)),
+                    RoomExportStatus::Failed(e) => {
+                        log_line(&run_log, &format!("{} | Failed | {}", room, e));
+                        failed_room_count += 1;
+                    }
+                }
+            }
+            (failed_room_count > 0).then(|| format!("Exported {} rooms, but couldn't export {} others.", export_report.exported_room_count, failed_room_count))
+        }
+        Err(e) => Some(format!("{:#}", e)),
+    };
+
+    match failure {
+        None => {
+            log_line(&run_log, "Run finished.");
+            println!("Job {} finished.", job_name);
+        }
+        Some(failure) => {
+            log_line(&run_log, &format!("Run failed: {}", failure));
+            eprintln!("Job {} failed: {} See {} for details.", job_name, failure, run_log_path.display());
+            if let Some(on_failure) = &run_notifications.on_failure {
+                notify_failure(on_failure, job_name, &failure, &run_log_path).await;
+            }
+        }
+    }
+    for export_hook in &run_notifications.export_hooks {
+        if let Err(e) = run_export_hook(export_hook, &export_summary).await {
+            log_line(&run_log, &format!("Couldn't run export hook due to error '{}'.", e));
+            eprintln!("Couldn't run export hook for job {} due to error '{}'.", job_name, e);
+        }
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Jobs run one at a time, so a run that overruns another job's scheduled time holds it up until it's done, rather than the two running at once; it's not skipped, though. Slots that pass while the daemon isn't running are, on the other hand.
+pub(crate) async fn daemon(config: DaemonCommand, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let Some(config_path) = config.config else {
+        anyhow::bail!(InvalidArguments(String::from("Received no daemon config file. Pass one with --config.")));
+    };
+    let daemon_config: DaemonConfig = toml::from_str(&read_to_string(&config_path)?)?;
+    if daemon_config.jobs.is_empty() {
+        anyhow::bail!(InvalidArguments(format!("Received daemon config {} with no jobs in it. Add at least one [[job]] table.", config_path.display())));
+    }
+    let mut job_names = HashSet::new();
+    for job in &daemon_config.jobs {
+        if !job_names.insert(job.name.as_str()) {
+            anyhow::bail!(InvalidArguments(format!("Received more than one job named {} in daemon config. Job names have to be unique.", job.name)));
+        }
+    }
+    let timezone = parse_timezone(Some(daemon_config.timezone.unwrap_or_else(|| String::from("local"))), "daemon")?;
+    let log_dir = daemon_config.log_dir.unwrap_or_else(|| data_dir.join("daemon-logs"));
+    create_dir_all(&log_dir)?;
+    let run_notifications = RunNotifications {
+        on_failure: daemon_config.on_failure,
+        export_hooks: daemon_config.webhook_url.map(ExportHook::Webhook).into_iter().chain(daemon_config.hook_command.map(ExportHook::Command)).collect(),
+    };
+    let scheduled_jobs = daemon_config.jobs.into_iter().map(scheduled_job).collect::<anyhow::Result<Vec<ScheduledJob>>>()?;
+
+    // The first Ctrl-C lets the current run wrap up what it's written so far, and a second one kills it outright
+    let cancellation = CancellationToken::new();
+    let ctrl_c_cancellation = cancellation.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Stopping daemon after the current page of the current run, if any. Press Ctrl-C again to stop immediately.");
+            ctrl_c_cancellation.cancel();
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    if config.once {
+        for scheduled_job in &scheduled_jobs {
+            if cancellation.is_cancelled() {
+                break
+            }
+            run_and_log(scheduled_job, &run_notifications, &log_dir, profile, sessions_file, data_dir, &cancellation).await;
+        }
+        return Ok(())
+    }
+
+    let now = Utc::now();
+    let mut next_runs = scheduled_jobs.iter().map(|scheduled_job| next_run(&scheduled_job.schedule, &timezone, now)).collect::<Vec<Option<DateTime<Utc>>>>();
+    println!("Started daemon with {} jobs. Logs of each run go in {}.", scheduled_jobs.len(), log_dir.display());
+    loop {
+        let Some((job_index, next_run_at)) = next_runs.iter().enumerate().filter_map(|(job_index, next_run_at)| next_run_at.map(|next_run_at| (job_index, next_run_at))).min_by_key(|(_, next_run_at)| *next_run_at) else {
+            println!("None of the jobs' schedules will come round again. Stopping daemon.");
+            return Ok(())
+        };
+        tokio::select! {
+            _ = tokio::time::sleep((next_run_at - Utc::now()).to_std().unwrap_or_default()) => (),
+            _ = cancellation.cancelled() => return Ok(()),
+        }
+        run_and_log(&scheduled_jobs[job_index], &run_notifications, &log_dir, profile, sessions_file, data_dir, &cancellation).await;
+        if cancellation.is_cancelled() {
+            return Ok(())
+        }
+        next_runs[job_index] = next_run(&scheduled_jobs[job_index].schedule, &timezone, Utc::now());
+    }
+}
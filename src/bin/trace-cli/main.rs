@@ -0,0 +1,3276 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+    HashSet,
+};
+use std::fs::{
+    read_to_string,
+    File,
+};
+use std::io::{
+    stderr,
+    stdin,
+    stdout,
+    IsTerminal,
+    Write,
+};
+use std::iter::once;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process::ExitCode;
+use std::sync::{
+    atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    Arc,
+    Mutex,
+};
+use std::time::Duration;
+
+use trace::{
+    checkpoint::CheckpointsFile,
+    hooks::{
+        ExportHook,
+        ExportSummary,
+    },
+    import::{
+        ImportOptions,
+        ImportProgress,
+    },
+    index::IndexSearchOptions,
+    media::MediaProblemKind,
+    object_storage::ObjectStorage,
+    profiles::ProfileCacheFile,
+    redact::RedactionFilter,
+    secrets::{
+        KeyringSecretStore,
+        SecretStore,
+    },
+    verify::ExportProblemKind,
+    CancellationToken,
+    ConnectionOptions,
+    ContentFilter,
+    EventTypeFilter,
+    ExportDestination,
+    ExportEventRange,
+    ExportOptions,
+    ExportOutputFormat,
+    ExportProgress,
+    ExportTimezone,
+    JsonOptions,
+    NameTemplate,
+    PaginationOptions,
+    RoomExportStatus,
+    RoomWithCachedInfo,
+    SessionStore,
+    SessionsFile,
+    SpaceHierarchyNode,
+    SplitMode,
+    TokenRejection,
+    TxtOptions,
+    UpgradeChainMode,
+    add_at_to_user_id_if_applicable,
+    nonfirst_login,
+    user_id_to_crypto_store_path,
+    watch_for_token_rejection,
+};
+
+use argh::FromArgs;
+use chrono::{
+    format::{
+        Item,
+        StrftimeItems,
+    },
+    DateTime,
+    NaiveDate,
+    NaiveTime,
+};
+use chrono_tz::Tz;
+use directories::ProjectDirs;
+use futures::StreamExt;
+use indicatif::{
+    MultiProgress,
+    ProgressBar,
+    ProgressDrawTarget,
+    ProgressStyle,
+};
+use matrix_sdk::{
+    config::SyncSettings,
+    encryption::verification::{
+        AcceptSettings,
+        SasState,
+        Verification,
+        VerificationRequest,
+        VerificationRequestState,
+    },
+    ruma::{
+        events::key::verification::{
+            request::ToDeviceKeyVerificationRequestEvent,
+            ShortAuthenticationString,
+            VerificationMethod,
+        },
+        presence::PresenceState,
+        EventId,
+        OwnedDeviceId,
+        OwnedRoomId,
+        OwnedServerName,
+        OwnedUserId,
+        ServerName,
+        UserId,
+    },
+    Client,
+    HttpError,
+};
+use regex::Regex;
+use rpassword::{
+    prompt_password,
+    read_password,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tracing_subscriber::{
+    fmt::MakeWriter,
+    EnvFilter,
+};
+use unicode_width::{
+    UnicodeWidthChar,
+    UnicodeWidthStr,
+};
+
+mod daemon;
+mod tui;
+
+//////////////
+//   Args   //
+//////////////
+
+#[derive(FromArgs)]
+/// Trace Matrix downloader client
+struct Args {
+    #[argh(option)]
+    /// directory to keep sessions and their local stores in; the TRACE_DATA_DIR environment variable can set it instead, and if neither is set, it's taken from the config file or else the platform's usual data directory
+    data_dir: Option<PathBuf>,
+    #[argh(option)]
+    /// name of the session to use among several logged into the same account (e.g. 'laptop'), or to log in under; sessions without one are used when unspecified
+    profile: Option<String>,
+    #[argh(switch)]
+    /// print errors to stderr as JSON objects, one per line, rather than as text; either way, the exit code tells what kind of failure it was (1 for other failures, 2 for authentication, 3 for rooms not found, 4 for network errors, 5 for exports which left out some of the rooms asked for, 6 for invalid arguments or config, 7 for redactions which left out some of the events found)
+    json_errors: bool,
+    #[argh(switch, short = 'v')]
+    /// log what's going on in more detail, e.g. each page of events fetched, for tracking down where a stuck export is stuck; shorthand for '--log-level trace=debug'
+    verbose: bool,
+    #[argh(option)]
+    /// how much to log, as a level (error, warn, info, debug, or trace) or as filter directives (e.g. 'trace=debug,matrix_sdk=info'); the RUST_LOG environment variable can set it instead, and if neither is set, only Trace's own warnings are logged
+    log_level: Option<String>,
+    #[argh(option)]
+    /// file to append logs to rather than printing them to stderr
+    log_file: Option<PathBuf>,
+    #[argh(subcommand)]
+    subcommand: RootSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum RootSubcommand {
+    Analyze(AnalyzeCommand),
+    Daemon(DaemonCommand),
+    Export(Export),
+    Import(Import),
+    Index(IndexCommand),
+    Invites(InvitesCommand),
+    Keys(KeysCommand),
+    ListRooms(ListRooms),
+    ListSpaces(ListSpaces),
+    Media(MediaCommand),
+    Redact(Redact),
+    Room(RoomCommand),
+    Search(Search),
+    Session(SessionCommand),
+    Stats(Stats),
+    Tui(TuiCommand),
+    VerifyExport(VerifyExport),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "analyze")]
+/// Look over rooms' history without exporting it
+struct AnalyzeCommand {
+    #[argh(subcommand)]
+    subcommand: AnalyzeSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum AnalyzeSubcommand {
+    Utd(AnalyzeUtd),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "utd")]
+/// Count the events in rooms which can't be decrypted, and when they're from
+struct AnalyzeUtd {
+    #[argh(positional)]
+    /// user_id (of the form @alice:example.com) or session alias to analyze rooms accessible to
+    user_id: String,
+    #[argh(positional)]
+    /// space-separated list of room IDs, aliases, or display names to analyze, as for export
+    rooms: Vec<String>,
+    #[argh(option)]
+    /// maximum number of events to look at per room; if unspecified, the room's full history is analyzed
+    limit: Option<usize>,
+    #[argh(switch)]
+    /// start from the most recent event and work backwards; combine with --limit to analyze only a room's most recent events
+    newest_first: bool,
+    #[argh(option, default = "8")]
+    /// maximum number of times to retry each request the homeserver rate-limits; defaults to 8
+    max_retries: u32,
+    #[argh(switch)]
+    /// output as JSON, including a breakdown by missing megolm session
+    json: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "daemon")]
+/// Run incremental exports on the schedules set out in a daemon config file, until stopped with Ctrl-C
+struct DaemonCommand {
+    #[argh(option, short = 'c')]
+    /// path of the daemon config file, listing each job's account, rooms and spaces, formats, output directory, and cron-style schedule; if unspecified, defaults to daemon.toml alongside config.toml
+    config: Option<PathBuf>,
+    #[argh(switch)]
+    /// run every job once straight away and then exit, rather than waiting on their schedules; useful for checking a new config
+    once: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export")]
+/// Export logs from rooms
+struct Export {
+    #[argh(positional)]
+    /// user_id (of the form @alice:example.com) or session alias to export rooms accessible to; can be left out to use the default session, unless the first room given looks like a user ID (as with --dm)
+    user_id: String,
+    #[argh(positional)]
+    /// space-separated list of room IDs (of the form !abcdefghijklmnopqr:example.com), aliases (of the form #room:example.com), or display names (e.g. 'Example Room') to export; names and aliases may contain '*' and '?' wildcards (e.g. 'Project *') to export every matching room
+    rooms: Vec<String>,
+    #[argh(switch)]
+    /// export world-readable rooms which the account hasn't joined by peeking into them, for room IDs and aliases which don't match any joined room
+    peek: bool,
+    #[argh(switch)]
+    /// fetch history through the Synapse admin API, which needs a server-admin account; gets each room's complete history regardless of history visibility, and exports rooms given by room ID or alias which the account hasn't joined, though events in those can't be decrypted
+    admin: bool,
+    #[argh(switch)]
+    /// export only the events already cached in the local store, without contacting the homeserver; the cache only holds events received through syncs since it was first enabled, so this usually misses older history; can't be combined with --peek, --admin, --incremental, --read-receipts, --room-data, --avatars, or --media
+    offline: bool,
+    #[argh(switch)]
+    /// treat the positional arguments as user IDs (of the form @bob:example.com) and export every direct-message room with each of them
+    dm: bool,
+    #[argh(switch)]
+    /// replace user IDs and display names throughout the export with stable pseudonyms (e.g. 'User-07'), leaving out avatars and media
+    pseudonymize: bool,
+    #[argh(option)]
+    /// regular expression to match room names and aliases against, exporting every matching room; flag can be used multiple times
+    room_regex: Vec<String>,
+    #[argh(option, short = 'f')]
+    /// format to export to; valid options are 'json' and 'txt'; flag can be used multiple times to export multiple formats in a single run; if flag is unspecified, default output format is json
+    formats: Vec<String>,
+    #[argh(option, short = 'o')]
+    /// path of directory to output files to, or '-' to write a single room's export to stdout (as JSON lines, for JSON); if unspecified, defaults to current directory
+    output: Option<PathBuf>,
+    #[argh(option)]
+    /// S3-compatible bucket to upload the export to, room by room, rather than keeping it on local disk; --output then sets the directory to stage each room in before uploading, defaulting to a temporary one; credentials are read from the AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, and AWS_SESSION_TOKEN environment variables, and the region from AWS_REGION; can't be combined with --follow or --index
+    s3_bucket: Option<String>,
+    #[argh(option)]
+    /// URL of the S3-compatible service to upload to (e.g. 'https://minio.example.com:9000'); if unspecified, defaults to AWS's endpoint for the region
+    s3_endpoint: Option<String>,
+    #[argh(option)]
+    /// prefix to put before every key uploaded to the bucket (e.g. 'trace/work'), as a directory to export into
+    s3_prefix: Option<String>,
+    #[argh(option)]
+    /// template for output filenames (e.g. '{alias|id}-{date}'); placeholders are {name}, {alias}, {id}, {server}, and {date}, with '|' separating fallbacks for rooms lacking a name or alias; if unspecified, files are named after the room's name, alias, and ID
+    name_template: Option<String>,
+    #[argh(switch)]
+    /// download each message sender's avatar into an 'avatars' subdirectory of the output directory, referenced from JSON output
+    avatars: bool,
+    #[argh(switch)]
+    /// download message attachments into a 'media' subdirectory of the output directory shared by all exported rooms, referenced from JSON and txt output
+    media: bool,
+    #[argh(switch)]
+    /// leave sender avatars undownloaded, even when the config file turns them on
+    no_avatars: bool,
+    #[argh(switch)]
+    /// leave message attachments undownloaded, even when the config file turns them on
+    no_media: bool,
+    #[argh(switch)]
+    /// export only events newer than the previous --incremental export of each room, writing them to separate delta files alongside the earlier output; can't be combined with --from-event, --to-event, --limit, or --newest-first
+    incremental: bool,
+    #[argh(switch)]
+    /// write events out as each page of them is fetched, rather than holding each room's whole history in memory first; edits, reactions, replies, and thread grouping are then only matched up within each page of up to --page-size events; can't be combined with --split
+    stream: bool,
+    #[argh(switch)]
+    /// once the export's done, keep syncing and append each new event in the exported rooms to the end of their output as it arrives, until stopped with Ctrl-C; txt output is appended to directly, and JSON output goes into a '.jsonl' file of JSON lines beside each room's export; can't be combined with --offline, --split, --newest-first, or --to-event
+    follow: bool,
+    #[argh(switch, short = 'q')]
+    /// don't show progress bars or per-room progress while exporting; warnings and the closing summary are still printed
+    quiet: bool,
+    #[argh(option)]
+    /// URL to POST a JSON summary of the export to once it's over, whether or not it succeeded, listing each room's status, event count, and output files, plus any failures
+    webhook_url: Option<String>,
+    #[argh(option)]
+    /// shell command to run once the export's over, whether or not it succeeded, with the same JSON summary as for --webhook-url on its stdin
+    hook_command: Option<String>,
+    #[argh(option)]
+    /// split each room's export into multiple files; valid options are 'monthly', 'yearly', or a size like '100MB' (approximate, measured by the events' JSON)
+    split: Option<String>,
+    #[argh(option, short = 'j', default = "4")]
+    /// number of rooms to fetch events from at once; output is still written one room at a time, and --stream exports always run one room at a time; defaults to 4
+    jobs: usize,
+    #[argh(option)]
+    /// event ID (of the form $abcdefghijklmnopqrstuvwxyz) to begin the export at, inclusive; only usable when exporting a single room
+    from_event: Option<String>,
+    #[argh(option)]
+    /// event ID (of the form $abcdefghijklmnopqrstuvwxyz) to end the export at, inclusive; only usable when exporting a single room
+    to_event: Option<String>,
+    #[argh(option)]
+    /// maximum number of events to export per room; if unspecified, the room's full history is exported
+    limit: Option<usize>,
+    #[argh(switch)]
+    /// export starting from the most recent event and working backwards, writing output in reverse-chronological order; combine with --limit to export only a room's most recent events
+    newest_first: bool,
+    #[argh(option)]
+    /// ask the account's other devices for the keys to events which couldn't be decrypted, waiting this many seconds per room for them to be forwarded before trying again; with --stream, the wait is per page instead; can't be combined with --offline
+    request_keys: Option<u64>,
+    #[argh(option, default = "8")]
+    /// maximum number of times to retry each request the homeserver rate-limits, waiting as long as it asks or else backing off exponentially; defaults to 8
+    max_retries: u32,
+    #[argh(option, default = "2")]
+    /// maximum number of times to retry a page of events which fails to load for reasons other than rate-limiting, before recording the rest of the room as a gap in the export; defaults to 2
+    backfill_retries: u32,
+    #[argh(option, default = "1000")]
+    /// number of events to request per page while paginating through rooms; smaller pages go easier on small servers; defaults to 1000, which some servers cap requests at anyway
+    page_size: u16,
+    #[argh(option, default = "0")]
+    /// number of milliseconds to wait between each room's requests while paginating; defaults to 0
+    request_delay_ms: u64,
+    #[argh(option, default = "24")]
+    /// number of hours to reuse senders' display names and avatars from earlier exports for, rather than looking them up again; defaults to 24
+    profile_cache_hours: u32,
+    #[argh(option)]
+    /// comma-separated list of event types (e.g. 'm.room.message,m.reaction') to export, with stable and unstable-prefixed names of poll events (e.g. 'm.poll.start' and 'org.matrix.msc3381.poll.start') matching either; if unspecified, all event types are exported
+    event_types: Option<String>,
+    #[argh(option)]
+    /// comma-separated list of event types (e.g. 'm.room.member') to leave out of the export, matched as for --event-types
+    exclude_event_types: Option<String>,
+    #[argh(option)]
+    /// regular expression to match message bodies against; if specified, only matching events (and any requested context) are exported
+    grep: Option<String>,
+    #[argh(option, short = 'C', default = "0")]
+    /// number of events before and after each --grep match to include as context; defaults to 0
+    context: usize,
+    #[argh(switch)]
+    /// in JSON output, include a table of each sender's current display name and avatar URL
+    sender_profiles: bool,
+    #[argh(switch)]
+    /// include read receipts, marking which members have read up to which events and when, along with the account's own read marker; they go on the events they point at in JSON output, and beneath them in txt output; only each member's latest receipt is known, and only for joined rooms
+    read_receipts: bool,
+    #[argh(switch)]
+    /// include each joined room's pinned events, resolved to the events themselves, along with the account's own account data for the room (e.g. its tags); they go in the room section of JSON output and the header of txt output
+    room_data: bool,
+    #[argh(switch)]
+    /// write JSON output without pretty-printing
+    compact: bool,
+    #[argh(switch)]
+    /// in txt output, group thread replies under their root messages rather than leaving them in timeline order
+    group_threads: bool,
+    #[argh(switch)]
+    /// in txt output, mark messages shown with their latest edit applied with '(edited)'
+    mark_edits: bool,
+    #[argh(switch)]
+    /// in txt output, list the previous versions of each edited message beneath it
+    edit_history: bool,
+    #[argh(switch)]
+    /// in txt output, append each event's ID and matrix.to permalink to it
+    permalinks: bool,
+    #[argh(switch)]
+    /// in txt output, leave out the separator lines otherwise inserted whenever the date changes
+    no_day_separators: bool,
+    #[argh(switch)]
+    /// in txt output, convert messages' HTML formatting (links, emphasis, code blocks, lists, and so forth) to Markdown rather than using their plaintext fallbacks
+    markdown: bool,
+    #[argh(switch)]
+    /// in txt output, attribute messages using their senders' current display names rather than the ones they had when sending them
+    current_display_names: bool,
+    #[argh(option)]
+    /// time zone to display txt output timestamps in; valid options are 'utc', 'local', or an IANA time zone name (e.g. 'Europe/Berlin'); if unspecified, defaults to UTC
+    timezone: Option<String>,
+    #[argh(option)]
+    /// strftime-style format string for txt output timestamps (e.g. '%Y-%m-%d %H:%M'); if unspecified, timestamps are formatted per RFC 3339
+    timestamp_format: Option<String>,
+    #[argh(option)]
+    /// also export the rooms each room was upgraded from; valid options are 'merged' (one chronological timeline per upgrade chain) and 'separate' (one set of files per room in the chain)
+    follow_upgrades: Option<String>,
+    #[argh(switch)]
+    /// once exporting finishes, add the output directory's JSON exports to the full-text index in Trace's data directory, for 'trace index search'; requires JSON output to a directory
+    index: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+/// Repost the messages from a Trace JSON export into a room, as when moving history off a homeserver that's gone
+struct Import {
+    #[argh(positional)]
+    /// user_id (of the form @alice:example.com) or session alias to post messages as
+    user_id: String,
+    #[argh(positional)]
+    /// path of the JSON or JSON lines export file to import; attachments are reuploaded from the 'media' directory beside it, if the export downloaded them
+    export: PathBuf,
+    #[argh(positional)]
+    /// room ID, alias, or display name of the joined room to post messages into; encrypted rooms aren't supported
+    room: String,
+    #[argh(switch)]
+    /// ask the homeserver to give each message its original timestamp, rather than putting the timestamp into the message text; only works when posting as an appservice, so timestamps still go into the message text if the first message posted shows the homeserver ignoring it
+    massage_timestamps: bool,
+    #[argh(option)]
+    /// ID of an event in the export to start after, for picking an interrupted import back up where it stopped
+    after: Option<String>,
+    #[argh(option)]
+    /// time zone to write original timestamps into message text in; valid options are 'utc', 'local', or an IANA time zone name (e.g. 'Europe/Berlin'); if unspecified, defaults to UTC
+    timezone: Option<String>,
+    #[argh(option, default = "8")]
+    /// maximum number of times to retry each request the homeserver rate-limits; defaults to 8
+    max_retries: u32,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "index")]
+/// Build and search a full-text index of exported messages
+struct IndexCommand {
+    #[argh(subcommand)]
+    subcommand: IndexSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum IndexSubcommand {
+    Build(IndexBuild),
+    Search(IndexSearch),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "build")]
+/// Add JSON exports to the full-text index; reindexing an export already in it replaces its events rather than duplicating them
+struct IndexBuild {
+    #[argh(positional)]
+    /// space-separated list of JSON export files, or directories of them, to index
+    exports: Vec<PathBuf>,
+    #[argh(option)]
+    /// directory holding the index; if unspecified, defaults to an 'index' directory within Trace's data directory
+    index_dir: Option<PathBuf>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "search")]
+/// Search the full-text index, best matches first
+struct IndexSearch {
+    #[argh(positional)]
+    /// text to search for; "quoted phrases" match exactly, and terms can be required with '+' or excluded with '-'
+    query: String,
+    #[argh(option)]
+    /// only show messages from this user ID; can be given multiple times
+    sender: Vec<String>,
+    #[argh(option)]
+    /// only show messages sent on or after this date (of the form 2020-01-31, in UTC)
+    from: Option<String>,
+    #[argh(option)]
+    /// only show messages sent on or before this date (of the form 2020-01-31, in UTC)
+    to: Option<String>,
+    #[argh(option, default = "20")]
+    /// maximum number of matches to show; defaults to 20
+    limit: usize,
+    #[argh(option)]
+    /// directory holding the index; if unspecified, defaults to an 'index' directory within Trace's data directory
+    index_dir: Option<PathBuf>,
+    #[argh(switch, short = 'j')]
+    /// output matches as JSON rather than as human-readable text
+    json: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "invites")]
+/// See and respond to an account's pending room invites
+struct InvitesCommand {
+    #[argh(subcommand)]
+    subcommand: InvitesSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum InvitesSubcommand {
+    Accept(InvitesAccept),
+    Decline(InvitesDecline),
+    List(InvitesList),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "accept")]
+/// Join rooms an account has been invited to
+struct InvitesAccept {
+    #[argh(positional)]
+    /// space-separated list of room IDs, aliases, or names of invites to accept
+    rooms: Vec<String>,
+    #[argh(switch)]
+    /// accept every pending invite, rather than just the rooms given
+    all: bool,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to accept invites with; if unspecified, the default session is used
+    user: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "decline")]
+/// Reject room invites an account has received
+struct InvitesDecline {
+    #[argh(positional)]
+    /// space-separated list of room IDs, aliases, or names of invites to decline
+    rooms: Vec<String>,
+    #[argh(switch)]
+    /// decline every pending invite, rather than just the rooms given
+    all: bool,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to decline invites with; if unspecified, the default session is used
+    user: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List an account's pending room invites
+struct InvitesList {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to list invites of; if unspecified, the default session is used
+    user_id: Option<String>,
+    #[argh(switch, short = 'j')]
+    /// display invite list as JSON rather than as human-readable text
+    json: bool,
+    #[argh(switch)]
+    /// leave out the header row (and the line introducing the table)
+    no_header: bool,
+    #[argh(switch)]
+    /// separate columns with tabs rather than aligning them, without truncating anything to fit the terminal, for scripting
+    plain: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "keys")]
+/// Manage a session's E2E encryption keys
+struct KeysCommand {
+    #[argh(subcommand)]
+    subcommand: KeysSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum KeysSubcommand {
+    EnableBackup(KeysEnableBackup),
+    Export(KeysExport),
+    Import(KeysImport),
+    RestoreBackup(KeysRestoreBackup),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "enable-backup")]
+/// Set up server-side backup of room keys for an account without one, printing the recovery key for it
+struct KeysEnableBackup {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to enable backup with; if unspecified, the default session is used
+    user_id: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export")]
+/// Write a session's room keys to a passphrase-encrypted file, in the format Element uses for key exports
+struct KeysExport {
+    #[argh(positional)]
+    /// path of the file to write the keys to
+    destination: PathBuf,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias whose keys are to be exported; if unspecified, the default session is used
+    user: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+/// Add room keys to a session from a passphrase-encrypted key export, e.g. one made with Element
+struct KeysImport {
+    #[argh(positional)]
+    /// path of the key export to import
+    source: PathBuf,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to import keys to; if unspecified, the default session is used
+    user: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "restore-backup")]
+/// Download room keys from the account's server-side backup, for decrypting older history
+struct KeysRestoreBackup {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to restore keys to; if unspecified, the default session is used
+    user_id: Option<String>,
+    #[argh(option)]
+    /// the account's recovery key or security passphrase, for getting at the backup; unnecessary if the session has already been recovered or enabled the backup itself
+    recovery_key: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list-rooms")]
+/// List rooms accessible from a given user ID's login
+struct ListRooms {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to list rooms from; if unspecified, the default session is used
+    user_id: Option<String>,
+    #[argh(switch, short = 'j')]
+    /// display room list as JSON rather than as human-readable text
+    json: bool,
+    #[argh(switch)]
+    /// leave out the header row (and the line introducing the table)
+    no_header: bool,
+    #[argh(switch)]
+    /// separate columns with tabs rather than aligning them, without truncating anything to fit the terminal, for scripting
+    plain: bool,
+    #[argh(option)]
+    /// what to sort rooms by: 'name' (the default), 'members' (most first), 'activity' (most recent first), or 'id'
+    sort: Option<String>,
+    #[argh(switch)]
+    /// only list encrypted rooms
+    encrypted: bool,
+    #[argh(switch)]
+    /// only list unencrypted rooms
+    unencrypted: bool,
+    #[argh(option)]
+    /// only list rooms directly within the given space, identified by ID, alias, or name
+    space: Option<String>,
+    #[argh(option)]
+    /// only list rooms with the given tag: 'favourite' or 'low-priority'
+    tag: Option<String>,
+    #[argh(option)]
+    /// only list rooms whose names contain the given text, ignoring case
+    name: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list-spaces")]
+/// List the spaces a given user ID's login has joined, as a tree of their subspaces and rooms
+struct ListSpaces {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to list spaces from; if unspecified, the default session is used
+    user_id: Option<String>,
+    #[argh(switch, short = 'j')]
+    /// display space hierarchy as JSON rather than as a human-readable tree
+    json: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "media")]
+/// Inspect or repair media in existing exports
+struct MediaCommand {
+    #[argh(subcommand)]
+    subcommand: MediaSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum MediaSubcommand {
+    Verify(MediaVerify),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "verify")]
+/// Check an existing export's media directory for missing or corrupted files
+struct MediaVerify {
+    #[argh(positional)]
+    /// path of the export directory to verify
+    export_dir: PathBuf,
+    #[argh(switch)]
+    /// redownload missing, corrupted, or unrecorded media, rather than only reporting it
+    redownload: bool,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to redownload media with; if unspecified, the default session is used
+    user: Option<String>,
+    #[argh(option, default = "8")]
+    /// maximum number of times to retry each redownload the homeserver rate-limits; defaults to 8
+    max_retries: u32,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "redact")]
+/// Redact matching events from a room, by default only the account's own; lists what matches and asks before redacting anything
+struct Redact {
+    #[argh(positional)]
+    /// user_id (of the form @alice:example.com) or session alias to redact as
+    user_id: String,
+    #[argh(positional)]
+    /// room ID, alias, or display name of the joined room to redact events from
+    room: String,
+    #[argh(option)]
+    /// only redact events from this user; redacting anyone else's events needs the power to in the room; if unspecified, defaults to the account redacting
+    sender: Option<String>,
+    #[argh(switch)]
+    /// redact matching events from every sender, rather than just one; needs the power to redact other people's events
+    all_senders: bool,
+    #[argh(option)]
+    /// only redact events sent from this date (of the form 2020-01-31) or RFC 3339 timestamp on
+    since: Option<String>,
+    #[argh(option)]
+    /// only redact events sent through this date (of the form 2020-01-31) or RFC 3339 timestamp
+    until: Option<String>,
+    #[argh(option)]
+    /// only redact events whose message text matches this regular expression
+    grep: Option<String>,
+    #[argh(option)]
+    /// reason to attach to each redaction, which other members of the room can see
+    reason: Option<String>,
+    #[argh(option)]
+    /// maximum number of events to look through, counting back from the present; if unspecified, the room's full history back to --since is searched
+    limit: Option<usize>,
+    #[argh(option, default = "8")]
+    /// maximum number of times to retry each request the homeserver rate-limits; defaults to 8
+    max_retries: u32,
+    #[argh(switch)]
+    /// only list matching events, without redacting anything
+    dry_run: bool,
+    #[argh(switch, short = 'y')]
+    /// redact without asking for confirmation first
+    yes: bool,
+    #[argh(switch, short = 'j')]
+    /// list matching events as JSON rather than as human-readable text
+    json: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "room")]
+/// Join or leave rooms
+struct RoomCommand {
+    #[argh(subcommand)]
+    subcommand: RoomSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum RoomSubcommand {
+    Join(RoomJoin),
+    Leave(RoomLeave),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "join")]
+/// Join a room by its ID or alias, e.g. before exporting it
+struct RoomJoin {
+    #[argh(positional)]
+    /// room ID (of the form !abcdefghijklmnopqr:example.com) or alias (of the form #room:example.com) to join
+    room: String,
+    #[argh(option)]
+    /// server to join through, for room IDs the account's homeserver can't reach on its own (e.g. with federation restricted); can be given multiple times
+    via: Vec<String>,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to join with; if unspecified, the default session is used
+    user: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "leave")]
+/// Leave a room, e.g. once it's been exported
+struct RoomLeave {
+    #[argh(positional)]
+    /// room ID, alias, or name of the room to leave
+    room: String,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to leave with; if unspecified, the default session is used
+    user: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "search")]
+/// Search message bodies in rooms' history
+struct Search {
+    #[argh(positional)]
+    /// user_id (of the form @alice:example.com) or session alias to search rooms accessible to
+    user_id: String,
+    #[argh(positional)]
+    /// text to search for; matched case-insensitively in encrypted rooms, and however the homeserver does full-text search in unencrypted ones
+    query: String,
+    #[argh(option)]
+    /// room ID, alias, or display name to search, as for export; can be given multiple times; if unspecified, every joined room is searched
+    room: Vec<String>,
+    #[argh(option)]
+    /// maximum number of events to look through per room when searching locally; if unspecified, the room's full history is searched
+    limit: Option<usize>,
+    #[argh(option, default = "8")]
+    /// maximum number of times to retry each request the homeserver rate-limits; defaults to 8
+    max_retries: u32,
+    #[argh(switch, short = 'j')]
+    /// output matches as JSON rather than as human-readable text
+    json: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "session")]
+/// Add, remove, list, or modify sessions
+struct SessionCommand {
+    #[argh(subcommand)]
+    subcommand: SessionSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum SessionSubcommand {
+    BootstrapCrossSigning(SessionBootstrapCrossSigning),
+    Devices(SessionDevicesCommand),
+    ExportSession(SessionExportSession),
+    ImportSession(SessionImportSession),
+    List(SessionList),
+    Login(SessionLogin),
+    Logout(SessionLogout),
+    Register(SessionRegister),
+    Rename(SessionRename),
+    SetAlias(SessionSetAlias),
+    SetDefault(SessionSetDefault),
+    Status(SessionStatus),
+    Verify(SessionVerify),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "bootstrap-cross-signing")]
+/// Set up cross-signing keys for an account which has none, so that other clients can tell the session apart from an impostor
+struct SessionBootstrapCrossSigning {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to set up cross-signing with; if unspecified, the default session is used
+    user_id: Option<String>,
+    #[argh(switch)]
+    /// replace the account's existing cross-signing keys, leaving all its other devices and everyone it's verified unverified; asks for confirmation first
+    reset: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "devices")]
+/// List or delete the devices logged into an account
+struct SessionDevicesCommand {
+    #[argh(subcommand)]
+    subcommand: DevicesSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum DevicesSubcommand {
+    Delete(DevicesDelete),
+    List(DevicesList),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "delete")]
+/// Log out other devices of an account, e.g. stale ones from old clients
+struct DevicesDelete {
+    #[argh(positional)]
+    /// space-separated list of device IDs to delete
+    device_ids: Vec<String>,
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to delete devices with; if unspecified, the default session is used
+    user: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List every device logged into an account
+struct DevicesList {
+    #[argh(option, short = 'u')]
+    /// user id (of the form @alice:example.com) or session alias to list devices of; if unspecified, the default session is used
+    user: Option<String>,
+    #[argh(switch, short = 'j')]
+    /// display device list as JSON rather than as human-readable text
+    json: bool,
+    #[argh(switch)]
+    /// leave out the header row (and the line introducing the table)
+    no_header: bool,
+    #[argh(switch)]
+    /// separate columns with tabs rather than aligning them, without truncating anything to fit the terminal, for scripting
+    plain: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export-session")]
+/// Bundle a logged-in session and its encryption keys into a passphrase-encrypted file, for moving to another machine
+struct SessionExportSession {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias whose session is to be exported
+    user_id: String,
+    #[argh(positional)]
+    /// path of the file to write the bundle to
+    destination: PathBuf,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import-session")]
+/// Restore a session from a file written by export-session
+struct SessionImportSession {
+    #[argh(positional)]
+    /// path of the bundle to import
+    source: PathBuf,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List currently-logged-in accounts
+struct SessionList {
+    #[argh(switch, short = 'j')]
+    /// display session list as JSON rather than as human-readable text
+    json: bool,
+    #[argh(switch)]
+    /// leave out the header row (and the line introducing the table)
+    no_header: bool,
+    #[argh(switch)]
+    /// separate columns with tabs rather than aligning them, without truncating anything to fit the terminal, for scripting
+    plain: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "login")]
+/// Log in a new account, creating a new session
+struct SessionLogin {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) to be logged in
+    user_id: String,
+    #[argh(positional)]
+    /// optional session name for use in place of the default randomized one
+    session_name: Option<String>,
+    #[argh(option)]
+    /// path of a file to read the account's password from, instead of prompting for it
+    password_file: Option<PathBuf>,
+    #[argh(switch)]
+    /// read the account's password from the first line of stdin, instead of prompting for it
+    password_stdin: bool,
+    #[argh(option)]
+    /// URL of the homeserver's client API (e.g. 'https://matrix.example.com'), remembered for the session's later use; if unspecified, it's taken from the config file's homeservers table, or else discovered from the user ID's server name
+    homeserver: Option<String>,
+    #[argh(option)]
+    /// HTTP or SOCKS5 proxy to connect through (e.g. 'http://proxy.example.com:3128' or 'socks5h://127.0.0.1:9050'), remembered for the session's later use; the TRACE_PROXY environment variable overrides it, and if neither is set, HTTPS_PROXY and ALL_PROXY are respected
+    proxy: Option<String>,
+    #[argh(option)]
+    /// path of a PEM file of extra CA certificates to trust, for homeservers with certificates from private CAs; remembered for the session's later use
+    ca_bundle: Option<PathBuf>,
+    #[argh(switch)]
+    /// DANGEROUS: skip verifying the homeserver's TLS certificate altogether, leaving the connection open to interception; remembered for the session's later use
+    insecure_skip_tls_verification: bool,
+    #[argh(option)]
+    /// the account's recovery key or security passphrase, to verify the new session with and restore backed-up room keys to it straight after login
+    recovery_key: Option<String>,
+    #[argh(switch)]
+    /// keep the session's tokens in the sessions file in plaintext, rather than in the OS keyring; this is the default anyway where there's no keyring available
+    plaintext_tokens: bool,
+    #[argh(switch)]
+    /// encrypt the sessions file and this session's local store with a passphrase, prompting for a new one if the sessions file isn't encrypted already; the TRACE_PASSPHRASE environment variable can supply it instead
+    encrypt: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "logout")]
+/// Log out a previously-logged-in account
+struct SessionLogout {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to be logged out; if unspecified, the default session is used
+    user_id: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "register")]
+/// Register a new account on a homeserver which allows it, creating a new session for it
+struct SessionRegister {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) to be registered
+    user_id: String,
+    #[argh(positional)]
+    /// optional session name for use in place of the default randomized one
+    session_name: Option<String>,
+    #[argh(option)]
+    /// registration token to register with, for homeservers requiring one
+    registration_token: Option<String>,
+    #[argh(option)]
+    /// path of a file to read the new account's password from, instead of prompting for it
+    password_file: Option<PathBuf>,
+    #[argh(switch)]
+    /// read the new account's password from the first line of stdin, instead of prompting for it
+    password_stdin: bool,
+    #[argh(option)]
+    /// URL of the homeserver's client API (e.g. 'https://matrix.example.com'), remembered for the session's later use; if unspecified, it's taken from the config file's homeservers table, or else discovered from the user ID's server name
+    homeserver: Option<String>,
+    #[argh(switch)]
+    /// keep the session's tokens in the sessions file in plaintext, rather than in the OS keyring; this is the default anyway where there's no keyring available
+    plaintext_tokens: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "rename")]
+/// Rename a logged-in session
+struct SessionRename {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to be renamed
+    user_id: String,
+    #[argh(positional)]
+    /// new name for session
+    session_name: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "status")]
+/// Check that a logged-in session is still working, and report on its encryption state and local store
+struct SessionStatus {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to check the session of; if unspecified, the default session is used
+    user_id: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "verify")]
+/// Verify a logged-in session for purposes of E2E encryption
+struct SessionVerify {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to verify your session with; if unspecified, the default session is used
+    user_id: Option<String>,
+    #[argh(option)]
+    /// the account's recovery key or security passphrase, to verify with through secret storage rather than with another device; also restores backed-up room keys
+    recovery_key: Option<String>,
+    #[argh(option)]
+    /// kind of short authentication string to compare with the other device: 'emoji' or 'decimal'; if unspecified, emoji is used
+    sas: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "set-alias")]
+/// Give a logged-in session a short alias to use in place of its user ID
+struct SessionSetAlias {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or current alias of the session
+    user_id: String,
+    #[argh(positional)]
+    /// new alias for the session (e.g. 'work'); if unspecified, the session's alias is removed
+    alias: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "set-default")]
+/// Mark a logged-in session as the one to use when no user ID is given
+struct SessionSetDefault {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or alias of the session
+    user_id: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "stats")]
+/// Count rooms' messages and media, broken down by sender and over time
+struct Stats {
+    #[argh(positional)]
+    /// user_id (of the form @alice:example.com) or session alias to count messages in rooms accessible to
+    user_id: String,
+    #[argh(positional)]
+    /// space-separated list of room IDs, aliases, or display names to count messages in, as for export
+    rooms: Vec<String>,
+    #[argh(option)]
+    /// maximum number of events to look at per room; if unspecified, the room's full history is counted
+    limit: Option<usize>,
+    #[argh(switch)]
+    /// start from the most recent event and work backwards; combine with --limit to count only a room's most recent events
+    newest_first: bool,
+    #[argh(option, default = "8")]
+    /// maximum number of times to retry each request the homeserver rate-limits; defaults to 8
+    max_retries: u32,
+    #[argh(option)]
+    /// time zone to break messages down by month, day, and hour in; valid options are 'utc', 'local', or an IANA time zone name (e.g. 'Europe/Berlin'); if unspecified, defaults to UTC
+    timezone: Option<String>,
+    #[argh(switch, short = 'j')]
+    /// output as JSON rather than as human-readable text
+    json: bool,
+    #[argh(switch)]
+    /// output as CSV, with a row per room, statistic, and key (e.g. a sender or month), for spreadsheets and scripts
+    csv: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "tui")]
+/// Browse sessions and rooms, and configure and run exports, from an interactive terminal interface
+struct TuiCommand {
+    #[argh(positional)]
+    /// user id (of the form @alice:example.com) or session alias to open straight away; if unspecified, sessions are listed to pick from
+    user_id: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "verify-export")]
+/// Check an existing export against the manifest written with it, for missing, altered, or unreadable files, and list the gaps in history it recorded
+struct VerifyExport {
+    #[argh(positional)]
+    /// path of the export directory to verify
+    export_dir: PathBuf,
+    #[argh(switch)]
+    /// leave the export's media directory unchecked
+    skip_media: bool,
+}
+
+///////////////////////
+//   Non-arg types   //
+///////////////////////
+
+enum RoomSortKey {
+    Name,
+    Members,
+    Activity,
+    Id,
+}
+
+enum RoomTag {
+    Favourite,
+    LowPriority,
+}
+
+#[derive(Serialize)]
+struct PrintableRoom {
+    name: Option<String>,
+    alias: Option<String>,
+    id: String,
+    joined_member_count: u64,
+    is_encrypted: bool,
+    is_space: bool,
+    is_direct: bool,
+    latest_event_ts: Option<i64>, // None if the account can't see any of the room's events
+}
+
+impl PrintableRoom {
+    fn from_room_info(room_info: RoomWithCachedInfo, latest_event_ts: Option<i64>) -> Self {
+        Self {
+            name: room_info.name,
+            alias: room_info.canonical_alias.map(|alias| alias.to_string()),
+            id: room_info.id.to_string(),
+            joined_member_count: room_info.joined_member_count,
+            is_encrypted: room_info.is_encrypted,
+            is_space: room_info.is_space,
+            is_direct: room_info.is_direct,
+            latest_event_ts,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PrintableDevice {
+    device_id: String,
+    display_name: Option<String>,
+    last_seen_ip: Option<String>,
+    last_seen_ts: Option<i64>,
+    is_current: bool,
+}
+
+#[derive(Serialize)]
+struct PrintableSession {
+    user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    is_default: bool,
+    name: String,
+}
+
+// A room's running totals during an export, as shown on its progress bar.
+struct RoomProgressBar {
+    bar: ProgressBar,
+    page_count: usize,
+    fetched_event_count: usize,
+    processed_event_count: usize, // Counts only events left after filtering
+    media_count: usize,
+    written_byte_count: usize,
+}
+
+impl RoomProgressBar {
+    // Total event counts aren't known ahead of time, so each bar is just a spinner with the counts so far.
+    fn new(multi_progress: &MultiProgress, room_id: &OwnedRoomId) -> Self {
+        let bar = multi_progress.add(ProgressBar::new_spinner());
+        bar.set_style(ProgressStyle::with_template("{spinner} {prefix}: {msg}").unwrap());
+        bar.set_prefix(room_id.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        let room_progress_bar = Self {
+            bar,
+            page_count: 0,
+            fetched_event_count: 0,
+            processed_event_count: 0,
+            media_count: 0,
+            written_byte_count: 0,
+        };
+        room_progress_bar.update();
+
+        room_progress_bar
+    }
+
+    // Bars get added as their rooms first turn up in the progress, which is usually but not always with RoomStarted.
+    fn get_or_add<'a>(room_progress_bars: &'a mut HashMap<OwnedRoomId, Self>, multi_progress: &MultiProgress, room_id: &OwnedRoomId) -> &'a mut Self {
+        room_progress_bars.entry(room_id.clone()).or_insert_with(|| Self::new(multi_progress, room_id))
+    }
+
+    fn update(&self) {
+        self.bar.set_message(format!("{} pages ({} events) fetched, {} events processed, {} media files downloaded", self.page_count, self.fetched_event_count, self.processed_event_count, self.media_count));
+    }
+}
+
+// Where logs bound for stderr actually go. While progress bars are up, they're printed around the bars rather than over them, and while the TUI has the screen, they're dropped, since there's nowhere to show them that wouldn't garble it.
+enum StderrLogTarget {
+    Stderr,
+    ProgressBars(MultiProgress),
+    Discarded,
+}
+
+static STDERR_LOG_TARGET: Mutex<StderrLogTarget> = Mutex::new(StderrLogTarget::Stderr);
+
+// Watches the token of the session the command logged into with login, for run to tell a token rejected partway through apart from whatever request it happened to fail. Each command only logs into the one session.
+static TOKEN_REJECTION: Mutex<Option<TokenRejection>> = Mutex::new(None);
+
+// Hands out a StderrLog per event, for the tracing subscriber.
+struct StderrLogWriter;
+
+impl<'a> MakeWriter<'a> for StderrLogWriter {
+    type Writer = StderrLog;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        StderrLog(Vec::new())
+    }
+}
+
+// Collects a whole event before writing it out to STDERR_LOG_TARGET, so that it's never split around a progress bar redraw.
+struct StderrLog(Vec<u8>);
+
+impl Write for StderrLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for StderrLog {
+    fn drop(&mut self) {
+        let target = STDERR_LOG_TARGET.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = match &*target {
+            StderrLogTarget::Stderr => stderr().write_all(&self.0),
+            StderrLogTarget::ProgressBars(multi_progress) => multi_progress.suspend(|| stderr().write_all(&self.0)),
+            StderrLogTarget::Discarded => Ok(()),
+        };
+    }
+}
+
+// What kind of failure the CLI exited with, for scripts to tell apart by exit code without parsing messages.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FailureClass {
+    Other,
+    Auth,
+    RoomNotFound,
+    Network,
+    PartialExport,
+    InvalidArguments,
+    PartialRedaction,
+}
+
+impl FailureClass {
+    fn exit_code(self) -> u8 {
+        match self {
+            Self::Other => 1,
+            Self::Auth => 2,
+            Self::RoomNotFound => 3,
+            Self::Network => 4,
+            Self::PartialExport => 5,
+            Self::InvalidArguments => 6,
+            Self::PartialRedaction => 7,
+        }
+    }
+
+    fn of(error: &anyhow::Error) -> Self {
+        if let Some(partial_export) = error.downcast_ref::<PartialExport>() {
+            return partial_export.failure_class()
+        }
+        if error.is::<InvalidArguments>() {
+            return Self::InvalidArguments
+        }
+        if error.is::<PartialRedaction>() {
+            return Self::PartialRedaction
+        }
+        if let Some(error) = error.downcast_ref::<trace::Error>() {
+            return Self::of_trace_error(error)
+        }
+        match (error.downcast_ref::<matrix_sdk::Error>(), error.downcast_ref::<HttpError>()) {
+            (Some(matrix_sdk::Error::Http(error)), _) => Self::of_http_error(error),
+            (_, Some(error)) => Self::of_http_error(error),
+            _ => Self::Other,
+        }
+    }
+
+    fn of_trace_error(error: &trace::Error) -> Self {
+        match error {
+            trace::Error::SessionNotFound(_) | trace::Error::SessionExpired(_) | trace::Error::Login(_) => Self::Auth,
+            trace::Error::RoomNotFound { .. } | trace::Error::AmbiguousRoomName { .. } => Self::RoomNotFound,
+            trace::Error::Pagination { .. } => Self::Network,
+            trace::Error::Http(error) => Self::of_http_error(error),
+            trace::Error::Matrix(matrix_sdk::Error::Http(error)) => Self::of_http_error(error),
+            _ => Self::Other,
+        }
+    }
+
+    // Homeservers answer bad credentials with 401 or 403; anything else going wrong over HTTP is put down to the network
+    fn of_http_error(error: &HttpError) -> Self {
+        match error.as_client_api_error().map(|error| error.status_code.as_u16()) {
+            Some(401 | 403) => Self::Auth,
+            _ => Self::Network,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct RoomFailure {
+    identifier: String,
+    kind: FailureClass,
+    message: String,
+}
+
+// Returned by export when some of the rooms asked for couldn't be found or exported. Whichever ones could be still get exported beforehand.
+#[derive(Debug)]
+struct PartialExport {
+    exported_room_count: usize,
+    room_failures: Vec<RoomFailure>,
+}
+
+impl PartialExport {
+    // Nothing having been exported at all counts as whatever went wrong with the first room, rather than as a partial export
+    fn failure_class(&self) -> FailureClass {
+        match (self.exported_room_count, self.room_failures.first()) {
+            (0, Some(room_failure)) => room_failure.kind,
+            _ => FailureClass::PartialExport,
+        }
+    }
+}
+
+impl std::fmt::Display for PartialExport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Exported {} rooms, but couldn't export {} others.", self.exported_room_count, self.room_failures.len())
+    }
+}
+
+impl std::error::Error for PartialExport {}
+
+// Returned when flags or config values are missing, malformed, or can't be used together, before anything's been done with them.
+#[derive(Debug)]
+struct InvalidArguments(String);
+
+impl std::fmt::Display for InvalidArguments {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidArguments {}
+
+// Returned by redact when some of the events found couldn't be redacted, after the rest have been. Each event's failure is printed as it's reported.
+#[derive(Debug)]
+struct PartialRedaction {
+    redacted_count: usize,
+    failed_count: usize,
+}
+
+impl std::fmt::Display for PartialRedaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Redacted {} events, but couldn't redact {} others.", self.redacted_count, self.failed_count)
+    }
+}
+
+impl std::error::Error for PartialRedaction {}
+
+#[derive(Serialize)]
+struct PrintableError {
+    kind: FailureClass,
+    exit_code: u8,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rooms: Vec<RoomFailure>,
+}
+
+// Read from config.toml in the platform's usual config directory. Everything here can be overridden by the equivalent flags.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    data_dir: Option<PathBuf>,
+    export: ExportDefaults,
+    homeservers: HashMap<String, String>, // Homeserver URLs to use in place of discovery when logging in, keyed by user IDs' server names
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ExportDefaults {
+    output: Option<PathBuf>,
+    formats: Vec<String>,
+    timezone: Option<String>,
+    avatars: Option<bool>,
+    media: Option<bool>,
+    webhook_url: Option<String>,
+    hook_command: Option<String>,
+    s3_bucket: Option<String>,
+    s3_endpoint: Option<String>,
+    s3_prefix: Option<String>,
+}
+
+impl ConfigFile {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        // Having no config file is fine, but one that can't be read or parsed shouldn't be silently ignored
+        match read_to_string(path) {
+            Ok(file) => match toml::from_str(&file) {
+                Ok(config) => Ok(config),
+                Err(e) => anyhow::bail!("Couldn't parse config file {} due to error '{}'.", path.display(), e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => anyhow::bail!("Couldn't read config file {} due to error '{}'.", path.display(), e),
+        }
+    }
+
+    // Flags take precedence over the config file, so e.g. --no-media turns off media downloads the config file turns on
+    fn apply_export_defaults(&self, config: &mut Export) -> anyhow::Result<()> {
+        if config.formats.is_empty() {
+            config.formats = self.export.formats.clone();
+        }
+        config.output = config.output.take().or_else(|| self.export.output.clone());
+        config.timezone = config.timezone.take().or_else(|| self.export.timezone.clone());
+        config.avatars = switch_pair("avatars", config.avatars, config.no_avatars)?.or(self.export.avatars).unwrap_or(false);
+        config.media = switch_pair("media", config.media, config.no_media)?.or(self.export.media).unwrap_or(false);
+        config.webhook_url = config.webhook_url.take().or_else(|| self.export.webhook_url.clone());
+        config.hook_command = config.hook_command.take().or_else(|| self.export.hook_command.clone());
+        config.s3_bucket = config.s3_bucket.take().or_else(|| self.export.s3_bucket.clone());
+        config.s3_endpoint = config.s3_endpoint.take().or_else(|| self.export.s3_endpoint.clone());
+        config.s3_prefix = config.s3_prefix.take().or_else(|| self.export.s3_prefix.clone());
+
+        Ok(())
+    }
+
+    fn homeserver_for(&self, user_id: &str) -> Option<String> {
+        let (_localpart, server_name) = user_id.split_once(':')?;
+        self.homeservers.get(server_name).cloned()
+    }
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+// Which way a --<name>/--no-<name> pair of switches was set, if either was
+fn switch_pair(name: &str, on: bool, off: bool) -> anyhow::Result<Option<bool>> {
+    match (on, off) {
+        (true, true) => anyhow::bail!(InvalidArguments(format!("Received both --{} and --no-{}. Only one can be used at a time.", name, name))),
+        (true, false) => Ok(Some(true)),
+        (false, true) => Ok(Some(false)),
+        (false, false) => Ok(None),
+    }
+}
+
+// As opposed to a session alias or room. Localparts are accepted without the '@', as elsewhere, so long as the server name is there.
+fn looks_like_user_id(user_id_or_alias: &str) -> bool {
+    user_id_or_alias.starts_with('@') || (user_id_or_alias.contains(':') && !user_id_or_alias.starts_with(['!', '#']))
+}
+
+// Resolves a user ID or session alias, or the default session if neither is given, to the user ID and profile of the session meant. User IDs given directly keep the profile given by --profile.
+fn resolve_session(sessions_file: &SessionsFile, user_id_or_alias: Option<&str>, profile: Option<&str>) -> anyhow::Result<(String, Option<String>)> {
+    let session = match user_id_or_alias {
+        Some(user_id_or_alias) => match trace::find_session_by_alias(sessions_file, user_id_or_alias)? {
+            Some(session) => session,
+            None => return Ok((add_at_to_user_id_if_applicable(user_id_or_alias), profile.map(String::from))),
+        },
+        None => match trace::default_session(sessions_file)? {
+            Some(session) => session,
+            None => anyhow::bail!(InvalidArguments(String::from("Received no user ID, and there's no default session to fall back on. Set one with 'trace session set-default'."))),
+        },
+    };
+
+    Ok((session.user_id.to_string(), session.profile))
+}
+
+// For scripted use, the password can also come from the TRACE_PASSWORD environment variable, which the flags take precedence over
+fn get_password(password_file: Option<PathBuf>, password_stdin: bool, command_name: &str, user_id: &str) -> anyhow::Result<String> {
+    let password = match (password_file, password_stdin) {
+        (Some(_), true) => anyhow::bail!(InvalidArguments(format!("Received both --password-file and --password-stdin on session {} command. Only one source of password can be used at a time.", command_name))),
+        (Some(password_file), false) => String::from(read_to_string(password_file)?.trim_end_matches(['\r', '\n'])),
+        (None, true) => {
+            let mut password = String::new();
+            stdin().read_line(&mut password)?;
+            String::from(password.trim_end_matches(['\r', '\n']))
+        }
+        (None, false) => match std::env::var("TRACE_PASSWORD") {
+            Ok(password) => password,
+            Err(_) => {
+                println!("Please input password for account {}.", user_id);
+                read_password().unwrap()
+            }
+        },
+    };
+
+    Ok(password)
+}
+
+// Logs go to stderr as bare messages by default, so that warnings read the same as any other output, and with timestamps and all when going to a file.
+fn init_logging(log_level: Option<&str>, verbose: bool, log_file: Option<&Path>) -> anyhow::Result<()> {
+    let filter = match (log_level, verbose) {
+        (Some(log_level), _) => EnvFilter::try_new(log_level)?,
+        (None, true) => EnvFilter::new("trace=debug"),
+        (None, false) => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("trace=warn")),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match log_file {
+        Some(log_file) => subscriber.with_writer(Mutex::new(File::options().create(true).append(true).open(log_file)?)).with_ansi(false).init(),
+        None => subscriber.with_writer(StderrLogWriter).without_time().with_target(false).init(),
+    }
+
+    Ok(())
+}
+
+// Returns the target it replaces, for putting back afterwards.
+fn set_stderr_log_target(target: StderrLogTarget) -> StderrLogTarget {
+    std::mem::replace(&mut STDERR_LOG_TARGET.lock().unwrap_or_else(|e| e.into_inner()), target)
+}
+
+// Logs into a saved session as nonfirst_login does, watching its token for run's error handling to check
+async fn login(user_id: &str, profile: Option<&str>, sessions_file: &SessionsFile, store_path: &Path) -> anyhow::Result<Client> {
+    let client = nonfirst_login(user_id, profile, sessions_file, store_path).await?;
+    *TOKEN_REJECTION.lock().unwrap() = Some(watch_for_token_rejection(&client, user_id, profile));
+
+    Ok(client)
+}
+
+fn format_millis(timestamp_millis: Option<i64>) -> String {
+    match timestamp_millis.and_then(DateTime::from_timestamp_millis) {
+        Some(datetime) => datetime.format("%Y-%m-%d %H:%M").to_string(),
+        None => String::from("[Unknown]"),
+    }
+}
+
+// With --all, every pending invite gets picked out by room ID, so that identically-named invites don't trip each other up.
+async fn invite_identifiers(client: &Client, rooms: Vec<String>, all: bool, action: &str) -> anyhow::Result<Vec<String>> {
+    match (all, rooms.is_empty()) {
+        (true, true) => Ok(trace::list_invites(client).await?.into_iter().map(|invite| invite.room_id.to_string()).collect()),
+        (true, false) => anyhow::bail!(InvalidArguments(format!("Received both --all and a list of rooms on invites {} command. Only one can be used at a time.", action))),
+        (false, true) => anyhow::bail!(InvalidArguments(format!("Received no rooms on invites {} command. Pass --all to {} every pending invite.", action, action))),
+        (false, false) => Ok(rooms),
+    }
+}
+
+// The prefixes carry the tree's lines down from the node's ancestors; the first goes before the node itself, and the second before everything beneath it.
+fn print_space_hierarchy_node(node: &SpaceHierarchyNode, node_prefix: &str, children_prefix: &str) {
+    let name = match (&node.name, node.is_joined) {
+        (Some(name), _) => name.as_str(),
+        (None, true) => "[Unnamed]",
+        (None, false) => "[Not joined]",
+    };
+    let space_marker = if node.is_space { " (space)" } else { "" };
+    println!("{}{} [{}]{}", node_prefix, name, node.room_id, space_marker);
+    for (index, child) in node.children.iter().enumerate() {
+        match index == node.children.len() - 1 {
+            true => print_space_hierarchy_node(child, &format!("{}└── ", children_prefix), &format!("{}    ", children_prefix)),
+            false => print_space_hierarchy_node(child, &format!("{}├── ", children_prefix), &format!("{}│   ", children_prefix)),
+        }
+    }
+}
+
+fn parse_timezone(timezone: Option<String>, command_name: &str) -> anyhow::Result<ExportTimezone> {
+    let timezone = match timezone {
+        None => ExportTimezone::Utc,
+        Some(timezone) => match timezone.to_lowercase().as_ref() {
+            "utc" => ExportTimezone::Utc,
+            "local" => ExportTimezone::Local,
+            _ => match timezone.parse::<Tz>() {
+                Ok(timezone) => ExportTimezone::Named(timezone),
+                Err(_) => anyhow::bail!(InvalidArguments(format!("Received invalid time zone {} on {} command. Valid options are 'utc', 'local', or an IANA time zone name.", timezone, command_name))),
+            },
+        },
+    };
+
+    Ok(timezone)
+}
+
+// Quotes fields only when they need it, as most CSV readers expect.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => String::from(field),
+    }
+}
+
+fn split_comma_separated_list(list: &str) -> HashSet<String> {
+    list.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+// Cuts text down to the given display width, marking where it was cut with an ellipsis.
+fn truncate_to_width(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return String::from(text)
+    }
+    let mut truncated = String::new();
+    let mut truncated_width = 0;
+    for character in text.chars() {
+        let character_width = character.width().unwrap_or(0);
+        if truncated_width + character_width + 1 > width {
+            break
+        }
+        truncated.push(character);
+        truncated_width += character_width;
+    }
+    truncated.push('…');
+
+    truncated
+}
+
+// Columns are aligned by display width rather than character count, so that wide characters (e.g. CJK or emoji in room names) don't throw them off. When printing to a terminal too narrow for the table, the widest columns get truncated until it fits, down to MIN_TRUNCATED_COLUMN_WIDTH.
+fn print_table(headers: &[&str], rows: &[Vec<String>], no_header: bool, plain: bool) {
+    const COLUMN_SEPARATOR: &str = "  ";
+    const MIN_TRUNCATED_COLUMN_WIDTH: usize = 10;
+
+    let header_row = headers.iter().map(|header| String::from(*header)).collect::<Vec<String>>();
+    let rows = match no_header {
+        true => rows.iter().collect::<Vec<&Vec<String>>>(),
+        false => once(&header_row).chain(rows).collect(),
+    };
+    if plain {
+        for row in rows {
+            println!("{}", row.join("\t"));
+        }
+        return
+    }
+
+    let mut column_widths = headers.iter().enumerate().map(|(column_index, _header)| rows.iter().map(|row| row[column_index].width()).max().unwrap_or(0)).collect::<Vec<usize>>();
+    if let (true, Ok((terminal_width, _))) = (stdout().is_terminal(), ratatui::crossterm::terminal::size()) {
+        let available_width = usize::from(terminal_width).saturating_sub(COLUMN_SEPARATOR.len() * headers.len().saturating_sub(1));
+        while column_widths.iter().sum::<usize>() > available_width {
+            let (widest_column_index, widest_column_width) = column_widths.iter().copied().enumerate().max_by_key(|(_column_index, column_width)| *column_width).unwrap();
+            if widest_column_width <= MIN_TRUNCATED_COLUMN_WIDTH {
+                break
+            }
+            column_widths[widest_column_index] -= 1;
+        }
+    }
+    for row in rows {
+        let cells = row.iter().zip(&column_widths).enumerate().map(|(column_index, (cell, column_width))| {
+            let cell = truncate_to_width(cell, *column_width);
+            match column_index == headers.len() - 1 {
+                true => cell, // No trailing padding
+                false => format!("{}{}", cell, " ".repeat(column_width - cell.width())),
+            }
+        }).collect::<Vec<String>>();
+        println!("{}", cells.join(COLUMN_SEPARATOR));
+    }
+}
+
+// Outgoing requests are the ones sent by Trace itself, which get answered on the other device, with Trace then starting the SAS verification; incoming ones are accepted here instead, with the other device starting it.
+// Decimals are always allowed as well as emoji, since the spec requires every client to support them, but are only shown when asked for or when the other device can't do emoji.
+async fn handle_verification_request(verification_request: VerificationRequest, outgoing: bool, sas_method: ShortAuthenticationString) -> anyhow::Result<()> {
+    if !outgoing {
+        verification_request.accept().await?;
+    }
+    let mut verification_state_stream = verification_request.changes();
+    while let Some(state) = verification_state_stream.next().await {
+        match state {
+            VerificationRequestState::Ready { .. } if outgoing => {
+                if verification_request.start_sas().await?.is_none() {
+                    println!("The other device doesn't support SAS verification, which is the only kind Trace CLI can handle, so this verification attempt has been aborted.");
+                    verification_request.cancel().await?;
+                    break
+                }
+            }
+            VerificationRequestState::Transitioned { verification } => {
+                if let Verification::SasV1(sas_verification) = verification {
+                    if !outgoing {
+                        let allowed_methods = match sas_method {
+                            ShortAuthenticationString::Emoji => vec![ShortAuthenticationString::Emoji, ShortAuthenticationString::Decimal],
+                            _ => vec![ShortAuthenticationString::Decimal],
+                        };
+                        sas_verification.accept_with_settings(AcceptSettings::with_allowed_methods(allowed_methods)).await?;
+                    }
+                    let mut sas_verification_state_stream = sas_verification.changes();
+                    while let Some(state) = sas_verification_state_stream.next().await {
+                        match state {
+                            SasState::KeysExchanged {emojis, decimals} => {
+                                match emojis.filter(|_| sas_method == ShortAuthenticationString::Emoji) {
+                                    Some(emojis) => {
+                                        let emojis = emojis.emojis.iter().map(|emoji| format!("{} ({})", emoji.symbol, emoji.description)).collect::<Vec<String>>();
+                                        println!("Attempting verification. SAS emoji: {}", emojis.join(", "));
+                                        println!("Do these emoji match those shown on the other side of the verification? (Y)es/(N)o/(C)ancel");
+                                    }
+                                    None => {
+                                        println!("Attempting verification. SAS decimals: {}, {}, {}", decimals.0, decimals.1, decimals.2);
+                                        println!("Do these decimals match those shown on the other side of the verification? (Y)es/(N)o/(C)ancel");
+                                    }
+                                }
+                                loop {
+                                    let input: String = text_io::read!();
+                                    match input.trim().to_ascii_lowercase().as_ref() {
+                                        "y" | "yes" => {
+                                            sas_verification.confirm().await?;
+                                            println!("Confirmed. Waiting for the other side of the verification to confirm as well.");
+                                            break
+                                        }
+                                        "n" | "no" => {
+                                            sas_verification.mismatch().await?;
+                                            println!("Verification failed due to string mismatch.");
+                                            break
+                                        }
+                                        "c" | "cancel" => {
+                                            sas_verification.cancel().await?;
+                                            println!("Canceled verification attempt.");
+                                            break
+                                        }
+                                        _ => println!("Input '{}' not recognized. Please try again.", input),
+                                    }
+                                }
+                            }
+                            SasState::Done { .. } | SasState::Cancelled(_) => break, // Reported on by the request's own state stream
+                            _ => (),
+                        }
+                    }
+                } else {
+                    println!("Received verification attempt of type other than SAS V1. Trace CLI can't handle QR code verification, and Trace's developers are unaware of any verification types aside from SAS V1 and QR, so this verification attempt has been aborted.");
+                    verification_request.cancel().await?;
+                    break
+                }
+            }
+            VerificationRequestState::Cancelled(info) => {
+                println!("Verification cancelled. Cancel info: {:?}", info);
+                break
+            }
+            VerificationRequestState::Done => {
+                println!("Verification done.");
+                break
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_verification(verification_request: VerificationRequest, outgoing: bool, sas_method: ShortAuthenticationString, verification_finished: CancellationToken) {
+    if let Err(e) = handle_verification_request(verification_request, outgoing, sas_method).await {
+        eprintln!("Verification failed: {}", e);
+    }
+    verification_finished.cancel();
+}
+
+//////////////
+//   Main   //
+//////////////
+
+async fn analyze_utd(config: AnalyzeUtd, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let pagination_options = PaginationOptions {
+        limit: config.limit,
+        newest_first: config.newest_first,
+        max_retries: config.max_retries,
+        ..Default::default()
+    };
+    let report = trace::analyze::undecryptable_event_stats(&client, config.rooms, pagination_options).await?;
+    for room_resolution in &report.room_resolutions {
+        if let Err(e) = &room_resolution.result {
+            eprintln!("{}", e);
+        }
+    }
+    if config.json {
+        println!("{}", serde_json::to_string(&report.room_stats).unwrap());
+        return Ok(())
+    }
+    for stats in report.room_stats {
+        let room_name = stats.room_name.unwrap_or_else(|| String::from("[Unnamed]"));
+        print!("{} ({}): {} of {} encrypted events undecryptable, out of {} events", room_name, stats.room_id, stats.undecryptable_event_count, stats.encrypted_event_count, stats.event_count);
+        if stats.undecryptable_event_count > 0 {
+            print!(", from {} to {}, across {} missing sessions", format_millis(stats.first_timestamp_millis), format_millis(stats.last_timestamp_millis), stats.missing_sessions.len());
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+async fn export(config: Export, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    // With a default session set, the user ID can be left out, so long as the first room given doesn't look like a user ID or session alias
+    let mut rooms = config.rooms;
+    let (user_id, profile) = if looks_like_user_id(&config.user_id) || trace::find_session_by_alias(sessions_file, &config.user_id)?.is_some() || trace::default_session(sessions_file)?.is_none() {
+        resolve_session(sessions_file, Some(&config.user_id), profile)?
+    } else {
+        rooms.insert(0, config.user_id);
+        resolve_session(sessions_file, None, profile)?
+    };
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let mut export_formats = HashSet::new();
+    for format in config.formats {
+        match format.to_lowercase().as_ref() {
+            "json" | ".json" => export_formats.insert(ExportOutputFormat::Json),
+            "txt" | ".txt" => export_formats.insert(ExportOutputFormat::Txt),
+            _ => anyhow::bail!(InvalidArguments(format!("Received invalid format specifier {} on export command. Valid options are 'json' and 'txt'.", format))), // It'd be nice if argh allowed more direct handling of this; track https://github.com/google/argh/issues/138 in case it eventually does.
+        };
+    }
+    if export_formats.is_empty() {
+        export_formats.insert(ExportOutputFormat::Json);
+    }
+
+    let export_room_count = rooms.len();
+    if export_room_count == 0 && config.room_regex.is_empty() {
+        println!("Successfully exported 0 rooms. (This may not be what you meant to do.)");
+        return Ok(()); // Plausibly replace with an error once I've got real error-handling
+    }
+
+    if (config.from_event.is_some() || config.to_event.is_some()) && (export_room_count > 1 || !config.room_regex.is_empty()) {
+        anyhow::bail!(InvalidArguments(String::from("Received --from-event or --to-event while exporting multiple rooms. Event ranges can only be used when exporting a single room.")));
+    }
+    let event_range = ExportEventRange {
+        from: config.from_event.as_deref().map(EventId::parse).transpose()?,
+        to: config.to_event.as_deref().map(EventId::parse).transpose()?,
+    };
+
+    let event_type_filter = EventTypeFilter {
+        include: config.event_types.as_deref().map(split_comma_separated_list).unwrap_or_default(),
+        exclude: config.exclude_event_types.as_deref().map(split_comma_separated_list).unwrap_or_default(),
+    };
+
+    let content_filter = match config.grep {
+        Some(pattern) => Some(ContentFilter {
+            pattern: Regex::new(&pattern)?,
+            context: config.context,
+        }),
+        None => None,
+    };
+
+    let follow_upgrades = match config.follow_upgrades.as_deref() {
+        Some("merged") => Some(UpgradeChainMode::Merged),
+        Some("separate") => Some(UpgradeChainMode::Separate),
+        Some(mode) => anyhow::bail!(InvalidArguments(format!("Received invalid upgrade-chain mode {} on export command. Valid options are 'merged' and 'separate'.", mode))),
+        None => None,
+    };
+    if follow_upgrades.is_some() && (event_range.from.is_some() || event_range.to.is_some()) {
+        anyhow::bail!(InvalidArguments(String::from("Received --follow-upgrades alongside --from-event or --to-event. Event ranges can't be combined with upgrade-chain exports.")));
+    }
+
+    let (rooms, dm_users) = if config.dm {
+        let dm_users = rooms.iter().map(|user_id| UserId::parse(add_at_to_user_id_if_applicable(user_id))).collect::<Result<Vec<OwnedUserId>, _>>()?;
+        (Vec::new(), dm_users)
+    } else {
+        (rooms, Vec::new())
+    };
+    let pagination_options = PaginationOptions {
+        limit: config.limit,
+        newest_first: config.newest_first,
+        max_retries: config.max_retries,
+        backfill_retries: config.backfill_retries,
+        page_size: config.page_size,
+        request_delay: Duration::from_millis(config.request_delay_ms),
+    };
+    let timezone = parse_timezone(config.timezone, "export")?;
+    if let Some(timestamp_format) = &config.timestamp_format {
+        if StrftimeItems::new(timestamp_format).any(|item| matches!(item, Item::Error)) {
+            anyhow::bail!(InvalidArguments(format!("Received invalid timestamp format {} on export command.", timestamp_format)));
+        }
+    }
+    let json_options = JsonOptions {
+        sender_profiles: config.sender_profiles,
+        compact: config.compact,
+    };
+    let txt_options = TxtOptions {
+        group_threads: config.group_threads,
+        mark_edits: config.mark_edits,
+        edit_history: config.edit_history,
+        timezone,
+        timestamp_format: config.timestamp_format,
+        permalinks: config.permalinks,
+        day_separators: !config.no_day_separators,
+        html_to_markdown: config.markdown,
+        historical_display_names: !config.current_display_names,
+    };
+    let destination = match (config.output, &config.s3_bucket) {
+        (Some(output), s3_bucket) if output == Path::new("-") => {
+            if export_formats.len() > 1 || config.avatars || config.media || s3_bucket.is_some() {
+                anyhow::bail!(InvalidArguments(String::from("Received --output - alongside multiple formats, --avatars, --media, or --s3-bucket. Exports to stdout are limited to a single format, without downloads.")));
+            }
+            ExportDestination::Stdout
+        }
+        (staging_path, Some(s3_bucket)) => ExportDestination::ObjectStorage(ObjectStorage::from_env(config.s3_endpoint.as_deref(), s3_bucket, config.s3_prefix.as_deref())?, staging_path),
+        (output, None) => ExportDestination::Directory(output),
+    };
+    let to_stdout = matches!(destination, ExportDestination::Stdout);
+    if config.index && (to_stdout || config.s3_bucket.is_some() || !export_formats.contains(&ExportOutputFormat::Json)) {
+        anyhow::bail!(InvalidArguments(String::from("Received --index alongside --output -, --s3-bucket, or without JSON output. Only JSON exports written to a local directory can be indexed.")));
+    }
+    let index_export_dir = match (config.index, &destination) {
+        (true, ExportDestination::Directory(output_dir)) => Some(output_dir.clone().unwrap_or_else(|| PathBuf::from("."))),
+        _ => None,
+    };
+    let split_mode = match config.split.as_deref().map(str::to_lowercase).as_deref() {
+        None => None,
+        Some("monthly") => Some(SplitMode::Monthly),
+        Some("yearly") => Some(SplitMode::Yearly),
+        Some(split) => match split.strip_suffix("mb").and_then(|megabytes| megabytes.trim().parse::<usize>().ok()) {
+            Some(megabytes) if megabytes > 0 => Some(SplitMode::Size(megabytes * 1_000_000)),
+            _ => anyhow::bail!(InvalidArguments(format!("Received invalid split mode {} on export command. Valid options are 'monthly', 'yearly', or a size like '100MB'.", split))),
+        },
+    };
+    if config.incremental && (config.from_event.is_some() || config.to_event.is_some() || config.limit.is_some() || config.newest_first) {
+        anyhow::bail!(InvalidArguments(String::from("Received --incremental alongside --from-event, --to-event, --limit, or --newest-first. Incremental exports always cover everything since the previous one.")));
+    }
+    let incremental_checkpoints = match config.incremental {
+        true => Some(CheckpointsFile::open(store_path.join("checkpoints.json"))?),
+        false => None,
+    };
+    let profile_cache = ProfileCacheFile::open(store_path.join("profiles.json"), chrono::Duration::hours(config.profile_cache_hours.into()))?;
+    let name_template = config.name_template.as_deref().map(NameTemplate::parse).transpose()?;
+    let room_patterns = config.room_regex.iter().map(|pattern| Regex::new(pattern)).collect::<Result<Vec<Regex>, _>>()?;
+
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    if !config.offline {
+        trace::light_sync(&client).await?;
+    }
+
+    // Bars are drawn to stderr, and only when it's a terminal. Everything else printed while they're up goes through multi_progress.suspend, so as not to get drawn over.
+    let multi_progress = match config.quiet {
+        true => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        false => MultiProgress::new(),
+    };
+    let previous_log_target = set_stderr_log_target(StderrLogTarget::ProgressBars(multi_progress.clone()));
+    let room_progress_bars = RefCell::new(HashMap::<OwnedRoomId, RoomProgressBar>::new());
+    let report_progress = |progress: ExportProgress| {
+        let mut room_progress_bars = room_progress_bars.borrow_mut();
+        match progress {
+            ExportProgress::RoomStarted { room_id } => {
+                RoomProgressBar::get_or_add(&mut room_progress_bars, &multi_progress, &room_id);
+            }
+            ExportProgress::PageFetched { room_id, event_count } => {
+                let room_progress_bar = RoomProgressBar::get_or_add(&mut room_progress_bars, &multi_progress, &room_id);
+                room_progress_bar.page_count += 1;
+                room_progress_bar.fetched_event_count += event_count;
+                room_progress_bar.update();
+            }
+            ExportProgress::EventsProcessed { room_id, event_count } => {
+                let room_progress_bar = RoomProgressBar::get_or_add(&mut room_progress_bars, &multi_progress, &room_id);
+                room_progress_bar.processed_event_count += event_count;
+                room_progress_bar.update();
+            }
+            ExportProgress::MediaDownloaded { room_id } => {
+                let room_progress_bar = RoomProgressBar::get_or_add(&mut room_progress_bars, &multi_progress, &room_id);
+                room_progress_bar.media_count += 1;
+                room_progress_bar.update();
+            }
+            ExportProgress::BytesWritten { room_id, byte_count } => RoomProgressBar::get_or_add(&mut room_progress_bars, &multi_progress, &room_id).written_byte_count += byte_count,
+            ExportProgress::GapFound { room_id, description } => multi_progress.suspend(|| eprintln!("Export of {} is incomplete. {}.", room_id, description)),
+            ExportProgress::DecryptionRetried { room_id, undecryptable_count, decrypted_count } => multi_progress.suspend(|| eprintln!("Decrypted {} of {} undecryptable events in {} with keys from other devices.", decrypted_count, undecryptable_count, room_id)),
+            ExportProgress::RoomFinished { room_id } => {
+                let Some(room_progress_bar) = room_progress_bars.remove(&room_id) else {
+                    return
+                };
+                room_progress_bar.bar.finish_and_clear();
+                if !config.quiet {
+                    multi_progress.suspend(|| eprintln!("Finished exporting {}: {} events, {} bytes written.", room_id, room_progress_bar.processed_event_count, room_progress_bar.written_byte_count));
+                }
+            }
+            ExportProgress::FollowStarted { room_count } => {
+                if !config.quiet {
+                    multi_progress.suspend(|| eprintln!("Following {} rooms for new events. Press Ctrl-C to stop.", room_count));
+                }
+            }
+            ExportProgress::EventAppended { .. } => (), // Too frequent to be worth printing
+            ExportProgress::RoomFailed { room_id, description } => {
+                if let Some(room_progress_bar) = room_progress_bars.remove(&room_id) {
+                    room_progress_bar.bar.finish_and_clear();
+                }
+                multi_progress.suspend(|| eprintln!("Couldn't export {} due to error '{}'. Continuing with the other rooms.", room_id, description));
+            }
+            ExportProgress::MediaSkipped { description, .. } => multi_progress.suspend(|| eprintln!("{}. Continuing without it.", description)),
+            ExportProgress::UpgradeChainTruncated { room_id, inaccessible_room_id } => multi_progress.suspend(|| eprintln!("Couldn't access room {}, which {} was upgraded from. Exporting only the later part of its upgrade chain.", inaccessible_room_id, room_id)),
+        }
+    };
+    // The first Ctrl-C lets the export wrap up what it's written so far, and a second one kills it outright
+    let cancellation = CancellationToken::new();
+    let ctrl_c_cancellation = cancellation.clone();
+    let follow = config.follow;
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            match follow {
+                true => eprintln!("Stopping export after the current page or event. Press Ctrl-C again to stop immediately."),
+                false => eprintln!("Cancelling export after the current page. Press Ctrl-C again to stop immediately."),
+            }
+            ctrl_c_cancellation.cancel();
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    let export_hooks = config.webhook_url.map(ExportHook::Webhook).into_iter().chain(config.hook_command.map(ExportHook::Command)).collect::<Vec<ExportHook>>();
+    let export_options = ExportOptions::new()
+        .rooms(rooms)
+        .room_patterns(room_patterns)
+        .dm_users(dm_users)
+        .peek(config.peek)
+        .admin(config.admin)
+        .destination(destination)
+        .name_template(name_template)
+        .formats(export_formats)
+        .split_mode(split_mode)
+        .streaming(config.stream)
+        .follow(config.follow)
+        .download_avatars(config.avatars)
+        .download_media(config.media)
+        .read_receipts(config.read_receipts)
+        .room_data(config.room_data)
+        .event_range(event_range)
+        .event_type_filter(event_type_filter)
+        .content_filter(content_filter)
+        .follow_upgrades(follow_upgrades)
+        .pseudonymize(config.pseudonymize)
+        .offline(config.offline)
+        .incremental_checkpoints(incremental_checkpoints)
+        .profile_cache(profile_cache)
+        .resume_dir(store_path.join("resume"))
+        .jobs(config.jobs)
+        .key_request_wait(config.request_keys.map(Duration::from_secs))
+        .pagination_options(pagination_options)
+        .json_options(json_options)
+        .txt_options(txt_options)
+        .progress(&report_progress)
+        .cancellation(&cancellation);
+    let export_result = trace::export(&client, export_options).await;
+    // Rooms cut short by cancellation never finish, so their bars would otherwise be left up
+    for room_progress_bar in room_progress_bars.take().into_values() {
+        room_progress_bar.bar.finish_and_clear();
+    }
+    set_stderr_log_target(previous_log_target);
+    // Hooks hear about the export however it went, including when it failed outright. Their own failures only get reported, rather than failing the export after the fact.
+    if !export_hooks.is_empty() {
+        let export_summary = match &export_result {
+            Ok(export_report) => ExportSummary::from_report(export_report),
+            Err(e) => ExportSummary::from_error(e),
+        };
+        for export_hook in &export_hooks {
+            if let Err(e) = trace::hooks::run_export_hook(export_hook, &export_summary).await {
+                eprintln!("Couldn't run export hook due to error '{}'.", e);
+            }
+        }
+    }
+    let export_report = export_result?;
+
+    // Goes to stderr for exports to stdout, like the rest of the non-export output
+    let print_line = |line: String| match to_stdout {
+        true => eprintln!("{}", line),
+        false => println!("{}", line),
+    };
+    let mut room_failures = Vec::new();
+    let mut summary_lines = Vec::new();
+    for room_resolution in export_report.room_resolutions {
+        let Err(e) = room_resolution.result else {
+            continue
+        };
+        let message = match &e {
+            trace::Error::RoomNotFound { .. } | trace::Error::AmbiguousRoomName { .. } => e.to_string(),
+            _ if config.admin => format!("Couldn't find any rooms accessible to {} with identifier {}, and couldn't look it up through the admin API due to error '{}'.", client.user_id().unwrap(), room_resolution.identifier, e),
+            _ => format!("Couldn't find any rooms accessible to {} with identifier {}, and couldn't peek into it due to error '{}'.", client.user_id().unwrap(), room_resolution.identifier, e),
+        };
+        summary_lines.push(format!("{} | Not found | {}", room_resolution.identifier, message));
+        room_failures.push(RoomFailure {
+            identifier: room_resolution.identifier,
+            kind: FailureClass::of_trace_error(&e),
+            message,
+        });
+    }
+    for room_outcome in export_report.room_outcomes {
+        let room = match room_outcome.room_name {
+            Some(name) => format!("{} [{}]", name, room_outcome.room_id),
+            None => room_outcome.room_id.to_string(),
+        };
+        match room_outcome.status {
+            RoomExportStatus::Exported { byte_count, event_count, .. } => summary_lines.push(format!("{} | Exported | {} events, {} bytes written", room, event_count, byte_count)),
+            RoomExportStatus::Skipped => summary_lines.push(format!("{} | Skipped | Nothing new since the last export", room)),
+            RoomExportStatus::Failed(e) => {
+                summary_lines.push(format!("{} | Failed | {}", room, e));
+                room_failures.push(RoomFailure {
+                    identifier: room_outcome.room_id.to_string(),
+                    kind: FailureClass::of_trace_error(&e),
+                    message: format!("Couldn't export {} due to error '{}'.", room, e),
+                });
+            }
+        }
+    }
+    // A single room's outcome is already clear enough from the progress output
+    if summary_lines.len() > 1 {
+        print_line(String::from("Summary:"));
+        for summary_line in summary_lines {
+            print_line(summary_line); // Replace with properly-justified table-formatting in the future
+        }
+    }
+    // Whatever did get exported is worth indexing, even if some rooms failed
+    if let Some(index_export_dir) = index_export_dir {
+        let index_dir = data_dir.join("index");
+        let index_report = trace::index::build_index(&index_dir, &[index_export_dir])?;
+        println!("Indexed {} events from {} export files into {}.", index_report.indexed_event_count, index_report.indexed_file_count, index_dir.display());
+    }
+
+    if !room_failures.is_empty() {
+        return Err(PartialExport {
+            exported_room_count: export_report.exported_room_count,
+            room_failures,
+        }.into())
+    }
+    if to_stdout {
+        eprintln!("Successfully exported {} rooms.", export_report.exported_room_count); // Kept out of the export itself
+    } else {
+        println!("Successfully exported {} rooms.", export_report.exported_room_count);
+    }
+
+    Ok(())
+}
+
+async fn import(config: Import, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let import_options = ImportOptions {
+        massage_timestamps: config.massage_timestamps,
+        after_event_id: config.after,
+        timezone: parse_timezone(config.timezone, "import")?,
+        max_retries: config.max_retries,
+    };
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let progress_bar = ProgressBar::new(0).with_style(ProgressStyle::with_template("{spinner} Posting messages: {pos}/{len}").unwrap());
+    let report_progress = |progress: ImportProgress| {
+        progress_bar.set_length(progress.total_count as u64);
+        progress_bar.set_position(progress.posted_count as u64);
+    };
+    let import_result = trace::import::import(&client, &config.export, &config.room, &import_options, &report_progress).await;
+    progress_bar.finish_and_clear();
+    let report = import_result?;
+    println!("Posted {} messages into {}.", report.posted_count, report.room_id);
+    for (reason, skipped_count) in report.skipped_counts {
+        println!("  Skipped {} {}", skipped_count, reason);
+    }
+
+    Ok(())
+}
+
+async fn index_build(config: IndexBuild, _profile: Option<&str>, _sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    if config.exports.is_empty() {
+        anyhow::bail!(InvalidArguments(String::from("Received no exports on index build command.")));
+    }
+    let index_dir = config.index_dir.unwrap_or_else(|| data_dir.join("index"));
+    let report = trace::index::build_index(&index_dir, &config.exports)?;
+    for (path, e) in &report.skipped_files {
+        eprintln!("Skipped {}, since it couldn't be read as an export due to error '{}'.", path.display(), e);
+    }
+
+    println!("Indexed {} events from {} export files into {}.", report.indexed_event_count, report.indexed_file_count, index_dir.display());
+
+    Ok(())
+}
+
+async fn index_search(config: IndexSearch, _profile: Option<&str>, _sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let date_to_millis = |date: &str, flag: &str| match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_time(NaiveTime::MIN).and_utc().timestamp_millis()),
+        Err(_) => Err(InvalidArguments(format!("Received invalid date {} for {} on index search command. Dates should be of the form 2020-01-31.", date, flag))),
+    };
+    let search_options = IndexSearchOptions {
+        senders: config.sender,
+        from_millis: config.from.as_deref().map(|from| date_to_millis(from, "--from")).transpose()?,
+        to_millis: config.to.as_deref().map(|to| date_to_millis(to, "--to").map(|to_millis| to_millis + 24 * 60 * 60 * 1000)).transpose()?, // Through the end of the day given
+        limit: config.limit,
+    };
+    let index_dir = config.index_dir.unwrap_or_else(|| data_dir.join("index"));
+    let hits = trace::index::search_index(&index_dir, &config.query, &search_options)?;
+    if config.json {
+        println!("{}", serde_json::to_string(&hits).unwrap());
+        return Ok(())
+    }
+    for hit in &hits {
+        let room = match (&hit.room_name, &hit.room_id) {
+            (Some(room_name), _) => room_name.as_str(),
+            (None, Some(room_id)) => room_id.as_str(),
+            (None, None) => "[Unknown room]",
+        };
+        println!("[{}] {} | {}: {}", format_millis(Some(hit.timestamp_millis)), room, hit.sender, hit.body);
+    }
+    println!("Found {} matching messages.", hits.len());
+
+    Ok(())
+}
+
+async fn invites_accept(config: InvitesAccept, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    for room in invite_identifiers(&client, config.rooms, config.all, "accept").await? {
+        let room_id = trace::accept_invite(&client, &room).await?;
+        println!("Joined {}.", room_id);
+    }
+
+    Ok(())
+}
+
+async fn invites_decline(config: InvitesDecline, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    for room in invite_identifiers(&client, config.rooms, config.all, "decline").await? {
+        let room_id = trace::decline_invite(&client, &room).await?;
+        println!("Declined invite to {}.", room_id);
+    }
+
+    Ok(())
+}
+
+async fn invites_list(config: InvitesList, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let invites = trace::list_invites(&client).await?;
+    if config.json {
+        println!("{}", serde_json::to_string(&invites).unwrap());
+    } else if invites.is_empty() && !(config.no_header || config.plain) {
+        println!("{} has no pending invites.", user_id);
+    } else {
+        if !(config.no_header || config.plain) {
+            println!("Pending invites for {}:", user_id);
+        }
+        let rows = invites.into_iter().map(|invite| vec![
+            invite.name.unwrap_or_else(|| String::from("[Unnamed]")),
+            invite.canonical_alias.map_or_else(|| String::from("[No canonical alias]"), |alias| alias.to_string()),
+            invite.room_id.to_string(),
+            invite.inviter.map_or_else(|| String::from("[Unknown]"), |inviter| inviter.to_string()),
+        ]).collect::<Vec<Vec<String>>>();
+        print_table(&["Name", "Canonical alias", "Room ID", "Invited by"], &rows, config.no_header, config.plain);
+    }
+
+    Ok(())
+}
+
+async fn keys_enable_backup(config: KeysEnableBackup, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    println!("Enabling key backup. Existing room keys will be uploaded to it, which may take a while.");
+    let recovery_key = trace::enable_key_backup(&client).await?;
+
+    println!("Successfully enabled key backup for account {}.", user_id);
+    println!("Recovery key: {}", recovery_key);
+    println!("Keep this somewhere safe. It's needed to restore keys from the backup in other sessions, and isn't stored anywhere else.");
+
+    Ok(())
+}
+
+async fn keys_export(config: KeysExport, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = trace::local_login(&user_id, profile, sessions_file, &store_path).await?; // Exporting keys only needs the store, so this still works for sessions the homeserver has logged out
+    let passphrase = prompt_password("Please input new passphrase for key export: ").unwrap();
+    if prompt_password("Please input it again to confirm: ").unwrap() != passphrase {
+        anyhow::bail!(InvalidArguments(String::from("Passphrases didn't match.")))
+    }
+    trace::export_room_keys(&client, &config.destination, &passphrase).await?;
+
+    println!("Successfully exported room keys to {}.", config.destination.display());
+
+    Ok(())
+}
+
+async fn keys_import(config: KeysImport, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    let passphrase = prompt_password("Please input passphrase for key export: ").unwrap();
+    let (imported_count, total_count) = trace::import_room_keys(&client, &config.source, &passphrase).await?;
+
+    println!("Successfully imported {} new room keys (of {} in the file).", imported_count, total_count);
+
+    Ok(())
+}
+
+async fn keys_restore_backup(config: KeysRestoreBackup, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    println!("Restoring room keys from backup. This may take a while.");
+    match config.recovery_key {
+        Some(recovery_key) => trace::recover_session(&client, &recovery_key).await?,
+        None => trace::restore_key_backup(&client).await?,
+    }
+
+    println!("Successfully restored room keys for {}'s session.", trace::session_key(&user_id, profile));
+
+    Ok(())
+}
+
+async fn list_rooms(config: ListRooms, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let sort_key = match config.sort.as_deref() {
+        Some("name") | None => RoomSortKey::Name,
+        Some("members") => RoomSortKey::Members,
+        Some("activity") => RoomSortKey::Activity,
+        Some("id") => RoomSortKey::Id,
+        Some(sort_key) => anyhow::bail!(InvalidArguments(format!("Received invalid sort key {} on list-rooms command. Valid options are 'name', 'members', 'activity', and 'id'.", sort_key))),
+    };
+    let tag = match config.tag.as_deref() {
+        Some("favourite") => Some(RoomTag::Favourite),
+        Some("low-priority") => Some(RoomTag::LowPriority),
+        Some(tag) => anyhow::bail!(InvalidArguments(format!("Received invalid tag {} on list-rooms command. Valid options are 'favourite' and 'low-priority'.", tag))),
+        None => None,
+    };
+    if config.encrypted && config.unencrypted {
+        anyhow::bail!(InvalidArguments(String::from("Received both --encrypted and --unencrypted on list-rooms command. No room is both.")));
+    }
+
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let normalized_user_id = add_at_to_user_id_if_applicable(&user_id);
+    let client = login(&normalized_user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let space_child_ids = match &config.space {
+        Some(space) => Some(trace::get_space_child_ids(&client, space).await?),
+        None => None,
+    };
+    let name_filter = config.name.map(|name| name.to_lowercase());
+    let mut rooms_info = trace::get_rooms_info(&client).await?;
+    rooms_info.retain(|room_info| {
+        (!config.encrypted || room_info.is_encrypted)
+            && (!config.unencrypted || !room_info.is_encrypted)
+            && space_child_ids.as_ref().is_none_or(|child_ids| child_ids.contains(&room_info.id))
+            && match tag {
+                Some(RoomTag::Favourite) => room_info.room.is_favourite(),
+                Some(RoomTag::LowPriority) => room_info.room.is_low_priority(),
+                None => true,
+            }
+            && name_filter.as_ref().is_none_or(|name_filter| room_info.name.as_ref().is_some_and(|name| name.to_lowercase().contains(name_filter)))
+    });
+    let latest_event_timestamps_millis = trace::get_latest_event_timestamps_millis(&rooms_info).await;
+
+    let mut printable_rooms = rooms_info
+        .into_iter()
+        .zip(latest_event_timestamps_millis)
+        .map(|(room_info, latest_event_ts)| PrintableRoom::from_room_info(room_info, latest_event_ts))
+        .collect::<Vec<PrintableRoom>>();
+    match sort_key {
+        RoomSortKey::Name => (), // Already sorted that way
+        RoomSortKey::Members => printable_rooms.sort_by_key(|room| Reverse(room.joined_member_count)),
+        RoomSortKey::Activity => printable_rooms.sort_by_key(|room| Reverse(room.latest_event_ts)), // Rooms without any visible events end up last, since None sorts lowest
+        RoomSortKey::Id => printable_rooms.sort_by(|room_1, room_2| room_1.id.cmp(&room_2.id)),
+    }
+    if config.json {
+        println!("{}", serde_json::to_string(&printable_rooms).unwrap());
+    } else {
+        if !(config.no_header || config.plain) {
+            println!("Rooms joined by {}:", normalized_user_id);
+        }
+        let rows = printable_rooms.into_iter().map(|room| {
+            let room_type = match (room.is_space, room.is_direct) {
+                (true, _) => "Space",
+                (false, true) => "DM",
+                (false, false) => "Room",
+            };
+            let latest_event = match room.latest_event_ts.and_then(DateTime::from_timestamp_millis) {
+                Some(latest_event) => latest_event.format("%Y-%m-%d %H:%M").to_string(),
+                None => String::from("[No visible events]"),
+            };
+            vec![
+                room.name.unwrap_or_else(|| String::from("[Unnamed]")),
+                room.alias.unwrap_or_else(|| String::from("[No canonical alias]")),
+                room.id,
+                String::from(room_type),
+                room.joined_member_count.to_string(),
+                String::from(if room.is_encrypted { "Yes" } else { "No" }),
+                latest_event,
+            ]
+        }).collect::<Vec<Vec<String>>>();
+        print_table(&["Name", "Canonical alias", "Room ID", "Type", "Members", "Encrypted", "Last activity"], &rows, config.no_header, config.plain);
+    }
+
+    Ok(())
+}
+
+async fn list_spaces(config: ListSpaces, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let normalized_user_id = add_at_to_user_id_if_applicable(&user_id);
+    let client = login(&normalized_user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let space_hierarchy = trace::get_space_hierarchy(&client).await?;
+    if config.json {
+        println!("{}", serde_json::to_string(&space_hierarchy).unwrap());
+    } else if space_hierarchy.is_empty() {
+        println!("{} hasn't joined any spaces.", normalized_user_id);
+    } else {
+        println!("Spaces joined by {}:", normalized_user_id);
+        for space in &space_hierarchy {
+            print_space_hierarchy_node(space, "", "");
+        }
+    }
+
+    Ok(())
+}
+
+async fn media_verify(config: MediaVerify, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let problems = trace::media::verify_media(&config.export_dir)?;
+    if problems.is_empty() {
+        println!("All media referenced from {} is present and intact.", config.export_dir.display());
+        return Ok(());
+    }
+
+    for problem in &problems {
+        let problem_description = match problem.kind {
+            MediaProblemKind::Missing => "Missing",
+            MediaProblemKind::HashMismatch => "Corrupted",
+            MediaProblemKind::Unrecorded => "Unrecorded",
+            MediaProblemKind::InvalidPath => "Invalid path",
+        };
+        match &problem.event_id {
+            Some(event_id) => println!("{}: {} (from event {})", problem_description, problem.media_file, event_id),
+            None => println!("{}: {}", problem_description, problem.media_file),
+        }
+    }
+    println!("Found {} missing, corrupted, unrecorded, or invalidly-referenced media files.", problems.len());
+
+    if config.redownload {
+        let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+        let profile = profile.as_deref();
+        let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+        let client = login(&user_id, profile, sessions_file, &store_path).await?;
+        let unrepairable_count = problems.iter().filter(|problem| !problem.is_repairable()).count();
+        if unrepairable_count > 0 {
+            println!("Couldn't find media sources or valid paths for {} of these files in the export; they can't be redownloaded.", unrepairable_count);
+        }
+        let repair_report = trace::media::repair_media(&client, &problems, config.max_retries).await?;
+        for failure in &repair_report.failures {
+            eprintln!("Couldn't redownload media {} due to error '{}'.", failure.media_file, failure.error);
+        }
+        println!("Successfully redownloaded {} media files.", repair_report.repaired_count);
+    }
+
+    Ok(())
+}
+
+async fn redact(config: Redact, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let time_to_millis = |time: &str, flag: &str, end_of_day: bool| match (NaiveDate::parse_from_str(time, "%Y-%m-%d"), DateTime::parse_from_rfc3339(time)) {
+        (Ok(date), _) if end_of_day => Ok(date.and_time(NaiveTime::MIN).and_utc().timestamp_millis() + 24 * 60 * 60 * 1000 - 1), // Through the end of the day given
+        (Ok(date), _) => Ok(date.and_time(NaiveTime::MIN).and_utc().timestamp_millis()),
+        (_, Ok(time)) => Ok(time.timestamp_millis()),
+        _ => Err(InvalidArguments(format!("Received invalid time {} for {} on redact command. Times should be either dates of the form 2020-01-31 or RFC 3339 timestamps of the form 2020-01-31T18:00:00Z.", time, flag))),
+    };
+    if config.all_senders && config.sender.is_some() {
+        anyhow::bail!(InvalidArguments(String::from("Received both --sender and --all-senders on redact command.")));
+    }
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let sender = match (config.all_senders, config.sender) {
+        (true, _) => None,
+        (false, Some(sender)) => Some(UserId::parse(add_at_to_user_id_if_applicable(&sender))?),
+        (false, None) => client.user_id().map(|user_id| user_id.to_owned()),
+    };
+    let filter = RedactionFilter {
+        sender,
+        since_millis: config.since.as_deref().map(|since| time_to_millis(since, "--since", false)).transpose()?,
+        until_millis: config.until.as_deref().map(|until| time_to_millis(until, "--until", true)).transpose()?,
+        pattern: config.grep.as_deref().map(Regex::new).transpose()?,
+    };
+    let pagination_options = PaginationOptions {
+        limit: config.limit,
+        max_retries: config.max_retries,
+        ..Default::default()
+    };
+    let candidates = trace::redact::find_redactable_events(&client, &config.room, &filter, pagination_options).await?;
+    let room = candidates.room_name.as_deref().unwrap_or(candidates.room_id.as_str());
+    if config.json {
+        println!("{}", serde_json::to_string(&candidates.events).unwrap());
+    } else {
+        for event in &candidates.events {
+            println!("{} {} {} ({}): {}", format_millis(Some(event.timestamp_millis)), event.sender, event.event_id, event.event_type, event.body.as_deref().unwrap_or("[no body]"));
+        }
+    }
+    if candidates.events.is_empty() {
+        eprintln!("Found no matching events in {}.", room);
+        return Ok(())
+    }
+    if config.dry_run {
+        eprintln!("Found {} matching events in {}. Rerun without --dry-run to redact them.", candidates.events.len(), room);
+        return Ok(())
+    }
+    if !config.yes {
+        eprintln!("Redacting these {} events from {} can't be undone. Continue? (Y)es/(N)o", candidates.events.len(), room); // On stderr so as not to end up amid --json output
+        let input: String = text_io::read!();
+        if !matches!(input.trim().to_ascii_lowercase().as_ref(), "y" | "yes") {
+            eprintln!("Canceled redaction.");
+            return Ok(())
+        }
+    }
+
+    let event_ids = candidates.events.into_iter().map(|event| event.event_id).collect::<Vec<_>>();
+    let progress_bar = ProgressBar::new(event_ids.len() as u64).with_style(ProgressStyle::with_template("{spinner} Redacting events: {pos}/{len}").unwrap());
+    let report_progress = |redacted_count: usize| progress_bar.set_position(redacted_count as u64);
+    let redaction_result = trace::redact::redact_events(&client, &candidates.room_id, event_ids, config.reason.as_deref(), config.max_retries, &report_progress).await;
+    progress_bar.finish_and_clear();
+    let report = redaction_result?;
+    for failure in &report.failures {
+        eprintln!("Couldn't redact event {} due to error '{}'.", failure.event_id, failure.error);
+    }
+    if !report.failures.is_empty() {
+        return Err(PartialRedaction {
+            redacted_count: report.redacted_count,
+            failed_count: report.failures.len(),
+        }.into())
+    }
+    println!("Redacted {} events from {}.", report.redacted_count, room);
+
+    Ok(())
+}
+
+async fn room_join(config: RoomJoin, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let via = config.via.iter().map(|server| ServerName::parse(server)).collect::<Result<Vec<OwnedServerName>, _>>()?;
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    let room_id = trace::join_room(&client, &config.room, &via).await?;
+
+    println!("Joined {} ({}).", config.room, room_id);
+
+    Ok(())
+}
+
+async fn room_leave(config: RoomLeave, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+    let room_id = trace::leave_room(&client, &config.room).await?;
+
+    println!("Left {} ({}).", config.room, room_id);
+
+    Ok(())
+}
+
+async fn search(config: Search, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let pagination_options = PaginationOptions {
+        limit: config.limit,
+        newest_first: true, // So that --limit keeps to recent history, like searching in a client would
+        max_retries: config.max_retries,
+        ..Default::default()
+    };
+    let report = trace::search::search(&client, &config.query, config.room, pagination_options).await?;
+    for room_resolution in &report.room_resolutions {
+        if let Err(e) = &room_resolution.result {
+            eprintln!("{}", e);
+        }
+    }
+    if config.json {
+        println!("{}", serde_json::to_string(&report.matches).unwrap());
+        return Ok(())
+    }
+    for search_match in &report.matches {
+        let room_name = search_match.room_name.as_deref().unwrap_or("[Unnamed]");
+        println!("[{}] {} | {}: {}", format_millis(Some(search_match.timestamp_millis)), room_name, search_match.sender, search_match.body);
+        println!("    {}", search_match.permalink);
+    }
+    println!("Found {} matching messages.", report.matches.len());
+
+    Ok(())
+}
+
+async fn stats(config: Stats, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    if config.json && config.csv {
+        anyhow::bail!(InvalidArguments(String::from("Received both --json and --csv on stats command. Only one output format can be used at a time.")));
+    }
+    let timezone = parse_timezone(config.timezone, "stats")?;
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+
+    let pagination_options = PaginationOptions {
+        limit: config.limit,
+        newest_first: config.newest_first,
+        max_retries: config.max_retries,
+        ..Default::default()
+    };
+    let report = trace::analyze::room_activity_stats(&client, config.rooms, pagination_options, &timezone).await?;
+    for room_resolution in &report.room_resolutions {
+        if let Err(e) = &room_resolution.result {
+            eprintln!("{}", e);
+        }
+    }
+    if config.json {
+        println!("{}", serde_json::to_string(&report.room_stats).unwrap());
+        return Ok(())
+    }
+    if config.csv {
+        println!("room_id,room_name,statistic,key,count");
+        for stats in &report.room_stats {
+            let room_name = csv_field(stats.room_name.as_deref().unwrap_or_default());
+            let print_row = |statistic: &str, key: &str, count: usize| println!("{},{},{},{},{}", stats.room_id, room_name, statistic, csv_field(key), count);
+            print_row("events", "", stats.event_count);
+            print_row("messages", "", stats.message_count);
+            print_row("media", "", stats.media_count);
+            print_row("undecryptable_events", "", stats.undecryptable_event_count);
+            for sender in &stats.senders {
+                print_row("messages_by_sender", &sender.sender, sender.message_count);
+            }
+            for (month, count) in &stats.messages_by_month {
+                print_row("messages_by_month", month, *count);
+            }
+            for (day, count) in &stats.messages_by_day {
+                print_row("messages_by_day", day, *count);
+            }
+            for (hour, count) in &stats.messages_by_hour {
+                print_row("messages_by_hour", hour, *count);
+            }
+        }
+        return Ok(())
+    }
+
+    for stats in report.room_stats {
+        let room_name = stats.room_name.unwrap_or_else(|| String::from("[Unnamed]"));
+        println!("{} ({}): {} messages, {} with media, out of {} events", room_name, stats.room_id, stats.message_count, stats.media_count, stats.event_count);
+        if stats.message_count == 0 {
+            continue
+        }
+        println!("  From {} to {}", format_millis(stats.first_timestamp_millis), format_millis(stats.last_timestamp_millis));
+        if stats.undecryptable_event_count > 0 {
+            println!("  {} undecryptable events left uncounted", stats.undecryptable_event_count);
+        }
+        println!("  Top senders:");
+        for sender in stats.senders.iter().take(10) {
+            println!("    {}: {}", sender.sender, sender.message_count);
+        }
+        // Bars are scaled to the busiest bucket, so they show the shape of activity rather than absolute numbers
+        let print_histogram = |title: &str, buckets: &BTreeMap<String, usize>| {
+            println!("  {}:", title);
+            let max_count = buckets.values().copied().max().unwrap_or_default().max(1);
+            for (key, count) in buckets {
+                println!("    {} | {:<40} {}", key, "#".repeat(count * 40 / max_count), count);
+            }
+        };
+        print_histogram("Messages by month", &stats.messages_by_month);
+        print_histogram("Messages by hour of day", &stats.messages_by_hour);
+    }
+
+    Ok(())
+}
+
+async fn session_bootstrap_cross_signing(config: SessionBootstrapCrossSigning, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    if config.reset {
+        println!("Resetting cross-signing will leave all of account {}'s other devices, and everyone it's verified, unverified. Continue? (Y)es/(N)o", user_id);
+        let input: String = text_io::read!();
+        if !matches!(input.trim().to_ascii_lowercase().as_ref(), "y" | "yes") {
+            println!("Canceled cross-signing reset.");
+            return Ok(())
+        }
+    }
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::bootstrap_cross_signing(&client, config.reset, || {
+        println!("Please input password for account {} to confirm.", user_id);
+        Ok(read_password()?)
+    }).await?;
+
+    println!("Successfully {} cross-signing for account {}.", if config.reset { "reset" } else { "set up" }, user_id);
+
+    Ok(())
+}
+
+async fn devices_delete(config: DevicesDelete, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    if config.device_ids.is_empty() {
+        anyhow::bail!(InvalidArguments(String::from("Received no device IDs on session devices delete command.")));
+    }
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    let device_ids = config.device_ids.iter().map(|device_id| OwnedDeviceId::from(device_id.as_str())).collect::<Vec<OwnedDeviceId>>();
+    trace::delete_devices(&client, &device_ids, || {
+        println!("Please input password for account {} to confirm deletion.", user_id);
+        Ok(read_password()?)
+    }).await?;
+
+    println!("Successfully deleted {} devices.", device_ids.len());
+
+    Ok(())
+}
+
+async fn devices_list(config: DevicesList, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    let mut printable_devices = trace::list_devices(&client).await?
+        .into_iter()
+        .map(|device| PrintableDevice {
+            is_current: client.device_id() == Some(&*device.device_id),
+            device_id: device.device_id.to_string(),
+            display_name: device.display_name,
+            last_seen_ip: device.last_seen_ip,
+            last_seen_ts: device.last_seen_ts.map(|last_seen_ts| last_seen_ts.get().into()),
+        })
+        .collect::<Vec<PrintableDevice>>();
+    printable_devices.sort_by(|device_1, device_2| device_2.last_seen_ts.cmp(&device_1.last_seen_ts)); // Most recently seen first, so stale ones end up at the bottom
+
+    if config.json {
+        println!("{}", serde_json::to_string(&printable_devices).unwrap());
+    } else {
+        if !(config.no_header || config.plain) {
+            println!("Devices logged into {}:", user_id);
+        }
+        let rows = printable_devices.into_iter().map(|device| {
+            let last_seen = match device.last_seen_ts.and_then(DateTime::from_timestamp_millis) {
+                Some(last_seen) => last_seen.format("%Y-%m-%d %H:%M").to_string(),
+                None => String::from("[Never seen]"),
+            };
+            let current_marker = if device.is_current { " (this session)" } else { "" };
+            vec![
+                format!("{}{}", device.device_id, current_marker),
+                device.display_name.unwrap_or_else(|| String::from("[Unnamed]")),
+                last_seen,
+                device.last_seen_ip.unwrap_or_else(|| String::from("[Unknown IP]")),
+            ]
+        }).collect::<Vec<Vec<String>>>();
+        print_table(&["Device ID", "Name", "Last seen", "Last seen IP"], &rows, config.no_header, config.plain);
+    }
+
+    Ok(())
+}
+
+async fn session_export_session(config: SessionExportSession, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let passphrase = prompt_password("Please input new passphrase for session bundle: ").unwrap();
+    if prompt_password("Please input it again to confirm: ").unwrap() != passphrase {
+        anyhow::bail!(InvalidArguments(String::from("Passphrases didn't match.")))
+    }
+    trace::export_session(sessions_file, &user_id, profile, &store_path, &config.destination, &passphrase)?;
+
+    println!("Successfully exported session to {}. Once it's imported elsewhere, don't use it from here again, since the same session can't safely be used from two places at once. (Logging out of it here would log it out everywhere.)", config.destination.display());
+
+    Ok(())
+}
+
+async fn session_import_session(config: SessionImportSession, profile: Option<&str>, sessions_file: &mut SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let passphrase = prompt_password("Please input passphrase for session bundle: ").unwrap();
+    let session = trace::import_session(sessions_file, data_dir, &config.source, &passphrase, profile.map(String::from))?;
+
+    println!("Successfully imported session for {}.", trace::session_key(session.user_id.as_str(), session.profile.as_deref()));
+
+    Ok(())
+}
+
+async fn session_list(config: SessionList, _profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let printable_sessions = trace::list_sessions(sessions_file, data_dir).await?
+        .into_iter()
+        .map(|(session, name)| PrintableSession {
+            user_id: session.user_id.to_string(),
+            profile: session.profile,
+            alias: session.alias,
+            is_default: session.is_default,
+            name,
+        })
+        .collect::<Vec<PrintableSession>>();
+    if config.json {
+        println!("{}", serde_json::to_string(&printable_sessions).unwrap());
+    } else if !printable_sessions.is_empty() {
+        if !(config.no_header || config.plain) {
+            println!("Currently-logged-in sessions:");
+        }
+        let rows = printable_sessions.into_iter().map(|session| vec![
+            trace::session_key(&session.user_id, session.profile.as_deref()),
+            session.alias.unwrap_or_default(),
+            String::from(if session.is_default { "yes" } else { "" }),
+            session.name,
+        ]).collect::<Vec<Vec<String>>>();
+        print_table(&["Session", "Alias", "Default", "Device name"], &rows, config.no_header, config.plain);
+    } else {
+        println!("You have no sessions currently logged in.");
+    }
+
+    Ok(())
+}
+
+async fn session_login(config: SessionLogin, profile: Option<&str>, sessions_file: &mut SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&config.user_id, profile));
+    let normalized_user_id = add_at_to_user_id_if_applicable(&config.user_id);
+    if sessions_file.get(&normalized_user_id, profile)?.is_some() {
+        anyhow::bail!(InvalidArguments(format!("Tried to log into account {}, but you already have a session logged into this account{}.", &normalized_user_id, if profile.is_some() { " under this profile" } else { "" })));
+    }
+
+    let password = get_password(config.password_file, config.password_stdin, "login", &normalized_user_id)?;
+    println!("Attempting login to account {}.", &normalized_user_id);
+
+    let user = UserId::parse(&normalized_user_id)?;
+    if config.insecure_skip_tls_verification {
+        println!("Warning: TLS verification is disabled for this session. Anyone between you and {} can read and tamper with its traffic, including your password.", user.server_name());
+    }
+    let connection_options = ConnectionOptions {
+        homeserver: config.homeserver,
+        proxy: config.proxy,
+        ca_bundle: config.ca_bundle,
+        skip_tls_verification: config.insecure_skip_tls_verification,
+    };
+    if config.encrypt && sessions_file.store_passphrase().is_none() {
+        let passphrase = match std::env::var("TRACE_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => {
+                let passphrase = prompt_password("Please input new passphrase for sessions file: ").unwrap();
+                if prompt_password("Please input it again to confirm: ").unwrap() != passphrase {
+                    anyhow::bail!(InvalidArguments(String::from("Passphrases didn't match.")))
+                }
+                passphrase
+            }
+        };
+        sessions_file.set_passphrase(passphrase);
+    }
+    let client = trace::build_client(&user, &connection_options, &store_path, sessions_file.store_passphrase()).await?;
+
+    sessions_file.store_new_secrets = !config.plaintext_tokens;
+    trace::first_login(&client, sessions_file, &normalized_user_id, profile.map(String::from), &password, config.session_name, connection_options).await?;
+
+    println!("Successfully logged into account {}.", normalized_user_id);
+
+    if let Some(recovery_key) = config.recovery_key {
+        println!("Verifying the session and restoring room keys from backup. This may take a while.");
+        trace::recover_session(&client, &recovery_key).await?;
+        println!("Successfully verified the session and restored its room keys.");
+    }
+
+    Ok(())
+}
+
+async fn session_logout(config: SessionLogout, profile: Option<&str>, sessions_file: &mut SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let normalized_user_id = add_at_to_user_id_if_applicable(&user_id);
+
+    let successful_remote_logout = match nonfirst_login(&user_id, profile, sessions_file, &store_path).await {
+        Ok(client) => match client.matrix_auth().logout().await {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Couldn't connect cilent to server due to error '{}'. Logging out on client side only. You may want to double-check {}'s sessions list in a different client just in case the session is still logged in on the server side.", e, normalized_user_id);
+                false
+            }
+        },
+        Err(trace::Error::SessionExpired(expired)) if !expired.soft_logout => {
+            println!("Session for {} was already logged out by the homeserver. Logging out on client side only.", trace::session_key(&normalized_user_id, profile));
+            false
+        }
+        Err(e) => {
+            println!("Couldn't connect cilent to server due to error '{}'. Logging out on client side only. You may want to double-check {}'s sessions list in a different client just in case the session is still logged in on the server side.", e, normalized_user_id);
+            false
+        }
+    };
+    trace::logout_local(&normalized_user_id, profile, sessions_file, &store_path)?;
+    if successful_remote_logout {
+        println!("Successfully logged out of account {}.", normalized_user_id);
+    } else {
+        println!("Successfully logged out of account {} on the client side.", normalized_user_id);
+    }
+
+    Ok(())
+}
+
+async fn session_register(config: SessionRegister, profile: Option<&str>, sessions_file: &mut SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&config.user_id, profile));
+    let normalized_user_id = add_at_to_user_id_if_applicable(&config.user_id);
+    if sessions_file.get(&normalized_user_id, profile)?.is_some() {
+        anyhow::bail!(InvalidArguments(format!("Tried to register account {}, but you already have a session logged into this account{}.", &normalized_user_id, if profile.is_some() { " under this profile" } else { "" })));
+    }
+    let user = UserId::parse(&normalized_user_id)?;
+
+    let password = get_password(config.password_file, config.password_stdin, "register", &normalized_user_id)?;
+    println!("Attempting registration of account {}.", &normalized_user_id);
+
+    let connection_options = ConnectionOptions {
+        homeserver: config.homeserver,
+        ..Default::default()
+    };
+    let client = trace::build_client(&user, &connection_options, &store_path, sessions_file.store_passphrase()).await?;
+
+    sessions_file.store_new_secrets = !config.plaintext_tokens;
+    trace::register(&client, sessions_file, user.localpart(), profile.map(String::from), &password, config.registration_token.as_deref(), config.session_name, connection_options).await?;
+
+    println!("Successfully registered account {}.", normalized_user_id);
+
+    Ok(())
+}
+
+async fn session_rename(config: SessionRename, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    trace::rename_session(&client, &config.session_name).await?;
+
+    println!("Successfully renamed account {}'s session to '{}'.", add_at_to_user_id_if_applicable(&user_id), config.session_name);
+
+    Ok(())
+}
+
+async fn session_set_alias(config: SessionSetAlias, profile: Option<&str>, sessions_file: &mut SessionsFile, _data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    if let Some(alias) = &config.alias {
+        if looks_like_user_id(alias) || alias.starts_with(['!', '#']) {
+            anyhow::bail!(InvalidArguments(format!("Received alias {} on session set-alias command, which could be mistaken for a user ID or room. Aliases can't contain ':' or begin with '@', '!', or '#'.", alias)))
+        }
+    }
+    sessions_file.set_alias(&user_id, profile.as_deref(), config.alias.clone())?;
+
+    match config.alias {
+        Some(alias) => println!("Successfully set alias of {}'s session to '{}'.", trace::session_key(&user_id, profile.as_deref()), alias),
+        None => println!("Successfully removed alias of {}'s session.", trace::session_key(&user_id, profile.as_deref())),
+    }
+
+    Ok(())
+}
+
+async fn session_set_default(config: SessionSetDefault, profile: Option<&str>, sessions_file: &mut SessionsFile, _data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, Some(&config.user_id), profile)?;
+    sessions_file.set_default(&user_id, profile.as_deref())?;
+
+    println!("Successfully set {}'s session as the default.", trace::session_key(&user_id, profile.as_deref()));
+
+    Ok(())
+}
+
+async fn session_status(config: SessionStatus, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = match nonfirst_login(&user_id, profile, sessions_file, &store_path).await {
+        Ok(client) => client,
+        Err(trace::Error::SessionExpired(expired)) => {
+            println!("Token: rejected by homeserver");
+            println!("{}", expired);
+            return Ok(())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let status = trace::session_status(&client, &store_path).await?;
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+
+    println!("Status of {}'s session:", trace::session_key(&user_id, profile));
+    println!("Token: valid (homeserver recognizes it as {})", status.whoami_user_id);
+    match (status.whoami_device_id, client.device_id()) {
+        (Some(whoami_device_id), Some(device_id)) if whoami_device_id != device_id.as_str() => println!("Device: {} (Warning: the homeserver associates the token with device {} instead)", device_id, whoami_device_id),
+        (_, Some(device_id)) => println!("Device: {}", device_id),
+        (_, None) => println!("Device: [Unknown]"),
+    }
+    println!("Device verified: {}", yes_no(status.device_verified));
+    println!("Cross-signing set up: {}", yes_no(status.cross_signing_set_up));
+    println!("Key backup enabled: {}", yes_no(status.backup_enabled));
+    println!("Store: {} ({:.1} MiB)", status.store_path.display(), status.store_size as f64 / (1024.0 * 1024.0));
+    if !status.device_verified {
+        println!("Unverified sessions may be unable to decrypt messages in encrypted rooms. Run 'trace session verify' to verify this one.");
+    }
+
+    Ok(())
+}
+
+async fn session_verify(config: SessionVerify, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let (user_id, profile) = resolve_session(sessions_file, config.user_id.as_deref(), profile)?;
+    let profile = profile.as_deref();
+    let store_path = data_dir.join(user_id_to_crypto_store_path(&user_id, profile));
+    let client = login(&user_id, profile, sessions_file, &store_path).await?;
+    if let Some(recovery_key) = config.recovery_key {
+        println!("Attempting verification through secret storage. Backed-up room keys will be downloaded too, which may take a while.");
+        trace::recover_session(&client, &recovery_key).await?;
+        println!("Successfully verified {}'s session.", trace::session_key(&user_id, profile));
+        return Ok(())
+    }
+    let sas_method = match config.sas.as_deref() {
+        Some("emoji") | None => ShortAuthenticationString::Emoji,
+        Some("decimal") => ShortAuthenticationString::Decimal,
+        Some(method) => anyhow::bail!(InvalidArguments(format!("Received invalid SAS method {} on session verify command. Valid options are 'emoji' and 'decimal'.", method))),
+    };
+    // Syncing is needed for the verification flow to go through, and stops once the first verification to be handled is done with, however it ends
+    let verification_finished = CancellationToken::new();
+    let incoming_request_received = Arc::new(AtomicBool::new(false));
+    client.add_event_handler({
+        let incoming_request_received = incoming_request_received.clone();
+        let sas_method = sas_method.clone();
+        let verification_finished = verification_finished.clone();
+        move |event: ToDeviceKeyVerificationRequestEvent, client: Client| {
+            let incoming_request_received = incoming_request_received.clone();
+            let sas_method = sas_method.clone();
+            let verification_finished = verification_finished.clone();
+            async move {
+                let user_id = event.sender;
+                let flow_id = event.content.transaction_id;
+                match client.encryption().get_verification_request(&user_id, flow_id).await {
+                    None => (),
+                    Some(verification_request) => {
+                        incoming_request_received.store(true, Ordering::SeqCst);
+                        tokio::spawn(run_verification(verification_request, false, sas_method, verification_finished)); // Asynchronousness is needed to keep the sync going, which is needed for the verification flow to go through successfully
+                    }
+                }
+            }
+        }
+    });
+
+    // Picks up any request already sent from another device before this started. Failing that, Trace sends its own, to all of the account's other devices.
+    client.sync_once(SyncSettings::new().set_presence(PresenceState::Offline)).await?;
+    if !incoming_request_received.load(Ordering::SeqCst) {
+        let Some(own_identity) = client.encryption().get_user_identity(client.user_id().unwrap()).await? else {
+            anyhow::bail!("Couldn't find a cross-signing identity for account {} to request verification from. Set up cross-signing from another client first, or start the verification from there.", user_id);
+        };
+        let verification_request = own_identity.request_verification_with_methods(vec![VerificationMethod::SasV1]).await?;
+        println!("Sent a verification request to your other devices. Accept it on one of them to continue.");
+        tokio::spawn(run_verification(verification_request, true, sas_method, verification_finished.clone()));
+    }
+
+    tokio::select! {
+        result = client.sync(SyncSettings::new().set_presence(PresenceState::Offline)) => result?,
+        _ = verification_finished.cancelled() => (),
+    }
+
+    Ok(())
+}
+
+async fn verify_export(config: VerifyExport, _profile: Option<&str>, _sessions_file: &SessionsFile, _data_dir: &Path) -> anyhow::Result<()> {
+    let verification = trace::verify::verify_export(&config.export_dir)?;
+    if !verification.has_manifest {
+        println!("{} has no export manifest, presumably being from before they were written, so files can only be checked for readability and event order.", config.export_dir.display());
+    }
+    for problem in &verification.problems {
+        let problem_description = match &problem.kind {
+            ExportProblemKind::Missing => String::from("Missing"),
+            ExportProblemKind::Unrecorded => String::from("Not in manifest"),
+            ExportProblemKind::ChecksumMismatch => String::from("Altered since export"),
+            ExportProblemKind::Unparseable(e) => format!("Unreadable due to error '{}'", e),
+            ExportProblemKind::EventCountMismatch { expected, found } => format!("Holds {} events rather than the {} exported", found, expected),
+            ExportProblemKind::OutOfOrder { out_of_order_event_count } => format!("{} events out of order", out_of_order_event_count),
+        };
+        println!("{}: {}", problem_description, problem.file);
+    }
+    for gap in &verification.gaps {
+        let position = match &gap.preceding_event_id {
+            Some(preceding_event_id) => format!("after {}", preceding_event_id),
+            None => String::from("before the first event"),
+        };
+        match &gap.error {
+            Some(error) => println!("Gap in {} {} ({}: {})", gap.file, position, gap.kind, error),
+            None => println!("Gap in {} {} ({})", gap.file, position, gap.kind),
+        }
+    }
+
+    let mut media_problem_count = 0;
+    if !config.skip_media {
+        for problem in trace::media::verify_media(&config.export_dir)? {
+            let problem_description = match problem.kind {
+                MediaProblemKind::Missing => "Missing media",
+                MediaProblemKind::HashMismatch => "Corrupted media",
+                MediaProblemKind::Unrecorded => "Unrecorded media",
+                MediaProblemKind::InvalidPath => "Invalid media path",
+            };
+            println!("{}: {}", problem_description, problem.media_file);
+            media_problem_count += 1;
+        }
+    }
+
+    let problem_count = verification.problems.len() + media_problem_count;
+    println!("Checked {} files, finding {} problems and {} recorded gaps.", verification.checked_file_count, problem_count, verification.gaps.len());
+    if problem_count > 0 {
+        anyhow::bail!("Export {} failed verification.", config.export_dir.display());
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Args = argh::from_env();
+    let json_errors = args.json_errors;
+    let Err(e) = run(args).await else {
+        return ExitCode::SUCCESS
+    };
+
+    let failure_class = FailureClass::of(&e);
+    let partial_export = e.downcast_ref::<PartialExport>();
+    if json_errors {
+        let printable_error = PrintableError {
+            kind: failure_class,
+            exit_code: failure_class.exit_code(),
+            message: format!("{:#}", e),
+            rooms: partial_export.map(|partial_export| partial_export.room_failures.clone()).unwrap_or_default(),
+        };
+        eprintln!("{}", serde_json::to_string(&printable_error).unwrap());
+    } else {
+        for room_failure in partial_export.iter().flat_map(|partial_export| &partial_export.room_failures) {
+            eprintln!("{}", room_failure.message);
+        }
+        eprintln!("Error: {:?}", e);
+    }
+
+    ExitCode::from(failure_class.exit_code())
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    init_logging(args.log_level.as_deref(), args.verbose, args.log_file.as_deref())?;
+    let dirs = ProjectDirs::from("", "", "Trace").unwrap(); // Figure out qualifier and organization
+    let config_file = ConfigFile::open(&dirs.config_dir().join("config.toml"))?;
+    let data_dir = args.data_dir
+        .or_else(|| std::env::var_os("TRACE_DATA_DIR").map(PathBuf::from))
+        .or_else(|| config_file.data_dir.clone())
+        .unwrap_or_else(|| PathBuf::from(dirs.data_local_dir()));
+    let secret_store = KeyringSecretStore::is_available().then(|| Arc::new(KeyringSecretStore) as Arc<dyn SecretStore>);
+    let sessions_path = data_dir.join("sessions.json");
+    // TRACE_PASSPHRASE only ever stands in for a prompt, so a plaintext sessions file stays plaintext unless encryption's asked for with --encrypt
+    let passphrase = match SessionsFile::is_encrypted(&sessions_path) {
+        true => Some(std::env::var("TRACE_PASSPHRASE").or_else(|_| prompt_password("Please input passphrase for sessions file: "))?),
+        false => None,
+    };
+    let mut sessions_file = SessionsFile::open(sessions_path, secret_store, passphrase)?;
+
+    if let Some(profile) = &args.profile {
+        if profile.is_empty() || !profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            anyhow::bail!(InvalidArguments(format!("Received invalid profile name {}. Profile names can contain only letters, numbers, '-', and '_'.", profile)))
+        }
+    }
+    let profile = args.profile.as_deref();
+    let result = match args.subcommand {
+        RootSubcommand::Analyze(a) => match a.subcommand {
+            AnalyzeSubcommand::Utd(config) => analyze_utd(config, profile, &sessions_file, &data_dir).await,
+        },
+        RootSubcommand::Daemon(mut config) => {
+            if config.config.is_none() {
+                config.config = Some(dirs.config_dir().join("daemon.toml"));
+            }
+            daemon::daemon(config, profile, &sessions_file, &data_dir).await
+        }
+        RootSubcommand::Export(mut config) => {
+            config_file.apply_export_defaults(&mut config)?;
+            export(config, profile, &sessions_file, &data_dir).await
+        }
+        RootSubcommand::Import(config) => import(config, profile, &sessions_file, &data_dir).await,
+        RootSubcommand::Index(i) => match i.subcommand {
+            IndexSubcommand::Build(config) => index_build(config, profile, &sessions_file, &data_dir).await,
+            IndexSubcommand::Search(config) => index_search(config, profile, &sessions_file, &data_dir).await,
+        },
+        RootSubcommand::Invites(i) => match i.subcommand {
+            InvitesSubcommand::Accept(config) => invites_accept(config, profile, &sessions_file, &data_dir).await,
+            InvitesSubcommand::Decline(config) => invites_decline(config, profile, &sessions_file, &data_dir).await,
+            InvitesSubcommand::List(config) => invites_list(config, profile, &sessions_file, &data_dir).await,
+        },
+        RootSubcommand::Keys(k) => match k.subcommand {
+            KeysSubcommand::EnableBackup(config) => keys_enable_backup(config, profile, &sessions_file, &data_dir).await,
+            KeysSubcommand::Export(config) => keys_export(config, profile, &sessions_file, &data_dir).await,
+            KeysSubcommand::Import(config) => keys_import(config, profile, &sessions_file, &data_dir).await,
+            KeysSubcommand::RestoreBackup(config) => keys_restore_backup(config, profile, &sessions_file, &data_dir).await,
+        },
+        RootSubcommand::ListRooms(config) => list_rooms(config, profile, &sessions_file, &data_dir).await,
+        RootSubcommand::ListSpaces(config) => list_spaces(config, profile, &sessions_file, &data_dir).await,
+        RootSubcommand::Media(m) => match m.subcommand {
+            MediaSubcommand::Verify(config) => media_verify(config, profile, &sessions_file, &data_dir).await,
+        },
+        RootSubcommand::Redact(config) => redact(config, profile, &sessions_file, &data_dir).await,
+        RootSubcommand::Room(r) => match r.subcommand {
+            RoomSubcommand::Join(config) => room_join(config, profile, &sessions_file, &data_dir).await,
+            RoomSubcommand::Leave(config) => room_leave(config, profile, &sessions_file, &data_dir).await,
+        },
+        RootSubcommand::Search(config) => search(config, profile, &sessions_file, &data_dir).await,
+        RootSubcommand::Session(s) => match s.subcommand {
+            SessionSubcommand::BootstrapCrossSigning(config) => session_bootstrap_cross_signing(config, profile, &sessions_file, &data_dir).await,
+            SessionSubcommand::Devices(d) => match d.subcommand {
+                DevicesSubcommand::Delete(config) => devices_delete(config, profile, &sessions_file, &data_dir).await,
+                DevicesSubcommand::List(config) => devices_list(config, profile, &sessions_file, &data_dir).await,
+            },
+            SessionSubcommand::ExportSession(config) => session_export_session(config, profile, &sessions_file, &data_dir).await,
+            SessionSubcommand::ImportSession(config) => session_import_session(config, profile, &mut sessions_file, &data_dir).await,
+            SessionSubcommand::List(config) => session_list(config, profile, &sessions_file, &data_dir).await,
+            SessionSubcommand::Login(mut config) => {
+                if config.homeserver.is_none() {
+                    config.homeserver = config_file.homeserver_for(&config.user_id);
+                }
+                session_login(config, profile, &mut sessions_file, &data_dir).await
+            }
+            SessionSubcommand::Logout(config) => session_logout(config, profile, &mut sessions_file, &data_dir).await,
+            SessionSubcommand::Register(mut config) => {
+                if config.homeserver.is_none() {
+                    config.homeserver = config_file.homeserver_for(&config.user_id);
+                }
+                session_register(config, profile, &mut sessions_file, &data_dir).await
+            }
+            SessionSubcommand::Rename(config) => session_rename(config, profile, &sessions_file, &data_dir).await,
+            SessionSubcommand::SetAlias(config) => session_set_alias(config, profile, &mut sessions_file, &data_dir).await,
+            SessionSubcommand::SetDefault(config) => session_set_default(config, profile, &mut sessions_file, &data_dir).await,
+            SessionSubcommand::Status(config) => session_status(config, profile, &sessions_file, &data_dir).await,
+            SessionSubcommand::Verify(config) => session_verify(config, profile, &sessions_file, &data_dir).await,
+        },
+        RootSubcommand::Stats(config) => stats(config, profile, &sessions_file, &data_dir).await,
+        RootSubcommand::Tui(config) => tui::tui(config, profile, &sessions_file, &data_dir).await,
+        RootSubcommand::VerifyExport(config) => verify_export(config, profile, &sessions_file, &data_dir).await,
+    };
+
+    // Sessions the homeserver has soft-logged-out can be picked back up here and now, rather than having to be logged into afresh. Ones whose tokens got rejected partway through the command get reported as such, rather than as whichever request they happened to fail.
+    if let Err(e) = result {
+        let rejected_session = TOKEN_REJECTION.lock().unwrap().take().and_then(|token_rejection| token_rejection.take());
+        let expired = match (e.downcast_ref::<trace::Error>(), rejected_session) {
+            (Some(trace::Error::SessionExpired(expired)), _) => expired.clone(),
+            (_, Some(expired)) => expired,
+            _ => return Err(e),
+        };
+        if !expired.soft_logout {
+            return Err(trace::Error::SessionExpired(expired).into())
+        }
+        println!("{}", expired);
+        println!("Please input password for account {}.", expired.user_id);
+        let password = read_password().unwrap();
+        let store_path = data_dir.join(user_id_to_crypto_store_path(&expired.user_id, expired.profile.as_deref()));
+        trace::resume_session(&mut sessions_file, &expired.user_id, expired.profile.as_deref(), &store_path, &password).await?;
+        println!("Successfully resumed session for {}. Please run the command again.", trace::session_key(&expired.user_id, expired.profile.as_deref()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,596 @@
+use std::cell::RefCell;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::Duration;
+
+use trace::{
+    checkpoint::CheckpointsFile,
+    profiles::ProfileCacheFile,
+    CancellationToken,
+    ExportDestination,
+    ExportOptions,
+    ExportOutputFormat,
+    ExportProgress,
+    ExportReport,
+    RoomExportStatus,
+    RoomWithCachedInfo,
+    Session,
+    SessionStore,
+    SessionsFile,
+    nonfirst_login,
+    user_id_to_crypto_store_path,
+};
+
+use matrix_sdk::{
+    ruma::OwnedRoomId,
+    Client,
+};
+use ratatui::{
+    crossterm::event::{
+        self,
+        Event,
+        KeyCode,
+        KeyEvent,
+        KeyEventKind,
+        KeyModifiers,
+    },
+    layout::{
+        Constraint,
+        Layout,
+        Rect,
+    },
+    style::{
+        Modifier,
+        Style,
+    },
+    widgets::{
+        Block,
+        List,
+        ListItem,
+        ListState,
+        Paragraph,
+    },
+    DefaultTerminal,
+    Frame,
+};
+
+use crate::{
+    resolve_session,
+    set_stderr_log_target,
+    StderrLogTarget,
+    TuiCommand,
+};
+
+// How often the export screen gets redrawn while an export is running
+const EXPORT_REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+///////////////
+//   Types   //
+///////////////
+
+enum Screen {
+    Sessions,
+    Rooms,
+    ExportForm,
+    Exporting,
+}
+
+// What a keypress asks of the main loop, for things which need awaiting or which end the TUI
+enum Action {
+    None,
+    Quit,
+    OpenSession(Session),
+    StartExport,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExportFormField {
+    OutputDir,
+    Json,
+    Txt,
+    Avatars,
+    Media,
+    Pseudonymize,
+    Incremental,
+    Start,
+}
+
+impl ExportFormField {
+    const ALL: [Self; 8] = [Self::OutputDir, Self::Json, Self::Txt, Self::Avatars, Self::Media, Self::Pseudonymize, Self::Incremental, Self::Start];
+}
+
+// The subset of export's options worth offering in a form; anything more particular is what the export command is for.
+struct ExportForm {
+    output_dir: String,
+    json: bool,
+    txt: bool,
+    avatars: bool,
+    media: bool,
+    pseudonymize: bool,
+    incremental: bool,
+    focused_field_index: usize,
+}
+
+impl Default for ExportForm {
+    fn default() -> Self {
+        Self {
+            output_dir: String::from("."),
+            json: true,
+            txt: false,
+            avatars: false,
+            media: false,
+            pseudonymize: false,
+            incremental: false,
+            focused_field_index: 0,
+        }
+    }
+}
+
+impl ExportForm {
+    fn focused_field(&self) -> ExportFormField {
+        ExportFormField::ALL[self.focused_field_index]
+    }
+
+    fn toggle(&mut self, field: ExportFormField) {
+        match field {
+            ExportFormField::Json => self.json = !self.json,
+            ExportFormField::Txt => self.txt = !self.txt,
+            ExportFormField::Avatars => self.avatars = !self.avatars,
+            ExportFormField::Media => self.media = !self.media,
+            ExportFormField::Pseudonymize => self.pseudonymize = !self.pseudonymize,
+            ExportFormField::Incremental => self.incremental = !self.incremental,
+            ExportFormField::OutputDir | ExportFormField::Start => (),
+        }
+    }
+
+    fn field_line(&self, field: ExportFormField) -> String {
+        let checkbox = |checked: bool| if checked { "[x]" } else { "[ ]" };
+        match field {
+            ExportFormField::OutputDir => format!("Output directory: {}", self.output_dir),
+            ExportFormField::Json => format!("{} Export as JSON", checkbox(self.json)),
+            ExportFormField::Txt => format!("{} Export as plain text", checkbox(self.txt)),
+            ExportFormField::Avatars => format!("{} Download senders' avatars", checkbox(self.avatars)),
+            ExportFormField::Media => format!("{} Download attachments", checkbox(self.media)),
+            ExportFormField::Pseudonymize => format!("{} Replace user IDs and display names with pseudonyms", checkbox(self.pseudonymize)),
+            ExportFormField::Incremental => format!("{} Only export what's new since the last incremental export", checkbox(self.incremental)),
+            ExportFormField::Start => String::from("> Start export"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct RoomProgress {
+    page_count: usize,
+    fetched_event_count: usize,
+    processed_event_count: usize,
+    media_count: usize,
+    written_byte_count: usize,
+    state: &'static str, // 'Exporting', 'Finished', or 'Failed'
+}
+
+// Everything the export screen shows, built up from the export's progress and then its report.
+#[derive(Default)]
+struct ExportState {
+    room_order: Vec<OwnedRoomId>, // In the order the rooms started in
+    progress_by_room: HashMap<OwnedRoomId, RoomProgress>,
+    messages: Vec<String>,
+    outcome: Option<String>, // Set once the export's over
+}
+
+impl ExportState {
+    fn room_progress(&mut self, room_id: OwnedRoomId) -> &mut RoomProgress {
+        if !self.progress_by_room.contains_key(&room_id) {
+            self.room_order.push(room_id.clone());
+        }
+        self.progress_by_room.entry(room_id).or_insert_with(|| RoomProgress {
+            state: "Exporting",
+            ..Default::default()
+        })
+    }
+
+    fn record(&mut self, progress: ExportProgress) {
+        match progress {
+            ExportProgress::RoomStarted { room_id } => {
+                self.room_progress(room_id);
+            }
+            ExportProgress::PageFetched { room_id, event_count } => {
+                let room_progress = self.room_progress(room_id);
+                room_progress.page_count += 1;
+                room_progress.fetched_event_count += event_count;
+            }
+            ExportProgress::EventsProcessed { room_id, event_count } => self.room_progress(room_id).processed_event_count += event_count,
+            ExportProgress::BytesWritten { room_id, byte_count } => self.room_progress(room_id).written_byte_count += byte_count,
+            ExportProgress::MediaDownloaded { room_id } => self.room_progress(room_id).media_count += 1,
+            ExportProgress::GapFound { room_id, description } => self.messages.push(format!("Export of {} is incomplete. {}.", room_id, description)),
+            ExportProgress::DecryptionRetried { room_id, undecryptable_count, decrypted_count } => self.messages.push(format!("Decrypted {} of {} undecryptable events in {} with keys from other devices.", decrypted_count, undecryptable_count, room_id)),
+            ExportProgress::RoomFinished { room_id } => self.room_progress(room_id).state = "Finished",
+            ExportProgress::FollowStarted { room_count } => self.messages.push(format!("Following {} rooms for new events.", room_count)),
+            ExportProgress::EventAppended { room_id, byte_count } => {
+                let room_progress = self.room_progress(room_id);
+                room_progress.processed_event_count += 1;
+                room_progress.written_byte_count += byte_count;
+            }
+            ExportProgress::RoomFailed { room_id, description } => {
+                self.messages.push(format!("Couldn't export {} due to error '{}'.", room_id, description));
+                self.room_progress(room_id).state = "Failed";
+            }
+            ExportProgress::MediaSkipped { description, .. } => self.messages.push(format!("{}. Continuing without it.", description)),
+            ExportProgress::UpgradeChainTruncated { room_id, inaccessible_room_id } => self.messages.push(format!("Couldn't access room {}, which {} was upgraded from. Exporting only the later part of its upgrade chain.", inaccessible_room_id, room_id)),
+        }
+    }
+
+    fn record_report(&mut self, export_report: ExportReport) {
+        for room_resolution in export_report.room_resolutions {
+            if let Err(e) = room_resolution.result {
+                self.messages.push(e.to_string());
+            }
+        }
+        let failed_room_count = export_report.room_outcomes.iter().filter(|room_outcome| matches!(room_outcome.status, RoomExportStatus::Failed(_))).count();
+        self.outcome = Some(format!("Export finished: {} rooms exported, {} failed.", export_report.exported_room_count, failed_room_count));
+    }
+}
+
+struct App {
+    screen: Screen,
+    status: String, // Errors and hints, shown along the bottom
+    sessions: Vec<Session>,
+    session_list_state: ListState,
+    client: Option<Client>,
+    store_path: PathBuf,
+    rooms: Vec<RoomWithCachedInfo>,
+    room_search: String,
+    searching: bool, // Whether keypresses go into room_search
+    room_list_state: ListState,
+    selected_room_ids: HashSet<OwnedRoomId>,
+    export_form: ExportForm,
+    export_state: ExportState,
+}
+
+impl App {
+    fn new(sessions: Vec<Session>) -> Self {
+        Self {
+            screen: Screen::Sessions,
+            status: String::new(),
+            session_list_state: ListState::default().with_selected((!sessions.is_empty()).then_some(0)),
+            sessions,
+            client: None,
+            store_path: PathBuf::new(),
+            rooms: Vec::new(),
+            room_search: String::new(),
+            searching: false,
+            room_list_state: ListState::default(),
+            selected_room_ids: HashSet::new(),
+            export_form: ExportForm::default(),
+            export_state: ExportState::default(),
+        }
+    }
+
+    // Searches match case-insensitively against rooms' names, canonical aliases, and IDs.
+    fn filtered_room_indices(&self) -> Vec<usize> {
+        let room_search = self.room_search.to_lowercase();
+        self.rooms.iter().enumerate().filter(|(_index, room_info)| {
+            room_search.is_empty()
+                || room_info.name.as_ref().is_some_and(|name| name.to_lowercase().contains(&room_search))
+                || room_info.canonical_alias.as_ref().is_some_and(|alias| alias.as_str().to_lowercase().contains(&room_search))
+                || room_info.id.as_str().to_lowercase().contains(&room_search)
+        }).map(|(index, _room_info)| index).collect()
+    }
+
+    fn highlighted_room_index(&self) -> Option<usize> {
+        self.room_list_state.selected().and_then(|selected| self.filtered_room_indices().get(selected).copied())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Action::Quit
+        }
+        match self.screen {
+            Screen::Sessions => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Action::Quit,
+                KeyCode::Up => self.session_list_state.select_previous(),
+                KeyCode::Down => self.session_list_state.select_next(),
+                KeyCode::Enter => {
+                    if let Some(session) = self.session_list_state.selected().and_then(|selected| self.sessions.get(selected)) {
+                        return Action::OpenSession(session.clone())
+                    }
+                }
+                _ => (),
+            },
+            Screen::Rooms if self.searching => {
+                match key.code {
+                    KeyCode::Enter | KeyCode::Esc => self.searching = false,
+                    KeyCode::Backspace => {
+                        self.room_search.pop();
+                    }
+                    KeyCode::Char(c) => self.room_search.push(c),
+                    _ => (),
+                }
+                self.room_list_state.select((!self.filtered_room_indices().is_empty()).then_some(0));
+            }
+            Screen::Rooms => match key.code {
+                KeyCode::Esc => {
+                    self.client = None;
+                    self.rooms.clear();
+                    self.selected_room_ids.clear();
+                    self.room_search.clear();
+                    self.screen = Screen::Sessions;
+                }
+                KeyCode::Char('/') => self.searching = true,
+                KeyCode::Up => self.room_list_state.select_previous(),
+                KeyCode::Down => self.room_list_state.select_next(),
+                KeyCode::Char(' ') => {
+                    if let Some(room_index) = self.highlighted_room_index() {
+                        let room_id = &self.rooms[room_index].id;
+                        if !self.selected_room_ids.remove(room_id) {
+                            self.selected_room_ids.insert(room_id.clone());
+                        }
+                    }
+                }
+                KeyCode::Enter => {
+                    if self.selected_room_ids.is_empty() {
+                        let Some(room_index) = self.highlighted_room_index() else {
+                            return Action::None
+                        };
+                        self.selected_room_ids.insert(self.rooms[room_index].id.clone());
+                    }
+                    self.screen = Screen::ExportForm;
+                }
+                _ => (),
+            },
+            Screen::ExportForm => match (key.code, self.export_form.focused_field()) {
+                (KeyCode::Esc, _) => self.screen = Screen::Rooms,
+                (KeyCode::Up, _) => self.export_form.focused_field_index = self.export_form.focused_field_index.saturating_sub(1),
+                (KeyCode::Down, _) => self.export_form.focused_field_index = (self.export_form.focused_field_index + 1).min(ExportFormField::ALL.len() - 1),
+                (KeyCode::Enter, ExportFormField::Start) => return Action::StartExport,
+                (KeyCode::Backspace, ExportFormField::OutputDir) => {
+                    self.export_form.output_dir.pop();
+                }
+                (KeyCode::Char(c), ExportFormField::OutputDir) => self.export_form.output_dir.push(c),
+                (KeyCode::Enter | KeyCode::Char(' '), field) => self.export_form.toggle(field),
+                _ => (),
+            },
+            Screen::Exporting => {
+                if matches!(key.code, KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q')) {
+                    self.selected_room_ids.clear();
+                    self.screen = Screen::Rooms;
+                }
+            }
+        }
+
+        Action::None
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [title_area, body_area, status_area] = Layout::vertical([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let (title, hint) = match self.screen {
+            Screen::Sessions => ("Sessions", "Up/Down: move | Enter: open session | q: quit"),
+            Screen::Rooms if self.searching => ("Rooms", "Type to search | Enter/Esc: stop searching"),
+            Screen::Rooms => ("Rooms", "Up/Down: move | Space: select | /: search | Enter: export selected rooms | Esc: back to sessions"),
+            Screen::ExportForm => ("Export", "Up/Down: move | Enter/Space: toggle | type to edit the output directory | Esc: back to rooms"),
+            Screen::Exporting if self.export_state.outcome.is_none() => ("Exporting", "Esc: cancel, keeping what's been exported so far"),
+            Screen::Exporting => ("Exporting", "Enter/Esc: back to rooms"),
+        };
+        frame.render_widget(Paragraph::new(format!("Trace | {}", title)).style(Style::default().add_modifier(Modifier::BOLD)), title_area);
+        let status = match self.status.is_empty() {
+            true => hint,
+            false => self.status.as_str(),
+        };
+        frame.render_widget(Paragraph::new(status).style(Style::default().add_modifier(Modifier::DIM)), status_area);
+
+        let highlight_style = Style::default().add_modifier(Modifier::REVERSED);
+        match self.screen {
+            Screen::Sessions => {
+                let items = self.sessions.iter().map(|session| {
+                    let alias = session.alias.as_ref().map(|alias| format!(" [{}]", alias)).unwrap_or_default();
+                    let default_marker = if session.is_default { " (default)" } else { "" };
+                    ListItem::new(format!("{}{}{}", trace::session_key(session.user_id.as_str(), session.profile.as_deref()), alias, default_marker))
+                }).collect::<Vec<ListItem>>();
+                let list = match items.is_empty() {
+                    true => List::new([ListItem::new("You have no sessions currently logged in. Log in with 'trace session login' first.")]),
+                    false => List::new(items).highlight_style(highlight_style),
+                };
+                frame.render_stateful_widget(list.block(Block::bordered().title("Logged-in sessions")), body_area, &mut self.session_list_state);
+            }
+            Screen::Rooms => {
+                let [search_area, list_area] = Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(body_area);
+                let search_cursor = if self.searching { "_" } else { "" };
+                frame.render_widget(Paragraph::new(format!("{}{}", self.room_search, search_cursor)).block(Block::bordered().title("Search")), search_area);
+                let items = self.filtered_room_indices().into_iter().map(|room_index| {
+                    let room_info = &self.rooms[room_index];
+                    let checkbox = if self.selected_room_ids.contains(&room_info.id) { "[x]" } else { "[ ]" };
+                    let room_name = room_info.name.clone().unwrap_or_else(|| String::from("[Unnamed]"));
+                    let room_alias = room_info.canonical_alias.as_ref().map(|alias| alias.to_string()).unwrap_or_else(|| String::from("[No alias]"));
+                    ListItem::new(format!("{} {} | {} | {}", checkbox, room_name, room_alias, room_info.id))
+                }).collect::<Vec<ListItem>>();
+                let title = format!("Rooms ({} selected)", self.selected_room_ids.len());
+                frame.render_stateful_widget(List::new(items).highlight_style(highlight_style).block(Block::bordered().title(title)), list_area, &mut self.room_list_state);
+            }
+            Screen::ExportForm => {
+                let items = ExportFormField::ALL.iter().enumerate().map(|(index, field)| {
+                    let item = ListItem::new(self.export_form.field_line(*field));
+                    match index == self.export_form.focused_field_index {
+                        true => item.style(highlight_style),
+                        false => item,
+                    }
+                }).collect::<Vec<ListItem>>();
+                let title = format!("Export {} rooms", self.selected_room_ids.len());
+                frame.render_widget(List::new(items).block(Block::bordered().title(title)), body_area);
+            }
+            Screen::Exporting => draw_export_state(frame, body_area, &self.export_state),
+        }
+    }
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn draw_export_state(frame: &mut Frame, area: Rect, export_state: &ExportState) {
+    let [rooms_area, messages_area] = Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(area);
+    let room_lines = export_state.room_order.iter().map(|room_id| {
+        let room_progress = &export_state.progress_by_room[room_id];
+        ListItem::new(format!("{} | {} | {} pages ({} events) fetched, {} events processed, {} media files downloaded, {} bytes written", room_id, room_progress.state, room_progress.page_count, room_progress.fetched_event_count, room_progress.processed_event_count, room_progress.media_count, room_progress.written_byte_count))
+    }).collect::<Vec<ListItem>>();
+    let rooms_title = export_state.outcome.clone().unwrap_or_else(|| String::from("Exporting..."));
+    frame.render_widget(List::new(room_lines).block(Block::bordered().title(rooms_title)), rooms_area);
+
+    // Only the latest messages that fit are shown
+    let visible_message_count = usize::from(messages_area.height.saturating_sub(2));
+    let messages = export_state.messages.iter().skip(export_state.messages.len().saturating_sub(visible_message_count)).map(|message| ListItem::new(message.as_str())).collect::<Vec<ListItem>>();
+    frame.render_widget(List::new(messages).block(Block::bordered().title("Messages")), messages_area);
+}
+
+async fn open_session(session: &Session, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<(Client, PathBuf, Vec<RoomWithCachedInfo>)> {
+    let store_path = data_dir.join(user_id_to_crypto_store_path(session.user_id.as_str(), session.profile.as_deref()));
+    let client = nonfirst_login(session.user_id.as_str(), session.profile.as_deref(), sessions_file, &store_path).await?;
+    trace::light_sync(&client).await?;
+    let mut rooms = trace::get_rooms_info(&client).await?;
+    rooms.sort_by(|room_1, room_2| room_1.name.cmp(&room_2.name));
+
+    Ok((client, store_path, rooms))
+}
+
+// Runs the export alongside the UI in the same task, since the progress callback isn't Send. Keypresses are checked for between redraws, so that the export can be cancelled.
+async fn run_export(terminal: &mut DefaultTerminal, app: &mut App) -> anyhow::Result<()> {
+    let Some(client) = app.client.clone() else {
+        return Ok(())
+    };
+    let mut formats = HashSet::new();
+    if app.export_form.json {
+        formats.insert(ExportOutputFormat::Json);
+    }
+    if app.export_form.txt {
+        formats.insert(ExportOutputFormat::Txt);
+    }
+    if formats.is_empty() {
+        app.status = String::from("Pick at least one format to export to.");
+        return Ok(())
+    }
+    let incremental_checkpoints = match app.export_form.incremental {
+        true => Some(CheckpointsFile::open(app.store_path.join("checkpoints.json"))?),
+        false => None,
+    };
+    let profile_cache = ProfileCacheFile::open(app.store_path.join("profiles.json"), chrono::Duration::hours(24))?;
+
+    app.screen = Screen::Exporting;
+    app.status.clear();
+    let export_state = RefCell::new(ExportState::default());
+    let report_progress = |progress: ExportProgress| export_state.borrow_mut().record(progress);
+    let cancellation = CancellationToken::new();
+    let export_options = ExportOptions::new()
+        .rooms(app.selected_room_ids.iter().map(|room_id| room_id.to_string()).collect())
+        .destination(ExportDestination::Directory(Some(PathBuf::from(&app.export_form.output_dir))))
+        .formats(formats)
+        .download_avatars(app.export_form.avatars)
+        .download_media(app.export_form.media)
+        .pseudonymize(app.export_form.pseudonymize)
+        .incremental_checkpoints(incremental_checkpoints)
+        .profile_cache(profile_cache)
+        .resume_dir(app.store_path.join("resume"))
+        .progress(&report_progress)
+        .cancellation(&cancellation);
+    let export_result = {
+        let export_future = trace::export(&client, export_options);
+        tokio::pin!(export_future);
+        loop {
+            terminal.draw(|frame| {
+                let [title_area, body_area, status_area] = Layout::vertical([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+                frame.render_widget(Paragraph::new("Trace | Exporting").style(Style::default().add_modifier(Modifier::BOLD)), title_area);
+                draw_export_state(frame, body_area, &export_state.borrow());
+                frame.render_widget(Paragraph::new("Esc: cancel, keeping what's been exported so far").style(Style::default().add_modifier(Modifier::DIM)), status_area);
+            })?;
+            tokio::select! {
+                export_result = &mut export_future => break export_result,
+                _ = tokio::time::sleep(EXPORT_REDRAW_INTERVAL) => (),
+            }
+            while event::poll(Duration::ZERO)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && (key.code == KeyCode::Esc || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))) && !cancellation.is_cancelled() {
+                        cancellation.cancel();
+                        export_state.borrow_mut().messages.push(String::from("Cancelling; whatever's been fetched so far is being written out..."));
+                    }
+                }
+            }
+        }
+    };
+
+    let mut export_state = export_state.into_inner();
+    match export_result {
+        Ok(export_report) => export_state.record_report(export_report),
+        Err(e) => export_state.outcome = Some(format!("Export failed: {}", e)),
+    }
+    app.export_state = export_state;
+
+    Ok(())
+}
+
+// Starts off by carrying out initial_action, for sessions picked on the command line
+async fn run_app(terminal: &mut DefaultTerminal, app: &mut App, initial_action: Action, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let mut action = initial_action;
+    loop {
+        match action {
+            Action::None => (),
+            Action::Quit => return Ok(()),
+            Action::OpenSession(session) => {
+                app.status = format!("Logging in as {}...", trace::session_key(session.user_id.as_str(), session.profile.as_deref()));
+                terminal.draw(|frame| app.draw(frame))?;
+                match open_session(&session, sessions_file, data_dir).await {
+                    Ok((client, store_path, rooms)) => {
+                        app.client = Some(client);
+                        app.store_path = store_path;
+                        app.room_list_state.select((!rooms.is_empty()).then_some(0));
+                        app.rooms = rooms;
+                        app.status.clear();
+                        app.screen = Screen::Rooms;
+                    }
+                    Err(e) => app.status = format!("Couldn't open session due to error '{:#}'.", e),
+                }
+            }
+            Action::StartExport => run_export(terminal, app).await?,
+        }
+        action = Action::None;
+
+        terminal.draw(|frame| app.draw(frame))?;
+        let Event::Key(key) = event::read()? else {
+            continue
+        };
+        if key.kind != KeyEventKind::Press {
+            continue
+        }
+        action = app.handle_key(key);
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+pub(crate) async fn tui(config: TuiCommand, profile: Option<&str>, sessions_file: &SessionsFile, data_dir: &Path) -> anyhow::Result<()> {
+    let initial_action = match config.user_id {
+        Some(user_id) => {
+            let (user_id, profile) = resolve_session(sessions_file, Some(&user_id), profile)?;
+            match sessions_file.get(&user_id, profile.as_deref())? {
+                Some(session) => Action::OpenSession(session),
+                None => return Err(trace::Error::SessionNotFound(trace::session_key(&user_id, profile.as_deref())).into()),
+            }
+        }
+        None => Action::None,
+    };
+    // With --profile, only that profile's sessions are offered
+    let sessions = sessions_file.list()?.into_iter().filter(|session| profile.is_none() || session.profile.as_deref() == profile).collect();
+
+    let mut app = App::new(sessions);
+    let previous_log_target = set_stderr_log_target(StderrLogTarget::Discarded);
+    let mut terminal = ratatui::init();
+    let result = run_app(&mut terminal, &mut app, initial_action, sessions_file, data_dir).await;
+    ratatui::restore();
+    set_stderr_log_target(previous_log_target);
+
+    result
+}
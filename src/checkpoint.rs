@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fs::{
+    create_dir_all,
+    read_to_string,
+    remove_file,
+    rename,
+    write,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    BufRead,
+    BufReader,
+    BufWriter,
+    Lines,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use crate::Result;
+
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    ruma::{
+        OwnedEventId,
+        OwnedRoomId,
+    },
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+///////////////
+//   Types   //
+///////////////
+
+// Where forward pagination through a room left off, such that picking it back up from end_token fetches only events newer than last_event_id.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RoomCheckpoint {
+    pub end_token: String,
+    pub last_event_id: Option<OwnedEventId>,
+}
+
+pub struct CheckpointsFile {
+    path: PathBuf,
+    pub rooms: HashMap<OwnedRoomId, RoomCheckpoint>,
+}
+
+impl CheckpointsFile {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let rooms = match read_to_string(&path) {
+            Ok(file) => serde_json::from_str(&file)?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(Self {
+            path,
+            rooms,
+        })
+    }
+
+    pub fn write(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&self.path, serde_json::to_string_pretty(&self.rooms)?)?;
+
+        Ok(())
+    }
+}
+
+// How far pagination got through a room before an export of it was interrupted. Only the first spooled_event_count events in the spool count, since the process may have died partway through appending a page.
+#[derive(Deserialize, Serialize)]
+pub struct ResumeProgress {
+    pub end_token: Option<String>,
+    pub last_event_id: Option<OwnedEventId>,
+    pub fetched_event_count: usize,
+    pub spooled_event_count: usize,
+    pub finished: bool,
+}
+
+// Keeps the events fetched so far during an export of a room on disk, one JSON-serialized event per line, so that an interrupted export can be picked back up without refetching them. Spools are keyed by everything that affects which events get fetched, so that a rerun of the same command finds its predecessor's spool and any other command doesn't.
+pub struct EventSpool {
+    events_path: PathBuf,
+    progress_path: PathBuf,
+}
+
+impl EventSpool {
+    pub fn new(resume_dir: &Path, key: &str) -> Self {
+        Self {
+            events_path: resume_dir.join(format!("{}.jsonl", key)),
+            progress_path: resume_dir.join(format!("{}.json", key)),
+        }
+    }
+
+    pub fn progress(&self) -> Result<Option<ResumeProgress>> {
+        match read_to_string(&self.progress_path) {
+            Ok(file) => Ok(Some(serde_json::from_str(&file)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn read_events(&self) -> Result<Lines<BufReader<File>>> {
+        Ok(BufReader::new(File::open(&self.events_path)?).lines())
+    }
+
+    // Cuts the spool back to its first event_count events, dropping whatever got appended after the last recorded progress (e.g. a page whose progress never got written before the process died), so that pages appended from here on follow straight on from the events which count.
+    pub fn truncate_to(&self, event_count: usize) -> Result<()> {
+        if !self.events_path.exists() {
+            return Ok(())
+        }
+        let mut events_file = BufReader::new(File::open(&self.events_path)?);
+        let mut byte_count = 0;
+        let mut line = Vec::new();
+        for _ in 0..event_count {
+            line.clear();
+            match events_file.read_until(b'\n', &mut line)? {
+                0 => break,
+                line_length => byte_count += line_length,
+            }
+        }
+        OpenOptions::new().write(true).open(&self.events_path)?.set_len(byte_count as u64)?;
+
+        Ok(())
+    }
+
+    // Progress gets written to a temporary file and moved into place, so that it's never seen half-written.
+    pub fn append_page(&self, events: &[TimelineEvent], progress: &ResumeProgress) -> Result<()> {
+        if let Some(parent) = self.events_path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut events_file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.events_path)?);
+        for event in events {
+            writeln!(events_file, "{}", serde_json::to_string(event)?)?;
+        }
+        events_file.flush()?;
+
+        let temporary_progress_path = self.progress_path.with_extension("json.tmp");
+        write(&temporary_progress_path, serde_json::to_string(progress)?)?;
+        rename(temporary_progress_path, &self.progress_path)?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        for path in [&self.progress_path, &self.events_path] {
+            if path.exists() {
+                remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+
+use matrix_sdk::{
+    encryption::{
+        recovery::RecoveryError,
+        CryptoStoreError,
+    },
+    ruma::{
+        IdParseError,
+        OwnedRoomId,
+    },
+    ClientBuildError,
+    HttpError,
+};
+
+use crate::SessionExpired;
+
+///////////////
+//   Types   //
+///////////////
+
+// Returned by all of the library's public functions, so that consumers can tell failures apart without picking through messages. Whatever doesn't fit any of the other variants ends up in Other.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Sessions file {} is invalid: {reason}", path.display())]
+    SessionsFile {
+        path: PathBuf,
+        reason: String,
+    },
+    #[error("Couldn't find currently-existing login session for {0}.")]
+    SessionNotFound(String), // Holds the session's key, as from session_key
+    #[error("There's already a logged-in session for {0}.")]
+    SessionExists(String), // Likewise
+    #[error(transparent)]
+    SessionExpired(#[from] SessionExpired),
+    #[error("Login failed: {0}")]
+    Login(String),
+    #[error("Couldn't find any rooms accessible to {user_id} matching {identifier}.")]
+    RoomNotFound {
+        user_id: String,
+        identifier: String,
+    },
+    #[error("Found more than one room accessible to {user_id} with name {name}. Room IDs: {room_ids:?}")]
+    AmbiguousRoomName {
+        user_id: String,
+        name: String,
+        room_ids: Vec<String>,
+    },
+    #[error("Couldn't fetch events from {room_id} due to error '{source}'.")]
+    Pagination {
+        room_id: OwnedRoomId,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("{0}")]
+    InvalidExportOptions(String),
+    #[error("Export cancelled. Whatever was fetched before cancellation has been written out; rerun the same command to resume from where it stopped.")]
+    ExportCancelled,
+    #[error("{0}")]
+    InvalidSchedule(String),
+    #[error("{0}")]
+    InvalidImport(String),
+    #[error("{0}")]
+    InvalidRedaction(String),
+    #[error(transparent)]
+    InvalidId(#[from] IdParseError),
+    #[error(transparent)]
+    ClientBuild(#[from] ClientBuildError),
+    #[error(transparent)]
+    Matrix(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Http(#[from] HttpError),
+    #[error(transparent)]
+    CryptoStore(#[from] CryptoStoreError),
+    #[error(transparent)]
+    Recovery(#[from] RecoveryError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Keyring(#[from] keyring::Error),
+    #[error(transparent)]
+    Index(#[from] tantivy::TantivyError),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Much of the library works in anyhow internally, so errors coming out of it get picked back apart into their variants where they can be.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<SessionExpired>() {
+            Ok(error) => return Self::SessionExpired(error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<matrix_sdk::Error>() {
+            Ok(error) => return Self::Matrix(error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<HttpError>() {
+            Ok(error) => return Self::Http(error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<std::io::Error>() {
+            Ok(error) => return Self::Io(error),
+            Err(error) => error,
+        };
+
+        Self::Other(error)
+    }
+}
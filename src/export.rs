@@ -1,225 +1,3493 @@
+use std::cmp::Reverse;
 use std::collections::{
     HashMap,
     HashSet,
 };
+use std::fmt::Display;
 use std::fs::{
     create_dir_all,
+    read,
+    remove_dir_all,
+    remove_file,
     write,
+    File,
+    OpenOptions,
 };
-use std::path::PathBuf;
+use std::io::{
+    stdout,
+    BufReader,
+    BufWriter,
+    Lines,
+    Write,
+};
+use std::iter::once;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::Duration;
 
 use crate::{
+    checkpoint::{
+        CheckpointsFile,
+        EventSpool,
+        ResumeProgress,
+        RoomCheckpoint,
+    },
     get_rooms_info,
+    media::{
+        download_event_media,
+        download_sender_avatars,
+        sha256_hex,
+        MEDIA_MANIFEST_FILENAME,
+    },
+    object_storage::ObjectStorage,
+    profiles::{
+        ProfileCacheFile,
+        SenderProfile,
+    },
+    receipts::{
+        get_read_receipts,
+        RoomReadReceipts,
+    },
+    retry::{
+        is_transient,
+        retry_rate_limited,
+        DEFAULT_MAX_RETRIES,
+    },
+    room_data::{
+        get_pinned_events,
+        get_room_account_data,
+        PinnedEvent,
+        RoomData,
+    },
+    synapse_admin::{
+        get_admin_event_context,
+        get_admin_room_details,
+        get_admin_room_messages,
+        is_server_admin,
+        AdminRoomDetails,
+    },
+    verify::{
+        record_appended_in_export_manifest,
+        record_in_export_manifest,
+        EXPORT_MANIFEST_FILENAME,
+    },
+    Error,
+    Result,
     RoomWithCachedInfo,
 };
 
-use chrono::{DateTime, SecondsFormat};
+use chrono::{
+    DateTime,
+    Local,
+    SecondsFormat,
+    TimeZone,
+    Utc,
+};
+use chrono_tz::Tz;
+use futures::{
+    stream,
+    StreamExt,
+};
+use regex::{
+    Captures,
+    Regex,
+};
+use serde_json::json;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{
+    debug,
+    info,
+    info_span,
+    instrument,
+    warn,
+    Instrument,
+};
 use matrix_sdk::{
-    deserialized_responses::TimelineEvent,
+    deserialized_responses::{
+        TimelineEvent,
+        TimelineEventKind,
+        UnableToDecryptInfo,
+        UnableToDecryptReason,
+    },
+    config::SyncSettings,
     room::MessagesOptions,
     ruma::{
+        api::{
+            client::message::get_message_events,
+            Direction,
+        },
         events::{
-            room::message::MessageType,
+            room::message::{
+                FormattedBody,
+                MessageFormat,
+                MessageType,
+                Relation,
+            },
             AnySyncMessageLikeEvent,
             AnySyncTimelineEvent,
+            AnyTimelineEvent,
+            SyncMessageLikeEvent,
         },
+        presence::PresenceState,
+        serde::Raw,
+        MxcUri,
+        OwnedEventId,
+        OwnedRoomAliasId,
+        OwnedRoomId,
+        OwnedUserId,
+        RoomAliasId,
+        RoomId,
+        RoomOrAliasId,
+        UInt,
         UserId
     },
     Client,
+    Room,
+    RoomMemberships,
+};
+
+// Bump this whenever the JSON output's structure changes in a way that could break its consumers.
+const JSON_SCHEMA_VERSION: u64 = 1;
+
+static UNKNOWN_SENDER_PROFILE: SenderProfile = SenderProfile {
+    display_name: None,
+    avatar_url: None,
+    fetched_at_millis: 0,
 };
 
+// Keys dropped from pseudonymized JSON output, since avatars and attachments can identify people as readily as their names can.
+const PSEUDONYMIZED_STRIPPED_KEYS: [&str; 7] = [
+    "avatar_url",
+    "file",
+    "media_file",
+    "sender_avatar",
+    "thumbnail_file",
+    "thumbnail_url",
+    "url",
+];
+
+// Keys whose values in pseudonymized JSON output are user IDs, or lists of them, to be swapped out whole. Everything else is left as it is, other than PSEUDONYMIZED_TEXT_KEYS, so that event and room IDs, event types, and the like come through intact.
+const PSEUDONYMIZED_USER_ID_KEYS: [&str; 6] = [
+    "exported_by",
+    "sender",
+    "senders", // Of reactions
+    "state_key",
+    "user_id",
+    "user_ids", // Of mentions
+];
+
+// Keys whose values are free text, or lists of it, which can mention people by name or user ID anywhere in it. Aliases are included since they often name people too, as they do in export filenames.
+const PSEUDONYMIZED_TEXT_KEYS: [&str; 13] = [
+    "alias",
+    "aliases",
+    "alt_aliases",
+    "body",
+    "canonical_alias",
+    "display_name",
+    "displayname",
+    "filename",
+    "formatted_body",
+    "name",
+    "question", // Of polls
+    "reason",
+    "topic",
+];
+
+// How long each sync while following waits for new events before returning empty-handed, and how long to wait before trying again after one fails.
+const FOLLOW_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+const FOLLOW_SYNC_RETRY_DELAY: Duration = Duration::from_secs(10);
+// How many events a limited sync while following backfills before giving up and reporting a gap instead, so that a room which wasn't exported beforehand doesn't get its whole history pulled in
+const FOLLOW_BACKFILL_EVENT_LIMIT: usize = 10_000;
+
 ///////////////
 //   Types   //
 ///////////////
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum ExportOutputFormat {
     Json,
     Txt,
 }
 
-enum RoomIndexRetrievalError {
-    MultipleRoomsWithSpecifiedName(Vec<String>),
-    NoRoomsWithSpecifiedName,
+#[derive(Clone, Copy)]
+pub enum UpgradeChainMode {
+    Merged,
+    Separate,
 }
 
-//////////////
-//   Main   //
-//////////////
+#[derive(Default)]
+pub enum ExportTimezone {
+    #[default]
+    Utc,
+    Local,
+    Named(Tz),
+}
 
-fn get_room_index_by_identifier(rooms_info: &[RoomWithCachedInfo], identifier: &str) -> Result<usize, RoomIndexRetrievalError> {
-    if let Some(index) = rooms_info.iter().position(|room_info| room_info.id == identifier) {
-        Ok(index)
-    } else if let Some(index) = rooms_info.iter().position(|room_info| room_info.canonical_alias.as_ref().is_some_and(|alias| alias == identifier)) {
-        Ok(index)
-    } else if let Some(index) = rooms_info.iter().position(|room_info| room_info.alt_aliases.iter().any(|alias| alias == identifier)) {
-        Ok(index)
-    } else {
-        let name_matches = rooms_info.iter().filter(|room_info| room_info.name.as_ref().is_some_and(|name| name == identifier)).collect::<Vec<&RoomWithCachedInfo>>();
-        match name_matches.len() {
-            0 => Err(RoomIndexRetrievalError::NoRoomsWithSpecifiedName),
-            1 => Ok(rooms_info.iter().position(|room_info| room_info.name.as_ref().is_some_and(|name| name  == identifier)).unwrap()),
-            _ => Err(RoomIndexRetrievalError::MultipleRoomsWithSpecifiedName(name_matches.iter().map(|room_info| room_info.id.to_string()).collect())),
+pub struct TxtOptions {
+    pub group_threads: bool, // Move thread replies up under their root messages rather than leaving them in timeline order
+    pub mark_edits: bool, // Append '(edited)' to messages displayed with their latest edit applied
+    pub edit_history: bool, // List each edited message's previous versions beneath it
+    pub timezone: ExportTimezone,
+    pub timestamp_format: Option<String>, // strftime-style format string; if unspecified, timestamps are formatted per RFC 3339
+    pub permalinks: bool, // Append each event's ID and matrix.to permalink to its line. (In merged upgrade-chain exports, these all point at the newest room in the chain.)
+    pub day_separators: bool, // Insert a header line whenever the date changes, in the selected time zone
+    pub html_to_markdown: bool, // Render messages with HTML formatting by converting it to Markdown, rather than using their plaintext fallbacks
+    pub historical_display_names: bool, // Attribute messages using the display names their senders had at the time, rather than their current ones
+}
+
+impl Default for TxtOptions {
+    fn default() -> Self {
+        Self {
+            group_threads: false,
+            mark_edits: false,
+            edit_history: false,
+            timezone: ExportTimezone::default(),
+            timestamp_format: None,
+            permalinks: false,
+            day_separators: true,
+            html_to_markdown: false,
+            historical_display_names: true,
         }
     }
 }
 
-fn format_export_filename(room_info: &RoomWithCachedInfo) -> String {
-    let (nonserver_id_component, server) = room_info.id.as_str().split_once(':').unwrap();
-    match (&room_info.name, &room_info.canonical_alias) {
-        (Some(name), Some(alias)) => format!("{} [{}, {}, {}]", name, alias.as_str().split_once(':').unwrap().0, nonserver_id_component, server),
-        (Some(name), None) => format!("{} [{}, {}]", name, nonserver_id_component, server),
-        (None, Some(alias)) => format!("{} [{}, {}]", alias.as_str().split_once(':').unwrap().0, nonserver_id_component, server),
-        (None, None) => format!("{} [{}]", nonserver_id_component, server),
+#[derive(Default)]
+pub struct JsonOptions {
+    pub sender_profiles: bool, // Add a table of each sender's current display name and avatar URL alongside the events
+    pub compact: bool, // Skip pretty-printing
+}
+
+pub struct PaginationOptions {
+    pub limit: Option<usize>, // Maximum number of events to fetch per room, counting from whichever end pagination starts at
+    pub newest_first: bool, // Paginate backwards from the present, writing output in reverse-chronological order
+    pub max_retries: u32, // Maximum number of times to retry each rate-limited request, for both pagination and media downloads
+    pub backfill_retries: u32, // Maximum number of times to retry pages which fail for reasons other than rate-limiting, before giving up on the rest of the room and recording a gap
+    pub page_size: u16, // Number of events to ask for per /messages request. 1000 seems to be a server-side cap, at least on matrix.org
+    pub request_delay: Duration, // Time to wait between each room's requests, to go easier on small servers
+}
+
+impl Default for PaginationOptions {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            newest_first: false,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backfill_retries: 2,
+            page_size: 1_000,
+            request_delay: Duration::ZERO,
+        }
     }
 }
 
-fn messages_to_json(events: &Vec<TimelineEvent>) -> String {
-    // Possibly add more secondary-representations-of-events here, analogous to e.g. the display-name-retrieval and datetime-formatting and so forth in the txt output?
-    // Also possibly some metadata analogous to what gets output at the head of DiscordChatExporter's JSON exports?
-    let mut events_to_export = Vec::new();
+// Bounds on an export, inclusive at both ends. Since event IDs are room-specific, these are only meaningful when exporting a single room.
+#[derive(Default)]
+pub struct ExportEventRange {
+    pub from: Option<OwnedEventId>,
+    pub to: Option<OwnedEventId>,
+}
 
-    for event in events {
-        let event_deserialized = event.raw().deserialize_as::<serde_json::Value>().expect("Failed to deserialize a message to JSON value. (This is surprising.)"); // Add real error-handling here
-        events_to_export.push(event_deserialized);
+// An empty include list means every event type not explicitly excluded gets exported. Event types with unstable-prefixed aliases (e.g. MSC3381's polls) match under either name.
+#[derive(Default)]
+pub struct EventTypeFilter {
+    pub include: HashSet<String>,
+    pub exclude: HashSet<String>,
+}
+
+impl EventTypeFilter {
+    fn matches(&self, event: &TimelineEvent) -> bool {
+        match event.raw().get_field::<String>("type") {
+            Ok(Some(event_type)) => {
+                let event_type = stable_event_type(&event_type);
+                let is_listed = |event_types: &HashSet<String>| event_types.iter().any(|listed_type| stable_event_type(listed_type) == event_type);
+                (self.include.is_empty() || is_listed(&self.include)) && !is_listed(&self.exclude)
+            }
+            _ => true,
+        }
     }
+}
 
-    serde_json::to_string_pretty(&events_to_export).unwrap()
+pub struct ContentFilter {
+    pub pattern: Regex,
+    pub context: usize, // Number of events on either side of each match to keep alongside it
 }
 
-async fn user_id_to_string_representation(user_ids_to_string_representations: &mut HashMap<String, String>, room_info: &RoomWithCachedInfo, event_sender_id: &UserId) -> anyhow::Result<String> {
-    let event_sender_id_string = event_sender_id.to_string();
-    match user_ids_to_string_representations.get(&event_sender_id_string) {
-        Some(string_representation) => Ok(string_representation.clone()),
-        None => match room_info.room.get_member_no_sync(event_sender_id).await? {
-            Some(room_member) => {
-                let string_representation = match room_member.display_name() {
-                    Some(display_name) => format!("{} ({})", display_name, event_sender_id_string),
-                    None => event_sender_id_string.clone(),
-                };
-                user_ids_to_string_representations.insert(event_sender_id_string.clone(), string_representation);
-                Ok(user_ids_to_string_representations.get(&event_sender_id_string).unwrap().clone())
+impl ContentFilter {
+    fn matches(&self, event: &TimelineEvent) -> bool {
+        match event.raw().get_field::<serde_json::Value>("content") {
+            Ok(Some(content)) => content.get("body").and_then(|body| body.as_str()).is_some_and(|body| self.pattern.is_match(body)),
+            _ => false,
+        }
+    }
+
+    fn filter(&self, events: Vec<TimelineEvent>) -> Vec<TimelineEvent> {
+        let mut events_to_keep = vec![false; events.len()];
+        for (index, event) in events.iter().enumerate() {
+            if self.matches(event) {
+                let context_end = (index + self.context + 1).min(events.len());
+                events_to_keep[index.saturating_sub(self.context)..context_end].fill(true);
             }
-            None => {
-                user_ids_to_string_representations.insert(event_sender_id_string.clone(), event_sender_id_string.clone());
-                Ok(event_sender_id_string)
-            },
-        },
+        }
+
+        events.into_iter().zip(events_to_keep).filter_map(|(event, keep)| keep.then_some(event)).collect()
     }
 }
 
-async fn messages_to_txt(events: &Vec<TimelineEvent>, room_info: &RoomWithCachedInfo) -> anyhow::Result<String> {
-    let mut user_ids_to_string_representations: HashMap<String, String> = HashMap::new();
-    let mut room_export = String::new();
+#[derive(Clone, Copy)]
+enum NameTemplatePlaceholder {
+    Name,
+    Alias,
+    Id,
+    Server,
+    Date,
+}
 
-    for event in events {
-        let event_deserialized = match event.raw().deserialize() {
-            Ok(event_deserialized) => event_deserialized,
-            Err(_) => {
-                // Add more nuanced error-handling here; it seems like a lot of these are in fact redacted messages, just weirdly-formed ones that don't deserialize right?
-                room_export.push_str("[Message skipped due to deserialization failure]\n");
-                continue
+enum NameTemplateSegment {
+    Literal(String),
+    Placeholders(Vec<NameTemplatePlaceholder>), // Alternatives separated by '|' in the template, with the first one the room has a value for getting used
+}
+
+// Templates for output filenames, e.g. '{alias|id}-{date}'. Placeholders are {name}, {alias} (without its server), {id} (without its server), {server}, and {date} (the export date, in UTC).
+pub struct NameTemplate {
+    segments: Vec<NameTemplateSegment>,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut remainder = template;
+        while let Some(placeholder_start) = remainder.find('{') {
+            if placeholder_start > 0 {
+                segments.push(NameTemplateSegment::Literal(remainder[..placeholder_start].to_owned()));
             }
-        };
+            let Some(placeholder_length) = remainder[placeholder_start..].find('}') else {
+                return Err(Error::InvalidExportOptions(format!("Name template {} has an unclosed '{{'.", template)))
+            };
+            let placeholders = remainder[placeholder_start + 1..placeholder_start + placeholder_length].split('|').map(|placeholder| match placeholder.trim() {
+                "name" => Ok(NameTemplatePlaceholder::Name),
+                "alias" => Ok(NameTemplatePlaceholder::Alias),
+                "id" => Ok(NameTemplatePlaceholder::Id),
+                "server" => Ok(NameTemplatePlaceholder::Server),
+                "date" => Ok(NameTemplatePlaceholder::Date),
+                _ => Err(Error::InvalidExportOptions(format!("Name template {} has unrecognized placeholder {}. Valid placeholders are name, alias, id, server, and date.", template, placeholder))),
+            }).collect::<Result<Vec<NameTemplatePlaceholder>>>()?;
+            segments.push(NameTemplateSegment::Placeholders(placeholders));
+            remainder = &remainder[placeholder_start + placeholder_length + 1..];
+        }
+        if !remainder.is_empty() {
+            segments.push(NameTemplateSegment::Literal(remainder.to_owned()));
+        }
 
-        let event_timestamp_millis = event_deserialized.origin_server_ts().0.into();
-        let event_timestamp_string_representation = DateTime::from_timestamp_millis(event_timestamp_millis).unwrap_or_else(|| panic!("Found message with millisecond timestamp {}, which can't be converted to datetime.", event_timestamp_millis)).to_rfc3339_opts(SecondsFormat::Millis, true); // Add real error-handling, and also an option to use local time zones
+        Ok(Self {
+            segments,
+        })
+    }
 
-        let event_sender_id = event_deserialized.sender();
-        let event_sender_string_representation = user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, event_sender_id).await?;
+    // The alias comes without its server name.
+    fn render(&self, id: &RoomId, name: Option<&str>, alias: Option<&str>, export_date: &str) -> String {
+        let (nonserver_id_component, server) = split_room_id(id);
+        self.segments.iter().map(|segment| match segment {
+            NameTemplateSegment::Literal(literal) => literal.as_str(),
+            NameTemplateSegment::Placeholders(placeholders) => placeholders.iter().find_map(|placeholder| match placeholder {
+                NameTemplatePlaceholder::Name => name,
+                NameTemplatePlaceholder::Alias => alias,
+                NameTemplatePlaceholder::Id => Some(nonserver_id_component),
+                NameTemplatePlaceholder::Server => server,
+                NameTemplatePlaceholder::Date => Some(export_date),
+            }).unwrap_or_default(),
+        }).collect()
+    }
+}
 
-        let event_prefix = format!("[{}] {}:", event_timestamp_string_representation, event_sender_string_representation);
+struct MessageEdit {
+    event_id: OwnedEventId,
+    timestamp_millis: i64,
+    msgtype: MessageType,
+}
 
-        let event_stringified = match &event_deserialized {
-            AnySyncTimelineEvent::MessageLike(e) => match e {
-                AnySyncMessageLikeEvent::RoomMessage(e) => match &e.as_original() {
-                    Some(unredacted_room_message) => match &unredacted_room_message.content.msgtype {
-                        // Possibly revisit here at some point to add more detail beyond the body into various of these formats
-                        MessageType::Audio(e) => format!("{} [Audio; textual representation: {}]", event_prefix, &e.body),
-                        MessageType::Emote(e) => format!("{} *{}*", event_prefix, &e.body), // Think harder about whether asterisks are the correct representation here
-                        MessageType::File(e) => format!("{} [File; textual representation: {}]", event_prefix, &e.body), // In the longer term maybe include filename directly? But currently it seems like the textual representation is the main thing that's actually used to encode the filename
-                        MessageType::Image(e) => format!("{} [Image; textual representation: {}]", event_prefix, &e.body),
-                        MessageType::Location(e) => format!("{} [Location; geo URI: {}; textual representation: {}]", event_prefix, &e.geo_uri, &e.body),
-                        MessageType::Notice(e) => format!("{} [{}]", event_prefix, &e.body), // Think harder about whether brackets are the correct representation here
-                        MessageType::ServerNotice(e) => format!("{} [Server notice: {}]", event_prefix, &e.body),
-                        MessageType::Text(e) => format!("{} {}", event_prefix, &e.body),
-                        MessageType::Video(e) => format!("{} [Video; textual representation: {}]", event_prefix, &e.body),
-                        MessageType::VerificationRequest(e) => format!("{} [Verification request sent to {}]", event_prefix, user_id_to_string_representation(&mut user_ids_to_string_representations, room_info, &e.to).await?),
-                        _ => String::from("[Message of unrecognized type]"),
-                    }
-                    None => format!("{} [Redacted message]", event_prefix),
-                },
-                _ => String::from("[Placeholder message-like]"),
-            },
-            AnySyncTimelineEvent::State(_e) => String::from("[Placeholder state-like]"),
-        };
-        room_export.push_str(&format!("{}\n", event_stringified))
+struct Poll {
+    question: String,
+    answers: Vec<(String, String)>, // (Answer ID, answer text) pairs
+    selections_by_sender: HashMap<OwnedUserId, Vec<String>>,
+    response_event_ids: HashSet<OwnedEventId>,
+}
+
+impl Poll {
+    fn tally(&self) -> String {
+        self.answers.iter().map(|(answer_id, answer_text)| {
+            let vote_count = self.selections_by_sender.values().filter(|selections| selections.contains(answer_id)).count();
+            format!("{} ×{}", answer_text, vote_count)
+        }).collect::<Vec<String>>().join(", ")
     }
+}
 
-    Ok(room_export)
+struct ReactionGroup {
+    key: String,
+    senders: Vec<OwnedUserId>,
+    event_ids: Vec<OwnedEventId>,
 }
 
-pub async fn export(client: &Client, rooms: Vec<String>, output_path: Option<PathBuf>, formats: HashSet<ExportOutputFormat>) -> anyhow::Result<()> {
-    if let Some(path) = output_path.as_ref() {
-        if path.exists() {
-            if !path.is_dir() {
-                // Add real error-handling here
-                panic!("Output path {} isn't a directory.", path.display());
+pub enum ExportDestination {
+    Directory(Option<PathBuf>), // If unspecified, the current directory
+    Stdout, // Writes the events alone, as JSON lines when exporting JSON, so that the output can be piped straight into other tools
+    // Each room gets written out to the staging directory (a temporary one, if unspecified) as usual, then uploaded along with its avatars and media and deleted, so that no more than a room's worth is ever kept locally. The manifests stay, for later rooms to add to, and get uploaded after each room too. Since the media store doesn't stay around, media shared between rooms gets downloaded again for each of them.
+    ObjectStorage(ObjectStorage, Option<PathBuf>),
+}
+
+impl ExportDestination {
+    // Where avatars and media get downloaded to. (These aren't supported for stdout exports, but fall back on the current directory regardless.)
+    fn directory(&self) -> PathBuf {
+        match self {
+            ExportDestination::Directory(output_path) => output_path.clone().unwrap_or_default(),
+            ExportDestination::Stdout => PathBuf::new(),
+            ExportDestination::ObjectStorage(_, staging_path) => staging_path.clone().unwrap_or_else(|| std::env::temp_dir().join(format!("trace-staging-{}", std::process::id()))),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum SplitMode {
+    Monthly,
+    Yearly,
+    Size(usize), // Approximate maximum bytes per file, as measured by the events' JSON
+}
+
+// Everything about an export besides the client to run it with. Start from ExportOptions::new() and chain on whatever's wanted; anything left unset keeps the default noted beside it. Optional settings take either the value itself or an Option of it.
+pub struct ExportOptions<'a> {
+    rooms: Vec<String>, // IDs, aliases, names, or globs over names and aliases
+    room_patterns: Vec<Regex>, // Matched against names and aliases
+    dm_users: Vec<OwnedUserId>, // Exports every direct-message room with each of these
+    peek: bool, // Fall back on peeking into world-readable rooms which were given by ID or alias but aren't joined
+    admin: bool, // Fetch every room's history through the Synapse admin API, including rooms given by ID or alias which aren't joined; needs a server-admin account
+    destination: ExportDestination, // Defaults to the current directory
+    name_template: Option<NameTemplate>,
+    formats: HashSet<ExportOutputFormat>, // Defaults to JSON alone
+    split_mode: Option<SplitMode>,
+    streaming: bool, // Write each page as it's fetched, rather than holding each room's history in memory first
+    follow: bool, // Keep syncing once the export's done, appending new events to each room's output as they arrive, until cancelled
+    download_avatars: bool,
+    download_media: bool,
+    read_receipts: bool, // Note how far each member has read on the events they've read up to, along with the account's own read marker
+    room_data: bool, // Include each joined room's pinned events, resolved to the events themselves, and the account's account data for it (e.g. tags)
+    event_range: ExportEventRange,
+    event_type_filter: EventTypeFilter,
+    content_filter: Option<ContentFilter>,
+    follow_upgrades: Option<UpgradeChainMode>,
+    pseudonymize: bool, // Replace user IDs and display names with stable pseudonyms, leaving out avatars and media
+    offline: bool, // Export only what's in the local cache, without contacting the homeserver
+    incremental_checkpoints: Option<CheckpointsFile>, // Pick up each room where the last export recorded in here left off, and record where this one leaves off
+    profile_cache: Option<ProfileCacheFile>,
+    resume_dir: Option<PathBuf>, // Where to spool fetched events, for an interrupted export to resume from
+    jobs: usize, // Number of rooms to fetch at once; defaults to 4
+    key_request_wait: Option<Duration>, // If set, missing room keys get requested from other devices, with this long to wait for them
+    pagination_options: PaginationOptions,
+    json_options: JsonOptions,
+    txt_options: TxtOptions,
+    progress: Option<&'a dyn Fn(ExportProgress)>,
+    cancellation: Option<&'a CancellationToken>,
+}
+
+impl Default for ExportOptions<'_> {
+    fn default() -> Self {
+        Self {
+            rooms: Vec::new(),
+            room_patterns: Vec::new(),
+            dm_users: Vec::new(),
+            peek: false,
+            admin: false,
+            destination: ExportDestination::Directory(None),
+            name_template: None,
+            formats: HashSet::from([ExportOutputFormat::Json]),
+            split_mode: None,
+            streaming: false,
+            follow: false,
+            download_avatars: false,
+            download_media: false,
+            read_receipts: false,
+            room_data: false,
+            event_range: ExportEventRange::default(),
+            event_type_filter: EventTypeFilter::default(),
+            content_filter: None,
+            follow_upgrades: None,
+            pseudonymize: false,
+            offline: false,
+            incremental_checkpoints: None,
+            profile_cache: None,
+            resume_dir: None,
+            jobs: 4,
+            key_request_wait: None,
+            pagination_options: PaginationOptions::default(),
+            json_options: JsonOptions::default(),
+            txt_options: TxtOptions::default(),
+            progress: None,
+            cancellation: None,
+        }
+    }
+}
+
+impl<'a> ExportOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rooms(mut self, rooms: Vec<String>) -> Self {
+        self.rooms = rooms;
+        self
+    }
+
+    pub fn room_patterns(mut self, room_patterns: Vec<Regex>) -> Self {
+        self.room_patterns = room_patterns;
+        self
+    }
+
+    pub fn dm_users(mut self, dm_users: Vec<OwnedUserId>) -> Self {
+        self.dm_users = dm_users;
+        self
+    }
+
+    pub fn peek(mut self, peek: bool) -> Self {
+        self.peek = peek;
+        self
+    }
+
+    pub fn admin(mut self, admin: bool) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    pub fn destination(mut self, destination: ExportDestination) -> Self {
+        self.destination = destination;
+        self
+    }
+
+    pub fn name_template(mut self, name_template: impl Into<Option<NameTemplate>>) -> Self {
+        self.name_template = name_template.into();
+        self
+    }
+
+    pub fn formats(mut self, formats: HashSet<ExportOutputFormat>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn split_mode(mut self, split_mode: impl Into<Option<SplitMode>>) -> Self {
+        self.split_mode = split_mode.into();
+        self
+    }
+
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    pub fn follow(mut self, follow: bool) -> Self {
+        self.follow = follow;
+        self
+    }
+
+    pub fn download_avatars(mut self, download_avatars: bool) -> Self {
+        self.download_avatars = download_avatars;
+        self
+    }
+
+    pub fn download_media(mut self, download_media: bool) -> Self {
+        self.download_media = download_media;
+        self
+    }
+
+    pub fn read_receipts(mut self, read_receipts: bool) -> Self {
+        self.read_receipts = read_receipts;
+        self
+    }
+
+    pub fn room_data(mut self, room_data: bool) -> Self {
+        self.room_data = room_data;
+        self
+    }
+
+    pub fn event_range(mut self, event_range: ExportEventRange) -> Self {
+        self.event_range = event_range;
+        self
+    }
+
+    pub fn event_type_filter(mut self, event_type_filter: EventTypeFilter) -> Self {
+        self.event_type_filter = event_type_filter;
+        self
+    }
+
+    pub fn content_filter(mut self, content_filter: impl Into<Option<ContentFilter>>) -> Self {
+        self.content_filter = content_filter.into();
+        self
+    }
+
+    pub fn follow_upgrades(mut self, follow_upgrades: impl Into<Option<UpgradeChainMode>>) -> Self {
+        self.follow_upgrades = follow_upgrades.into();
+        self
+    }
+
+    pub fn pseudonymize(mut self, pseudonymize: bool) -> Self {
+        self.pseudonymize = pseudonymize;
+        self
+    }
+
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn incremental_checkpoints(mut self, incremental_checkpoints: impl Into<Option<CheckpointsFile>>) -> Self {
+        self.incremental_checkpoints = incremental_checkpoints.into();
+        self
+    }
+
+    pub fn profile_cache(mut self, profile_cache: impl Into<Option<ProfileCacheFile>>) -> Self {
+        self.profile_cache = profile_cache.into();
+        self
+    }
+
+    pub fn resume_dir(mut self, resume_dir: impl Into<Option<PathBuf>>) -> Self {
+        self.resume_dir = resume_dir.into();
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    pub fn key_request_wait(mut self, key_request_wait: impl Into<Option<Duration>>) -> Self {
+        self.key_request_wait = key_request_wait.into();
+        self
+    }
+
+    pub fn pagination_options(mut self, pagination_options: PaginationOptions) -> Self {
+        self.pagination_options = pagination_options;
+        self
+    }
+
+    pub fn json_options(mut self, json_options: JsonOptions) -> Self {
+        self.json_options = json_options;
+        self
+    }
+
+    pub fn txt_options(mut self, txt_options: TxtOptions) -> Self {
+        self.txt_options = txt_options;
+        self
+    }
+
+    pub fn progress(mut self, progress: &'a dyn Fn(ExportProgress)) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn cancellation(mut self, cancellation: &'a CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum EventSource<'a> {
+    Joined(&'a Room),
+    // Peeking goes around the SDK's room handling, since it only keeps track of rooms the account is in; as such, events from peeked rooms are never decrypted. (World-readable rooms are rarely encrypted anyway.)
+    Peeked(&'a Client, &'a RoomId),
+    // Offline exports read from the SDK's local event cache instead, which only holds whatever's come in through syncs since it was first enabled, rather than the room's full history
+    Cached(&'a Room),
+    // The Synapse admin API hands out a room's whole history regardless of history visibility or membership, but as raw events, so only rooms the account's in (given alongside) get their events decrypted
+    Admin(&'a Client, &'a RoomId, Option<&'a Room>),
+}
+
+#[derive(Clone)]
+enum TimelineGapKind {
+    HistoryUnavailable, // Pagination ran out without reaching the room's creation, e.g. due to history purged server-side or hidden by history visibility
+    FetchFailed(String),
+}
+
+// A stretch of a room's timeline known to be missing from an export. Gaps sit right after preceding_event_id in pagination order, or before everything if it's None.
+#[derive(Clone)]
+struct TimelineGap {
+    preceding_event_id: Option<OwnedEventId>,
+    kind: TimelineGapKind,
+}
+
+// Fetches a room's events a page at a time, so that each page can be dealt with before the next one gets fetched.
+pub(crate) struct EventPager<'a> {
+    source: EventSource<'a>,
+    start_event: Option<OwnedEventId>,
+    end_event: Option<OwnedEventId>,
+    newest_first: bool,
+    event_limit: usize,
+    max_retries: u32,
+    remaining_backfill_retries: u32,
+    page_size: u16,
+    request_delay: Duration,
+    made_request: bool,
+    gaps: Vec<TimelineGap>,
+    fetched_event_count: usize,
+    last_end_token: Option<String>,
+    last_event_id: Option<OwnedEventId>,
+    resumed_through_event: Option<OwnedEventId>, // The last event exported before resuming from a checkpoint, which the first page fetched afterwards can still overlap
+    finished: bool,
+    spool: Option<EventSpool>,
+    replay: Option<(Lines<BufReader<File>>, usize)>, // Spooled events left to replay before fetching resumes
+}
+
+impl<'a> EventPager<'a> {
+    // Event ranges are ignored for peeked rooms, since there's no /context equivalent available to them
+    pub(crate) fn new(source: EventSource<'a>, event_range: &ExportEventRange, pagination_options: &PaginationOptions) -> Self {
+        let (start_event, end_event) = match (&source, pagination_options.newest_first) {
+            (EventSource::Peeked(..), _) => (None, None),
+            (EventSource::Joined(_) | EventSource::Cached(_) | EventSource::Admin(..), true) => (event_range.to.clone(), event_range.from.clone()),
+            (EventSource::Joined(_) | EventSource::Cached(_) | EventSource::Admin(..), false) => (event_range.from.clone(), event_range.to.clone()),
+        };
+        Self {
+            source,
+            start_event,
+            end_event,
+            newest_first: pagination_options.newest_first,
+            event_limit: pagination_options.limit.unwrap_or(usize::MAX),
+            max_retries: pagination_options.max_retries,
+            remaining_backfill_retries: pagination_options.backfill_retries,
+            page_size: pagination_options.page_size.max(1),
+            request_delay: pagination_options.request_delay,
+            made_request: false,
+            gaps: Vec::new(),
+            fetched_event_count: 0,
+            last_end_token: None,
+            last_event_id: None,
+            resumed_through_event: None,
+            finished: false,
+            spool: None,
+            replay: None,
+        }
+    }
+
+    // Saves each fetched page to the given spool. If the spool is left over from an interrupted run, its events get replayed first, and fetching picks up where that run left off.
+    fn spool_to(&mut self, spool: EventSpool) -> anyhow::Result<()> {
+        if let Some(progress) = spool.progress()? {
+            self.start_event = None;
+            self.last_end_token = progress.end_token;
+            self.last_event_id = progress.last_event_id;
+            self.fetched_event_count = progress.fetched_event_count;
+            self.finished = progress.finished;
+            spool.truncate_to(progress.spooled_event_count)?;
+            self.replay = Some((spool.read_events()?, progress.spooled_event_count));
+        }
+        self.spool = Some(spool);
+
+        Ok(())
+    }
+
+    fn discard_spool(&mut self) -> anyhow::Result<()> {
+        match self.spool.take() {
+            Some(spool) => Ok(spool.remove()?),
+            None => Ok(()),
+        }
+    }
+
+    fn room_id(&self) -> &RoomId {
+        match self.source {
+            EventSource::Joined(room) | EventSource::Cached(room) => room.room_id(),
+            EventSource::Peeked(_, room_id) | EventSource::Admin(_, room_id, _) => room_id,
+        }
+    }
+
+    // For consumers other than export, which have nowhere to note gaps down, so that a page which wouldn't load fails them rather than leaving them with results quietly missing part of the room.
+    pub(crate) fn ensure_no_fetch_failures(&self) -> Result<()> {
+        for gap in &self.gaps {
+            if let TimelineGapKind::FetchFailed(reason) = &gap.kind {
+                return Err(Error::Pagination {
+                    room_id: self.room_id().to_owned(),
+                    source: reason.clone().into(),
+                })
             }
-        } else {
-            create_dir_all(path).unwrap();
         }
+
+        Ok(())
     }
 
-    let accessible_rooms_info = get_rooms_info(client).await?; // This should be possible to optimize out for request-piles without names included, given client.resolve_room_alias and client.get_room. Although that might end up actually costlier if handled indelicately, since it'll involve more serial processing.
+    fn resume_from(&mut self, checkpoint: &RoomCheckpoint) {
+        self.start_event = None;
+        self.last_end_token = Some(checkpoint.end_token.clone());
+        self.last_event_id = checkpoint.last_event_id.clone();
+        self.resumed_through_event = checkpoint.last_event_id.clone();
+    }
 
-    for room_identifier in rooms {
-        let room_to_export_info = match get_room_index_by_identifier(&accessible_rooms_info, &room_identifier) {
-            Ok(index) => &accessible_rooms_info[index],
-            Err(e) => match e {
-                // This is currently CLI-biased; modify it to return error-info in a more neutral way
-                RoomIndexRetrievalError::MultipleRoomsWithSpecifiedName(room_ids) => {
-                    println!("Found more than one room accessible to {} with name {}. Room IDs: {:?}", client.user_id().unwrap(), room_identifier, room_ids);
-                    continue
-                },
-                RoomIndexRetrievalError::NoRoomsWithSpecifiedName => {
-                    println!("Couldn't find any rooms accessible to {} with name {}.", client.user_id().unwrap(), room_identifier);
-                    continue
-                },
+    fn checkpoint(&self) -> Option<RoomCheckpoint> {
+        Some(RoomCheckpoint {
+            end_token: self.last_end_token.clone()?,
+            last_event_id: self.last_event_id.clone(),
+        })
+    }
+
+    #[instrument(level = "debug", skip_all, fields(room_id = %self.room_id()))]
+    pub(crate) async fn next_page(&mut self) -> anyhow::Result<Option<Vec<TimelineEvent>>> {
+        if let Some((spooled_events, remaining_event_count)) = self.replay.as_mut() {
+            let page = spooled_events.by_ref().take((*remaining_event_count).min(self.page_size.into())).map(|line| Ok(serde_json::from_str::<TimelineEvent>(&line?)?)).collect::<anyhow::Result<Vec<TimelineEvent>>>()?;
+            *remaining_event_count -= page.len();
+            if !page.is_empty() {
+                debug!(event_count = page.len(), "Replayed spooled page");
+                return Ok(Some(page))
+            }
+            self.replay = None;
+        }
+
+        // Pagination can't skip past a page which won't load, since the next page's token comes from it, so the rest of the room gets recorded as a gap instead. The pagination token is left where it was, so that incremental exports fill the gap in next time. Only transient failures get this treatment; others (e.g. being forbidden from the room, or a bad start event) would fail again on every try, so they fail the room outright.
+        let page = loop {
+            match self.fetch_page().await {
+                Ok(page) => break page,
+                Err(e) if !is_transient(&e) => return Err(Error::Pagination {
+                    room_id: self.room_id().to_owned(),
+                    source: e.into(),
+                }.into()),
+                Err(e) if self.remaining_backfill_retries > 0 => {
+                    self.remaining_backfill_retries -= 1;
+                    warn!("{} Retrying.", Error::Pagination {
+                        room_id: self.room_id().to_owned(),
+                        source: e.into(),
+                    });
+                }
+                Err(e) => {
+                    self.gaps.push(TimelineGap {
+                        preceding_event_id: self.last_event_id.clone(),
+                        kind: TimelineGapKind::FetchFailed(e.to_string()),
+                    });
+                    self.finished = true;
+                    break None
+                }
             }
         };
+        if let Some(page) = &page {
+            debug!(event_count = page.len(), fetched_event_count = self.fetched_event_count, "Fetched page");
+        }
+        if let (Some(page), Some(spool)) = (page.as_ref(), self.spool.as_ref()) {
+            let spooled_event_count = spool.progress()?.map(|progress| progress.spooled_event_count).unwrap_or_default() + page.len();
+            spool.append_page(page, &ResumeProgress {
+                end_token: self.last_end_token.clone(),
+                last_event_id: self.last_event_id.clone(),
+                fetched_event_count: self.fetched_event_count,
+                spooled_event_count,
+                finished: self.finished,
+            })?;
+        }
 
-        let mut events = Vec::new();
-        let mut last_end_token = None;
-        let mut total_messages = 0;
-        loop {
-            let mut messages_options = MessagesOptions::forward().from(last_end_token.as_deref());
-            messages_options.limit = 1_000_u16.into(); // On an initial test, this seems to be a server-side limit, at least on matrix.org. Worth setting higher just in case other servers are less limited?
-            let mut messages = room_to_export_info.room.messages(messages_options).await?;
-            let messages_length = messages.chunk.len();
-            total_messages += messages_length;
-            if messages_length == 0 || total_messages > 10_000_000 {
-                break
+        Ok(page)
+    }
+
+    async fn fetch_page(&mut self) -> anyhow::Result<Option<Vec<TimelineEvent>>> {
+        if self.finished {
+            return Ok(None)
+        }
+        if self.made_request && !self.request_delay.is_zero() && !matches!(self.source, EventSource::Cached(_)) {
+            tokio::time::sleep(self.request_delay).await;
+        }
+        self.made_request = true;
+
+        let start_event_context = match (self.start_event.clone(), self.source) {
+            (Some(start_event), EventSource::Joined(room)) => {
+                let context = retry_rate_limited(self.max_retries, || async { Ok(room.event_with_context(&start_event, true, UInt::MIN, None).await?) }).await?;
+                Some((start_event, context.event, context.prev_batch_token, context.next_batch_token))
             }
-            events.append(&mut messages.chunk);
-            last_end_token = messages.end;
+            (Some(start_event), EventSource::Admin(client, room_id, room)) => {
+                let context = get_admin_event_context(client, room_id, &start_event).await?;
+                Some((start_event, Some(admin_event_to_timeline_event(context.event, room).await), context.start, context.end))
+            }
+            _ => None,
+        };
+        if let Some((start_event, event, prev_batch_token, next_batch_token)) = start_event_context {
+            self.start_event = None;
+            let page = event.into_iter().collect::<Vec<TimelineEvent>>();
+            self.fetched_event_count += page.len();
+            self.last_event_id = page.last().and_then(TimelineEvent::event_id).or(self.last_event_id.take());
+            self.last_end_token = match self.newest_first {
+                true => prev_batch_token,
+                false => next_batch_token,
+            };
+            self.finished = self.end_event.as_ref() == Some(&start_event) || self.fetched_event_count >= self.event_limit || self.last_end_token.is_none();
+            return Ok(Some(page))
         }
 
-        let base_output_path = output_path.clone().unwrap_or_default();
-        let base_output_filename = format_export_filename(room_to_export_info);
-        if formats.contains(&ExportOutputFormat::Json) {
-            let json_output_file = messages_to_json(&events);
-            let mut json_output_path_buf = base_output_path.clone();
-            json_output_path_buf.push(format!("{}.json", base_output_filename));
-            write(json_output_path_buf, json_output_file).unwrap();
+        let (mut chunk, end_token) = match self.source {
+            EventSource::Joined(room) => {
+                let messages_options = match self.newest_first {
+                    true => MessagesOptions::backward(),
+                    false => MessagesOptions::forward(),
+                };
+                let mut messages_options = messages_options.from(self.last_end_token.as_deref());
+                messages_options.limit = self.page_size.into();
+                let messages = retry_rate_limited(self.max_retries, || async { Ok(room.messages(messages_options.clone()).await?) }).await?;
+                (messages.chunk, messages.end)
+            }
+            EventSource::Peeked(client, room_id) => {
+                let direction = match self.newest_first {
+                    true => Direction::Backward,
+                    false => Direction::Forward,
+                };
+                let mut request = get_message_events::v3::Request::new(room_id.to_owned(), direction);
+                request.from = self.last_end_token.clone();
+                request.limit = self.page_size.into();
+                let response = retry_rate_limited(self.max_retries, || async { Ok(client.send(request.clone()).await?) }).await?;
+                (response.chunk.into_iter().map(|event| TimelineEvent::from_plaintext(event.cast())).collect::<Vec<TimelineEvent>>(), response.end)
+            }
+            EventSource::Admin(client, room_id, room) => {
+                let direction = match self.newest_first {
+                    true => Direction::Backward,
+                    false => Direction::Forward,
+                };
+                let messages = get_admin_room_messages(client, room_id, self.last_end_token.as_deref(), direction, self.page_size).await?;
+                let mut chunk = Vec::new();
+                for event in messages.chunk {
+                    chunk.push(admin_event_to_timeline_event(event, room).await);
+                }
+                (chunk, messages.end)
+            }
+            EventSource::Cached(room) => {
+                // The cache has no pagination tokens to go by, so it all comes back as a single page, with the range's start found by hand
+                let (room_event_cache, _drop_handles) = room.event_cache().await?;
+                let mut events = room_event_cache.events().await?;
+                if self.newest_first {
+                    events.reverse();
+                }
+                if let Some(start_event) = self.start_event.take() {
+                    let Some(start_index) = events.iter().position(|event| event.event_id().as_ref() == Some(&start_event)) else {
+                        anyhow::bail!("Couldn't find event {} in the local cache of room {}.", start_event, room.room_id());
+                    };
+                    events.drain(..start_index);
+                }
+                (events, None)
+            }
+        };
+        // Forward pagination over a room's whole history should start at its creation, and backward pagination should end there
+        let covers_whole_history = self.end_event.is_none() && self.event_limit == usize::MAX;
+        let is_history_start = !self.newest_first && self.last_end_token.is_none() && self.fetched_event_count == 0;
+        if covers_whole_history && is_history_start && chunk.first().is_some_and(|event| !is_room_creation(event)) {
+            self.gaps.push(TimelineGap {
+                preceding_event_id: None,
+                kind: TimelineGapKind::HistoryUnavailable,
+            });
         }
-        if formats.contains(&ExportOutputFormat::Txt) {
-            let txt_output_file = messages_to_txt(&events, room_to_export_info).await?;
-            let mut txt_output_path_buf = base_output_path.clone();
-            txt_output_path_buf.push(format!("{}.txt", base_output_filename));
-            write(txt_output_path_buf, txt_output_file).unwrap();
+        if chunk.is_empty() {
+            self.finished = true;
+            return Ok(None)
         }
+        if let Some(resumed_through_event) = self.resumed_through_event.take() {
+            if let Some(resumed_through_index) = chunk.iter().position(|event| event.event_id().as_ref() == Some(&resumed_through_event)) {
+                chunk.drain(..=resumed_through_index);
+            }
+        }
+
+        let mut page = Vec::new();
+        for event in chunk {
+            let is_range_end = self.end_event.is_some() && event.event_id() == self.end_event;
+            page.push(event);
+            self.fetched_event_count += 1;
+            if is_range_end || self.fetched_event_count >= self.event_limit {
+                self.finished = true;
+                break
+            }
+        }
+        let reached_history_end = end_token.is_none();
+        match end_token {
+            Some(end_token) => self.last_end_token = Some(end_token),
+            None => self.finished = true,
+        }
+        self.last_event_id = page.last().and_then(TimelineEvent::event_id).or(self.last_event_id.take());
+        if covers_whole_history && self.newest_first && reached_history_end && page.last().is_some_and(|event| !is_room_creation(event)) {
+            self.gaps.push(TimelineGap {
+                preceding_event_id: self.last_event_id.clone(),
+                kind: TimelineGapKind::HistoryUnavailable,
+            });
+        }
+
+        Ok(Some(page))
     }
+}
 
-    Ok(())
+// Reported to export's progress callback as the export goes along. Rooms are identified by the ID of the room each set of output files is named after, so merged upgrade chains report as their newest room.
+pub enum ExportProgress {
+    RoomStarted {
+        room_id: OwnedRoomId,
+    },
+    PageFetched {
+        room_id: OwnedRoomId,
+        event_count: usize,
+    },
+    EventsProcessed {
+        room_id: OwnedRoomId,
+        event_count: usize, // Counts only events left after filtering
+    },
+    BytesWritten {
+        room_id: OwnedRoomId,
+        byte_count: usize,
+    },
+    GapFound {
+        room_id: OwnedRoomId,
+        description: String,
+    },
+    DecryptionRetried {
+        room_id: OwnedRoomId,
+        undecryptable_count: usize, // Events whose keys were requested from other devices
+        decrypted_count: usize, // Those of them which could be decrypted with forwarded keys
+    },
+    RoomFinished {
+        room_id: OwnedRoomId,
+    },
+    FollowStarted {
+        room_count: usize, // Rooms whose output new events get appended to
+    },
+    EventAppended {
+        room_id: OwnedRoomId,
+        byte_count: usize, // Across every format
+    },
+    RoomFailed {
+        room_id: OwnedRoomId,
+        description: String,
+    },
+    MediaDownloaded {
+        room_id: OwnedRoomId, // Once per avatar or attachment, not counting ones already downloaded for another room
+    },
+    MediaSkipped {
+        room_id: OwnedRoomId,
+        description: String, // Of the avatar or attachment left out, and why
+    },
+    UpgradeChainTruncated {
+        room_id: OwnedRoomId, // The earliest room of the chain that could be exported
+        inaccessible_room_id: OwnedRoomId, // The room it was upgraded from, which this client has no record of
+    },
+}
+
+// How one of the rooms asked for got resolved. Each room identifier, DM partner, and regex given to export gets one of these, in the order given, whether or not it resolved to anything.
+pub struct RoomResolution {
+    pub identifier: String, // As given, with regexes prefixed by 'regex ' and DM partners by 'direct messages with '
+    pub result: Result<Vec<OwnedRoomId>>, // Failures are Error::RoomNotFound or Error::AmbiguousRoomName, or whatever error peeking into the room or looking it up through the admin API failed with
+}
+
+pub enum RoomExportStatus {
+    Exported {
+        byte_count: usize,
+        event_count: usize,
+        files: Vec<PathBuf>, // Everything written for the room, bar media and avatars; empty for exports to stdout, and 's3://' URLs for uploads
+    },
+    Skipped, // Incremental exports skip writing out rooms with nothing new since the last one
+    Failed(Error), // The room's checkpoint and spool are left as they were, so that rerunning the export picks it back up
+}
+
+// What became of each room which resolved, in the order they finished. Rooms are identified the same way as for ExportProgress.
+pub struct RoomExportOutcome {
+    pub room_id: OwnedRoomId,
+    pub room_name: Option<String>,
+    pub status: RoomExportStatus,
+}
+
+pub struct ExportReport {
+    pub exported_room_count: usize, // Rooms which didn't fail, including skipped ones
+    pub room_resolutions: Vec<RoomResolution>,
+    pub room_outcomes: Vec<RoomExportOutcome>,
+}
+
+// Keeps track of how much has been written through it, for progress reporting, and hashes it along the way, for the export manifest.
+struct CountingWriter<W: Write> {
+    inner: W,
+    byte_count: usize,
+    hasher: Sha256,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            byte_count: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // For appending to an existing file, whose hash has to cover what's already in it too
+    fn appending_to(inner: W, path: &Path) -> std::io::Result<Self> {
+        let mut writer = Self::new(inner);
+        if path.exists() {
+            std::io::copy(&mut File::open(path)?, &mut writer.hasher)?;
+        }
+        Ok(writer)
+    }
+
+    fn sha256_hex(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written_byte_count = self.inner.write(buf)?;
+        self.byte_count += written_byte_count;
+        self.hasher.update(&buf[..written_byte_count]);
+        Ok(written_byte_count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// What got written out for a room, for its outcome to report.
+struct WrittenExport {
+    byte_count: usize,
+    event_count: usize,
+    files: Vec<PathBuf>,
+}
+
+// A single set of output files, along with the pagers fetching its events. Units are all set up before any fetching starts, so that filename disambiguation and checkpoint lookups don't depend on which rooms happen to finish fetching first.
+struct ExportUnit<'a> {
+    room_id: OwnedRoomId,
+    room_info: Option<&'a RoomWithCachedInfo>,
+    peeked_alias: Option<OwnedRoomAliasId>, // For rooms which aren't joined, the alias they were given by, if any
+    admin_room_details: Option<AdminRoomDetails>, // For rooms which aren't joined but are exported through the admin API
+    read_receipts: Option<RoomReadReceipts>, // Merged across the whole upgrade chain, for merged exports
+    room_data: Option<RoomData>, // Just the room the unit's named after, for merged exports, since older rooms' pins and tags are left behind by upgrades
+    filename: String,
+    event_pagers: Vec<EventPager<'a>>,
+    is_delta: bool,
+}
+
+// What every room in an export gets written out with, gathered up from ExportOptions once for the helpers which do the writing. The pseudonymizer's shared across rooms, so that people keep the same pseudonyms from room to room.
+struct ExportContext<'a> {
+    client: &'a Client,
+    destination: &'a ExportDestination,
+    formats: &'a HashSet<ExportOutputFormat>,
+    download_avatars: bool, // Only for joined rooms, whatever this says, since avatars come from the SDK's membership tracking
+    download_media: bool,
+    max_retries: u32,
+    split_mode: Option<SplitMode>,
+    event_type_filter: &'a EventTypeFilter,
+    content_filter: Option<&'a ContentFilter>,
+    key_request_wait: Option<Duration>,
+    pseudonymizer: Option<&'a mut Pseudonymizer>,
+    progress: &'a dyn Fn(ExportProgress),
+    cancellation: &'a CancellationToken,
+    json_options: &'a JsonOptions,
+    txt_options: &'a TxtOptions,
+}
+
+// A room whose export is done, to append new events to while following. Its outputs only get opened once following starts.
+struct FollowedRoom<'a> {
+    room_info: &'a RoomWithCachedInfo,
+    filename: String,
+    seen_event_ids: HashSet<OwnedEventId>, // Everything already exported, since the first sync while following can overlap with it
+    sender_profiles: HashMap<OwnedUserId, SenderProfile>,
+    json_output: Option<CountingWriter<Box<dyn Write>>>,
+    txt_output: Option<CountingWriter<Box<dyn Write>>>,
+    last_event_date: Option<String>, // For txt day separators, which start over from following's first event
+}
+
+// Context prepended to each export, so that it still makes sense once separated from the account and homeserver it came from. Fields which aren't known for peeked rooms are left as None.
+#[derive(Clone)]
+struct RoomMetadata {
+    room_id: OwnedRoomId,
+    name: Option<String>,
+    aliases: Vec<OwnedRoomAliasId>,
+    topic: Option<String>,
+    member_count: Option<u64>,
+    is_encrypted: Option<bool>,
+    exported_at_millis: i64,
+    exported_by: Option<OwnedUserId>,
+    time_range_millis: Option<(i64, i64)>,
+    gaps: Vec<TimelineGap>,
+    read_receipts: Option<RoomReadReceipts>, // Only if asked for, and only for joined rooms
+    room_data: Option<RoomData>, // Likewise
+}
+
+// Hands out pseudonyms in order of first appearance, shared across every room in an export so that people stay recognizable from room to room.
+struct Pseudonymizer {
+    pseudonyms: HashMap<OwnedUserId, String>,
+    display_names: HashMap<String, OwnedUserId>,
+    user_id_pattern: Regex,
+}
+
+impl Pseudonymizer {
+    fn new() -> Self {
+        Self {
+            pseudonyms: HashMap::new(),
+            display_names: HashMap::new(),
+            user_id_pattern: Regex::new(r"@[A-Za-z0-9._=/+\-]+:[A-Za-z0-9.\-]+(?::[0-9]+)?").unwrap(),
+        }
+    }
+
+    fn learn_display_name(&mut self, display_name: &str, user_id: &UserId) {
+        if !display_name.is_empty() {
+            self.display_names.insert(display_name.to_owned(), user_id.to_owned());
+        }
+    }
+
+    // Picks up both the current and previous display names from any membership events.
+    fn learn_display_names(&mut self, events: &[TimelineEvent]) {
+        for event in events {
+            if event.raw().get_field::<String>("type").ok().flatten().as_deref() != Some("m.room.member") {
+                continue
+            }
+            let Some(user_id) = event.raw().get_field::<OwnedUserId>("state_key").ok().flatten() else {
+                continue
+            };
+            let content = event.raw().get_field::<serde_json::Value>("content").ok().flatten();
+            let prev_content = event.raw().get_field::<serde_json::Value>("unsigned").ok().flatten().and_then(|unsigned| unsigned.get("prev_content").cloned());
+            for content in content.iter().chain(prev_content.iter()) {
+                if let Some(display_name) = content.get("displayname").and_then(|display_name| display_name.as_str()) {
+                    self.learn_display_name(display_name, &user_id);
+                }
+            }
+        }
+    }
+
+    // Display names are only replaced where they stand as words of their own, so that ones which happen to turn up inside IDs or longer words (e.g. 'Al' in an event ID) don't mangle them. Names which are also common words will still get replaced wherever they appear as such; for anonymization purposes, over-replacing beats leaking.
+    fn pseudonymize_text(&mut self, text: &str) -> String {
+        let pseudonyms = &mut self.pseudonyms;
+        let mut text = self.user_id_pattern.replace_all(text, |captures: &Captures| match UserId::parse(&captures[0]) {
+            Ok(user_id) => assign_pseudonym(pseudonyms, &user_id),
+            Err(_) => captures[0].to_owned(),
+        }).into_owned();
+
+        let mut display_names = self.display_names.iter().collect::<Vec<(&String, &OwnedUserId)>>();
+        display_names.sort_by_key(|(display_name, _user_id)| Reverse(display_name.len())); // Longest first, so that names containing other names get replaced whole
+        for (display_name, user_id) in display_names {
+            if text.contains(display_name.as_str()) {
+                text = replace_whole_words(&text, display_name, &assign_pseudonym(&mut self.pseudonyms, user_id));
+            }
+        }
+        text
+    }
+
+    fn pseudonymize_user_id(&mut self, user_id: &str) -> String {
+        match UserId::parse(user_id) {
+            Ok(user_id) => assign_pseudonym(&mut self.pseudonyms, &user_id),
+            Err(_) => user_id.to_owned(),
+        }
+    }
+
+    // Only touches the parts of events which can identify people: user IDs where they're expected, and free text. Object keys which are user IDs get swapped out too, since e.g. the sender profile table is keyed by them.
+    fn pseudonymize_json(&mut self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Array(values) => values.iter_mut().for_each(|value| self.pseudonymize_json(value)),
+            serde_json::Value::Object(object) => {
+                for (key, mut value) in std::mem::take(object) {
+                    if PSEUDONYMIZED_STRIPPED_KEYS.contains(&key.as_str()) {
+                        continue
+                    }
+                    let pseudonymize_string: Option<fn(&mut Self, &str) -> String> = match key.as_str() {
+                        key if PSEUDONYMIZED_USER_ID_KEYS.contains(&key) => Some(Self::pseudonymize_user_id),
+                        key if PSEUDONYMIZED_TEXT_KEYS.contains(&key) => Some(Self::pseudonymize_text),
+                        _ => None,
+                    };
+                    match (&mut value, pseudonymize_string) {
+                        (serde_json::Value::String(string), Some(pseudonymize_string)) => *string = pseudonymize_string(self, string),
+                        (serde_json::Value::Array(values), Some(pseudonymize_string)) => {
+                            for value in values {
+                                if let serde_json::Value::String(string) = value {
+                                    *string = pseudonymize_string(self, string);
+                                }
+                            }
+                        }
+                        _ => self.pseudonymize_json(&mut value),
+                    }
+                    object.insert(self.pseudonymize_user_id(&key), value);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+pub(crate) enum RoomIndexRetrievalError {
+    MultipleRoomsWithSpecifiedName(Vec<String>),
+    NoRoomsWithSpecifiedName,
+}
+
+impl RoomIndexRetrievalError {
+    pub(crate) fn into_error(self, client: &Client, identifier: &str) -> Error {
+        let user_id = client.user_id().map(|user_id| user_id.to_string()).unwrap_or_default();
+        match self {
+            Self::MultipleRoomsWithSpecifiedName(room_ids) => Error::AmbiguousRoomName {
+                user_id,
+                name: identifier.to_owned(),
+                room_ids,
+            },
+            Self::NoRoomsWithSpecifiedName => Error::RoomNotFound {
+                user_id,
+                identifier: identifier.to_owned(),
+            },
+        }
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+pub(crate) fn get_room_index_by_identifier(rooms_info: &[RoomWithCachedInfo], identifier: &str) -> Result<usize, RoomIndexRetrievalError> {
+    if let Some(index) = rooms_info.iter().position(|room_info| room_info.id == identifier) {
+        Ok(index)
+    } else if let Some(index) = rooms_info.iter().position(|room_info| room_info.canonical_alias.as_ref().is_some_and(|alias| alias == identifier)) {
+        Ok(index)
+    } else if let Some(index) = rooms_info.iter().position(|room_info| room_info.alt_aliases.iter().any(|alias| alias == identifier)) {
+        Ok(index)
+    } else {
+        let name_matches = rooms_info.iter().filter(|room_info| room_info.name.as_ref().is_some_and(|name| name == identifier)).collect::<Vec<&RoomWithCachedInfo>>();
+        match name_matches.len() {
+            0 => Err(RoomIndexRetrievalError::NoRoomsWithSpecifiedName),
+            1 => Ok(rooms_info.iter().position(|room_info| room_info.name.as_ref().is_some_and(|name| name  == identifier)).unwrap()),
+            _ => Err(RoomIndexRetrievalError::MultipleRoomsWithSpecifiedName(name_matches.iter().map(|room_info| room_info.id.to_string()).collect())),
+        }
+    }
+}
+
+pub(crate) fn is_glob(identifier: &str) -> bool {
+    identifier.contains(['*', '?'])
+}
+
+pub(crate) fn glob_to_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    for character in glob.chars() {
+        match character {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            _ => pattern.push_str(&regex::escape(&character.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).unwrap() // Everything other than the wildcards is escaped, so this should never fail
+}
+
+pub(crate) fn get_room_indices_by_pattern(rooms_info: &[RoomWithCachedInfo], pattern: &Regex) -> Vec<usize> {
+    rooms_info.iter().enumerate().filter(|(_index, room_info)| {
+        room_info.name.as_ref().is_some_and(|name| pattern.is_match(name))
+            || room_info.canonical_alias.as_ref().is_some_and(|alias| pattern.is_match(alias.as_str()))
+            || room_info.alt_aliases.iter().any(|alias| pattern.is_match(alias.as_str()))
+    }).map(|(index, _room_info)| index).collect()
+}
+
+pub(crate) fn room_indices_to_resolution(client: &Client, rooms_info: &[RoomWithCachedInfo], identifier: String, room_indices: &[usize]) -> RoomResolution {
+    let result = match room_indices.is_empty() {
+        true => Err(RoomIndexRetrievalError::NoRoomsWithSpecifiedName.into_error(client, &identifier)),
+        false => Ok(room_indices.iter().map(|room_index| rooms_info[*room_index].id.clone()).collect()),
+    };
+
+    RoomResolution {
+        identifier,
+        result,
+    }
+}
+
+// Direct-message status comes from the account's m.direct account data, as cached by the client.
+fn get_dm_room_indices(rooms_info: &[RoomWithCachedInfo], user_id: &UserId) -> Vec<usize> {
+    rooms_info.iter().enumerate().filter(|(_index, room_info)| {
+        room_info.room.direct_targets().iter().any(|target| target.as_user_id() == Some(user_id))
+    }).map(|(index, _room_info)| index).collect()
+}
+
+// Returns the rooms which the given room was upgraded from, newest first, stopping at the first one this client has no record of.
+fn get_predecessor_rooms_info(client: &Client, room_info: &RoomWithCachedInfo, progress: &dyn Fn(ExportProgress)) -> Vec<RoomWithCachedInfo> {
+    let mut predecessor_rooms_info = Vec::new();
+    let mut seen_room_ids = HashSet::from([room_info.id.clone()]);
+    let mut current_room = room_info.room.clone();
+    while let Some(predecessor_room_id) = current_room.create_content().and_then(|create_content| create_content.predecessor).map(|predecessor| predecessor.room_id) {
+        if !seen_room_ids.insert(predecessor_room_id.clone()) {
+            break
+        }
+        match client.get_room(&predecessor_room_id) {
+            Some(predecessor_room) => {
+                predecessor_rooms_info.push(RoomWithCachedInfo::from_room(predecessor_room.clone()));
+                current_room = predecessor_room;
+            }
+            None => {
+                progress(ExportProgress::UpgradeChainTruncated {
+                    room_id: current_room.room_id().to_owned(),
+                    inaccessible_room_id: predecessor_room_id,
+                });
+                break
+            }
+        }
+    }
+
+    predecessor_rooms_info
+}
+
+// Rooms exported through the admin API get their details looked up along the way, which also checks that the server knows of them at all.
+async fn resolve_unjoined_room(client: &Client, identifier: &str, admin: bool) -> anyhow::Result<(OwnedRoomId, Option<OwnedRoomAliasId>, Option<AdminRoomDetails>)> {
+    let (room_id, alias) = match OwnedRoomId::try_from(RoomOrAliasId::parse(identifier)?) {
+        Ok(room_id) => (room_id, None),
+        Err(alias) => (client.resolve_room_alias(&alias).await?.room_id, Some(alias)),
+    };
+    let admin_room_details = match admin {
+        true => Some(get_admin_room_details(client, &room_id).await?),
+        false => None,
+    };
+    Ok((room_id, alias, admin_room_details))
+}
+
+fn format_export_filename(room_info: &RoomWithCachedInfo, name_template: Option<&NameTemplate>, pseudonymizer: Option<&mut Pseudonymizer>) -> String {
+    sanitize_filename(&format_export_filename_from_parts(&room_info.id, room_info.name.as_deref(), room_info.canonical_alias.as_deref(), name_template, pseudonymizer))
+}
+
+// Room names and aliases often name people (e.g. in DMs), so they get pseudonymized along with everything else when there's a pseudonymizer. Room IDs don't, and are left as they are.
+fn format_export_filename_from_parts(id: &RoomId, name: Option<&str>, canonical_alias: Option<&RoomAliasId>, name_template: Option<&NameTemplate>, pseudonymizer: Option<&mut Pseudonymizer>) -> String {
+    let alias = canonical_alias.map(|alias| alias.as_str().split_once(':').map_or(alias.as_str(), |(alias_without_server, _)| alias_without_server));
+    let (name, alias) = match pseudonymizer {
+        Some(pseudonymizer) => (name.map(|name| pseudonymizer.pseudonymize_text(name)), alias.map(|alias| pseudonymizer.pseudonymize_text(alias))),
+        None => (name.map(String::from), alias.map(String::from)),
+    };
+    if let Some(name_template) = name_template {
+        return name_template.render(id, name.as_deref(), alias.as_deref(), &Utc::now().format("%Y-%m-%d").to_string());
+    }
+    let (nonserver_id_component, server) = split_room_id(id);
+    let id_components = once(nonserver_id_component).chain(server).collect::<Vec<&str>>().join(", ");
+    match (name, alias) {
+        (Some(name), Some(alias)) => format!("{} [{}, {}]", name, alias, id_components),
+        (Some(name), None) => format!("{} [{}]", name, id_components),
+        (None, Some(alias)) => format!("{} [{}]", alias, id_components),
+        (None, None) => match server {
+            Some(server) => format!("{} [{}]", nonserver_id_component, server),
+            None => nonserver_id_component.to_owned(),
+        },
+    }
+}
+
+// Room IDs from room version 12 onward are bare hashes with no server name, so there's only sometimes one to split off.
+fn split_room_id(id: &RoomId) -> (&str, Option<&str>) {
+    match id.server_name() {
+        Some(server_name) => (id.as_str().strip_suffix(server_name.as_str()).and_then(|nonserver_id_component| nonserver_id_component.strip_suffix(':')).unwrap_or(id.as_str()), Some(server_name.as_str())),
+        None => (id.as_str(), None),
+    }
+}
+
+// Replaces occurrences of the target which aren't part of a longer run of letters and digits.
+fn replace_whole_words(text: &str, target: &str, replacement: &str) -> String {
+    let is_word_character = |character: Option<char>| character.is_some_and(char::is_alphanumeric);
+    let mut replaced = String::with_capacity(text.len());
+    let mut remainder = text;
+    while let Some(index) = remainder.find(target) {
+        let (before, after) = (&remainder[..index], &remainder[index + target.len()..]);
+        let stands_alone = !(is_word_character(before.chars().next_back()) && is_word_character(target.chars().next())) && !(is_word_character(after.chars().next()) && is_word_character(target.chars().next_back()));
+        replaced.push_str(before);
+        replaced.push_str(if stands_alone { replacement } else { target });
+        remainder = after;
+    }
+    replaced.push_str(remainder);
+    replaced
+}
+
+fn assign_pseudonym(pseudonyms: &mut HashMap<OwnedUserId, String>, user_id: &UserId) -> String {
+    let pseudonym_number = pseudonyms.len() + 1;
+    pseudonyms.entry(user_id.to_owned()).or_insert_with(|| format!("User-{:02}", pseudonym_number)).clone()
+}
+
+// Room names can contain anything, so they get stripped down to what every common filesystem will accept, and capped comfortably short of the usual 255-byte limit to leave room for extensions and disambiguators.
+fn sanitize_filename(filename: &str) -> String {
+    const MAX_FILENAME_BYTES: usize = 200;
+    const RESERVED_WINDOWS_NAMES: [&str; 22] = ["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+
+    let mut sanitized_filename = filename.chars().filter(|character| !character.is_control() && !matches!(character, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|')).collect::<String>();
+    if sanitized_filename.len() > MAX_FILENAME_BYTES {
+        let mut truncation_point = MAX_FILENAME_BYTES;
+        while !sanitized_filename.is_char_boundary(truncation_point) {
+            truncation_point -= 1;
+        }
+        sanitized_filename.truncate(truncation_point);
+    }
+    let sanitized_filename = sanitized_filename.trim_end_matches(['.', ' ']).trim_start(); // Windows silently drops trailing dots and spaces, which can cause collisions of its own
+    let filename_stem = sanitized_filename.split('.').next().unwrap_or_default();
+    if sanitized_filename.is_empty() {
+        String::from("_")
+    } else if RESERVED_WINDOWS_NAMES.iter().any(|reserved_name| filename_stem.eq_ignore_ascii_case(reserved_name)) {
+        format!("_{}", sanitized_filename)
+    } else {
+        sanitized_filename.to_owned()
+    }
+}
+
+// Appends a numbered disambiguator to filenames already used earlier in the export. Comparison is case-insensitive, since that's how Windows and macOS filesystems compare them by default.
+fn disambiguate_filename(filename: String, used_filenames: &mut HashSet<String>) -> String {
+    if used_filenames.insert(filename.to_lowercase()) {
+        return filename
+    }
+    let mut disambiguator = 2;
+    loop {
+        let disambiguated_filename = format!("{} ({})", filename, disambiguator);
+        if used_filenames.insert(disambiguated_filename.to_lowercase()) {
+            return disambiguated_filename
+        }
+        disambiguator += 1;
+    }
+}
+
+fn event_time_range_millis(events: &[TimelineEvent]) -> Option<(i64, i64)> {
+    let timestamps_millis = events.iter().filter_map(|event| event.raw().get_field::<i64>("origin_server_ts").ok().flatten()).collect::<Vec<i64>>();
+    timestamps_millis.iter().min().zip(timestamps_millis.iter().max()).map(|(start, end)| (*start, *end)) // Min and max rather than first and last, since newest-first exports run backwards
+}
+
+// Federation backfill sometimes hands back the same event more than once across pages, or out of order. Sorting is stable, so events with equal timestamps stay in the order pagination returned them, which follows the room's DAG.
+fn dedup_and_sort_events(events: &mut Vec<TimelineEvent>, seen_event_ids: &mut HashSet<OwnedEventId>, newest_first: bool) {
+    events.retain(|event| event.event_id().is_none_or(|event_id| seen_event_ids.insert(event_id)));
+    let timestamp_millis = |event: &TimelineEvent| event.raw().get_field::<i64>("origin_server_ts").ok().flatten();
+    match newest_first {
+        true => events.sort_by_key(|event| Reverse(timestamp_millis(event))),
+        false => events.sort_by_key(timestamp_millis),
+    }
+}
+
+// Returns (filename suffix, events) pairs, one per output file. Periods are split in UTC.
+fn split_events(events: &[TimelineEvent], split_mode: Option<SplitMode>) -> Vec<(Option<String>, &[TimelineEvent])> {
+    let Some(split_mode) = split_mode else {
+        return vec![(None, events)];
+    };
+    let mut event_chunks = Vec::new();
+    let mut chunk_start = 0;
+    let mut chunk_key = None;
+    let mut chunk_bytes = 0;
+    let mut part_number = 1;
+    for (index, event) in events.iter().enumerate() {
+        let event_key = match split_mode {
+            SplitMode::Monthly | SplitMode::Yearly => {
+                let period_format = if matches!(split_mode, SplitMode::Monthly) { "%Y-%m" } else { "%Y" };
+                event.raw().get_field::<i64>("origin_server_ts").ok().flatten().map(|timestamp_millis| format_timestamp_as(timestamp_millis, &ExportTimezone::Utc, Some(period_format))).or_else(|| chunk_key.clone())
+            }
+            SplitMode::Size(max_bytes) => {
+                let event_bytes = event.raw().json().get().len();
+                if chunk_bytes > 0 && chunk_bytes + event_bytes > max_bytes {
+                    part_number += 1;
+                    chunk_bytes = 0;
+                }
+                chunk_bytes += event_bytes;
+                Some(format!("part {}", part_number))
+            }
+        };
+        if index > 0 && event_key != chunk_key {
+            event_chunks.push((chunk_key.take(), &events[chunk_start..index]));
+            chunk_start = index;
+        }
+        chunk_key = event_key;
+    }
+    event_chunks.push((chunk_key, &events[chunk_start..]));
+    event_chunks
+}
+
+// Incremental exports which pick up from a checkpoint get written alongside the earlier output rather than over it.
+fn delta_filename(filename: String, is_delta: bool) -> String {
+    match is_delta {
+        true => format!("{} delta {}", filename, Utc::now().format("%Y-%m-%dT%H-%M-%SZ")),
+        false => filename,
+    }
+}
+
+fn collect_room_metadata(client: &Client, room_id: &RoomId, room_info: Option<&RoomWithCachedInfo>, peeked_alias: Option<&RoomAliasId>, admin_room_details: Option<&AdminRoomDetails>, events: &[TimelineEvent]) -> RoomMetadata {
+    let mut metadata = RoomMetadata {
+        room_id: room_id.to_owned(),
+        name: None,
+        aliases: peeked_alias.map(RoomAliasId::to_owned).into_iter().collect(),
+        topic: None,
+        member_count: None,
+        is_encrypted: None,
+        exported_at_millis: Utc::now().timestamp_millis(),
+        exported_by: client.user_id().map(UserId::to_owned),
+        time_range_millis: event_time_range_millis(events),
+        gaps: Vec::new(),
+        read_receipts: None,
+        room_data: None,
+    };
+    if let Some(room_info) = room_info {
+        metadata.name = room_info.name.clone();
+        metadata.aliases = room_info.canonical_alias.iter().chain(room_info.alt_aliases.iter()).cloned().collect();
+        metadata.topic = room_info.room.topic();
+        metadata.member_count = Some(room_info.room.joined_members_count());
+        metadata.is_encrypted = Some(room_info.room.encryption_state().is_encrypted());
+    } else if let Some(admin_room_details) = admin_room_details {
+        metadata.name = admin_room_details.name.clone();
+        if let Some(canonical_alias) = &admin_room_details.canonical_alias {
+            metadata.aliases = vec![canonical_alias.clone()];
+        }
+        metadata.topic = admin_room_details.topic.clone();
+        metadata.member_count = admin_room_details.joined_members;
+        metadata.is_encrypted = Some(admin_room_details.encryption.is_some());
+    }
+    metadata
+}
+
+fn room_metadata_to_json(room_metadata: &RoomMetadata) -> serde_json::Value {
+    let mut room_json = json!({
+        "room_id": room_metadata.room_id,
+        "name": room_metadata.name,
+        "aliases": room_metadata.aliases,
+        "topic": room_metadata.topic,
+        "member_count": room_metadata.member_count,
+        "encrypted": room_metadata.is_encrypted,
+        "exported_at": room_metadata.exported_at_millis,
+        "exported_by": room_metadata.exported_by,
+        "time_range": room_metadata.time_range_millis.map(|(start, end)| json!({
+            "start": start,
+            "end": end,
+        })),
+        "gaps": room_metadata.gaps.iter().map(|gap| {
+            let mut gap_json = json!({
+                "preceding_event_id": gap.preceding_event_id,
+                "kind": match gap.kind {
+                    TimelineGapKind::HistoryUnavailable => "history_unavailable",
+                    TimelineGapKind::FetchFailed(_) => "fetch_failed",
+                },
+            });
+            if let TimelineGapKind::FetchFailed(error) = &gap.kind {
+                gap_json["error"] = json!(error);
+            }
+            gap_json
+        }).collect::<Vec<serde_json::Value>>(),
+    });
+    if let Some(read_receipts) = &room_metadata.read_receipts {
+        room_json["read_marker"] = json!(read_receipts.read_marker);
+    }
+    if let Some(room_data) = &room_metadata.room_data {
+        room_json["pinned_events"] = serde_json::Value::Array(room_data.pinned_events.iter().map(|pinned_event| json!({
+            "event_id": pinned_event.event_id,
+            "event": pinned_event.event.as_ref().and_then(|event| event.raw().deserialize_as::<serde_json::Value>().ok()),
+        })).collect());
+        room_json["account_data"] = serde_json::Value::Object(room_data.account_data.clone());
+    }
+    room_json
+}
+
+// Leaves out the time range covered, since streamed exports only know that once they've finished.
+fn room_metadata_to_txt(room_metadata: &RoomMetadata, txt_options: &TxtOptions) -> String {
+    let mut header = match &room_metadata.name {
+        Some(name) => format!("Room: {} ({})\n", name, room_metadata.room_id),
+        None => format!("Room: {}\n", room_metadata.room_id),
+    };
+    if !room_metadata.aliases.is_empty() {
+        header.push_str(&format!("Aliases: {}\n", room_metadata.aliases.iter().map(|alias| alias.as_str()).collect::<Vec<&str>>().join(", ")));
+    }
+    if let Some(topic) = &room_metadata.topic {
+        header.push_str(&format!("Topic: {}\n", topic));
+    }
+    if let Some(member_count) = room_metadata.member_count {
+        header.push_str(&format!("Members: {}\n", member_count));
+    }
+    if let Some(is_encrypted) = room_metadata.is_encrypted {
+        header.push_str(&format!("Encrypted: {}\n", if is_encrypted { "yes" } else { "no" }));
+    }
+    if let Some(room_data) = &room_metadata.room_data {
+        let tags = room_data.account_data.get("m.tag").and_then(|tag| tag.get("tags")).and_then(|tags| tags.as_object());
+        if let Some(tags) = tags.filter(|tags| !tags.is_empty()) {
+            header.push_str(&format!("Tags: {}\n", tags.keys().map(String::as_str).collect::<Vec<&str>>().join(", ")));
+        }
+        for pinned_event in &room_data.pinned_events {
+            header.push_str(&format!("Pinned: {}\n", pinned_event_to_txt(pinned_event, txt_options)));
+        }
+    }
+    let exported_at = format_timestamp(room_metadata.exported_at_millis, txt_options);
+    match &room_metadata.exported_by {
+        Some(exported_by) => header.push_str(&format!("Exported by {} at {}\n", exported_by, exported_at)),
+        None => header.push_str(&format!("Exported at {}\n", exported_at)),
+    }
+    header
+}
+
+// Kept brief, by sender ID and first line alone, since the header gets written before any display names are looked up.
+fn pinned_event_to_txt(pinned_event: &PinnedEvent, txt_options: &TxtOptions) -> String {
+    let Some(event) = &pinned_event.event else {
+        return format!("[Event {}, which couldn't be fetched]", pinned_event.event_id)
+    };
+    let sender = event.raw().get_field::<String>("sender").ok().flatten().unwrap_or_default();
+    let timestamp = event.raw().get_field::<i64>("origin_server_ts").ok().flatten().map(|timestamp_millis| format_timestamp(timestamp_millis, txt_options)).unwrap_or_default();
+    let content = event.raw().get_field::<serde_json::Value>("content").ok().flatten();
+    let body = match content.as_ref().and_then(|content| content.get("body")).and_then(|body| body.as_str()) {
+        Some(body) => String::from(first_line_without_reply_fallback(body)),
+        None => format!("[{}]", event.raw().get_field::<String>("type").ok().flatten().unwrap_or_default()),
+    };
+    format!("[{}] {}: {}", timestamp, sender, body)
+}
+
+fn timeline_gap_description(gap: &TimelineGap) -> String {
+    let position = match &gap.preceding_event_id {
+        Some(preceding_event_id) => format!("after {}", preceding_event_id),
+        None => String::from("before the first event"),
+    };
+    match &gap.kind {
+        TimelineGapKind::HistoryUnavailable => format!("Earlier history unavailable {}", position),
+        TimelineGapKind::FetchFailed(error) => format!("Events {} couldn't be fetched due to error '{}'", position, error),
+    }
+}
+
+fn gaps_to_txt(gaps: &[TimelineGap]) -> String {
+    gaps.iter().map(|gap| format!("Gap: {}\n", timeline_gap_description(gap))).collect()
+}
+
+fn is_room_creation(event: &TimelineEvent) -> bool {
+    event.raw().get_field::<String>("type").ok().flatten().as_deref() == Some("m.room.create")
+}
+
+fn time_range_to_txt(time_range_millis: Option<(i64, i64)>, txt_options: &TxtOptions) -> String {
+    match time_range_millis {
+        Some((start, end)) => format!("Covers {} to {}\n", format_timestamp(start, txt_options), format_timestamp(end, txt_options)),
+        None => String::from("Covers no events\n"),
+    }
+}
+
+fn format_timestamp(timestamp_millis: i64, txt_options: &TxtOptions) -> String {
+    format_timestamp_as(timestamp_millis, &txt_options.timezone, txt_options.timestamp_format.as_deref())
+}
+
+pub(crate) fn format_timestamp_as(timestamp_millis: i64, timezone: &ExportTimezone, timestamp_format: Option<&str>) -> String {
+    // Timestamps are whatever the sending server claimed, so out-of-range ones get shown as they are rather than failing the export
+    let Some(timestamp) = DateTime::from_timestamp_millis(timestamp_millis) else {
+        return format!("[Invalid timestamp {}]", timestamp_millis)
+    };
+    match timezone {
+        ExportTimezone::Utc => format_datetime(timestamp, timestamp_format),
+        ExportTimezone::Local => format_datetime(timestamp.with_timezone(&Local), timestamp_format),
+        ExportTimezone::Named(timezone) => format_datetime(timestamp.with_timezone(timezone), timestamp_format),
+    }
+}
+
+fn format_datetime<Tz: TimeZone>(datetime: DateTime<Tz>, timestamp_format: Option<&str>) -> String where Tz::Offset: Display {
+    match timestamp_format {
+        Some(timestamp_format) => datetime.format(timestamp_format).to_string(),
+        None => datetime.to_rfc3339_opts(SecondsFormat::Millis, true),
+    }
+}
+
+// Maps the IDs of edited messages to their edits, oldest first by timestamp regardless of the order the events came in (e.g. with --newest-first), so that the last is always the current version. Edits from anyone other than the original message's sender are invalid, and left out.
+fn collect_message_edits(events: &[TimelineEvent]) -> HashMap<OwnedEventId, Vec<MessageEdit>> {
+    let events_deserialized = events.iter().filter_map(|event| event.raw().deserialize().ok()).collect::<Vec<AnySyncTimelineEvent>>();
+    let senders_by_event_id = events_deserialized.iter().map(|event| (event.event_id().to_owned(), event.sender().to_owned())).collect::<HashMap<OwnedEventId, OwnedUserId>>();
+
+    let mut message_edits_by_target: HashMap<OwnedEventId, Vec<MessageEdit>> = HashMap::new();
+    for event in &events_deserialized {
+        let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(e))) = event else {
+            continue
+        };
+        let Some(Relation::Replacement(replacement)) = &e.content.relates_to else {
+            continue
+        };
+        if senders_by_event_id.get(&replacement.event_id) != Some(&e.sender) {
+            continue
+        }
+        message_edits_by_target.entry(replacement.event_id.clone()).or_default().push(MessageEdit {
+            event_id: e.event_id.clone(),
+            timestamp_millis: e.origin_server_ts.0.into(),
+            msgtype: replacement.new_content.msgtype.clone(),
+        });
+    }
+    for message_edits in message_edits_by_target.values_mut() {
+        message_edits.sort_by_key(|edit| edit.timestamp_millis);
+    }
+
+    message_edits_by_target
+}
+
+// Maps the IDs of reacted-to events to their reactions, grouped by key in order of first appearance. Reactions to events outside the export are left out.
+fn collect_reactions(events: &[TimelineEvent]) -> HashMap<OwnedEventId, Vec<ReactionGroup>> {
+    let event_ids = events.iter().filter_map(|event| event.event_id()).collect::<HashSet<OwnedEventId>>();
+
+    let mut reactions_by_target: HashMap<OwnedEventId, Vec<ReactionGroup>> = HashMap::new();
+    for event in events {
+        let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(SyncMessageLikeEvent::Original(e)))) = event.raw().deserialize() else {
+            continue
+        };
+        let annotation = e.content.relates_to;
+        if !event_ids.contains(&annotation.event_id) {
+            continue
+        }
+        let reaction_groups = reactions_by_target.entry(annotation.event_id).or_default();
+        match reaction_groups.iter_mut().find(|reaction_group| reaction_group.key == annotation.key) {
+            Some(reaction_group) => {
+                if !reaction_group.senders.contains(&e.sender) {
+                    reaction_group.senders.push(e.sender);
+                }
+                reaction_group.event_ids.push(e.event_id);
+            }
+            None => reaction_groups.push(ReactionGroup {
+                key: annotation.key,
+                senders: vec![e.sender],
+                event_ids: vec![e.event_id],
+            }),
+        }
+    }
+
+    reactions_by_target
+}
+
+// Returns the redacting user and redaction reason, if any, from the redaction event the server bundles into a redacted event's unsigned data.
+fn redaction_details(event: &TimelineEvent) -> Option<(OwnedUserId, Option<String>)> {
+    let unsigned = event.raw().get_field::<serde_json::Value>("unsigned").ok()??;
+    let redacted_because = unsigned.get("redacted_because")?;
+    let redacter = serde_json::from_value(redacted_because.get("sender")?.clone()).ok()?;
+    let reason = redacted_because.get("content").and_then(|content| content.get("reason")).and_then(|reason| reason.as_str()).map(String::from);
+    Some((redacter, reason))
+}
+
+// Unstable-prefixed event types still sent by some clients, alongside the stable types they became
+const UNSTABLE_EVENT_TYPE_ALIASES: &[(&str, &str)] = &[
+    ("org.matrix.msc3381.poll.start", "m.poll.start"),
+    ("org.matrix.msc3381.poll.response", "m.poll.response"),
+    ("org.matrix.msc3381.poll.end", "m.poll.end"),
+];
+
+fn stable_event_type(event_type: &str) -> &str {
+    match UNSTABLE_EVENT_TYPE_ALIASES.iter().find(|(unstable_type, _)| *unstable_type == event_type) {
+        Some((_, stable_type)) => stable_type,
+        None => event_type,
+    }
+}
+
+// Polls are handled as raw JSON rather than through ruma's types, since clients are split between the stable event types and MSC3381's unstable-prefixed ones, with differently-shaped content.
+fn poll_event_relation(content: &serde_json::Value) -> Option<OwnedEventId> {
+    serde_json::from_value(content.get("m.relates_to")?.get("event_id")?.clone()).ok()
+}
+
+fn poll_text(value: &serde_json::Value) -> Option<String> {
+    let stable_text = value.get("m.text").and_then(|text_blocks| text_blocks.get(0)).and_then(|text_block| text_block.get("body"));
+    let unstable_text = value.get("org.matrix.msc1767.text");
+    stable_text.or(unstable_text)?.as_str().map(String::from)
+}
+
+fn parse_poll_start(content: &serde_json::Value) -> Option<Poll> {
+    let poll = content.get("m.poll").or_else(|| content.get("org.matrix.msc3381.poll.start"))?;
+    let answers = poll.get("answers")?.as_array()?.iter().filter_map(|answer| {
+        let answer_id = answer.get("m.id").or_else(|| answer.get("id"))?.as_str()?;
+        Some((String::from(answer_id), poll_text(answer)?))
+    }).collect();
+    Some(Poll {
+        question: poll_text(poll.get("question")?)?,
+        answers,
+        selections_by_sender: HashMap::new(),
+        response_event_ids: HashSet::new(),
+    })
+}
+
+fn parse_poll_selections(content: &serde_json::Value) -> Vec<String> {
+    let stable_selections = content.get("m.selections");
+    let unstable_selections = content.get("org.matrix.msc3381.poll.response").and_then(|response| response.get("answers"));
+    match stable_selections.or(unstable_selections).and_then(|selections| selections.as_array()) {
+        Some(selections) => selections.iter().filter_map(|selection| selection.as_str().map(String::from)).collect(),
+        None => Vec::new(),
+    }
+}
+
+// Maps the IDs of poll-start events to their polls, with each sender's latest response counted as their vote.
+fn collect_polls(events: &[TimelineEvent]) -> HashMap<OwnedEventId, Poll> {
+    let mut polls = HashMap::new();
+    for event in events {
+        let (Ok(Some(event_type)), Ok(Some(content)), Some(event_id)) = (event.raw().get_field::<String>("type"), event.raw().get_field::<serde_json::Value>("content"), event.event_id()) else {
+            continue
+        };
+        if matches!(event_type.as_str(), "m.poll.start" | "org.matrix.msc3381.poll.start") {
+            if let Some(poll) = parse_poll_start(&content) {
+                polls.insert(event_id, poll);
+            }
+        }
+    }
+    for event in events {
+        let (Ok(Some(event_type)), Ok(Some(content)), Ok(Some(sender)), Some(event_id)) = (event.raw().get_field::<String>("type"), event.raw().get_field::<serde_json::Value>("content"), event.raw().get_field::<OwnedUserId>("sender"), event.event_id()) else {
+            continue
+        };
+        if matches!(event_type.as_str(), "m.poll.response" | "org.matrix.msc3381.poll.response") {
+            if let Some(poll) = poll_event_relation(&content).and_then(|poll_start_id| polls.get_mut(&poll_start_id)) {
+                poll.selections_by_sender.insert(sender, parse_poll_selections(&content));
+                poll.response_event_ids.insert(event_id);
+            }
+        }
+    }
+
+    polls
+}
+
+fn poll_event_stringified(event: &TimelineEvent, event_prefix: &str, polls: &HashMap<OwnedEventId, Poll>) -> Option<String> {
+    let event_type = event.raw().get_field::<String>("type").ok()??;
+    let content = event.raw().get_field::<serde_json::Value>("content").ok()??;
+    match event_type.as_str() {
+        "m.poll.start" | "org.matrix.msc3381.poll.start" => {
+            let poll = polls.get(&event.event_id()?)?;
+            let answers = poll.answers.iter().map(|(_answer_id, answer_text)| answer_text.as_str()).collect::<Vec<&str>>().join(", ");
+            Some(format!("{} [Poll: {}; options: {}]", event_prefix, poll.question, answers))
+        }
+        "m.poll.response" | "org.matrix.msc3381.poll.response" => Some(format!("{} [Response to a poll which isn't part of this export]", event_prefix)),
+        "m.poll.end" | "org.matrix.msc3381.poll.end" => match poll_event_relation(&content).and_then(|poll_start_id| polls.get(&poll_start_id)) {
+            Some(poll) => Some(format!("{} [Poll ended: {}; results: {}]", event_prefix, poll.question, poll.tally())),
+            None => Some(format!("{} [Ended a poll which isn't part of this export]", event_prefix)),
+        },
+        _ => None,
+    }
+}
+
+// Maps event IDs to their senders' display names as of when they were sent, reconstructed from the membership events within the export. Senders with no membership events in the export are left out, to fall back on their current display names.
+fn collect_historical_display_names(events: &[TimelineEvent]) -> HashMap<OwnedEventId, Option<String>> {
+    struct MembershipChange {
+        user_id: OwnedUserId,
+        display_name: Option<String>,
+        previous_display_name: Option<Option<String>>, // Outer None if the user wasn't previously a member
+    }
+
+    let mut chronological_events = events.iter().filter_map(|event| {
+        let event_id = event.event_id()?;
+        let sender = event.raw().get_field::<OwnedUserId>("sender").ok()??;
+        let timestamp = event.raw().get_field::<i64>("origin_server_ts").ok()??;
+        let membership_change = match event.raw().get_field::<String>("type") {
+            Ok(Some(event_type)) if event_type == "m.room.member" => {
+                let display_name_from = |content: &serde_json::Value| content.get("displayname").and_then(|display_name| display_name.as_str()).map(String::from);
+                let content = event.raw().get_field::<serde_json::Value>("content").ok()??;
+                let unsigned = event.raw().get_field::<serde_json::Value>("unsigned").ok().flatten();
+                Some(MembershipChange {
+                    user_id: event.raw().get_field::<OwnedUserId>("state_key").ok()??,
+                    display_name: display_name_from(&content),
+                    previous_display_name: unsigned.as_ref().and_then(|unsigned| unsigned.get("prev_content")).map(display_name_from),
+                })
+            }
+            _ => None,
+        };
+        Some((event_id, sender, timestamp, membership_change))
+    }).collect::<Vec<_>>();
+    chronological_events.sort_by_key(|(_event_id, _sender, timestamp, _membership_change)| *timestamp);
+
+    // Seed each user's name with whatever it was before their first membership change within the export
+    let mut current_display_names: HashMap<OwnedUserId, Option<String>> = HashMap::new();
+    for (_event_id, _sender, _timestamp, membership_change) in &chronological_events {
+        if let Some(membership_change) = membership_change {
+            current_display_names.entry(membership_change.user_id.clone()).or_insert_with(|| membership_change.previous_display_name.clone().flatten());
+        }
+    }
+
+    let mut historical_display_names = HashMap::new();
+    for (event_id, sender, _timestamp, membership_change) in chronological_events {
+        if let Some(membership_change) = membership_change {
+            current_display_names.insert(membership_change.user_id, membership_change.display_name);
+        }
+        if let Some(display_name) = current_display_names.get(&sender) {
+            historical_display_names.insert(event_id, display_name.clone());
+        }
+    }
+
+    historical_display_names
+}
+
+// Uses the Markdown-converted HTML body where there is one, if requested, and the plaintext body otherwise.
+fn message_text(body: &str, formatted: Option<&FormattedBody>, txt_options: &TxtOptions) -> String {
+    match formatted {
+        Some(formatted) if txt_options.html_to_markdown && formatted.format == MessageFormat::Html => {
+            let html_without_reply_fallback = match (formatted.body.find("<mx-reply>"), formatted.body.find("</mx-reply>")) {
+                (Some(fallback_start), Some(fallback_end)) if fallback_start < fallback_end => format!("{}{}", &formatted.body[..fallback_start], &formatted.body[fallback_end + "</mx-reply>".len()..]),
+                _ => formatted.body.clone(),
+            };
+            html2md::parse_html(&html_without_reply_fallback).trim().to_owned()
+        }
+        _ => String::from(body),
+    }
+}
+
+// Strips the quoted lines which legacy reply fallbacks prepend to message bodies, so the first line of the message proper can be quoted in turn.
+fn first_line_without_reply_fallback(body: &str) -> &str {
+    body.lines().skip_while(|line| line.starts_with('>')).find(|line| !line.is_empty()).unwrap_or_default()
+}
+
+fn replied_to_event_id(event: &AnySyncTimelineEvent) -> Option<OwnedEventId> {
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(e))) = event else {
+        return None;
+    };
+    match &e.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.clone()),
+        _ => None,
+    }
+}
+
+fn thread_root_id(event: &TimelineEvent) -> Option<OwnedEventId> {
+    let content = event.raw().get_field::<serde_json::Value>("content").ok()??;
+    let relation = content.get("m.relates_to")?;
+    if relation.get("rel_type")?.as_str()? != "m.thread" {
+        return None;
+    }
+    serde_json::from_value(relation.get("event_id")?.clone()).ok()
+}
+
+// Returns (event index, whether it's a thread reply) pairs, with each thread's replies moved up to directly follow its root. Replies whose roots aren't part of the export stay where they are.
+fn thread_grouped_event_order(events: &[TimelineEvent]) -> Vec<(usize, bool)> {
+    let event_indices_by_id = events.iter().enumerate().filter_map(|(index, event)| Some((event.event_id()?, index))).collect::<HashMap<OwnedEventId, usize>>();
+    let mut thread_reply_indices_by_root_index: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut is_grouped = vec![false; events.len()];
+    for (index, event) in events.iter().enumerate() {
+        if let Some(root_index) = thread_root_id(event).and_then(|root_id| event_indices_by_id.get(&root_id)) {
+            thread_reply_indices_by_root_index.entry(*root_index).or_default().push(index);
+            is_grouped[index] = true;
+        }
+    }
+
+    let mut event_order = Vec::new();
+    for (index, grouped) in is_grouped.into_iter().enumerate() {
+        if grouped {
+            continue
+        }
+        event_order.push((index, false));
+        if let Some(reply_indices) = thread_reply_indices_by_root_index.get(&index) {
+            event_order.extend(reply_indices.iter().map(|reply_index| (*reply_index, true)));
+        }
+    }
+
+    event_order
+}
+
+fn utd_reason_description(utd_info: &UnableToDecryptInfo) -> &'static str {
+    match &utd_info.reason {
+        UnableToDecryptReason::MissingMegolmSession { .. } => "session not found",
+        UnableToDecryptReason::UnknownMegolmMessageIndex { .. } => "session doesn't cover this message",
+        _ => "decryption failed",
+    }
+}
+
+fn encryption_info_to_json(event: &TimelineEvent) -> serde_json::Value {
+    match &event.kind {
+        TimelineEventKind::Decrypted(decrypted_event) => {
+            let mut encryption_info = serde_json::to_value(&decrypted_event.encryption_info).unwrap_or_else(|_| json!({}));
+            if let Some(encryption_info_object) = encryption_info.as_object_mut() {
+                encryption_info_object.insert(String::from("status"), json!("decrypted"));
+            }
+            encryption_info
+        }
+        TimelineEventKind::UnableToDecrypt { utd_info, .. } => json!({
+            "status": "undecryptable",
+            "session_id": utd_info.session_id,
+            "reason": utd_reason_description(utd_info),
+        }),
+        TimelineEventKind::PlainText { .. } => json!({
+            "status": "plaintext",
+        }),
+    }
+}
+
+fn messages_to_json(events: &[TimelineEvent], room_metadata: &RoomMetadata, sender_profiles: Option<&HashMap<OwnedUserId, SenderProfile>>, sender_avatars: Option<&HashMap<String, String>>, event_media: Option<&HashMap<String, String>>, pseudonymizer: Option<&mut Pseudonymizer>) -> anyhow::Result<serde_json::Value> {
+    // Possibly add more secondary-representations-of-events here, analogous to e.g. the display-name-retrieval and datetime-formatting and so forth in the txt output?
+    let mut events_to_export = Vec::new();
+    let reactions_by_target = collect_reactions(events);
+
+    for event in events {
+        let mut event_deserialized = event.raw().deserialize_as::<serde_json::Value>()?;
+        if let Some(sender_avatars) = sender_avatars {
+            let avatar_path = event_deserialized.get("sender").and_then(|sender| sender.as_str()).and_then(|sender| sender_avatars.get(sender)).cloned();
+            if let (Some(avatar_path), Some(event_object)) = (avatar_path, event_deserialized.as_object_mut()) {
+                event_object.insert(String::from("sender_avatar"), serde_json::Value::String(avatar_path));
+            }
+        }
+        if let (Some(reaction_groups), Some(event_object)) = (event.event_id().and_then(|event_id| reactions_by_target.get(&event_id)), event_deserialized.as_object_mut()) {
+            let reactions = reaction_groups.iter().map(|reaction_group| json!({
+                "key": reaction_group.key,
+                "count": reaction_group.senders.len(),
+                "senders": reaction_group.senders,
+            })).collect();
+            event_object.insert(String::from("reactions"), serde_json::Value::Array(reactions));
+        }
+        if let Some(event_object) = event_deserialized.as_object_mut() {
+            event_object.insert(String::from("encryption_info"), encryption_info_to_json(event));
+        }
+        let receipts = room_metadata.read_receipts.as_ref().zip(event.event_id()).and_then(|(read_receipts, event_id)| read_receipts.receipts_by_event.get(&event_id));
+        if let (Some(receipts), Some(event_object)) = (receipts, event_deserialized.as_object_mut()) {
+            let receipts = receipts.iter().map(|receipt| json!({
+                "user_id": receipt.user_id,
+                "type": receipt.receipt_type,
+                "ts": receipt.timestamp_millis,
+                "thread_id": receipt.thread_id,
+            })).collect();
+            event_object.insert(String::from("read_receipts"), serde_json::Value::Array(receipts));
+        }
+        if let (Some(thread_root), Some(event_object)) = (thread_root_id(event), event_deserialized.as_object_mut()) {
+            event_object.insert(String::from("thread_root"), serde_json::Value::String(thread_root.to_string()));
+        }
+        if let Some(event_media) = event_media {
+            let media_path = event_deserialized.get("event_id").and_then(|event_id| event_id.as_str()).and_then(|event_id| event_media.get(event_id)).cloned();
+            if let (Some(media_path), Some(event_object)) = (media_path, event_deserialized.as_object_mut()) {
+                event_object.insert(String::from("media_file"), serde_json::Value::String(media_path));
+            }
+        }
+        events_to_export.push(event_deserialized);
+    }
+
+    let mut export = json!({
+        "schema": JSON_SCHEMA_VERSION,
+        "room": room_metadata_to_json(room_metadata),
+        "events": events_to_export,
+    });
+    if let (Some(sender_profiles), Some(export_object)) = (sender_profiles, export.as_object_mut()) {
+        let senders = events.iter().filter_map(|event| event.raw().get_field::<OwnedUserId>("sender").ok().flatten()).filter_map(|sender| {
+            let sender_profile = sender_profiles.get(&sender)?;
+            Some((sender.to_string(), json!({
+                "display_name": sender_profile.display_name,
+                "avatar_url": sender_profile.avatar_url,
+            })))
+        }).collect::<serde_json::Map<String, serde_json::Value>>();
+        export_object.insert(String::from("senders"), serde_json::Value::Object(senders));
+    }
+    if let Some(pseudonymizer) = pseudonymizer {
+        pseudonymizer.pseudonymize_json(&mut export);
+    }
+
+    Ok(export)
+}
+
+// Picks up each room's pagination where the last incremental export of it left off, returning whether any of them had such a checkpoint.
+fn resume_event_pagers(event_pagers: &mut [EventPager<'_>], checkpoints_file: Option<&CheckpointsFile>) -> bool {
+    let Some(checkpoints_file) = checkpoints_file else {
+        return false
+    };
+    let mut resumed_any = false;
+    for event_pager in event_pagers {
+        if let Some(checkpoint) = checkpoints_file.rooms.get(event_pager.room_id()) {
+            event_pager.resume_from(checkpoint);
+            resumed_any = true;
+        }
+    }
+    resumed_any
+}
+
+// Gives each room's pagination a spool in resume_dir, resuming from whatever an interrupted run of the same export left there.
+fn spool_event_pagers(event_pagers: &mut [EventPager<'_>], resume_dir: Option<&Path>, event_range: &ExportEventRange, pagination_options: &PaginationOptions) -> anyhow::Result<()> {
+    let Some(resume_dir) = resume_dir else {
+        return Ok(())
+    };
+    for event_pager in event_pagers {
+        let spool_parameters = format!("{}|{:?}|{:?}|{:?}|{}|{:?}", event_pager.room_id(), event_range.from, event_range.to, pagination_options.limit, pagination_options.newest_first, event_pager.last_end_token);
+        event_pager.spool_to(EventSpool::new(resume_dir, &sha256_hex(spool_parameters.as_bytes())))?;
+    }
+
+    Ok(())
+}
+
+// Called once a room's export has been written out in full, to record incremental checkpoints and clean up the spools it no longer needs.
+// A cancelled export's spools are left in place for a rerun to resume from, unless it's incremental, in which case its checkpoints already mark where the next run should pick up.
+fn finish_event_pagers(event_pagers: &mut [EventPager<'_>], checkpoints_file: Option<&mut CheckpointsFile>, cancelled: bool) -> anyhow::Result<()> {
+    if cancelled && checkpoints_file.is_none() {
+        return Ok(())
+    }
+    for event_pager in event_pagers.iter_mut() {
+        event_pager.discard_spool()?;
+    }
+    let Some(checkpoints_file) = checkpoints_file else {
+        return Ok(())
+    };
+    for event_pager in event_pagers.iter() {
+        if let Some(checkpoint) = event_pager.checkpoint() {
+            checkpoints_file.rooms.insert(event_pager.room_id().to_owned(), checkpoint);
+        }
+    }
+    Ok(checkpoints_file.write()?)
+}
+
+fn room_outcome(export_unit: ExportUnit<'_>, status: RoomExportStatus) -> RoomExportOutcome {
+    RoomExportOutcome {
+        room_id: export_unit.room_id,
+        room_name: export_unit.room_info.and_then(|room_info| room_info.name.clone()),
+        status,
+    }
+}
+
+fn failed_room_outcome(export_unit: ExportUnit<'_>, error: anyhow::Error, progress: &dyn Fn(ExportProgress)) -> RoomExportOutcome {
+    progress(ExportProgress::RoomFailed {
+        room_id: export_unit.room_id.clone(),
+        description: error.to_string(),
+    });
+
+    room_outcome(export_unit, RoomExportStatus::Failed(error.into()))
+}
+
+// Syncs only lazy-load the members who've been active recently, so the rest get fetched here, for display names and avatars to be found for them.
+async fn sync_room_members(room_info: &RoomWithCachedInfo, max_retries: u32) -> anyhow::Result<()> {
+    retry_rate_limited(max_retries, || async { Ok(room_info.room.sync_members().await?) }).await
+}
+
+fn cached_sender_profiles(profile_cache: Option<&ProfileCacheFile>, room_id: &RoomId) -> HashMap<OwnedUserId, SenderProfile> {
+    profile_cache.and_then(|profile_cache| profile_cache.rooms.get(room_id)).cloned().unwrap_or_default()
+}
+
+// Peeked rooms are left out, since their profiles are never known in the first place.
+fn store_sender_profiles(profile_cache: Option<&mut ProfileCacheFile>, export_unit: &ExportUnit<'_>, sender_profiles: HashMap<OwnedUserId, SenderProfile>) -> anyhow::Result<()> {
+    let (Some(profile_cache), Some(_)) = (profile_cache, export_unit.room_info) else {
+        return Ok(())
+    };
+    profile_cache.rooms.insert(export_unit.room_id.clone(), sender_profiles);
+    Ok(profile_cache.write()?)
+}
+
+fn collect_event_pager_gaps(event_pagers: &[EventPager<'_>], progress: &dyn Fn(ExportProgress), room_id: &RoomId) -> Vec<TimelineGap> {
+    let gaps = event_pagers.iter().flat_map(|event_pager| event_pager.gaps.iter().cloned()).collect::<Vec<TimelineGap>>();
+    for gap in &gaps {
+        progress(ExportProgress::GapFound {
+            room_id: room_id.to_owned(),
+            description: timeline_gap_description(gap),
+        });
+    }
+    gaps
+}
+
+// Stops early once cancelled, returning whatever's been fetched so far.
+async fn collect_event_pages(event_pager: &mut EventPager<'_>, room_id: &RoomId, progress: &dyn Fn(ExportProgress), cancellation: &CancellationToken) -> anyhow::Result<Vec<TimelineEvent>> {
+    let mut events = Vec::new();
+    while !cancellation.is_cancelled() {
+        let Some(page) = event_pager.next_page().await? else {
+            break
+        };
+        progress(ExportProgress::PageFetched {
+            room_id: room_id.to_owned(),
+            event_count: page.len(),
+        });
+        events.extend(page);
+    }
+
+    Ok(events)
+}
+
+fn is_missing_room_key(utd_info: &UnableToDecryptInfo) -> bool {
+    matches!(utd_info.reason, UnableToDecryptReason::MissingMegolmSession { .. } | UnableToDecryptReason::UnknownMegolmMessageIndex { .. })
+}
+
+// Events from the admin API come back raw, so encrypted ones from rooms the account's in get decrypted here, as the SDK would've done with events from /messages. Ones it can't decrypt come back as undecryptable, the same as from /messages, so key requests can still pick them up.
+async fn admin_event_to_timeline_event(event: Raw<AnyTimelineEvent>, room: Option<&Room>) -> TimelineEvent {
+    if let (Some(room), Some("m.room.encrypted")) = (room, event.get_field::<String>("type").ok().flatten().as_deref()) {
+        if let Ok(decrypted_event) = room.decrypt_event(event.cast_ref_unchecked(), None).await {
+            return decrypted_event
+        }
+    }
+    TimelineEvent::from_plaintext(event.cast())
+}
+
+// Asks the account's other devices for the keys to events which couldn't be decrypted for want of them, then waits for the keys to be forwarded before trying those events again. Forwarded keys come in through syncs, so something needs to be syncing in the meantime; export keeps a sync going for this.
+async fn retry_undecryptable_events(room: &Room, events: &mut [TimelineEvent], key_request_wait: Duration, progress: &dyn Fn(ExportProgress), room_id: &RoomId) -> anyhow::Result<()> {
+    let encryption = room.client().encryption();
+    let mut requested_session_ids = HashSet::new();
+    let mut undecryptable_count = 0;
+    for event in events.iter() {
+        let TimelineEventKind::UnableToDecrypt { event, utd_info } = &event.kind else {
+            continue
+        };
+        if !is_missing_room_key(utd_info) {
+            continue
+        }
+        undecryptable_count += 1;
+        if let Some(session_id) = &utd_info.session_id {
+            if requested_session_ids.insert(session_id.clone()) {
+                encryption.request_room_key(event.cast_ref_unchecked(), room.room_id()).await?; // One request per megolm session covers every event encrypted with it
+            }
+        }
+    }
+    if requested_session_ids.is_empty() {
+        return Ok(())
+    }
+
+    tokio::time::sleep(key_request_wait).await;
+    let mut decrypted_count = 0;
+    for event in events.iter_mut() {
+        let TimelineEventKind::UnableToDecrypt { event: raw_event, utd_info } = &event.kind else {
+            continue
+        };
+        if !is_missing_room_key(utd_info) {
+            continue
+        }
+        let retried_event = room.decrypt_event(raw_event.cast_ref_unchecked(), None).await?;
+        if let TimelineEventKind::Decrypted(_) = retried_event.kind {
+            *event = retried_event;
+            decrypted_count += 1;
+        }
+    }
+    progress(ExportProgress::DecryptionRetried {
+        room_id: room_id.to_owned(),
+        undecryptable_count,
+        decrypted_count,
+    });
+
+    Ok(())
+}
+
+fn filter_events(mut events: Vec<TimelineEvent>, event_type_filter: &EventTypeFilter, content_filter: Option<&ContentFilter>) -> Vec<TimelineEvent> {
+    events.retain(|event| event_type_filter.matches(event)); // Filtered client-side rather than via the /messages filter, since server-side filtering can't see the types of encrypted events
+    match content_filter {
+        Some(content_filter) => content_filter.filter(events),
+        None => events,
+    }
+}
+
+// Looks up the profiles of everyone who sent any of the given events ahead of time, teaching their display names to the pseudonymizer if there is one.
+async fn prefetch_sender_profiles(sender_profiles: &mut HashMap<OwnedUserId, SenderProfile>, room_info: Option<&RoomWithCachedInfo>, events: &[TimelineEvent], mut pseudonymizer: Option<&mut Pseudonymizer>) -> anyhow::Result<()> {
+    // Past a handful of unknown senders, it's quicker to load the whole member list in one go than to look each of them up separately
+    let unknown_senders = events.iter().filter_map(|event| event.raw().get_field::<OwnedUserId>("sender").ok().flatten()).filter(|sender| !sender_profiles.contains_key(sender)).collect::<HashSet<OwnedUserId>>();
+    if let (Some(room_info), true) = (room_info, unknown_senders.len() > 10) {
+        let fetched_at_millis = Utc::now().timestamp_millis();
+        for room_member in room_info.room.members_no_sync(RoomMemberships::empty()).await? {
+            if unknown_senders.contains(room_member.user_id()) {
+                sender_profiles.insert(room_member.user_id().to_owned(), SenderProfile {
+                    display_name: room_member.display_name().map(String::from),
+                    avatar_url: room_member.avatar_url().map(MxcUri::to_owned),
+                    fetched_at_millis,
+                });
+            }
+        }
+    }
+
+    for event in events {
+        if let Some(sender) = event.raw().get_field::<OwnedUserId>("sender").ok().flatten() {
+            let sender_profile = get_sender_profile(sender_profiles, room_info, &sender).await?;
+            if let (Some(pseudonymizer), Some(display_name)) = (pseudonymizer.as_deref_mut(), &sender_profile.display_name) {
+                pseudonymizer.learn_display_name(display_name, &sender);
+            }
+        }
+    }
+    if let Some(pseudonymizer) = pseudonymizer {
+        pseudonymizer.learn_display_names(events);
+    }
+
+    Ok(())
+}
+
+// Looks up senders' current profiles through the SDK's membership tracking, caching them so that txt and JSON output share lookups, as do later runs when there's a profile cache file.
+async fn get_sender_profile<'a>(sender_profiles: &'a mut HashMap<OwnedUserId, SenderProfile>, room_info: Option<&RoomWithCachedInfo>, user_id: &UserId) -> anyhow::Result<&'a SenderProfile> {
+    // Nothing gets stored without room_info to look profiles up through, since it'd end up in the profile cache as though the sender had no display name
+    let Some(room_info) = room_info else {
+        return Ok(sender_profiles.get(user_id).unwrap_or(&UNKNOWN_SENDER_PROFILE))
+    };
+    if !sender_profiles.contains_key(user_id) {
+        let room_member = room_info.room.get_member_no_sync(user_id).await?;
+        let sender_profile = SenderProfile {
+            display_name: room_member.as_ref().and_then(|room_member| room_member.display_name()).map(String::from),
+            avatar_url: room_member.as_ref().and_then(|room_member| room_member.avatar_url()).map(MxcUri::to_owned),
+            fetched_at_millis: Utc::now().timestamp_millis(),
+        };
+        sender_profiles.insert(user_id.to_owned(), sender_profile);
+    }
+    Ok(&sender_profiles[user_id])
+}
+
+async fn user_id_to_string_representation(sender_profiles: &mut HashMap<OwnedUserId, SenderProfile>, room_info: Option<&RoomWithCachedInfo>, event_sender_id: &UserId) -> anyhow::Result<String> {
+    let sender_profile = get_sender_profile(sender_profiles, room_info, event_sender_id).await?;
+    match &sender_profile.display_name {
+        Some(display_name) => Ok(format!("{} ({})", display_name, event_sender_id)),
+        None => Ok(event_sender_id.to_string()),
+    }
+}
+
+// Day separators pick up from last_event_date, so that output rendered in several pieces still only gets a separator where the date actually changes.
+async fn messages_to_txt(context: &mut ExportContext<'_>, events: &[TimelineEvent], room_metadata: &RoomMetadata, room_info: Option<&RoomWithCachedInfo>, sender_profiles: &mut HashMap<OwnedUserId, SenderProfile>, event_media: Option<&HashMap<String, String>>, last_event_date: &mut Option<String>) -> anyhow::Result<String> {
+    let txt_options = context.txt_options;
+    let pseudonymizer = context.pseudonymizer.as_deref_mut();
+    // When pseudonymizing, everyone gets displayed by bare user ID, which then gets swapped out for their pseudonym along with the rest of the text
+    let room_info = if pseudonymizer.is_some() { None } else { room_info };
+    let mut room_export = String::new();
+
+    let message_edits_by_target = collect_message_edits(events);
+    let reactions_by_target = collect_reactions(events);
+    let events_by_id = events.iter().filter_map(|event| Some((event.event_id()?, event))).collect::<HashMap<OwnedEventId, &TimelineEvent>>();
+    let applied_edit_ids = message_edits_by_target.values().flatten().map(|edit| edit.event_id.clone()).collect::<HashSet<OwnedEventId>>();
+    let attached_reaction_ids = reactions_by_target.values().flatten().flat_map(|reaction_group| reaction_group.event_ids.iter().cloned()).collect::<HashSet<OwnedEventId>>();
+    let polls = collect_polls(events);
+    let tallied_poll_response_ids = polls.values().flat_map(|poll| poll.response_event_ids.iter().cloned()).collect::<HashSet<OwnedEventId>>();
+
+    let historical_display_names = if txt_options.historical_display_names && pseudonymizer.is_none() {
+        collect_historical_display_names(events)
+    } else {
+        HashMap::new()
+    };
+
+    let event_order = if txt_options.group_threads {
+        thread_grouped_event_order(events)
+    } else {
+        (0..events.len()).map(|index| (index, false)).collect()
+    };
+    for (event_index, is_thread_reply) in event_order {
+        let event = &events[event_index];
+        let line_prefix = if is_thread_reply { "    | " } else { "" };
+        let event_deserialized = match event.raw().deserialize() {
+            Ok(event_deserialized) => event_deserialized,
+            Err(_) => {
+                // Add more nuanced error-handling here; it seems like a lot of these are in fact redacted messages, just weirdly-formed ones that don't deserialize right?
+                room_export.push_str(&format!("{}[Message skipped due to deserialization failure]\n", line_prefix));
+                continue
+            }
+        };
+
+        if applied_edit_ids.contains(event_deserialized.event_id()) || attached_reaction_ids.contains(event_deserialized.event_id()) || tallied_poll_response_ids.contains(event_deserialized.event_id()) {
+            continue
+        }
+
+        let event_timestamp_millis = event_deserialized.origin_server_ts().0.into();
+        let event_timestamp_string_representation = format_timestamp(event_timestamp_millis, txt_options);
+        if txt_options.day_separators && !is_thread_reply {
+            let event_date = format_timestamp_as(event_timestamp_millis, &txt_options.timezone, Some("%Y-%m-%d"));
+            if last_event_date.as_ref() != Some(&event_date) {
+                room_export.push_str(&format!("--- {} ---\n", event_date));
+                *last_event_date = Some(event_date);
+            }
+        }
+
+        let event_sender_id = event_deserialized.sender();
+        let event_sender_string_representation = match historical_display_names.get(event_deserialized.event_id()) {
+            Some(Some(display_name)) => format!("{} ({})", display_name, event_sender_id),
+            Some(None) => event_sender_id.to_string(),
+            None => user_id_to_string_representation(sender_profiles, room_info, event_sender_id).await?,
+        };
+
+        let event_prefix = format!("[{}] {}:", event_timestamp_string_representation, event_sender_string_representation);
+        let media_suffix = match event_media.and_then(|event_media| event_media.get(event_deserialized.event_id().as_str())) {
+            Some(media_path) => format!("; saved as {}", media_path),
+            None => String::new(),
+        };
+
+        let event_stringified = match &event_deserialized {
+            AnySyncTimelineEvent::MessageLike(e) => match e {
+                AnySyncMessageLikeEvent::RoomMessage(e) => match &e.as_original() {
+                    Some(unredacted_room_message) => {
+                        let message_edits = message_edits_by_target.get(&unredacted_room_message.event_id).map(Vec::as_slice).unwrap_or_default();
+                        let msgtype = message_edits.last().map(|edit| &edit.msgtype).unwrap_or(&unredacted_room_message.content.msgtype);
+                        let mut message_stringified = match msgtype {
+                            // Possibly revisit here at some point to add more detail beyond the body into various of these formats
+                            MessageType::Audio(e) => format!("{} [Audio; textual representation: {}{}]", event_prefix, &e.body, media_suffix),
+                            MessageType::Emote(e) => format!("{} *{}*", event_prefix, message_text(&e.body, e.formatted.as_ref(), txt_options)), // Think harder about whether asterisks are the correct representation here
+                            MessageType::File(e) => format!("{} [File; textual representation: {}{}]", event_prefix, &e.body, media_suffix), // In the longer term maybe include filename directly? But currently it seems like the textual representation is the main thing that's actually used to encode the filename
+                            MessageType::Image(e) => format!("{} [Image; textual representation: {}{}]", event_prefix, &e.body, media_suffix),
+                            MessageType::Location(e) => format!("{} [Location; geo URI: {}; textual representation: {}]", event_prefix, &e.geo_uri, &e.body),
+                            MessageType::Notice(e) => format!("{} [{}]", event_prefix, message_text(&e.body, e.formatted.as_ref(), txt_options)), // Think harder about whether brackets are the correct representation here
+                            MessageType::ServerNotice(e) => format!("{} [Server notice: {}]", event_prefix, &e.body),
+                            MessageType::Text(e) => format!("{} {}", event_prefix, message_text(&e.body, e.formatted.as_ref(), txt_options)),
+                            MessageType::Video(e) => format!("{} [Video; textual representation: {}{}]", event_prefix, &e.body, media_suffix),
+                            MessageType::VerificationRequest(e) => format!("{} [Verification request sent to {}]", event_prefix, user_id_to_string_representation(sender_profiles, room_info, &e.to).await?),
+                            _ => String::from("[Message of unrecognized type]"),
+                        };
+                        if !message_edits.is_empty() {
+                            if txt_options.mark_edits {
+                                message_stringified.push_str(" (edited)");
+                            }
+                            if txt_options.edit_history {
+                                message_stringified.push_str(&format!("\n{}    [Original, {}]: {}", line_prefix, event_timestamp_string_representation, unredacted_room_message.content.msgtype.body()));
+                                for edit in &message_edits[..message_edits.len() - 1] {
+                                    message_stringified.push_str(&format!("\n{}    [Edit, {}]: {}", line_prefix, format_timestamp(edit.timestamp_millis, txt_options), edit.msgtype.body()));
+                                }
+                            }
+                        }
+                        message_stringified
+                    }
+                    None => match redaction_details(event) {
+                        Some((redacter, reason)) => {
+                            let redacter_string_representation = user_id_to_string_representation(sender_profiles, room_info, &redacter).await?;
+                            match reason {
+                                Some(reason) => format!("{} [Redacted message; redacted by {}; reason: {}]", event_prefix, redacter_string_representation, reason),
+                                None => format!("{} [Redacted message; redacted by {}]", event_prefix, redacter_string_representation),
+                            }
+                        }
+                        None => format!("{} [Redacted message]", event_prefix),
+                    },
+                },
+                AnySyncMessageLikeEvent::RoomEncrypted(_) => match &event.kind {
+                    TimelineEventKind::UnableToDecrypt { utd_info, .. } => format!("{} [Unable to decrypt: {}]", event_prefix, utd_reason_description(utd_info)),
+                    _ => format!("{} [Encrypted message]", event_prefix),
+                },
+                AnySyncMessageLikeEvent::RoomRedaction(_) => {
+                    let content = event.raw().get_field::<serde_json::Value>("content").ok().flatten();
+                    let reason = content.as_ref().and_then(|content| content.get("reason")).and_then(|reason| reason.as_str());
+                    let redacted_event_id = event.raw().get_field::<String>("redacts").ok().flatten().or_else(|| content.as_ref().and_then(|content| content.get("redacts")).and_then(|redacts| redacts.as_str()).map(String::from)); // Moved from the top level into content as of room version 11
+                    match (redacted_event_id, reason) {
+                        (Some(redacted_event_id), Some(reason)) => format!("{} [Redacted event {}; reason: {}]", event_prefix, redacted_event_id, reason),
+                        (Some(redacted_event_id), None) => format!("{} [Redacted event {}]", event_prefix, redacted_event_id),
+                        (None, _) => format!("{} [Redacted an event]", event_prefix),
+                    }
+                }
+                AnySyncMessageLikeEvent::Sticker(SyncMessageLikeEvent::Original(e)) => format!("{} [Sticker; textual representation: {}]", event_prefix, &e.content.body),
+                AnySyncMessageLikeEvent::Sticker(SyncMessageLikeEvent::Redacted(_)) => format!("{} [Redacted sticker]", event_prefix),
+                AnySyncMessageLikeEvent::CallInvite(_) => format!("{} [Started a call]", event_prefix),
+                AnySyncMessageLikeEvent::CallAnswer(_) => format!("{} [Answered a call]", event_prefix),
+                AnySyncMessageLikeEvent::CallHangup(SyncMessageLikeEvent::Original(e)) => format!("{} [Ended a call; reason: {}]", event_prefix, e.content.reason.as_str()),
+                AnySyncMessageLikeEvent::CallHangup(SyncMessageLikeEvent::Redacted(_)) => format!("{} [Ended a call]", event_prefix),
+                _ => poll_event_stringified(event, &event_prefix, &polls).unwrap_or_else(|| String::from("[Placeholder message-like]")),
+            },
+            AnySyncTimelineEvent::State(_e) => String::from("[Placeholder state-like]"),
+        };
+        if let Some(replied_to_event_id) = replied_to_event_id(&event_deserialized) {
+            let reply_quote = match events_by_id.get(&replied_to_event_id).and_then(|replied_to_event| replied_to_event.raw().deserialize().ok()) {
+                Some(replied_to_event) => {
+                    let replied_to_sender = user_id_to_string_representation(sender_profiles, room_info, replied_to_event.sender()).await?;
+                    let replied_to_snippet = match &replied_to_event {
+                        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(e))) => {
+                            let msgtype = message_edits_by_target.get(&e.event_id).and_then(|message_edits| message_edits.last()).map(|edit| &edit.msgtype).unwrap_or(&e.content.msgtype);
+                            String::from(first_line_without_reply_fallback(msgtype.body()))
+                        }
+                        _ => format!("[{}]", replied_to_event.event_type()),
+                    };
+                    format!("> {}: {}", replied_to_sender, replied_to_snippet)
+                }
+                None => format!("> [Reply to event {}, which isn't part of this export]", replied_to_event_id),
+            };
+            room_export.push_str(&format!("{}    {}\n", line_prefix, reply_quote));
+        }
+        if txt_options.permalinks {
+            room_export.push_str(&format!("{}{} ({}, {})\n", line_prefix, event_stringified, event_deserialized.event_id(), room_metadata.room_id.matrix_to_event_uri(event_deserialized.event_id())));
+        } else {
+            room_export.push_str(&format!("{}{}\n", line_prefix, event_stringified));
+        }
+        if let Some(reaction_groups) = reactions_by_target.get(event_deserialized.event_id()) {
+            let reactions_stringified = reaction_groups.iter().map(|reaction_group| format!("{} ×{}", reaction_group.key, reaction_group.senders.len())).collect::<Vec<String>>().join("  ");
+            room_export.push_str(&format!("{}    {}\n", line_prefix, reactions_stringified));
+        }
+        // Receipts on edits, reactions, and poll responses get dropped along with the events themselves, since they're folded into what they apply to
+        if let Some(read_receipts) = &room_metadata.read_receipts {
+            if let Some(receipts) = read_receipts.receipts_by_event.get(event_deserialized.event_id()) {
+                let mut readers = Vec::new();
+                for receipt in receipts {
+                    let reader = user_id_to_string_representation(sender_profiles, room_info, &receipt.user_id).await?;
+                    match receipt.timestamp_millis {
+                        Some(timestamp_millis) => readers.push(format!("{} at {}", reader, format_timestamp(timestamp_millis, txt_options))),
+                        None => readers.push(reader),
+                    }
+                }
+                room_export.push_str(&format!("{}    [Read up to here by {}]\n", line_prefix, readers.join(", ")));
+            }
+            if read_receipts.read_marker.as_deref() == Some(event_deserialized.event_id()) {
+                room_export.push_str(&format!("{}    [Read marker]\n", line_prefix));
+            }
+        }
+    }
+
+    if let Some(pseudonymizer) = pseudonymizer {
+        room_export = pseudonymizer.pseudonymize_text(&room_export);
+    }
+
+    Ok(room_export)
+}
+
+// Uploads a room's files from the staging directory, along with the avatars and media it references (given by their paths within the export) and the manifests, then deletes the local copies of everything but the manifests. Other rooms' avatars and media are left for their own uploads, since with several rooms exporting at once, they may not be done downloading yet. Returns where the room's files were uploaded to, in place of their local paths.
+async fn upload_staged_files(object_storage: &ObjectStorage, staging_path: &Path, files: Vec<PathBuf>, downloaded_files: HashSet<&String>) -> anyhow::Result<Vec<PathBuf>> {
+    let mut uploaded_files = Vec::new();
+    for file in files {
+        let path = file.strip_prefix(staging_path).unwrap_or(&file).to_string_lossy().replace('\\', "/");
+        object_storage.put_file(&path, &file).await?;
+        remove_file(&file)?;
+        uploaded_files.push(PathBuf::from(object_storage.object_url(&path)));
+    }
+    for path in downloaded_files {
+        let file = staging_path.join(path);
+        // Avatars and media shared with another room may have been uploaded and deleted along with that room's files already
+        if !file.exists() {
+            continue
+        }
+        object_storage.put_file(path, &file).await?;
+        remove_file(&file)?;
+    }
+    let media_manifest_path = format!("media/{}", MEDIA_MANIFEST_FILENAME);
+    for manifest_path in [media_manifest_path.as_str(), EXPORT_MANIFEST_FILENAME] {
+        if let Ok(manifest) = read(staging_path.join(manifest_path)) {
+            object_storage.put_object(manifest_path, manifest).await?;
+        }
+    }
+
+    Ok(uploaded_files)
+}
+
+// Rooms without room_info (i.e. peeked ones) get exported without display names or avatars, since those come from the SDK's membership tracking.
+async fn write_room_export(context: &mut ExportContext<'_>, room_metadata: &RoomMetadata, room_info: Option<&RoomWithCachedInfo>, base_output_filename: &str, events: &Vec<TimelineEvent>, sender_profiles: &mut HashMap<OwnedUserId, SenderProfile>) -> anyhow::Result<WrittenExport> {
+    let ExportContext { client, destination, formats, download_avatars, download_media, max_retries, split_mode, progress, json_options, txt_options, .. } = *context;
+    let base_output_path = destination.directory();
+    let mut written_byte_count = 0;
+    let mut written_files = Vec::new();
+    if context.pseudonymizer.is_some() || (formats.contains(&ExportOutputFormat::Json) && json_options.sender_profiles) {
+        prefetch_sender_profiles(sender_profiles, room_info, events, context.pseudonymizer.as_deref_mut()).await?;
+    }
+    let sender_avatars = match room_info {
+        Some(room_info) if download_avatars => {
+            let avatars_path = base_output_path.join("avatars");
+            create_dir_all(&avatars_path)?;
+            Some(download_sender_avatars(client, room_info, events, &avatars_path, max_retries, progress).await?)
+        }
+        _ => None,
+    };
+    let event_media = if download_media {
+        let media_path = base_output_path.join("media");
+        create_dir_all(&media_path)?;
+        Some(download_event_media(client, &room_metadata.room_id, events, &media_path, max_retries, progress).await?)
+    } else {
+        None
+    };
+
+    // Each file gets rendered on its own, so edits, reactions, and replies which cross a split boundary don't get attached to their targets
+    for (filename_suffix, events) in split_events(events, split_mode) {
+        let output_filename = match filename_suffix {
+            Some(filename_suffix) => format!("{} {}", base_output_filename, filename_suffix),
+            None => String::from(base_output_filename),
+        };
+        let mut room_metadata = room_metadata.clone();
+        room_metadata.time_range_millis = event_time_range_millis(events);
+        if formats.contains(&ExportOutputFormat::Json) {
+            let json_export = messages_to_json(events, &room_metadata, json_options.sender_profiles.then_some(&*sender_profiles), sender_avatars.as_ref(), event_media.as_ref(), context.pseudonymizer.as_deref_mut())?;
+            match destination {
+                ExportDestination::Directory(_) | ExportDestination::ObjectStorage(..) => {
+                    let json_output_file = match json_options.compact {
+                        true => serde_json::to_string(&json_export)?,
+                        false => serde_json::to_string_pretty(&json_export)?,
+                    };
+                    let mut json_output_path_buf = base_output_path.clone();
+                    json_output_path_buf.push(format!("{}.json", output_filename));
+                    written_byte_count += json_output_file.len();
+                    write(&json_output_path_buf, &json_output_file)?;
+                    record_in_export_manifest(&base_output_path, &format!("{}.json", output_filename), room_metadata.room_id.as_str(), events.len(), sha256_hex(json_output_file.as_bytes()))?;
+                    written_files.push(json_output_path_buf);
+                }
+                ExportDestination::Stdout => {
+                    let mut stdout = stdout().lock();
+                    for event in json_export.get("events").and_then(|events| events.as_array()).into_iter().flatten() {
+                        let event_line = event.to_string();
+                        written_byte_count += event_line.len() + 1;
+                        writeln!(stdout, "{}", event_line)?;
+                    }
+                }
+            }
+        }
+        if formats.contains(&ExportOutputFormat::Txt) {
+            let mut txt_output_file = room_metadata_to_txt(&room_metadata, txt_options) + &time_range_to_txt(room_metadata.time_range_millis, txt_options) + &gaps_to_txt(&room_metadata.gaps) + "==========\n";
+            if let Some(pseudonymizer) = context.pseudonymizer.as_deref_mut() {
+                txt_output_file = pseudonymizer.pseudonymize_text(&txt_output_file);
+            }
+            txt_output_file.push_str(&messages_to_txt(context, events, &room_metadata, room_info, sender_profiles, event_media.as_ref(), &mut None).await?);
+            written_byte_count += txt_output_file.len();
+            match destination {
+                ExportDestination::Directory(_) | ExportDestination::ObjectStorage(..) => {
+                    let mut txt_output_path_buf = base_output_path.clone();
+                    txt_output_path_buf.push(format!("{}.txt", output_filename));
+                    write(&txt_output_path_buf, &txt_output_file)?;
+                    record_in_export_manifest(&base_output_path, &format!("{}.txt", output_filename), room_metadata.room_id.as_str(), events.len(), sha256_hex(txt_output_file.as_bytes()))?;
+                    written_files.push(txt_output_path_buf);
+                }
+                ExportDestination::Stdout => stdout().lock().write_all(txt_output_file.as_bytes())?,
+            }
+        }
+    }
+    if let ExportDestination::ObjectStorage(object_storage, _) = destination {
+        let downloaded_files = sender_avatars.iter().chain(&event_media).flat_map(HashMap::values).collect();
+        written_files = upload_staged_files(object_storage, &base_output_path, written_files, downloaded_files).await?;
+    }
+
+    Ok(WrittenExport {
+        byte_count: written_byte_count,
+        event_count: events.len(),
+        files: written_files,
+    })
+}
+
+// Writes each page of events out as soon as it's fetched, rather than holding a room's whole history in memory first. Pages get rendered on their own, so edits, reactions, poll responses, and replies only get attached to their targets within the same page, and likewise for thread grouping and --grep context. Streamed JSON has one event per line, regardless of --compact. Once cancelled, the files get closed off as they stand.
+async fn stream_room_export(context: &mut ExportContext<'_>, mut room_metadata: RoomMetadata, room_info: Option<&RoomWithCachedInfo>, event_pagers: &mut [EventPager<'_>], seen_event_ids: &mut HashSet<OwnedEventId>, base_output_filename: &str, sender_profiles: &mut HashMap<OwnedUserId, SenderProfile>) -> anyhow::Result<WrittenExport> {
+    let ExportContext { client, destination, formats, download_media, max_retries, event_type_filter, content_filter, key_request_wait, progress, cancellation, json_options, txt_options, .. } = *context;
+    let download_avatars = context.download_avatars && room_info.is_some();
+    let base_output_path = destination.directory();
+    let to_stdout = matches!(destination, ExportDestination::Stdout);
+    let open_output = |extension: &str| -> anyhow::Result<CountingWriter<Box<dyn Write>>> {
+        let inner: Box<dyn Write> = match destination {
+            ExportDestination::Directory(_) | ExportDestination::ObjectStorage(..) => Box::new(BufWriter::new(File::create(base_output_path.join(format!("{}.{}", base_output_filename, extension)))?)),
+            ExportDestination::Stdout => Box::new(stdout().lock()),
+        };
+        Ok(CountingWriter::new(inner))
+    };
+    let mut json_output = formats.contains(&ExportOutputFormat::Json).then(|| open_output("json")).transpose()?;
+    let mut txt_output = formats.contains(&ExportOutputFormat::Txt).then(|| open_output("txt")).transpose()?;
+
+    if let (Some(json_output), false) = (json_output.as_mut(), to_stdout) {
+        write!(json_output, "{{\"schema\":{},\"events\":[", JSON_SCHEMA_VERSION)?;
+    }
+    if let Some(txt_output) = txt_output.as_mut() {
+        let mut header = room_metadata_to_txt(&room_metadata, txt_options) + "==========\n";
+        if let Some(pseudonymizer) = context.pseudonymizer.as_deref_mut() {
+            header = pseudonymizer.pseudonymize_text(&header);
+        }
+        txt_output.write_all(header.as_bytes())?;
+    }
+
+    let mut sender_avatars = HashMap::new();
+    let mut event_media = HashMap::new();
+    let mut json_senders = serde_json::Map::new();
+    let mut wrote_json_event = false;
+    let mut last_event_date = None;
+    let mut time_range_millis: Option<(i64, i64)> = None;
+    let mut written_event_count = 0;
+    for event_pager in event_pagers.iter_mut() {
+        while !cancellation.is_cancelled() {
+            let Some(mut page) = event_pager.next_page().await? else {
+                break
+            };
+            progress(ExportProgress::PageFetched {
+                room_id: room_metadata.room_id.clone(),
+                event_count: page.len(),
+            });
+            if let (Some(key_request_wait), EventSource::Joined(room) | EventSource::Admin(_, _, Some(room))) = (key_request_wait, event_pager.source) {
+                retry_undecryptable_events(room, &mut page, key_request_wait, progress, &room_metadata.room_id).await?;
+            }
+            let mut page = filter_events(page, event_type_filter, content_filter);
+            dedup_and_sort_events(&mut page, seen_event_ids, event_pager.newest_first); // Only sorted within each page, since earlier pages are already written out
+            if page.is_empty() {
+                continue
+            }
+            progress(ExportProgress::EventsProcessed {
+                room_id: room_metadata.room_id.clone(),
+                event_count: page.len(),
+            });
+            written_event_count += page.len();
+            if let Some((page_start, page_end)) = event_time_range_millis(&page) {
+                time_range_millis = Some(match time_range_millis {
+                    Some((start, end)) => (start.min(page_start), end.max(page_end)),
+                    None => (page_start, page_end),
+                });
+            }
+
+            if context.pseudonymizer.is_some() || (json_output.is_some() && json_options.sender_profiles) {
+                prefetch_sender_profiles(sender_profiles, room_info, &page, context.pseudonymizer.as_deref_mut()).await?;
+            }
+            if let (Some(room_info), true) = (room_info, download_avatars) {
+                let avatars_path = base_output_path.join("avatars");
+                create_dir_all(&avatars_path)?;
+                sender_avatars.extend(download_sender_avatars(client, room_info, &page, &avatars_path, max_retries, progress).await?);
+            }
+            if download_media {
+                let media_path = base_output_path.join("media");
+                create_dir_all(&media_path)?;
+                event_media.extend(download_event_media(client, &room_metadata.room_id, &page, &media_path, max_retries, progress).await?);
+            }
+
+            if let Some(json_output) = json_output.as_mut() {
+                let mut json_page = messages_to_json(&page, &room_metadata, json_options.sender_profiles.then_some(&*sender_profiles), download_avatars.then_some(&sender_avatars), download_media.then_some(&event_media), context.pseudonymizer.as_deref_mut())?;
+                if let Some(serde_json::Value::Object(senders)) = json_page.get_mut("senders").map(serde_json::Value::take) {
+                    json_senders.extend(senders);
+                }
+                if let Some(serde_json::Value::Array(events)) = json_page.get_mut("events").map(serde_json::Value::take) {
+                    for event in events {
+                        match (to_stdout, wrote_json_event) {
+                            (true, _) => writeln!(json_output, "{}", event)?,
+                            (false, false) => write!(json_output, "\n{}", event)?,
+                            (false, true) => write!(json_output, ",\n{}", event)?,
+                        }
+                        wrote_json_event = true;
+                    }
+                }
+            }
+            if let Some(txt_output) = txt_output.as_mut() {
+                let txt_page = messages_to_txt(context, &page, &room_metadata, room_info, sender_profiles, download_media.then_some(&event_media), &mut last_event_date).await?;
+                txt_output.write_all(txt_page.as_bytes())?;
+            }
+        }
+    }
+
+    room_metadata.time_range_millis = time_range_millis;
+    room_metadata.gaps = collect_event_pager_gaps(event_pagers, progress, &room_metadata.room_id);
+    let mut written_byte_count = 0;
+    let mut written_hashes = Vec::new();
+    if let Some(mut json_output) = json_output {
+        if !to_stdout {
+            let mut room_json = room_metadata_to_json(&room_metadata);
+            if let Some(pseudonymizer) = context.pseudonymizer.as_deref_mut() {
+                pseudonymizer.pseudonymize_json(&mut room_json);
+            }
+            write!(json_output, "\n],\"room\":{}", room_json)?;
+            if json_options.sender_profiles {
+                write!(json_output, ",\"senders\":{}", serde_json::Value::Object(json_senders))?;
+            }
+            writeln!(json_output, "}}")?;
+        }
+        json_output.flush()?;
+        written_byte_count += json_output.byte_count;
+        written_hashes.push((ExportOutputFormat::Json, json_output.sha256_hex()));
+    }
+    if let Some(mut txt_output) = txt_output {
+        write!(txt_output, "==========\n{}{}", time_range_to_txt(room_metadata.time_range_millis, txt_options), gaps_to_txt(&room_metadata.gaps))?;
+        txt_output.flush()?;
+        written_byte_count += txt_output.byte_count;
+        written_hashes.push((ExportOutputFormat::Txt, txt_output.sha256_hex()));
+    }
+    let mut written_files = Vec::new();
+    if !to_stdout {
+        for (format, sha256) in written_hashes {
+            let filename = match format {
+                ExportOutputFormat::Json => format!("{}.json", base_output_filename),
+                ExportOutputFormat::Txt => format!("{}.txt", base_output_filename),
+            };
+            record_in_export_manifest(&base_output_path, &filename, room_metadata.room_id.as_str(), written_event_count, sha256)?;
+            written_files.push(base_output_path.join(&filename));
+        }
+    }
+    if let ExportDestination::ObjectStorage(object_storage, _) = destination {
+        let downloaded_files = sender_avatars.values().chain(event_media.values()).collect();
+        written_files = upload_staged_files(object_storage, &base_output_path, written_files, downloaded_files).await?;
+    }
+
+    Ok(WrittenExport {
+        byte_count: written_byte_count,
+        event_count: written_event_count,
+        files: written_files,
+    })
+}
+
+// Peeked rooms can't be followed, since syncs only cover joined ones, and neither can rooms which have since been upgraded, since nothing more gets sent in them.
+fn followed_room<'a>(export_unit: &ExportUnit<'a>, seen_event_ids: HashSet<OwnedEventId>, profile_cache: Option<&ProfileCacheFile>) -> Option<FollowedRoom<'a>> {
+    let room_info = export_unit.room_info.filter(|room_info| !room_info.room.is_tombstoned())?;
+    Some(FollowedRoom {
+        room_info,
+        filename: export_unit.filename.clone(),
+        seen_event_ids,
+        sender_profiles: cached_sender_profiles(profile_cache, &export_unit.room_id),
+        json_output: None,
+        txt_output: None,
+        last_event_date: None,
+    })
+}
+
+// Fetches the events a limited sync skipped over, going backward from its prev_batch token until reaching events which were already exported. Returns None if that can't be reached within FOLLOW_BACKFILL_EVENT_LIMIT events.
+async fn backfill_limited_timeline(room: &Room, prev_batch: &str, seen_event_ids: &HashSet<OwnedEventId>, max_retries: u32) -> anyhow::Result<Option<Vec<TimelineEvent>>> {
+    let mut events = Vec::new();
+    let mut from = prev_batch.to_owned();
+    while events.len() < FOLLOW_BACKFILL_EVENT_LIMIT {
+        let messages_options = MessagesOptions::backward().from(Some(from.as_str()));
+        let messages = retry_rate_limited(max_retries, || async { Ok(room.messages(messages_options.clone()).await?) }).await?;
+        let reached_seen_event = messages.chunk.iter().any(|event| event.event_id().is_some_and(|event_id| seen_event_ids.contains(&event_id)));
+        events.extend(messages.chunk);
+        match messages.end {
+            Some(end) if !reached_seen_event => from = end,
+            _ => return Ok(Some(events)),
+        }
+    }
+
+    Ok(None)
+}
+
+// Keeps syncing once the historical export's done, appending each new event in the followed rooms to the end of their output as it arrives and flushing it straight away, until cancelled. A JSON export can't be appended to and still parse, so new events go into a JSON lines file beside it instead (or to stdout as JSON lines, as with streamed exports), which carries on from wherever the last follow left off. The first sync picks up from wherever the one before the export left off, so that events sent while the export was running aren't missed either, and syncs which skip events get them backfilled. The export manifest gets brought up to date after each batch of events, so that it stays accurate even if following gets killed rather than stopped.
+async fn follow_export(context: &mut ExportContext<'_>, mut followed_rooms: Vec<FollowedRoom<'_>>) -> anyhow::Result<()> {
+    let ExportContext { client, destination, formats, download_avatars, download_media, max_retries, event_type_filter, content_filter, progress, cancellation, .. } = *context;
+    let base_output_path = destination.directory();
+    let open_output = |filename: String| -> anyhow::Result<CountingWriter<Box<dyn Write>>> {
+        Ok(match destination {
+            ExportDestination::Directory(_) | ExportDestination::ObjectStorage(..) => {
+                let path = base_output_path.join(filename);
+                CountingWriter::appending_to(Box::new(OpenOptions::new().create(true).append(true).open(&path)?), &path)?
+            }
+            ExportDestination::Stdout => CountingWriter::new(Box::new(stdout())),
+        })
+    };
+    for followed_room in &mut followed_rooms {
+        if formats.contains(&ExportOutputFormat::Json) {
+            followed_room.json_output = Some(open_output(format!("{}.jsonl", followed_room.filename))?);
+        }
+        if formats.contains(&ExportOutputFormat::Txt) {
+            followed_room.txt_output = Some(open_output(format!("{}.txt", followed_room.filename))?);
+        }
+    }
+    let room_metadata_by_id = followed_rooms.iter().map(|followed_room| (followed_room.room_info.id.clone(), collect_room_metadata(client, &followed_room.room_info.id, Some(followed_room.room_info), None, None, &[]))).collect::<HashMap<OwnedRoomId, RoomMetadata>>();
+
+    info!(room_count = followed_rooms.len(), "Following rooms");
+    progress(ExportProgress::FollowStarted {
+        room_count: followed_rooms.len(),
+    });
+    let mut sender_avatars = HashMap::new();
+    let mut event_media = HashMap::new();
+    while !cancellation.is_cancelled() {
+        let sync_response = tokio::select! {
+            sync_response = client.sync_once(SyncSettings::new().timeout(FOLLOW_SYNC_TIMEOUT).set_presence(PresenceState::Offline)) => sync_response,
+            _ = cancellation.cancelled() => break,
+        };
+        let sync_response = match sync_response {
+            Ok(sync_response) => sync_response,
+            Err(e) => {
+                warn!("Couldn't sync while following due to error '{}'. Trying again in {} seconds.", e, FOLLOW_SYNC_RETRY_DELAY.as_secs());
+                tokio::select! {
+                    _ = tokio::time::sleep(FOLLOW_SYNC_RETRY_DELAY) => (),
+                    _ = cancellation.cancelled() => (),
+                }
+                continue
+            }
+        };
+
+        for (room_id, room_update) in sync_response.rooms.joined {
+            let (Some(followed_room), Some(room_metadata)) = (followed_rooms.iter_mut().find(|followed_room| followed_room.room_info.id == room_id), room_metadata_by_id.get(&room_id)) else {
+                continue
+            };
+            let mut events = room_update.timeline.events;
+            if room_update.timeline.limited {
+                let backfilled_events = match room_update.timeline.prev_batch.as_deref() {
+                    Some(prev_batch) => backfill_limited_timeline(&followed_room.room_info.room, prev_batch, &followed_room.seen_event_ids, max_retries).await.unwrap_or_else(|e| {
+                        warn!("Couldn't backfill events skipped while following room {} due to error '{}'.", room_id, e);
+                        None
+                    }),
+                    None => None,
+                };
+                match backfilled_events {
+                    Some(backfilled_events) => events.extend(backfilled_events),
+                    None => progress(ExportProgress::GapFound {
+                        room_id: room_id.clone(),
+                        description: String::from("Too many events arrived at once while following for the homeserver to send them all, and the rest couldn't be backfilled, so only the latest ones were appended"),
+                    }),
+                }
+            }
+            let mut events = filter_events(events, event_type_filter, content_filter);
+            dedup_and_sort_events(&mut events, &mut followed_room.seen_event_ids, false);
+            let appended_event_count = events.len();
+
+            // Each event gets written out and flushed on its own, so that the output's never more than one event behind the room
+            for event in events {
+                let page = [event];
+                if context.pseudonymizer.is_some() {
+                    prefetch_sender_profiles(&mut followed_room.sender_profiles, Some(followed_room.room_info), &page, context.pseudonymizer.as_deref_mut()).await?;
+                }
+                if download_avatars {
+                    let avatars_path = base_output_path.join("avatars");
+                    create_dir_all(&avatars_path)?;
+                    sender_avatars.extend(download_sender_avatars(client, followed_room.room_info, &page, &avatars_path, max_retries, progress).await?);
+                }
+                if download_media {
+                    let media_path = base_output_path.join("media");
+                    create_dir_all(&media_path)?;
+                    event_media.extend(download_event_media(client, &room_id, &page, &media_path, max_retries, progress).await?);
+                }
+
+                let mut byte_count = 0;
+                if let Some(json_output) = followed_room.json_output.as_mut() {
+                    let mut json_page = messages_to_json(&page, room_metadata, None, download_avatars.then_some(&sender_avatars), download_media.then_some(&event_media), context.pseudonymizer.as_deref_mut())?;
+                    if let Some(serde_json::Value::Array(events)) = json_page.get_mut("events").map(serde_json::Value::take) {
+                        for event in events {
+                            let line = format!("{}\n", event);
+                            json_output.write_all(line.as_bytes())?;
+                            byte_count += line.len();
+                        }
+                    }
+                    json_output.flush()?;
+                }
+                if let Some(txt_output) = followed_room.txt_output.as_mut() {
+                    let txt_page = messages_to_txt(context, &page, room_metadata, Some(followed_room.room_info), &mut followed_room.sender_profiles, download_media.then_some(&event_media), &mut followed_room.last_event_date).await?;
+                    txt_output.write_all(txt_page.as_bytes())?;
+                    txt_output.flush()?;
+                    byte_count += txt_page.len();
+                }
+                progress(ExportProgress::EventAppended {
+                    room_id: room_id.clone(),
+                    byte_count,
+                });
+            }
+
+            if appended_event_count > 0 && matches!(destination, ExportDestination::Directory(_)) {
+                if let Some(json_output) = followed_room.json_output.as_ref() {
+                    record_appended_in_export_manifest(&base_output_path, &format!("{}.jsonl", followed_room.filename), room_id.as_str(), appended_event_count, json_output.sha256_hex())?;
+                }
+                if let Some(txt_output) = followed_room.txt_output.as_ref() {
+                    record_appended_in_export_manifest(&base_output_path, &format!("{}.txt", followed_room.filename), room_id.as_str(), appended_event_count, txt_output.sha256_hex())?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn export(client: &Client, options: ExportOptions<'_>) -> Result<ExportReport> {
+    let ExportOptions {
+        rooms,
+        room_patterns,
+        dm_users,
+        peek,
+        admin,
+        destination,
+        name_template,
+        formats,
+        split_mode,
+        streaming,
+        follow,
+        download_avatars,
+        download_media,
+        read_receipts,
+        room_data,
+        event_range,
+        event_type_filter,
+        content_filter,
+        follow_upgrades,
+        pseudonymize,
+        offline,
+        mut incremental_checkpoints,
+        mut profile_cache,
+        resume_dir,
+        jobs,
+        key_request_wait,
+        pagination_options,
+        json_options,
+        txt_options,
+        progress,
+        cancellation,
+    } = options;
+    let progress = progress.unwrap_or(&|_| ());
+    let cancellation = cancellation.cloned().unwrap_or_default();
+    if let ExportDestination::Directory(Some(path)) | ExportDestination::ObjectStorage(_, Some(path)) = &destination {
+        if path.exists() {
+            if !path.is_dir() {
+                return Err(Error::InvalidExportOptions(format!("Output path {} isn't a directory.", path.display())));
+            }
+        } else {
+            create_dir_all(path)?;
+        }
+    }
+
+    // Avatars and attachments would undo the pseudonymization, so they're left out entirely
+    let download_avatars = download_avatars && !pseudonymize;
+    let download_media = download_media && !pseudonymize;
+    let mut pseudonymizer = pseudonymize.then(Pseudonymizer::new);
+    // The manifests get picked up from the bucket, so that this export adds to them rather than replacing them with ones covering itself alone
+    if let ExportDestination::ObjectStorage(object_storage, _) = &destination {
+        let staging_path = destination.directory();
+        create_dir_all(staging_path.join("media"))?;
+        for path in [String::from(EXPORT_MANIFEST_FILENAME), format!("media/{}", MEDIA_MANIFEST_FILENAME)] {
+            if let Some(manifest) = object_storage.get_object(&path).await? {
+                write(staging_path.join(&path), manifest)?;
+            }
+        }
+    }
+
+    if offline && (peek || admin || incremental_checkpoints.is_some() || read_receipts || room_data || download_avatars || download_media || key_request_wait.is_some()) {
+        return Err(Error::InvalidExportOptions(String::from("Offline exports can't peek into rooms, use the admin API, be incremental, include read receipts or room data, download avatars or media, or request room keys, since those all need the homeserver.")));
+    }
+    if admin && !is_server_admin(client).await? {
+        return Err(Error::InvalidExportOptions(format!("{} isn't a server admin, so can't export through the Synapse admin API.", client.user_id().map(UserId::as_str).unwrap_or("This account"))));
+    }
+    let accessible_rooms_info = get_rooms_info(client).await?; // This should be possible to optimize out for request-piles without names included, given client.resolve_room_alias and client.get_room. Although that might end up actually costlier if handled indelicately, since it'll involve more serial processing.
+
+    // Rooms which don't resolve get left out of the export, with why recorded for the caller, rather than failing the whole export
+    let mut room_indices_to_export = Vec::new();
+    let mut unjoined_rooms = Vec::new(); // Peeked into, or fetched through the admin API
+    let mut room_resolutions = Vec::new();
+    for room_identifier in rooms {
+        let room_indices = match get_room_index_by_identifier(&accessible_rooms_info, &room_identifier) {
+            Ok(index) => vec![index],
+            Err(RoomIndexRetrievalError::NoRoomsWithSpecifiedName) if is_glob(&room_identifier) => get_room_indices_by_pattern(&accessible_rooms_info, &glob_to_regex(&room_identifier)),
+            Err(RoomIndexRetrievalError::NoRoomsWithSpecifiedName) if (peek || admin) && (room_identifier.starts_with('#') || room_identifier.starts_with('!')) => {
+                let result = match resolve_unjoined_room(client, &room_identifier, admin).await {
+                    Ok((room_id, alias, admin_room_details)) => {
+                        unjoined_rooms.push((room_id.clone(), alias, admin_room_details));
+                        Ok(vec![room_id])
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                room_resolutions.push(RoomResolution {
+                    identifier: room_identifier,
+                    result,
+                });
+                continue
+            }
+            Err(e) => {
+                room_resolutions.push(RoomResolution {
+                    result: Err(e.into_error(client, &room_identifier)),
+                    identifier: room_identifier,
+                });
+                continue
+            }
+        };
+        room_resolutions.push(room_indices_to_resolution(client, &accessible_rooms_info, room_identifier, &room_indices));
+        room_indices_to_export.extend(room_indices);
+    }
+    for dm_user in dm_users {
+        let room_indices = get_dm_room_indices(&accessible_rooms_info, &dm_user);
+        room_resolutions.push(room_indices_to_resolution(client, &accessible_rooms_info, format!("direct messages with {}", dm_user), &room_indices));
+        room_indices_to_export.extend(room_indices);
+    }
+    for room_pattern in room_patterns {
+        let room_indices = get_room_indices_by_pattern(&accessible_rooms_info, &room_pattern);
+        room_resolutions.push(room_indices_to_resolution(client, &accessible_rooms_info, format!("regex {}", room_pattern), &room_indices));
+        room_indices_to_export.extend(room_indices);
+    }
+    let mut seen_room_indices = HashSet::new();
+    room_indices_to_export.retain(|index| seen_room_indices.insert(*index));
+    // Incremental exports (i.e. ones given checkpoints) have to run forward through everything, or their checkpoints would skip over whatever got left out
+    if incremental_checkpoints.is_some() && (pagination_options.newest_first || pagination_options.limit.is_some() || event_range.from.is_some() || event_range.to.is_some()) {
+        return Err(Error::InvalidExportOptions(String::from("Incremental exports can't be combined with event ranges, event limits, or newest-first pagination.")));
+    }
+    if streaming && split_mode.is_some() {
+        return Err(Error::InvalidExportOptions(String::from("Streamed exports can't be split.")));
+    }
+    // Followed exports append new events to the end of each room's output, which only makes sense if everything before them runs forward to the present
+    if follow && (offline || split_mode.is_some() || pagination_options.newest_first || event_range.to.is_some() || matches!(destination, ExportDestination::ObjectStorage(..))) {
+        return Err(Error::InvalidExportOptions(String::from("Followed exports can't be offline, split, newest-first, uploaded to object storage, or end at a given event.")));
+    }
+    if matches!(destination, ExportDestination::Stdout) {
+        if room_indices_to_export.len() + unjoined_rooms.len() > 1 {
+            return Err(Error::InvalidExportOptions(format!("Can only export a single room at a time to stdout, but found {} matching rooms.", room_indices_to_export.len() + unjoined_rooms.len())));
+        }
+        if formats.len() > 1 {
+            return Err(Error::InvalidExportOptions(String::from("Can only export a single format at a time to stdout.")));
+        }
+    }
+
+    // Predecessor rooms' info gets gathered up front, so that the export units built from it can all borrow from it at once
+    let predecessor_rooms_info = room_indices_to_export.iter().map(|room_index| match follow_upgrades {
+        Some(_) => get_predecessor_rooms_info(client, &accessible_rooms_info[*room_index], progress),
+        None => Vec::new(),
+    }).collect::<Vec<Vec<RoomWithCachedInfo>>>();
+
+    // Filenames get pseudonymized before any events have been seen, so the pseudonymizer learns the display names of everyone in the rooms up front
+    if let Some(pseudonymizer) = pseudonymizer.as_mut() {
+        for room_info in room_indices_to_export.iter().map(|room_index| &accessible_rooms_info[*room_index]).chain(predecessor_rooms_info.iter().flatten()) {
+            for room_member in room_info.room.members_no_sync(RoomMemberships::empty()).await? {
+                if let Some(display_name) = room_member.display_name() {
+                    pseudonymizer.learn_display_name(display_name, room_member.user_id());
+                }
+            }
+        }
+    }
+
+    let mut export_units = Vec::new();
+    for (room_index, predecessor_rooms_info) in room_indices_to_export.iter().zip(&predecessor_rooms_info) {
+        let room_to_export_info = &accessible_rooms_info[*room_index];
+
+        // Each entry here gets written out as its own set of files, from the rooms listed in the order they're to be paginated. Merging collapses the whole upgrade chain into a single entry named after its newest room.
+        let upgrade_chain = predecessor_rooms_info.iter().rev().chain(once(room_to_export_info)).collect::<Vec<&RoomWithCachedInfo>>();
+        let upgrade_chain_entries = match follow_upgrades {
+            Some(UpgradeChainMode::Merged) => {
+                let mut merged_rooms_info = upgrade_chain;
+                if pagination_options.newest_first {
+                    merged_rooms_info.reverse();
+                }
+                vec![(room_to_export_info, merged_rooms_info)]
+            }
+            _ => upgrade_chain.into_iter().map(|room_info| (room_info, vec![room_info])).collect(),
+        };
+
+        for (room_info, rooms_to_paginate) in upgrade_chain_entries {
+            export_units.push(ExportUnit {
+                room_id: room_info.id.clone(),
+                room_info: Some(room_info),
+                peeked_alias: None,
+                admin_room_details: None,
+                read_receipts: None,
+                room_data: None,
+                filename: format_export_filename(room_info, name_template.as_ref(), pseudonymizer.as_mut()),
+                event_pagers: rooms_to_paginate.iter().map(|room_to_paginate| EventPager::new(match (offline, admin) {
+                    (true, _) => EventSource::Cached(&room_to_paginate.room),
+                    (false, true) => EventSource::Admin(client, &room_to_paginate.id, Some(&room_to_paginate.room)),
+                    (false, false) => EventSource::Joined(&room_to_paginate.room),
+                }, &event_range, &pagination_options)).collect(),
+                is_delta: false,
+            });
+        }
+    }
+    for (room_id, alias, admin_room_details) in &unjoined_rooms {
+        // The admin API knows rooms' names and aliases even without anyone on the server in them, which is more than peeking has to go on
+        let (name, canonical_alias, event_source) = match admin_room_details {
+            Some(admin_room_details) => (admin_room_details.name.as_deref(), admin_room_details.canonical_alias.as_deref().or(alias.as_deref()), EventSource::Admin(client, room_id, None)),
+            None => (None, alias.as_deref(), EventSource::Peeked(client, room_id)),
+        };
+        export_units.push(ExportUnit {
+            room_id: room_id.clone(),
+            room_info: None,
+            peeked_alias: alias.clone(),
+            filename: sanitize_filename(&format_export_filename_from_parts(room_id, name, canonical_alias, name_template.as_ref(), pseudonymizer.as_mut())),
+            admin_room_details: admin_room_details.clone(),
+            read_receipts: None, // Receipts only come through syncs, which only cover joined rooms
+            room_data: None,
+            event_pagers: vec![EventPager::new(event_source, &event_range, &pagination_options)],
+            is_delta: false,
+        });
+    }
+    if read_receipts {
+        let joined_room_ids = export_units.iter().filter(|export_unit| export_unit.room_info.is_some()).flat_map(|export_unit| export_unit.event_pagers.iter().map(|event_pager| event_pager.room_id().to_owned())).collect();
+        let mut read_receipts_by_room = get_read_receipts(client, joined_room_ids).await?;
+        for export_unit in export_units.iter_mut().filter(|export_unit| export_unit.room_info.is_some()) {
+            let mut unit_read_receipts = RoomReadReceipts::default();
+            for event_pager in &export_unit.event_pagers {
+                let Some(room_read_receipts) = read_receipts_by_room.remove(event_pager.room_id()) else {
+                    continue
+                };
+                unit_read_receipts.receipts_by_event.extend(room_read_receipts.receipts_by_event);
+                if *event_pager.room_id() == *export_unit.room_id {
+                    unit_read_receipts.read_marker = room_read_receipts.read_marker; // Older rooms' markers in merged upgrade chains are left stranded by the upgrade
+                }
+            }
+            export_unit.read_receipts = Some(unit_read_receipts);
+        }
+    }
+    if room_data {
+        let joined_room_ids = export_units.iter().filter(|export_unit| export_unit.room_info.is_some()).map(|export_unit| export_unit.room_id.clone()).collect();
+        let mut account_data_by_room = get_room_account_data(client, joined_room_ids).await?;
+        for export_unit in &mut export_units {
+            let Some(room_info) = export_unit.room_info else {
+                continue
+            };
+            export_unit.room_data = Some(RoomData {
+                pinned_events: get_pinned_events(&room_info.room, pagination_options.max_retries).await,
+                account_data: account_data_by_room.remove(&export_unit.room_id).unwrap_or_default(),
+            });
+        }
+    }
+    let mut room_outcomes = Vec::new();
+    let mut followed_rooms = Vec::new();
+    let mut used_filenames = HashSet::new();
+    for export_unit in &mut export_units {
+        export_unit.is_delta = resume_event_pagers(&mut export_unit.event_pagers, incremental_checkpoints.as_ref());
+        spool_event_pagers(&mut export_unit.event_pagers, resume_dir.as_deref(), &event_range, &pagination_options)?;
+        export_unit.filename = disambiguate_filename(delta_filename(std::mem::take(&mut export_unit.filename), export_unit.is_delta), &mut used_filenames);
+    }
+
+    info!(room_count = export_units.len(), "Exporting rooms");
+
+    // Keys forwarded in response to key requests only arrive through syncs, so one runs alongside the export for as long as it goes on
+    let key_request_sync_stop = CancellationToken::new();
+    let _key_request_sync_guard = key_request_sync_stop.clone().drop_guard();
+    if key_request_wait.is_some() {
+        let client = client.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = client.sync(SyncSettings::new().set_presence(PresenceState::Offline)) => (),
+                _ = key_request_sync_stop.cancelled() => (),
+            }
+        });
+    }
+
+    let mut context = ExportContext {
+        client,
+        destination: &destination,
+        formats: &formats,
+        download_avatars,
+        download_media,
+        max_retries: pagination_options.max_retries,
+        split_mode,
+        event_type_filter: &event_type_filter,
+        content_filter: content_filter.as_ref(),
+        key_request_wait,
+        pseudonymizer: pseudonymizer.as_mut(),
+        progress,
+        cancellation: &cancellation,
+        json_options: &json_options,
+        txt_options: &txt_options,
+    };
+    if streaming {
+        // Streamed exports write as they fetch, which doesn't leave any fetching to do in the background, so they run one room at a time
+        for mut export_unit in export_units {
+            if cancellation.is_cancelled() {
+                break
+            }
+            progress(ExportProgress::RoomStarted {
+                room_id: export_unit.room_id.clone(),
+            });
+            let mut sender_profiles = cached_sender_profiles(profile_cache.as_ref(), &export_unit.room_id);
+            let mut seen_event_ids = HashSet::new();
+            let room_span = info_span!("room", room_id = %export_unit.room_id);
+            let written_export = async {
+                if let (Some(room_info), false) = (export_unit.room_info, offline) {
+                    sync_room_members(room_info, pagination_options.max_retries).await?;
+                }
+                let mut room_metadata = collect_room_metadata(client, &export_unit.room_id, export_unit.room_info, export_unit.peeked_alias.as_deref(), export_unit.admin_room_details.as_ref(), &[]);
+                room_metadata.read_receipts = export_unit.read_receipts.clone();
+                room_metadata.room_data = export_unit.room_data.clone();
+                stream_room_export(&mut context, room_metadata, export_unit.room_info, &mut export_unit.event_pagers, &mut seen_event_ids, &export_unit.filename, &mut sender_profiles).await
+            }.instrument(room_span).await;
+            let written_export = match written_export {
+                Ok(written_export) => written_export,
+                Err(e) => {
+                    room_outcomes.push(failed_room_outcome(export_unit, e, progress));
+                    continue
+                }
+            };
+            store_sender_profiles(profile_cache.as_mut(), &export_unit, sender_profiles)?;
+            progress(ExportProgress::BytesWritten {
+                room_id: export_unit.room_id.clone(),
+                byte_count: written_export.byte_count,
+            });
+            finish_event_pagers(&mut export_unit.event_pagers, incremental_checkpoints.as_mut(), cancellation.is_cancelled())?;
+            progress(ExportProgress::RoomFinished {
+                room_id: export_unit.room_id.clone(),
+            });
+            if follow {
+                followed_rooms.extend(followed_room(&export_unit, seen_event_ids, profile_cache.as_ref()));
+            }
+            room_outcomes.push(room_outcome(export_unit, RoomExportStatus::Exported {
+                byte_count: written_export.byte_count,
+                event_count: written_export.event_count,
+                files: written_export.files,
+            }));
+        }
+    } else {
+        // Up to `jobs` rooms get fetched at once, with each one written out as soon as it's fetched
+        let event_type_filter = &event_type_filter;
+        let content_filter = content_filter.as_ref();
+        let cancellation = &cancellation;
+        let newest_first = pagination_options.newest_first;
+        let max_retries = pagination_options.max_retries;
+        let mut fetched_export_units = stream::iter(export_units).map(|mut export_unit| async move {
+            if cancellation.is_cancelled() {
+                return (export_unit, Ok(Vec::new()))
+            }
+            progress(ExportProgress::RoomStarted {
+                room_id: export_unit.room_id.clone(),
+            });
+            let room_span = info_span!("room", room_id = %export_unit.room_id);
+            let events = async {
+                if let (Some(room_info), false) = (export_unit.room_info, offline) {
+                    sync_room_members(room_info, max_retries).await?;
+                }
+                let mut events = Vec::new();
+                for event_pager in &mut export_unit.event_pagers {
+                    let mut pager_events = collect_event_pages(event_pager, &export_unit.room_id, progress, cancellation).await?;
+                    if let (Some(key_request_wait), EventSource::Joined(room) | EventSource::Admin(_, _, Some(room))) = (key_request_wait, event_pager.source) {
+                        retry_undecryptable_events(room, &mut pager_events, key_request_wait, progress, &export_unit.room_id).await?;
+                    }
+                    events.extend(filter_events(pager_events, event_type_filter, content_filter));
+                }
+                dedup_and_sort_events(&mut events, &mut HashSet::new(), newest_first);
+                anyhow::Result::<Vec<TimelineEvent>>::Ok(events)
+            }.instrument(room_span).await;
+            (export_unit, events)
+        }).buffer_unordered(jobs.max(1));
+        while let Some((mut export_unit, events)) = fetched_export_units.next().await {
+            let events = match events {
+                Ok(events) => events,
+                Err(e) => {
+                    room_outcomes.push(failed_room_outcome(export_unit, e, progress));
+                    continue
+                }
+            };
+            if cancellation.is_cancelled() && events.is_empty() {
+                continue // Never got started, or got cancelled before fetching anything
+            }
+            progress(ExportProgress::EventsProcessed {
+                room_id: export_unit.room_id.clone(),
+                event_count: events.len(),
+            });
+            let gaps = collect_event_pager_gaps(&export_unit.event_pagers, progress, &export_unit.room_id);
+            let status = if !(export_unit.is_delta && events.is_empty() && gaps.is_empty()) {
+                let mut room_metadata = collect_room_metadata(client, &export_unit.room_id, export_unit.room_info, export_unit.peeked_alias.as_deref(), export_unit.admin_room_details.as_ref(), &events);
+                room_metadata.gaps = gaps;
+                room_metadata.read_receipts = export_unit.read_receipts.clone();
+                room_metadata.room_data = export_unit.room_data.clone();
+                let mut sender_profiles = cached_sender_profiles(profile_cache.as_ref(), &export_unit.room_id);
+                let room_span = info_span!("room", room_id = %export_unit.room_id);
+                let written_export = match write_room_export(&mut context, &room_metadata, export_unit.room_info, &export_unit.filename, &events, &mut sender_profiles).instrument(room_span).await {
+                    Ok(written_export) => written_export,
+                    Err(e) => {
+                        room_outcomes.push(failed_room_outcome(export_unit, e, progress));
+                        continue
+                    }
+                };
+                store_sender_profiles(profile_cache.as_mut(), &export_unit, sender_profiles)?;
+                progress(ExportProgress::BytesWritten {
+                    room_id: export_unit.room_id.clone(),
+                    byte_count: written_export.byte_count,
+                });
+                RoomExportStatus::Exported {
+                    byte_count: written_export.byte_count,
+                    event_count: written_export.event_count,
+                    files: written_export.files,
+                }
+            } else {
+                RoomExportStatus::Skipped
+            };
+            finish_event_pagers(&mut export_unit.event_pagers, incremental_checkpoints.as_mut(), cancellation.is_cancelled())?;
+            progress(ExportProgress::RoomFinished {
+                room_id: export_unit.room_id.clone(),
+            });
+            if follow {
+                let seen_event_ids = events.iter().filter_map(|event| event.event_id()).collect();
+                followed_rooms.extend(followed_room(&export_unit, seen_event_ids, profile_cache.as_ref()));
+            }
+            room_outcomes.push(room_outcome(export_unit, status));
+        }
+    }
+
+    // Everything's been uploaded by now, so only the manifests are left behind in temporary staging directories
+    if let ExportDestination::ObjectStorage(_, None) = &destination {
+        remove_dir_all(destination.directory())?;
+    }
+    if cancellation.is_cancelled() {
+        return Err(Error::ExportCancelled);
+    }
+    // Cancelling is the only way for following to stop, so it doesn't count against the export once following's begun
+    if follow {
+        key_request_sync_stop.cancel(); // Following's own syncs bring in forwarded keys just the same
+        follow_export(&mut context, followed_rooms).await?;
+    }
+
+    Ok(ExportReport {
+        exported_room_count: room_outcomes.iter().filter(|room_outcome| !matches!(room_outcome.status, RoomExportStatus::Failed(_))).count(),
+        room_resolutions,
+        room_outcomes,
+    })
+}
+
+///////////////
+//   Tests   //
+///////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room_id() -> OwnedRoomId {
+        RoomId::parse("!abcdef:example.org").unwrap()
+    }
+
+    #[test]
+    fn renders_name_templates_with_fallbacks() {
+        let template = NameTemplate::parse("{alias|id}-{date}").unwrap();
+        assert_eq!(template.render(&room_id(), Some("Room"), Some("#room"), "2026-01-02"), "#room-2026-01-02");
+        assert_eq!(template.render(&room_id(), Some("Room"), None, "2026-01-02"), "!abcdef-2026-01-02");
+
+        let template = NameTemplate::parse("{ name | alias } on {server}").unwrap();
+        assert_eq!(template.render(&room_id(), None, None, "2026-01-02"), " on example.org");
+        assert_eq!(NameTemplate::parse("export").unwrap().render(&room_id(), None, None, "2026-01-02"), "export");
+    }
+
+    #[test]
+    fn rejects_invalid_name_templates() {
+        assert!(matches!(NameTemplate::parse("{alias"), Err(Error::InvalidExportOptions(_))));
+        assert!(matches!(NameTemplate::parse("{alias-{date}"), Err(Error::InvalidExportOptions(_))));
+        assert!(matches!(NameTemplate::parse("{nickname}"), Err(Error::InvalidExportOptions(_))));
+        assert!(matches!(NameTemplate::parse("{name|}"), Err(Error::InvalidExportOptions(_))));
+    }
+
+    #[test]
+    fn formats_filenames_without_a_template() {
+        let alias = RoomAliasId::parse("#room:example.org").unwrap();
+        assert_eq!(format_export_filename_from_parts(&room_id(), Some("Room"), Some(&*alias), None, None), "Room [#room, !abcdef, example.org]");
+        assert_eq!(format_export_filename_from_parts(&room_id(), None, None, None, None), "!abcdef [example.org]");
+    }
+
+    #[test]
+    fn pseudonymizes_user_ids_in_order_of_appearance() {
+        let mut pseudonymizer = Pseudonymizer::new();
+        assert_eq!(pseudonymizer.pseudonymize_text("@alice:example.org asked @bob:example.org:8448 about @alice:example.org"), "User-01 asked User-02 about User-01");
+        assert_eq!(pseudonymizer.pseudonymize_user_id("@bob:example.org:8448"), "User-02");
+        assert_eq!(pseudonymizer.pseudonymize_user_id("not a user ID"), "not a user ID");
+    }
+
+    // Longer names go first, so 'Ann Lee' gets its pseudonym before 'Ann' does
+    #[test]
+    fn pseudonymizes_display_names_as_whole_words_only() {
+        let mut pseudonymizer = Pseudonymizer::new();
+        pseudonymizer.learn_display_name("Al", &UserId::parse("@al:example.org").unwrap());
+        pseudonymizer.learn_display_name("Ann", &UserId::parse("@ann:example.org").unwrap());
+        pseudonymizer.learn_display_name("Ann Lee", &UserId::parse("@annlee:example.org").unwrap());
+        assert_eq!(pseudonymizer.pseudonymize_text("Ann Lee told Al and Alice, but not Ann"), "User-01 told User-03 and Alice, but not User-02");
+    }
+
+    #[test]
+    fn pseudonymizes_json_keys_and_values() {
+        let mut pseudonymizer = Pseudonymizer::new();
+        let mut value = json!({
+            "sender": "@alice:example.org",
+            "event_id": "$abcdef",
+            "content": {
+                "body": "Hi, I'm @alice:example.org",
+                "url": "mxc://example.org/abcdef",
+            },
+            "sender_profiles": {
+                "@alice:example.org": {
+                    "display_name": "Alice",
+                },
+            },
+        });
+        pseudonymizer.pseudonymize_json(&mut value);
+        assert_eq!(value, json!({
+            "sender": "User-01",
+            "event_id": "$abcdef",
+            "content": {
+                "body": "Hi, I'm User-01",
+            },
+            "sender_profiles": {
+                "User-01": {
+                    "display_name": "Alice",
+                },
+            },
+        }));
+    }
 }
@@ -0,0 +1,166 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::{
+    Error,
+    ExportReport,
+    Result,
+    RoomExportStatus,
+};
+
+use matrix_sdk::ruma::OwnedRoomId;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    time::timeout,
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(300); // Longer than for webhooks, since commands may well do real work, like uploading the export somewhere
+
+///////////////
+//   Types   //
+///////////////
+
+// Somewhere to send word of how an export went, once it's over.
+pub enum ExportHook {
+    Webhook(String), // URL to POST the summary to, as JSON
+    Command(String), // Shell command to run, with the summary as JSON on its stdin
+}
+
+// What gets sent to hooks: an ExportReport boiled down to JSON, or the error the export failed with outright.
+#[derive(Serialize)]
+pub struct ExportSummary {
+    pub job: Option<String>, // Set for runs of daemon jobs, to tell them apart
+    pub succeeded: bool, // Only if every room was found and exported (or skipped)
+    pub finished_at_millis: i64,
+    pub exported_room_count: usize, // Including skipped rooms, as in ExportReport
+    pub failed_room_count: usize, // Including rooms which weren't found
+    pub event_count: usize,
+    pub byte_count: usize,
+    pub rooms: Vec<RoomSummary>,
+    pub unresolved_rooms: Vec<UnresolvedRoomSummary>,
+    pub error: Option<String>, // Only if the export failed as a whole, in which case there are no rooms to go on
+}
+
+#[derive(Serialize)]
+pub struct RoomSummary {
+    pub room_id: OwnedRoomId,
+    pub room_name: Option<String>,
+    pub status: &'static str, // 'exported', 'skipped', or 'failed'
+    pub event_count: usize,
+    pub byte_count: usize,
+    pub files: Vec<PathBuf>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UnresolvedRoomSummary {
+    pub identifier: String,
+    pub error: String,
+}
+
+impl ExportSummary {
+    pub fn from_report(export_report: &ExportReport) -> Self {
+        let rooms = export_report.room_outcomes.iter().map(|room_outcome| {
+            let (status, event_count, byte_count, files, error) = match &room_outcome.status {
+                RoomExportStatus::Exported { byte_count, event_count, files } => ("exported", *event_count, *byte_count, files.clone(), None),
+                RoomExportStatus::Skipped => ("skipped", 0, 0, Vec::new(), None),
+                RoomExportStatus::Failed(e) => ("failed", 0, 0, Vec::new(), Some(e.to_string())),
+            };
+            RoomSummary {
+                room_id: room_outcome.room_id.clone(),
+                room_name: room_outcome.room_name.clone(),
+                status,
+                event_count,
+                byte_count,
+                files,
+                error,
+            }
+        }).collect::<Vec<RoomSummary>>();
+        let unresolved_rooms = export_report.room_resolutions.iter().filter_map(|room_resolution| match &room_resolution.result {
+            Ok(_) => None,
+            Err(e) => Some(UnresolvedRoomSummary {
+                identifier: room_resolution.identifier.clone(),
+                error: e.to_string(),
+            }),
+        }).collect::<Vec<UnresolvedRoomSummary>>();
+        let failed_room_count = rooms.iter().filter(|room| room.status == "failed").count() + unresolved_rooms.len();
+
+        Self {
+            job: None,
+            succeeded: failed_room_count == 0,
+            finished_at_millis: chrono::Utc::now().timestamp_millis(),
+            exported_room_count: export_report.exported_room_count,
+            failed_room_count,
+            event_count: rooms.iter().map(|room| room.event_count).sum(),
+            byte_count: rooms.iter().map(|room| room.byte_count).sum(),
+            rooms,
+            unresolved_rooms,
+            error: None,
+        }
+    }
+
+    pub fn from_error(error: impl Display) -> Self {
+        Self {
+            job: None,
+            succeeded: false,
+            finished_at_millis: chrono::Utc::now().timestamp_millis(),
+            exported_room_count: 0,
+            failed_room_count: 0,
+            event_count: 0,
+            byte_count: 0,
+            rooms: Vec::new(),
+            unresolved_rooms: Vec::new(),
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Webhooks count as failed if they don't respond with a 2xx status within WEBHOOK_TIMEOUT, and commands if they exit with a nonzero one or are still running after COMMAND_TIMEOUT, at which point they're killed. Commands are free to ignore the summary rather than reading it.
+pub async fn run_export_hook(hook: &ExportHook, summary: &ExportSummary) -> Result<()> {
+    let payload = serde_json::to_string(summary)?;
+    match hook {
+        ExportHook::Webhook(url) => {
+            let client = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build().map_err(|e| Error::Other(e.into()))?;
+            let response = client.post(url).header(CONTENT_TYPE, "application/json").body(payload).send().await.map_err(|e| Error::Other(anyhow::anyhow!("Couldn't reach webhook {} due to error '{}'.", url, e)))?;
+            if !response.status().is_success() {
+                return Err(Error::Other(anyhow::anyhow!("Webhook {} responded with status {}.", url, response.status())))
+            }
+        }
+        ExportHook::Command(command) => {
+            let (shell, shell_flag) = match cfg!(windows) {
+                true => ("cmd", "/C"),
+                false => ("sh", "-c"),
+            };
+            let mut child = Command::new(shell).arg(shell_flag).arg(command).stdin(Stdio::piped()).spawn()?;
+            let stdin = child.stdin.take();
+            let finished = timeout(COMMAND_TIMEOUT, async {
+                if let Some(mut stdin) = stdin {
+                    let _ = stdin.write_all(payload.as_bytes()).await; // Fails only if the command exits (or closes stdin) without reading it all, which is up to it
+                } // Dropped here, so the command sees the end of its input
+                child.wait().await
+            }).await;
+            let status = match finished {
+                Ok(status) => status?,
+                Err(_) => {
+                    let _ = child.kill().await; // Fails only if it's exited in the meantime
+                    return Err(Error::Other(anyhow::anyhow!("Hook command '{}' didn't finish within {} seconds, so was killed.", command, COMMAND_TIMEOUT.as_secs())))
+                }
+            };
+            if !status.success() {
+                return Err(Error::Other(anyhow::anyhow!("Hook command '{}' exited with {}.", command, status)))
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::fs::{
+    read,
+    read_to_string,
+};
+use std::path::Path;
+
+use crate::{
+    export::{
+        format_timestamp_as,
+        get_room_index_by_identifier,
+        ExportTimezone,
+    },
+    get_rooms_info,
+    media::{
+        media_file_to_filename,
+        sha256_hex,
+    },
+    retry::retry_rate_limited,
+    verify::parse_export_events,
+    Error,
+    Result,
+};
+
+use matrix_sdk::{
+    ruma::{
+        api::client::{
+            media::create_content::v3::Request as UploadRequest,
+            message::send_message_event::v3::Request as SendRequest,
+            room::get_room_event::v3::Request as GetEventRequest,
+        },
+        events::MessageLikeEventType,
+        serde::Raw,
+        EventId,
+        MilliSecondsSinceUnixEpoch,
+        OwnedEventId,
+        OwnedRoomId,
+        OwnedTransactionId,
+        RoomId,
+        UInt,
+    },
+    Client,
+};
+use tracing::warn;
+
+const TEXT_MSGTYPES: [&str; 3] = ["m.text", "m.notice", "m.emote"];
+const MEDIA_MSGTYPES: [&str; 4] = ["m.image", "m.file", "m.audio", "m.video"];
+
+///////////////
+//   Types   //
+///////////////
+
+pub struct ImportOptions {
+    pub massage_timestamps: bool, // Ask the homeserver to give each event its original timestamp, rather than putting it into the message text, if it turns out to go along with it
+    pub after_event_id: Option<String>, // Start after this event from the export, as when picking an interrupted import back up
+    pub timezone: ExportTimezone, // For timestamps put into message text
+    pub max_retries: u32,
+}
+
+pub struct ImportProgress {
+    pub posted_count: usize,
+    pub total_count: usize, // Of events to post, leaving out skipped ones
+}
+
+pub struct ImportReport {
+    pub room_id: OwnedRoomId,
+    pub posted_count: usize,
+    pub skipped_counts: BTreeMap<&'static str, usize>, // Keyed by why they were skipped
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn event_id(event: &serde_json::Value) -> Option<&str> {
+    event.get("event_id").and_then(|event_id| event_id.as_str())
+}
+
+fn origin_server_ts(event: &serde_json::Value) -> Option<i64> {
+    event.get("origin_server_ts").and_then(|timestamp| timestamp.as_i64())
+}
+
+// Maps the IDs of edited messages to their latest edits' new content. As in the export itself, edits from anyone other than the original message's sender are invalid, and left out.
+fn collect_edits(events: &[serde_json::Value]) -> HashMap<String, serde_json::Value> {
+    let senders = events.iter().filter_map(|event| Some((event_id(event)?, event.get("sender")?))).collect::<HashMap<&str, &serde_json::Value>>();
+    let mut edits = HashMap::new();
+    for event in events {
+        let Some(relates_to) = event.pointer("/content/m.relates_to") else {
+            continue
+        };
+        let (Some("m.replace"), Some(edited_event_id), Some(new_content)) = (relates_to.get("rel_type").and_then(|rel_type| rel_type.as_str()), relates_to.get("event_id").and_then(|event_id| event_id.as_str()), event.pointer("/content/m.new_content")) else {
+            continue
+        };
+        if senders.get(edited_event_id) == event.get("sender").as_ref() {
+            edits.insert(String::from(edited_event_id), new_content.clone()); // Events are in timeline order, so later edits win
+        }
+    }
+    edits
+}
+
+// The content to repost the event with, edits applied, or why it's being skipped.
+fn importable_content(event: &serde_json::Value, edits: &HashMap<String, serde_json::Value>) -> std::result::Result<serde_json::Value, &'static str> {
+    match event.get("type").and_then(|event_type| event_type.as_str()) {
+        _ if event.get("state_key").is_some() => return Err("state events"),
+        Some("m.room.message") => (),
+        Some("m.room.encrypted") => return Err("undecryptable messages"),
+        _ => return Err("other event types"),
+    }
+    let Some(mut content) = event.get("content").filter(|content| content.get("msgtype").is_some()).cloned() else {
+        return Err("redacted messages")
+    };
+    if content.pointer("/m.relates_to/rel_type").and_then(|rel_type| rel_type.as_str()) == Some("m.replace") {
+        return Err("edits (folded into the messages they edit)")
+    }
+    if let Some(new_content) = event_id(event).and_then(|event_id| edits.get(event_id)) {
+        let relates_to = content.get("m.relates_to").cloned();
+        content = new_content.clone();
+        if let (Some(relates_to), Some(content_object)) = (relates_to, content.as_object_mut()) {
+            content_object.insert(String::from("m.relates_to"), relates_to); // New content leaves out the original's relations
+        }
+    }
+    Ok(content)
+}
+
+// Points replies and thread relations at the reposted events they refer to. Relations to events which weren't reposted are dropped, as they'd point into a room that doesn't have them.
+fn relink_relations(content: &mut serde_json::Value, reposted_event_ids: &HashMap<String, OwnedEventId>) {
+    let Some(relates_to) = content.get_mut("m.relates_to") else {
+        return
+    };
+    if let Some(in_reply_to) = relates_to.get_mut("m.in_reply_to") {
+        match in_reply_to.get("event_id").and_then(|event_id| event_id.as_str()).and_then(|event_id| reposted_event_ids.get(event_id)) {
+            Some(reposted_event_id) => in_reply_to["event_id"] = serde_json::Value::String(reposted_event_id.to_string()),
+            None => *in_reply_to = serde_json::Value::Null,
+        }
+    }
+    if let Some(relates_to_object) = relates_to.as_object_mut() {
+        relates_to_object.retain(|_, value| !value.is_null());
+    }
+    if relates_to.get("rel_type").is_some() {
+        match relates_to.get("event_id").and_then(|event_id| event_id.as_str()).and_then(|event_id| reposted_event_ids.get(event_id)) {
+            Some(reposted_event_id) => relates_to["event_id"] = serde_json::Value::String(reposted_event_id.to_string()),
+            None => {
+                // Only the reply's left, if that
+                let in_reply_to = relates_to.get("m.in_reply_to").cloned();
+                *relates_to = serde_json::json!({});
+                if let Some(in_reply_to) = in_reply_to {
+                    relates_to["m.in_reply_to"] = in_reply_to;
+                }
+            }
+        }
+    }
+    if relates_to.as_object().is_some_and(|relates_to_object| relates_to_object.is_empty()) {
+        if let Some(content_object) = content.as_object_mut() {
+            content_object.remove("m.relates_to");
+        }
+    }
+}
+
+// Attachments get reuploaded from the export's media directory, if they were downloaded into it, since their original URLs point at the homeserver they came from. Those which weren't get replaced with a notice saying so.
+async fn reupload_media(client: &Client, event: &serde_json::Value, content: &mut serde_json::Value, export_dir: &Path, max_retries: u32) -> Result<()> {
+    if !content.get("msgtype").and_then(|msgtype| msgtype.as_str()).is_some_and(|msgtype| MEDIA_MSGTYPES.contains(&msgtype)) {
+        return Ok(())
+    }
+    let media_path = event.get("media_file").and_then(|media_file| media_file.as_str()).and_then(media_file_to_filename).map(|media_filename| export_dir.join("media").join(media_filename)).filter(|media_path| media_path.is_file());
+    let Some(media_path) = media_path else {
+        let filename = content.get("filename").or_else(|| content.get("body")).and_then(|filename| filename.as_str()).unwrap_or("unnamed");
+        *content = serde_json::json!({
+            "msgtype": "m.notice",
+            "body": format!("[Attachment {} wasn't included in the export]", filename),
+        });
+        return Ok(())
+    };
+
+    let mut request = UploadRequest::new(read(&media_path)?);
+    request.content_type = content.pointer("/info/mimetype").and_then(|mimetype| mimetype.as_str()).map(String::from);
+    request.filename = content.get("filename").or_else(|| content.get("body")).and_then(|filename| filename.as_str()).map(String::from);
+    let response = retry_rate_limited(max_retries, || async { Ok(client.send(request.clone()).await?) }).await?;
+    let Some(content_object) = content.as_object_mut() else {
+        return Ok(())
+    };
+    content_object.remove("file"); // Encrypted attachments are stored decrypted, so they go back up unencrypted
+    content_object.insert(String::from("url"), serde_json::Value::String(response.content_uri.to_string()));
+    if let Some(info) = content_object.get_mut("info").and_then(|info| info.as_object_mut()) {
+        info.remove("thumbnail_url");
+        info.remove("thumbnail_file");
+        info.remove("thumbnail_info");
+    }
+
+    Ok(())
+}
+
+// Homeservers quietly ignore requested timestamps from anyone but appservices, so whether one took can only be told from the event as posted.
+async fn posted_timestamp_millis(client: &Client, room_id: &RoomId, event_id: &EventId, max_retries: u32) -> Result<Option<i64>> {
+    let request = GetEventRequest::new(room_id.to_owned(), event_id.to_owned());
+    let response = retry_rate_limited(max_retries, || async { Ok(client.send(request.clone()).await?) }).await?;
+
+    Ok(response.event.get_field::<i64>("origin_server_ts")?)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// Text messages get the annotation put in front of them, and attachments get it as their caption, with their filename kept separately as captioned attachments do.
+fn annotate(content: &mut serde_json::Value, annotation: &str) {
+    let msgtype = content.get("msgtype").and_then(|msgtype| msgtype.as_str()).unwrap_or_default().to_owned();
+    let body = content.get("body").and_then(|body| body.as_str()).unwrap_or_default().to_owned();
+    let Some(content_object) = content.as_object_mut() else {
+        return
+    };
+    if TEXT_MSGTYPES.contains(&msgtype.as_str()) {
+        content_object.insert(String::from("body"), serde_json::Value::String(format!("{} {}", annotation, body)));
+        if let Some(formatted_body) = content_object.get("formatted_body").and_then(|formatted_body| formatted_body.as_str()).map(String::from) {
+            content_object.insert(String::from("formatted_body"), serde_json::Value::String(format!("<strong>{}</strong> {}", escape_html(annotation), formatted_body)));
+        }
+    } else if MEDIA_MSGTYPES.contains(&msgtype.as_str()) {
+        let filename = content_object.get("filename").and_then(|filename| filename.as_str()).map(String::from);
+        let caption = match filename {
+            Some(filename) if filename != body => format!("{} {}", annotation, body),
+            _ => {
+                content_object.insert(String::from("filename"), serde_json::Value::String(body));
+                String::from(annotation)
+            }
+        };
+        content_object.insert(String::from("body"), serde_json::Value::String(caption));
+        content_object.remove("format");
+        content_object.remove("formatted_body");
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Reposts the messages from a Trace JSON or JSON lines export into a joined room, oldest first. Everything gets posted by the importing account, so each message is annotated with who sent it originally, and when. With timestamp massaging, the homeserver is asked to backdate each event to its original timestamp, but only appservices are allowed to do that; homeservers mostly ignore it from anyone else. So the time still goes into the annotation until the first event posted shows the homeserver going along with it, and for good if it doesn't. Replies and threads get pointed at the reposted events, edits get folded into the messages they edit, and attachments get reuploaded from the export's media directory. State events, reactions, and redacted and undecryptable messages get skipped.
+pub async fn import(client: &Client, export_path: &Path, target_room: &str, options: &ImportOptions, progress: &dyn Fn(ImportProgress)) -> Result<ImportReport> {
+    let accessible_rooms_info = get_rooms_info(client).await?;
+    let room_info = match get_room_index_by_identifier(&accessible_rooms_info, target_room) {
+        Ok(room_index) => &accessible_rooms_info[room_index],
+        Err(e) => return Err(e.into_error(client, target_room)),
+    };
+    // Sending through the SDK's room handling would encrypt events, but it can't massage timestamps
+    if room_info.is_encrypted {
+        return Err(Error::InvalidImport(format!("Room {} is encrypted, which importing into isn't supported. Import into an unencrypted room instead, and turn on encryption afterwards if need be.", room_info.id)));
+    }
+
+    let filename = export_path.file_name().and_then(|filename| filename.to_str()).unwrap_or_default();
+    let (mut events, export) = parse_export_events(filename, &read_to_string(export_path)?).map_err(|e| Error::InvalidImport(format!("Couldn't read export {}: {}.", export_path.display(), e)))?;
+    events.sort_by_key(|event| origin_server_ts(event).unwrap_or_default()); // Exports can run newest-first
+    let edits = collect_edits(&events);
+    if let Some(after_event_id) = &options.after_event_id {
+        let Some(after_index) = events.iter().position(|event| event_id(event) == Some(after_event_id.as_str())) else {
+            return Err(Error::InvalidImport(format!("Couldn't find event {} in export {}.", after_event_id, export_path.display())))
+        };
+        events.drain(..=after_index);
+    }
+
+    let mut skipped_counts = BTreeMap::new();
+    let mut events_to_post = Vec::new();
+    for event in &events {
+        match importable_content(event, &edits) {
+            Ok(content) => events_to_post.push((event, content)),
+            Err(reason) => *skipped_counts.entry(reason).or_default() += 1,
+        }
+    }
+
+    let export_dir = export_path.parent().unwrap_or(Path::new(""));
+    let total_count = events_to_post.len();
+    let mut reposted_event_ids = HashMap::new();
+    let mut last_posted_event_id = None;
+    let mut timestamps_honored = match options.massage_timestamps {
+        true => None, // Not known until something's been posted
+        false => Some(false),
+    };
+    for (posted_count, (event, mut content)) in events_to_post.into_iter().enumerate() {
+        let original_event_id = event_id(event).unwrap_or_default();
+        let sender = event.get("sender").and_then(|sender| sender.as_str()).unwrap_or("unknown sender");
+        let sender_name = match export.pointer(&format!("/senders/{}/display_name", sender.replace('~', "~0").replace('/', "~1"))).and_then(|display_name| display_name.as_str()) {
+            Some(display_name) => format!("{} ({})", display_name, sender),
+            None => String::from(sender),
+        };
+        let annotation = match (timestamps_honored, origin_server_ts(event)) {
+            (Some(true), _) | (_, None) => format!("{}:", sender_name),
+            (_, Some(timestamp_millis)) => format!("[{}] {}:", format_timestamp_as(timestamp_millis, &options.timezone, None), sender_name),
+        };
+
+        let posted = async {
+            relink_relations(&mut content, &reposted_event_ids);
+            reupload_media(client, event, &mut content, export_dir, options.max_retries).await?;
+            annotate(&mut content, &annotation);
+            // Transaction IDs come from the original event, so that retried requests don't post anything twice
+            let transaction_id = OwnedTransactionId::from(format!("trace-import-{}", sha256_hex(format!("{} {}", room_info.id, original_event_id).as_bytes())));
+            let mut request = SendRequest::new_raw(room_info.id.clone(), transaction_id, MessageLikeEventType::RoomMessage, Raw::from_json(serde_json::value::to_raw_value(&content)?));
+            if options.massage_timestamps {
+                request.timestamp = origin_server_ts(event).and_then(|timestamp_millis| UInt::new(timestamp_millis.try_into().ok()?)).map(MilliSecondsSinceUnixEpoch);
+            }
+            Ok::<OwnedEventId, Error>(retry_rate_limited(options.max_retries, || async { Ok(client.send(request.clone()).await?) }).await?.event_id)
+        }.await;
+        let reposted_event_id = match posted {
+            Ok(reposted_event_id) => reposted_event_id,
+            Err(e) => return Err(Error::Other(anyhow::anyhow!("Couldn't post event {} due to error '{}'. {} events were posted before it{}.", original_event_id, e, posted_count, match &last_posted_event_id {
+                Some(last_posted_event_id) => format!(", so the import can be picked back up after {}", last_posted_event_id),
+                None => String::new(),
+            }))),
+        };
+        if let (None, Some(timestamp_millis)) = (timestamps_honored, origin_server_ts(event)) {
+            let honored = matches!(posted_timestamp_millis(client, &room_info.id, &reposted_event_id, options.max_retries).await, Ok(Some(posted_timestamp_millis)) if posted_timestamp_millis == timestamp_millis);
+            if !honored {
+                warn!("Homeserver didn't give the imported events their original timestamps, as it only does for appservices. Putting them into the message text instead.");
+            }
+            timestamps_honored = Some(honored);
+        }
+        reposted_event_ids.insert(String::from(original_event_id), reposted_event_id);
+        last_posted_event_id = Some(original_event_id);
+        progress(ImportProgress {
+            posted_count: posted_count + 1,
+            total_count,
+        });
+    }
+
+    Ok(ImportReport {
+        room_id: room_info.id.clone(),
+        posted_count: total_count,
+        skipped_counts,
+    })
+}
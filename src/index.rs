@@ -0,0 +1,263 @@
+use std::fs::{
+    create_dir_all,
+    read_dir,
+    read_to_string,
+};
+use std::ops::Bound;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use crate::{
+    verify::EXPORT_MANIFEST_FILENAME,
+    Error,
+    Result,
+};
+
+use serde::Serialize;
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    doc,
+    query::{
+        BooleanQuery,
+        Occur,
+        Query,
+        QueryParser,
+        RangeQuery,
+        TermQuery,
+    },
+    schema::{
+        Field,
+        IndexRecordOption,
+        Schema,
+        Value,
+        FAST,
+        INDEXED,
+        STORED,
+        STRING,
+        TEXT,
+    },
+    Index,
+    IndexWriter,
+    TantivyDocument,
+    Term,
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+const INDEX_WRITER_MEMORY_BUDGET: usize = 50_000_000; // In bytes; tantivy's own examples use this much, and it's plenty for message bodies
+
+///////////////
+//   Types   //
+///////////////
+
+struct IndexFields {
+    event_id: Field,
+    room_id: Field,
+    room_name: Field,
+    sender: Field,
+    timestamp: Field,
+    body: Field,
+}
+
+pub struct IndexBuildReport {
+    pub indexed_file_count: usize,
+    pub indexed_event_count: usize,
+    pub skipped_files: Vec<(PathBuf, Error)>, // JSON files which couldn't be read as exports, e.g. ones from other tools
+}
+
+// Anything left unset doesn't narrow the search down.
+pub struct IndexSearchOptions {
+    pub senders: Vec<String>, // Matches must be from one of these user IDs, if any are given
+    pub from_millis: Option<i64>, // Inclusive
+    pub to_millis: Option<i64>, // Exclusive
+    pub limit: usize,
+}
+
+impl Default for IndexSearchOptions {
+    fn default() -> Self {
+        Self {
+            senders: Vec::new(),
+            from_millis: None,
+            to_millis: None,
+            limit: 20,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct IndexHit {
+    pub score: f32, // Higher is more relevant
+    pub event_id: String,
+    pub room_id: Option<String>, // None for events from exports too old to have a room header
+    pub room_name: Option<String>,
+    pub sender: String,
+    pub timestamp_millis: i64,
+    pub body: String,
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn index_schema() -> Schema {
+    let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field("event_id", STRING | STORED);
+    schema_builder.add_text_field("room_id", STRING | STORED);
+    schema_builder.add_text_field("room_name", STORED);
+    schema_builder.add_text_field("sender", STRING | STORED);
+    schema_builder.add_i64_field("timestamp", INDEXED | STORED | FAST);
+    schema_builder.add_text_field("body", TEXT | STORED);
+    schema_builder.build()
+}
+
+fn index_fields(schema: &Schema) -> Result<IndexFields> {
+    Ok(IndexFields {
+        event_id: schema.get_field("event_id")?,
+        room_id: schema.get_field("room_id")?,
+        room_name: schema.get_field("room_name")?,
+        sender: schema.get_field("sender")?,
+        timestamp: schema.get_field("timestamp")?,
+        body: schema.get_field("body")?,
+    })
+}
+
+fn open_index(index_dir: &Path) -> Result<Index> {
+    create_dir_all(index_dir)?;
+    Ok(Index::open_or_create(MmapDirectory::open(index_dir).map_err(tantivy::TantivyError::from)?, index_schema())?)
+}
+
+// Takes export files as well as directories of them, where every JSON file directly within (bar the export manifest) gets indexed, much as media verification goes through them.
+fn export_files(export_paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for export_path in export_paths {
+        if export_path.is_dir() {
+            for entry in read_dir(export_path)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().is_some_and(|extension| extension == "json") && path.file_name().is_none_or(|filename| filename != EXPORT_MANIFEST_FILENAME) {
+                    files.push(path);
+                }
+            }
+        } else {
+            files.push(export_path.clone());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+// Returns the number of events indexed. Events without a text body (state events, reactions, and the like) are left out, since there's nothing in them to search.
+fn index_export_file(writer: &mut IndexWriter, fields: &IndexFields, path: &Path) -> Result<usize> {
+    let export: serde_json::Value = serde_json::from_str(&read_to_string(path)?)?;
+    let (room, events) = match export {
+        serde_json::Value::Array(events) => (serde_json::Value::Null, events), // Exports from before the room header was added
+        mut export => match export.get_mut("events").map(serde_json::Value::take) {
+            Some(serde_json::Value::Array(events)) => (export.get_mut("room").map(serde_json::Value::take).unwrap_or_default(), events),
+            _ => return Err(Error::Other(anyhow::anyhow!("{} has no events list, so it doesn't look like a Trace export.", path.display()))),
+        },
+    };
+    let room_id = room.get("room_id").and_then(|room_id| room_id.as_str()).unwrap_or_default();
+    let room_name = room.get("name").and_then(|name| name.as_str()).unwrap_or_default();
+
+    let mut indexed_event_count = 0;
+    for event in events {
+        let (Some(event_id), Some(sender), Some(timestamp_millis), Some(body)) = (
+            event.get("event_id").and_then(|event_id| event_id.as_str()),
+            event.get("sender").and_then(|sender| sender.as_str()),
+            event.get("origin_server_ts").and_then(|timestamp| timestamp.as_i64()),
+            event.get("content").and_then(|content| content.get("body")).and_then(|body| body.as_str()),
+        ) else {
+            continue
+        };
+        writer.delete_term(Term::from_field_text(fields.event_id, event_id)); // So that reindexing an export, or overlapping ones, doesn't turn up the same event twice
+        writer.add_document(doc!(
+            fields.event_id => event_id,
+            fields.room_id => room_id,
+            fields.room_name => room_name,
+            fields.sender => sender,
+            fields.timestamp => timestamp_millis,
+            fields.body => body,
+        ))?;
+        indexed_event_count += 1;
+    }
+
+    Ok(indexed_event_count)
+}
+
+fn stored_text(document: &TantivyDocument, field: Field) -> Option<String> {
+    document.get_first(field).and_then(|value| value.as_str()).filter(|text| !text.is_empty()).map(String::from)
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Adds the events of JSON exports to the full-text index in index_dir, creating it if need be. Export paths can be JSON files or directories holding them. Streamed exports to stdout (JSON lines) aren't supported.
+pub fn build_index(index_dir: &Path, export_paths: &[PathBuf]) -> Result<IndexBuildReport> {
+    let index = open_index(index_dir)?;
+    let fields = index_fields(&index.schema())?;
+    let mut writer: IndexWriter = index.writer(INDEX_WRITER_MEMORY_BUDGET)?;
+    let mut report = IndexBuildReport {
+        indexed_file_count: 0,
+        indexed_event_count: 0,
+        skipped_files: Vec::new(),
+    };
+    for path in export_files(export_paths)? {
+        match index_export_file(&mut writer, &fields, &path) {
+            Ok(indexed_event_count) => {
+                debug!(path = %path.display(), indexed_event_count, "Indexed export file");
+                report.indexed_file_count += 1;
+                report.indexed_event_count += indexed_event_count;
+            }
+            Err(e) => {
+                warn!("Skipping {} while indexing due to error '{}'.", path.display(), e);
+                report.skipped_files.push((path, e));
+            }
+        }
+    }
+    writer.commit()?;
+
+    Ok(report)
+}
+
+// Queries use tantivy's syntax over message bodies: words are matched by relevance, "quoted phrases" exactly, and +required or -excluded terms likewise. Best matches come first.
+pub fn search_index(index_dir: &Path, query: &str, options: &IndexSearchOptions) -> Result<Vec<IndexHit>> {
+    let index = Index::open_in_dir(index_dir)?;
+    let fields = index_fields(&index.schema())?;
+    let searcher = index.reader()?.searcher();
+
+    let text_query = QueryParser::for_index(&index, vec![fields.body]).parse_query(query).map_err(|e| Error::Other(anyhow::anyhow!("Couldn't parse search query '{}' due to error '{}'.", query, e)))?;
+    let mut subqueries: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, text_query)];
+    if !options.senders.is_empty() {
+        let sender_queries = options.senders.iter().map(|sender| -> (Occur, Box<dyn Query>) {
+            (Occur::Should, Box::new(TermQuery::new(Term::from_field_text(fields.sender, sender), IndexRecordOption::Basic)))
+        }).collect();
+        subqueries.push((Occur::Must, Box::new(BooleanQuery::new(sender_queries))));
+    }
+    if options.from_millis.is_some() || options.to_millis.is_some() {
+        let from = options.from_millis.map_or(Bound::Unbounded, |from_millis| Bound::Included(Term::from_field_i64(fields.timestamp, from_millis)));
+        let to = options.to_millis.map_or(Bound::Unbounded, |to_millis| Bound::Excluded(Term::from_field_i64(fields.timestamp, to_millis)));
+        subqueries.push((Occur::Must, Box::new(RangeQuery::new(from, to))));
+    }
+
+    let mut hits = Vec::new();
+    for (score, document_address) in searcher.search(&BooleanQuery::new(subqueries), &TopDocs::with_limit(options.limit))? {
+        let document: TantivyDocument = searcher.doc(document_address)?;
+        hits.push(IndexHit {
+            score,
+            event_id: stored_text(&document, fields.event_id).unwrap_or_default(),
+            room_id: stored_text(&document, fields.room_id),
+            room_name: stored_text(&document, fields.room_name),
+            sender: stored_text(&document, fields.sender).unwrap_or_default(),
+            timestamp_millis: document.get_first(fields.timestamp).and_then(|value| value.as_i64()).unwrap_or_default(),
+            body: stored_text(&document, fields.body).unwrap_or_default(),
+        });
+    }
+
+    Ok(hits)
+}
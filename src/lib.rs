@@ -1,39 +1,116 @@
 use std::{
     cmp::Ordering,
+    collections::{
+        BTreeMap,
+        HashMap,
+        HashSet,
+    },
     fs::{
         create_dir_all,
-        read_to_string,
+        read,
         remove_dir_all,
-        write,
+        rename,
+        File,
     },
+    io::Write,
     path::{
         Path,
         PathBuf,
     },
+    sync::{
+        Arc,
+        Mutex,
+    },
 };
 
-use directories::ProjectDirs;
-use futures::future::join_all;
+use futures::{
+    future::join_all,
+    stream,
+    StreamExt,
+};
 use matrix_sdk::{
-    Client, Room, SessionMeta, authentication::{SessionTokens, matrix::MatrixSession}, config::SyncSettings, ruma::{
-        OwnedRoomAliasId, OwnedRoomId, UserId, api::client::session::get_login_types::v3::LoginType, presence::PresenceState
+    Client, HttpError, Room, SessionChange, SessionMeta, authentication::{SessionTokens, matrix::MatrixSession}, config::{RequestConfig, SyncSettings}, deserialized_responses::SyncOrStrippedState, encryption::CrossSigningResetAuthType, room::MessagesOptions, ruma::{
+        OwnedDeviceId, OwnedRoomAliasId, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, OwnedUserId, UInt, UserId, api::client::{account::{register, whoami}, device::Device, error::ErrorKind, filter::{Filter, FilterDefinition, LazyLoadOptions, RoomEventFilter}, session::get_login_types::v3::LoginType, sync::sync_events::v3::{Filter as SyncFilter, JoinedRoom, Request as SyncRequest}, uiaa::{self, AuthData, AuthType, UserIdentifier}}, events::{SyncStateEvent, space::child::SpaceChildEventContent}, presence::PresenceState
     }, store::RoomLoadSettings
 };
+use age::secrecy::SecretString;
+use reqwest::Certificate;
 use serde::{
     Deserialize,
     Serialize,
 };
+use tracing::{
+    debug,
+    instrument,
+    warn,
+};
+
+use retry::{
+    retry_rate_limited,
+    DEFAULT_MAX_RETRIES,
+};
+use secrets::{
+    SecretStore,
+    SessionSecrets,
+};
 
+pub mod analyze;
+pub mod checkpoint;
+mod error;
 pub mod export;
+pub mod hooks;
+pub mod import;
+pub mod index;
+pub mod media;
+pub mod object_storage;
+pub mod profiles;
+mod receipts;
+pub mod redact;
+mod retry;
+mod room_data;
+pub mod schedule;
+pub mod search;
+pub mod secrets;
+mod synapse_admin;
+pub mod verify;
 
 ////////////////////
 //   Re-exports   //
 ////////////////////
 
+pub use error::{
+    Error,
+    Result,
+};
 pub use export::{
     export,
+    ContentFilter,
+    EventTypeFilter,
+    ExportDestination,
+    ExportEventRange,
+    ExportOptions,
     ExportOutputFormat,
+    ExportProgress,
+    ExportReport,
+    ExportTimezone,
+    JsonOptions,
+    NameTemplate,
+    PaginationOptions,
+    RoomExportOutcome,
+    RoomExportStatus,
+    RoomResolution,
+    SplitMode,
+    TxtOptions,
+    UpgradeChainMode,
 };
+pub use tokio_util::sync::CancellationToken;
+
+// How encrypted sessions files begin, as opposed to the plaintext JSON ones
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1";
+// Bump whenever the sessions file's layout changes in a way older versions of Trace can't read. Files from before versioning hold the bare list of sessions, and count as version 0.
+const SESSIONS_FILE_VERSION: u64 = 1;
+// How many rooms get their latest event fetched at once, so that listing hundreds of rooms doesn't fire off hundreds of requests together and get rate-limited
+const LATEST_EVENT_FETCH_JOBS: usize = 8;
 
 ///////////////
 //   Types   //
@@ -41,81 +118,404 @@ pub use export::{
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Session {
-    pub user_id: String,
-    pub device_id: String,
-    pub access_token: String,
+    pub user_id: OwnedUserId, // Checked as the sessions file gets read, rather than whenever it's first used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>, // Distinguishes between sessions logged into the same account, e.g. for separate devices on separate machines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>, // Short name to refer to the session by in place of its user ID
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_default: bool, // Whether this is the session to use when none is specified; at most one session should have this set
+    pub device_id: OwnedDeviceId,
+    #[serde(default)]
+    pub access_token: String, // Left empty in the file itself for sessions with secrets_in_store set
     pub refresh_token: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secrets_in_store: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub store_encrypted: bool, // Whether the session's sqlite store is encrypted with the sessions file's passphrase
+    #[serde(flatten)]
+    pub connection_options: ConnectionOptions,
+}
+
+impl Session {
+    fn is(&self, user_id: &str, profile: Option<&str>) -> bool {
+        self.user_id.as_str() == user_id && self.profile.as_deref() == profile
+    }
+}
+
+// How to reach a session's homeserver, as given at login and reused for the session from then on.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ConnectionOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homeserver: Option<String>, // Only set when given explicitly; otherwise the homeserver gets discovered from the user ID's server name each time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<PathBuf>, // PEM file of extra certificates to trust, for homeservers with certificates from private CAs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_tls_verification: bool, // Dangerous; leaves the connection open to interception
+}
+
+// Where logged-in sessions are kept between runs. SessionsFile is the default; implement this to keep them somewhere else instead, e.g. a database or secret manager.
+pub trait SessionStore: Send + Sync {
+    fn get(&self, user_id: &str, profile: Option<&str>) -> Result<Option<Session>>;
+    fn insert(&mut self, session: Session) -> Result<()>; // Should fail if there's already a session for the user ID and profile
+    fn update_tokens(&mut self, user_id: &str, profile: Option<&str>, access_token: String, refresh_token: Option<String>) -> Result<()>;
+    fn delete(&mut self, user_id: &str, profile: Option<&str>) -> Result<()>;
+    fn set_alias(&mut self, user_id: &str, profile: Option<&str>, alias: Option<String>) -> Result<()>; // Should fail if another session already has the alias
+    fn set_default(&mut self, user_id: &str, profile: Option<&str>) -> Result<()>; // Unsets whichever session was the default before
+    fn list(&self) -> Result<Vec<Session>>; // The sessions listed needn't have their tokens filled in
+    // Opens a separate handle onto the same sessions, for refreshed tokens to get saved through from the background.
+    fn reopen(&self) -> Result<Box<dyn SessionStore>>;
+    // Passphrase for new sessions' sqlite stores to be encrypted with, if any
+    fn store_passphrase(&self) -> Option<&str> {
+        None
+    }
+}
+
+// Sessions' tokens are kept in secret_store when there is one, with only the rest of each session written to the file itself. Sessions from before there was one keep their tokens in the file.
+// With a passphrase, the file gets written encrypted with it (in age's passphrase format), and new sessions' sqlite stores get encrypted with it too.
+#[derive(Serialize)]
+struct SessionsFileContents<'a> {
+    version: u64,
+    sessions: &'a [Session],
 }
 
 pub struct SessionsFile {
     path: PathBuf,
     pub sessions: Vec<Session>,
+    secret_store: Option<Arc<dyn SecretStore>>,
+    pub store_new_secrets: bool, // Whether new sessions' tokens go into secret_store, if there is one, rather than the file
+    passphrase: Option<String>,
 }
 
+// Changes are made with the file locked, to sessions freshly reread from it, so that concurrent Trace invocations don't clobber each other's changes. Writes go to a temporary file which then gets renamed over the real one, so that a crash mid-write can't leave it truncated.
 impl SessionsFile {
-    pub fn open(path: PathBuf) -> Self {
-        if let Ok(file) = read_to_string(&path) {
-            let sessions = serde_json::from_str(&file).expect("Sessions file is invalid JSON."); // Replace with better error-handling
-            Self {
-                path,
-                sessions,
+    pub fn open(path: PathBuf, secret_store: Option<Arc<dyn SecretStore>>, passphrase: Option<String>) -> Result<Self> {
+        let sessions = read_sessions(&path, passphrase.as_deref())?;
+        let is_new = sessions.is_none();
+        let sessions_file = Self {
+            path,
+            sessions: sessions.unwrap_or_default(),
+            secret_store,
+            store_new_secrets: true,
+            passphrase,
+        };
+        if is_new {
+            if let Some(parent) = sessions_file.path.parent() {
+                create_dir_all(parent)?;
             }
-        } else {
-            create_dir_all(path.parent().expect("Tried to open root as sessions file. (This should never happen.")).unwrap();
-            write(&path, "[]").unwrap();
-            Self {
-                path,
-                sessions: Vec::new(),
+            sessions_file.write()?;
+        }
+
+        Ok(sessions_file)
+    }
+
+    pub fn is_encrypted(path: &Path) -> bool {
+        read(path).is_ok_and(|file| file.starts_with(AGE_HEADER))
+    }
+
+    // Takes effect for the file from its next write on, and for sessions logged in from then on. Already-existing sessions' stores stay unencrypted, since the SDK can't encrypt a store after the fact.
+    pub fn set_passphrase(&mut self, passphrase: String) {
+        self.passphrase = Some(passphrase);
+    }
+
+    pub fn write(&self) -> Result<()> {
+        let _lock = self.lock()?;
+        self.write_locked()
+    }
+
+    // The lock is held until the returned file gets dropped. It's taken on a separate file, since the sessions file itself gets replaced on each write.
+    fn lock(&self) -> Result<File> {
+        let lock_file = File::options().create(true).truncate(false).write(true).open(self.path.with_extension("lock"))?;
+        lock_file.lock()?;
+
+        Ok(lock_file)
+    }
+
+    // Rereads the sessions from the file, to make changes on top of. Only to be called with the lock held.
+    fn reload(&mut self) -> Result<()> {
+        if let Some(sessions) = read_sessions(&self.path, self.passphrase.as_deref())? {
+            self.sessions = sessions;
+        }
+
+        Ok(())
+    }
+
+    // Only to be called with the lock held
+    fn write_locked(&self) -> Result<()> {
+        let updated_file = serde_json::to_vec(&SessionsFileContents {
+            version: SESSIONS_FILE_VERSION,
+            sessions: &self.sessions,
+        })?;
+        let updated_file = match &self.passphrase {
+            Some(passphrase) => age::encrypt(&age::scrypt::Recipient::new(SecretString::from(passphrase.clone())), &updated_file).map_err(anyhow::Error::from)?,
+            None => updated_file,
+        };
+        let temp_path = self.path.with_extension(format!("json.{}.tmp", std::process::id()));
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&updated_file)?;
+        temp_file.sync_all()?;
+        rename(&temp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl SessionStore for SessionsFile {
+    // Fills in the session's tokens from the secret store, for sessions keeping them there.
+    fn get(&self, user_id: &str, profile: Option<&str>) -> Result<Option<Session>> {
+        let Some(mut session) = self.sessions.iter().find(|session| session.is(user_id, profile)).cloned() else {
+            return Ok(None)
+        };
+        if session.secrets_in_store {
+            let session_key = session_key(user_id, profile);
+            let Some(secret_store) = &self.secret_store else {
+                return Err(Error::Other(anyhow::anyhow!("Session for {} keeps its tokens in a secret store, but none is available.", session_key)));
+            };
+            let Some(secrets) = secret_store.load(&session_key)? else {
+                return Err(Error::Other(anyhow::anyhow!("Couldn't find tokens for {} in the secret store.", session_key)));
+            };
+            session.access_token = secrets.access_token;
+            session.refresh_token = secrets.refresh_token;
+        }
+
+        Ok(Some(session))
+    }
+
+    fn insert(&mut self, mut session: Session) -> Result<()> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        let session_key = session_key(session.user_id.as_str(), session.profile.as_deref());
+        if self.sessions.iter().any(|preexisting_session| preexisting_session.is(session.user_id.as_str(), session.profile.as_deref())) {
+            return Err(Error::SessionExists(session_key));
+        }
+        if let (Some(secret_store), true) = (&self.secret_store, self.store_new_secrets) {
+            secret_store.save(&session_key, &SessionSecrets {
+                access_token: std::mem::take(&mut session.access_token),
+                refresh_token: session.refresh_token.take(),
+            })?;
+            session.secrets_in_store = true;
+        }
+        self.sessions.push(session);
+        self.write_locked()?;
+
+        Ok(())
+    }
+
+    fn update_tokens(&mut self, user_id: &str, profile: Option<&str>, access_token: String, refresh_token: Option<String>) -> Result<()> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        let session_key = session_key(user_id, profile);
+        let Some(session) = self.sessions.iter_mut().find(|session| session.is(user_id, profile)) else {
+            return Err(Error::SessionNotFound(session_key));
+        };
+        match (&self.secret_store, session.secrets_in_store) {
+            (Some(secret_store), true) => secret_store.save(&session_key, &SessionSecrets {
+                access_token,
+                refresh_token,
+            })?,
+            _ => {
+                session.access_token = access_token;
+                session.refresh_token = refresh_token;
+                self.write_locked()?;
             }
         }
+
+        Ok(())
     }
 
-    pub fn get(&self, user_id: &str) -> Result<Session, String> {
-        match self.sessions.iter().find(|session| session.user_id == user_id) {
-            Some(session) => Ok(session.clone()),
-            None => Err(format!("Couldn't find currently-existing login session for user_id {}.", user_id))
+    fn delete(&mut self, user_id: &str, profile: Option<&str>) -> Result<()> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        let session_key = session_key(user_id, profile);
+        let Some(session_index) = self.sessions.iter().position(|session| session.is(user_id, profile)) else {
+            return Err(Error::SessionNotFound(session_key));
+        };
+        if let (Some(secret_store), true) = (&self.secret_store, self.sessions[session_index].secrets_in_store) {
+            secret_store.delete(&session_key)?;
         }
+        self.sessions.remove(session_index);
+        self.write_locked()?;
+
+        Ok(())
     }
 
-    pub fn delete_session(&mut self, user_id: &str) -> Result<(), String> {
-        match self.sessions.iter().position(|session| session.user_id == user_id) {
-            Some(session_index) => {
-                self.sessions.remove(session_index);
-                self.write();
-                Ok(())
+    fn set_alias(&mut self, user_id: &str, profile: Option<&str>, alias: Option<String>) -> Result<()> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        if let Some(alias) = &alias {
+            if let Some(aliased_session) = self.sessions.iter().find(|session| session.alias.as_ref() == Some(alias) && !session.is(user_id, profile)) {
+                return Err(Error::Other(anyhow::anyhow!("Alias {} is already in use by the session for {}.", alias, session_key(aliased_session.user_id.as_str(), aliased_session.profile.as_deref()))));
             }
-            None => Err(format!("Couldn't find currently-existing login session for user_id {}.", user_id))
         }
+        let Some(session) = self.sessions.iter_mut().find(|session| session.is(user_id, profile)) else {
+            return Err(Error::SessionNotFound(session_key(user_id, profile)));
+        };
+        session.alias = alias;
+        self.write_locked()?;
+
+        Ok(())
     }
 
-    pub fn new_session(&mut self, session: Session) -> Result<(), String> {
-        if !self.sessions.iter().any(|preexisting_session| preexisting_session.user_id == session.user_id) {
-            self.sessions.push(session);
-            self.write();
-            Ok(())
-        } else {
-            Err(format!("Tried to create new session with user_id {}, but you already have a logged-in session with that user ID.", session.user_id))
+    fn set_default(&mut self, user_id: &str, profile: Option<&str>) -> Result<()> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        if !self.sessions.iter().any(|session| session.is(user_id, profile)) {
+            return Err(Error::SessionNotFound(session_key(user_id, profile)));
+        }
+        for session in &mut self.sessions {
+            session.is_default = session.is(user_id, profile);
+        }
+        self.write_locked()?;
+
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Session>> {
+        Ok(self.sessions.clone())
+    }
+
+    fn reopen(&self) -> Result<Box<dyn SessionStore>> {
+        let mut sessions_file = SessionsFile::open(self.path.clone(), self.secret_store.clone(), self.passphrase.clone())?;
+        sessions_file.store_new_secrets = self.store_new_secrets;
+
+        Ok(Box::new(sessions_file))
+    }
+
+    fn store_passphrase(&self) -> Option<&str> {
+        self.passphrase.as_deref()
+    }
+}
+
+// Returned when the homeserver no longer accepts a session's access token. Soft-logged-out sessions can be picked back up, device and keys and all, with resume_session; other sessions need logging out of and into afresh, but their stores are left alone until then, so that their room keys can be exported first.
+#[derive(Clone, Debug)]
+pub struct SessionExpired {
+    pub user_id: String,
+    pub profile: Option<String>,
+    pub soft_logout: bool,
+}
+
+impl std::fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.soft_logout {
+            true => write!(f, "Session for {} was logged out by the homeserver, but can be resumed by entering the account's password again.", session_key(&self.user_id, self.profile.as_deref())),
+            false => write!(f, "Session for {} is no longer valid on the homeserver. Its store has been kept, so run 'trace keys export' first if you want to keep its room keys, then 'trace session logout' and log in again to keep using the account.", session_key(&self.user_id, self.profile.as_deref())),
         }
     }
+}
 
-    pub fn write(&self) {
-        let updated_file = serde_json::to_string(&self.sessions).unwrap();
-        write(&self.path, updated_file).unwrap();
+impl std::error::Error for SessionExpired {}
+
+// Whether the homeserver has rejected a restored session's token since, e.g. partway through a sync or pagination, as noted down by watch_for_token_rejection. Each is tied to the one client it was set up for, so that rejections of one client's token never get blamed for another's failures.
+#[derive(Clone, Default)]
+pub struct TokenRejection(Arc<Mutex<Option<SessionExpired>>>);
+
+impl TokenRejection {
+    // Taken, so it's only handed back once.
+    pub fn take(&self) -> Option<SessionExpired> {
+        self.0.lock().unwrap().take()
     }
 }
 
+// A session's health as far as the homeserver and its local store are concerned, for telling whether it's fit to export with before trying.
+pub struct SessionStatus {
+    pub whoami_user_id: String,
+    pub whoami_device_id: Option<String>, // Should match the session's own device ID; older homeservers don't say
+    pub device_verified: bool, // Whether the session's device is cross-signed by the account
+    pub cross_signing_set_up: bool, // Whether the session has the account's master, self-signing, and user-signing keys
+    pub backup_enabled: bool, // Whether room keys are being backed up to the homeserver
+    pub store_path: PathBuf,
+    pub store_size: u64, // In bytes
+}
+
 pub struct RoomWithCachedInfo {
     pub id: OwnedRoomId,
     pub name: Option<String>,
     pub canonical_alias: Option<OwnedRoomAliasId>,
     pub alt_aliases: Vec<OwnedRoomAliasId>,
+    pub joined_member_count: u64,
+    pub is_encrypted: bool,
+    pub is_space: bool,
+    pub is_direct: bool, // Whether the account's m.direct data marks the room as a DM
     pub room: Room,
 }
 
+impl RoomWithCachedInfo {
+    pub fn from_room(room: Room) -> Self {
+        Self {
+            id: room.room_id().to_owned(),
+            name: room.name(),
+            canonical_alias: room.canonical_alias(),
+            alt_aliases: room.alt_aliases(),
+            joined_member_count: room.joined_members_count(),
+            is_encrypted: room.encryption_state().is_encrypted(),
+            is_space: room.is_space(),
+            is_direct: !room.direct_targets().is_empty(),
+            room,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SpaceHierarchyNode {
+    pub room_id: OwnedRoomId,
+    pub name: Option<String>, // None for rooms the account hasn't joined, along with unnamed ones
+    pub is_space: bool,
+    pub is_joined: bool,
+    pub children: Vec<SpaceHierarchyNode>, // Empty for anything other than joined spaces
+}
+
+// A room the account has been invited to but hasn't joined. Only the stripped state sent along with the invite is known, so the name may be missing even for named rooms.
+#[derive(Serialize)]
+pub struct RoomInvite {
+    pub room_id: OwnedRoomId,
+    pub name: Option<String>,
+    pub canonical_alias: Option<OwnedRoomAliasId>,
+    pub inviter: Option<OwnedUserId>,
+}
+
 ////////////////////////
 //   Shared helpers   //
 ////////////////////////
 
+// Returns None if there's no sessions file yet.
+fn read_sessions(path: &Path, passphrase: Option<&str>) -> Result<Option<Vec<Session>>> {
+    let Ok(file) = read(path) else {
+        return Ok(None)
+    };
+    let file = if file.starts_with(AGE_HEADER) {
+        let Some(passphrase) = passphrase else {
+            return Err(Error::SessionsFile {
+                path: path.to_path_buf(),
+                reason: String::from("it's encrypted, but no passphrase was given"),
+            })
+        };
+        age::decrypt(&age::scrypt::Identity::new(SecretString::from(passphrase.to_owned())), &file).map_err(|e| Error::SessionsFile {
+            path: path.to_path_buf(),
+            reason: format!("couldn't decrypt it due to error '{}'. Is the passphrase correct?", e),
+        })?
+    } else {
+        file
+    };
+    let invalid_file = |reason: String| Error::SessionsFile {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let sessions = match serde_json::from_slice(&file).map_err(|e| invalid_file(format!("couldn't parse it due to error '{}'", e)))? {
+        sessions @ serde_json::Value::Array(_) => sessions, // Version 0
+        serde_json::Value::Object(mut contents) => match contents.get("version").and_then(serde_json::Value::as_u64) {
+            Some(version) if version <= SESSIONS_FILE_VERSION => contents.remove("sessions").unwrap_or_default(),
+            Some(version) => return Err(invalid_file(format!("it's of version {}, which is newer than this version of Trace supports ({}). Please update Trace", version, SESSIONS_FILE_VERSION))),
+            None => return Err(invalid_file(String::from("it lacks a version number"))),
+        },
+        _ => return Err(invalid_file(String::from("it's neither a list of sessions nor an object holding them"))),
+    };
+    let sessions = serde_json::from_value(sessions).map_err(|e| invalid_file(format!("it holds an invalid session due to error '{}'", e)))?;
+
+    Ok(Some(sessions))
+}
+
 pub fn add_at_to_user_id_if_applicable(user_id: &str) -> String {
     if user_id.starts_with('@') {
         String::from(user_id)
@@ -124,7 +524,66 @@ pub fn add_at_to_user_id_if_applicable(user_id: &str) -> String {
     }
 }
 
-pub fn user_id_to_crypto_store_path(user_id: &str) -> PathBuf {
+// Returns the session going by the alias, if any.
+pub fn find_session_by_alias(session_store: &dyn SessionStore, alias: &str) -> Result<Option<Session>> {
+    Ok(session_store.list()?.into_iter().find(|session| session.alias.as_deref() == Some(alias)))
+}
+
+pub fn default_session(session_store: &dyn SessionStore) -> Result<Option<Session>> {
+    Ok(session_store.list()?.into_iter().find(|session| session.is_default))
+}
+
+// Returns Some with whether it was a soft logout if the error is the homeserver rejecting the access token.
+fn unknown_token_soft_logout(error: &HttpError) -> Option<bool> {
+    match error.as_client_api_error()?.error_kind()? {
+        ErrorKind::UnknownToken { soft_logout } => Some(*soft_logout),
+        _ => None,
+    }
+}
+
+fn session_store_passphrase<'a>(session: &Session, session_store: &'a dyn SessionStore) -> Result<Option<&'a str>> {
+    match (session.store_encrypted, session_store.store_passphrase()) {
+        (true, None) => Err(Error::Other(anyhow::anyhow!("Session for {} has an encrypted store, but no passphrase was given.", session_key(session.user_id.as_str(), session.profile.as_deref())))),
+        (true, Some(passphrase)) => Ok(Some(passphrase)),
+        (false, _) => Ok(None),
+    }
+}
+
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+
+    Ok(size)
+}
+
+// For answering homeservers' user-interactive auth with the account's password, which is the only form of it supported so far. action describes what the auth is for, for the error when a password won't do.
+fn uiaa_password_auth(client: &Client, uiaa_info: &uiaa::UiaaInfo, password: impl FnOnce() -> Result<String>, action: &str) -> Result<AuthData> {
+    if !uiaa_info.flows.iter().any(|flow| flow.stages.iter().any(|stage| *stage == AuthType::Password)) {
+        return Err(Error::Other(anyhow::anyhow!("Homeserver requires a form of authentication other than a password to {}, which isn't supported yet.", action)));
+    }
+    let mut password_auth = uiaa::Password::new(UserIdentifier::UserIdOrLocalpart(client.user_id().unwrap().to_string()), password()?);
+    password_auth.session = uiaa_info.session.clone();
+
+    Ok(AuthData::Password(password_auth))
+}
+
+// Identifies a session among all those logged in, e.g. for keying secret stores by, and for mentioning in messages.
+pub fn session_key(user_id: &str, profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{} ({})", user_id, profile),
+        None => String::from(user_id),
+    }
+}
+
+// Stores for sessions with profiles go alongside the account's profileless one, with the profile name appended. '~' can't appear in user IDs, so these can't collide with other accounts' stores.
+pub fn user_id_to_crypto_store_path(user_id: &str, profile: Option<&str>) -> PathBuf {
     let atless_user_id = if user_id.starts_with('@') {
         user_id.chars().skip(1).collect()
     } else {
@@ -135,38 +594,225 @@ pub fn user_id_to_crypto_store_path(user_id: &str) -> PathBuf {
     for component in atless_user_id.split(':').rev() {
         store_path.push(component);
     }
+    if let Some(profile) = profile {
+        let localpart_with_profile = format!("{}~{}", store_path.file_name().unwrap().to_string_lossy(), profile);
+        store_path.set_file_name(localpart_with_profile);
+    }
     store_path
 }
 
-pub async fn nonfirst_login(user_id: &str, sessions_file: &SessionsFile, store_path: &Path) -> anyhow::Result<Client> {
-    let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
-    let session = sessions_file.get(&normalized_user_id).unwrap();
-    let user = UserId::parse(&session.user_id)?;
-    let client = Client::builder().server_name(user.server_name()).sqlite_store(store_path, None).build().await?;
+// An explicit homeserver URL wins out. Failing that, the homeserver gets discovered through the server name's .well-known, falling back to the server name itself for servers without one.
+// Proxies can be HTTP or SOCKS5 (e.g. 'socks5h://127.0.0.1:9050' for Tor), with TRACE_PROXY taking precedence over the one passed in. Without either, the standard HTTPS_PROXY and ALL_PROXY environment variables are respected.
+// Consumers with a client of their own can skip this and hand theirs to first_login/restore_login instead.
+pub async fn build_client(user: &UserId, connection_options: &ConnectionOptions, store_path: &Path, store_passphrase: Option<&str>) -> Result<Client> {
+    let proxy = std::env::var("TRACE_PROXY").ok().or(connection_options.proxy.clone());
+    let root_certificates = match &connection_options.ca_bundle {
+        Some(ca_bundle) => Certificate::from_pem_bundle(&read(ca_bundle)?).map_err(anyhow::Error::from)?,
+        None => Vec::new(),
+    };
+    let client_builder = || {
+        let mut client_builder = Client::builder().sqlite_store(store_path, store_passphrase).handle_refresh_tokens().add_root_certificates(root_certificates.clone());
+        if let Some(proxy) = &proxy {
+            client_builder = client_builder.proxy(proxy);
+        }
+        if connection_options.skip_tls_verification {
+            client_builder = client_builder.disable_ssl_verification();
+        }
+        client_builder
+    };
+    let client = match connection_options.homeserver.as_deref() {
+        Some(homeserver) => client_builder().homeserver_url(homeserver).build().await?,
+        None => match client_builder().server_name(user.server_name()).build().await {
+            Ok(client) => client,
+            Err(_) => client_builder().homeserver_url(format!("https://{}", user.server_name())).build().await?,
+        },
+    };
+
+    Ok(client)
+}
+
+// The SDK refreshes expired access tokens by itself, but only keeps the new ones in memory, so they get written back to the sessions file here for later runs to start from. Refresh tokens are generally single-use, so losing track of a new one would mean logging in afresh.
+fn persist_refreshed_tokens(client: &Client, session_store: &dyn SessionStore, user_id: &str, profile: Option<&str>) -> Result<()> {
+    let mut session_changes = client.subscribe_to_session_changes();
+    let client = client.clone();
+    let session_store = session_store.reopen()?;
+    let user_id = user_id.to_owned();
+    let profile = profile.map(String::from);
+    tokio::spawn(async move {
+        while let Ok(session_change) = session_changes.recv().await {
+            let (SessionChange::TokensRefreshed, Some(tokens)) = (session_change, client.session_tokens()) else {
+                continue
+            };
+            // Reopened each time, so as not to clobber changes made to it since
+            if let Err(e) = session_store.reopen().and_then(|mut session_store| session_store.update_tokens(&user_id, profile.as_deref(), tokens.access_token, tokens.refresh_token)) {
+                warn!("Couldn't save refreshed tokens for {} due to error '{}'. You may need to log in again next time.", session_key(&user_id, profile.as_deref()), e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn restore_saved_session(client: &Client, session: Session) -> Result<()> {
     client.matrix_auth().restore_session(MatrixSession {
         meta: SessionMeta {
-            user_id: user,
-            device_id: session.device_id.into(),
+            user_id: session.user_id,
+            device_id: session.device_id,
         },
         tokens: SessionTokens {
             access_token: session.access_token,
             refresh_token: session.refresh_token,
         }
     }, RoomLoadSettings::default()).await?;
-    client.encryption().wait_for_e2ee_initialization_tasks().await;
+
+    Ok(())
+}
+
+// The whoami in restore_login only catches tokens rejected before anything gets going, so any rejection after that (e.g. of a token expiring partway through a long export) gets noted down in the returned TokenRejection, since the error it causes wherever it happens doesn't say whose session it was. Callers can check it once something they ran with the client fails.
+pub fn watch_for_token_rejection(client: &Client, user_id: &str, profile: Option<&str>) -> TokenRejection {
+    let token_rejection = TokenRejection::default();
+    let mut session_changes = client.subscribe_to_session_changes();
+    let user_id = add_at_to_user_id_if_applicable(user_id);
+    let profile = profile.map(String::from);
+    let rejected_session = token_rejection.0.clone();
+    tokio::spawn(async move {
+        while let Ok(session_change) = session_changes.recv().await {
+            let SessionChange::UnknownToken { soft_logout } = session_change else {
+                continue
+            };
+            warn!("Homeserver rejected the token of {}'s session.", session_key(&user_id, profile.as_deref()));
+            *rejected_session.lock().unwrap() = Some(SessionExpired {
+                user_id,
+                profile,
+                soft_logout,
+            });
+            break
+        }
+    });
+
+    token_rejection
+}
+
+#[instrument(skip_all, fields(user_id = %user_id, profile = ?profile))]
+pub async fn nonfirst_login(user_id: &str, profile: Option<&str>, session_store: &dyn SessionStore, store_path: &Path) -> Result<Client> {
+    let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
+    let Some(session) = session_store.get(&normalized_user_id, profile)? else {
+        return Err(Error::SessionNotFound(session_key(&normalized_user_id, profile)));
+    };
+    let client = build_client(&session.user_id, &session.connection_options, store_path, session_store_passphrase(&session, session_store)?).await?;
+    restore_login(&client, session_store, &normalized_user_id, profile).await?;
 
     Ok(client)
 }
 
+// Restores a saved session without checking its token with the homeserver, for working with its local store alone, e.g. to export the room keys of a session the homeserver has logged out for good before logging out of it.
+#[instrument(skip_all, fields(user_id = %user_id, profile = ?profile))]
+pub async fn local_login(user_id: &str, profile: Option<&str>, session_store: &dyn SessionStore, store_path: &Path) -> Result<Client> {
+    let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
+    let Some(session) = session_store.get(&normalized_user_id, profile)? else {
+        return Err(Error::SessionNotFound(session_key(&normalized_user_id, profile)));
+    };
+    let client = build_client(&session.user_id, &session.connection_options, store_path, session_store_passphrase(&session, session_store)?).await?;
+    restore_saved_session(&client, session).await?;
+
+    Ok(client)
+}
+
+// Restores a saved session into a client built by the caller, e.g. one with its own HTTP settings, or its own store path or an in-memory store, rather than the one nonfirst_login would build. The client mustn't be logged in yet. Rejections of its token from then on can be watched for with watch_for_token_rejection.
+// Sessions the homeserver has logged out for good are left in the session store, along with the store they went with, for the caller to log out of once whatever's worth keeping from the store is kept.
+#[instrument(skip_all, fields(user_id = %user_id, profile = ?profile))]
+pub async fn restore_login(client: &Client, session_store: &dyn SessionStore, user_id: &str, profile: Option<&str>) -> Result<()> {
+    let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
+    let Some(session) = session_store.get(&normalized_user_id, profile)? else {
+        return Err(Error::SessionNotFound(session_key(&normalized_user_id, profile)));
+    };
+    let has_refresh_token = session.refresh_token.is_some();
+    restore_saved_session(client, session).await?;
+    if has_refresh_token {
+        persist_refreshed_tokens(client, session_store, &normalized_user_id, profile)?;
+    }
+
+    // Catches sessions the homeserver has since logged out (expired refresh tokens, password changes, and so forth) before anything else trips over them. Other errors, e.g. from being offline, are left for whatever comes next to deal with.
+    if let Err(e) = client.send(whoami::v3::Request::new()).with_request_config(RequestConfig::short_retry()).await {
+        if let Some(soft_logout) = unknown_token_soft_logout(&e) {
+            return Err(SessionExpired {
+                user_id: normalized_user_id,
+                profile: profile.map(String::from),
+                soft_logout,
+            }.into())
+        }
+    }
+    client.encryption().wait_for_e2ee_initialization_tasks().await;
+    client.event_cache().subscribe().map_err(anyhow::Error::from)?; // Keeps events received through syncs in the local store, for offline exports to draw on later
+    debug!("Restored session");
+
+    Ok(())
+}
+
+// Children whose m.space.child events have no via servers have been taken out of the space, so they're left out.
+async fn space_child_ids(space: &Room) -> Result<Vec<OwnedRoomId>> {
+    let mut child_ids = Vec::new();
+    for child_event in space.get_state_events_static::<SpaceChildEventContent>().await? {
+        match child_event.deserialize() {
+            Ok(SyncOrStrippedState::Sync(SyncStateEvent::Original(child_event))) if !child_event.content.via.is_empty() => child_ids.push(child_event.state_key),
+            _ => (),
+        }
+    }
+
+    Ok(child_ids)
+}
+
+// Pending invites can be picked out by room ID, alias, or name, as joined rooms can.
+fn find_invited_room(client: &Client, identifier: &str) -> Result<Room> {
+    let mut invites_info = client.invited_rooms().into_iter().map(RoomWithCachedInfo::from_room).collect::<Vec<RoomWithCachedInfo>>();
+    let invite_index = export::get_room_index_by_identifier(&invites_info, identifier).map_err(|e| e.into_error(client, identifier))?;
+
+    Ok(invites_info.swap_remove(invite_index).room)
+}
+
+// Spaces can contain each other, so ancestor_ids keeps track of the current branch, to stop a cycle from recursing forever. Everything reached gets added to visited_ids.
+fn space_hierarchy_node(room_id: &OwnedRoomId, rooms_info: &HashMap<OwnedRoomId, &RoomWithCachedInfo>, child_ids_by_space: &HashMap<OwnedRoomId, Vec<OwnedRoomId>>, ancestor_ids: &mut Vec<OwnedRoomId>, visited_ids: &mut HashSet<OwnedRoomId>) -> SpaceHierarchyNode {
+    visited_ids.insert(room_id.clone());
+    let room_info = rooms_info.get(room_id);
+    let mut node = SpaceHierarchyNode {
+        room_id: room_id.clone(),
+        name: room_info.and_then(|room_info| room_info.name.clone()),
+        is_space: room_info.is_some_and(|room_info| room_info.is_space),
+        is_joined: room_info.is_some(),
+        children: Vec::new(),
+    };
+    if let Some(child_ids) = child_ids_by_space.get(room_id) {
+        if !ancestor_ids.contains(room_id) {
+            ancestor_ids.push(room_id.clone());
+            node.children = child_ids.iter().map(|child_id| space_hierarchy_node(child_id, rooms_info, child_ids_by_space, ancestor_ids, visited_ids)).collect();
+            ancestor_ids.pop();
+        }
+    }
+
+    node
+}
+
+async fn save_new_session(client: &Client, session_store: &mut dyn SessionStore, mut session: Session) -> Result<()> {
+    session.store_encrypted = session_store.store_passphrase().is_some();
+    session_store.insert(session)?;
+
+    client.encryption().wait_for_e2ee_initialization_tasks().await;
+    client.sync_once(SyncSettings::new().set_presence(PresenceState::Offline)).await?;
+
+    Ok(())
+}
+
 ///////////////////////////////
 //   Shared core functions   //
 ///////////////////////////////
 
-pub async fn first_login(client: &Client, sessions_file: &mut SessionsFile, user_id: &str, password: &str, session_name: Option<String>) -> anyhow::Result<()> {
+// The client should have been built with the session store's store passphrase (if any), since the session gets marked as having its store encrypted with it.
+#[instrument(skip_all, fields(user_id = %user_id, profile = ?profile))]
+pub async fn first_login(client: &Client, session_store: &mut dyn SessionStore, user_id: &str, profile: Option<String>, password: &str, session_name: Option<String>, connection_options: ConnectionOptions) -> Result<()> {
     let auth = client.matrix_auth();
     let supported_login_types = auth.get_login_types().await?.flows;
     let login_result = if supported_login_types.iter().any(|login_type| matches!(login_type, LoginType::Password(_))) {
-        let login_request = auth.login_username(user_id, password);
+        let login_request = auth.login_username(user_id, password).request_refresh_token(); // Servers which don't do refresh tokens just ignore this
         if let Some(name) = session_name {
             login_request.initial_device_display_name(&name).send().await?
         } else {
@@ -174,72 +820,368 @@ pub async fn first_login(client: &Client, sessions_file: &mut SessionsFile, user
             login_request.send().await?
         }
     } else {
-        panic!("Attempted login to a server which lacks password-based login support. (SSO support will be added eventually.)");
+        return Err(Error::Login(String::from("Homeserver lacks password-based login support. (SSO support will be added eventually.)")))
     };
 
-    sessions_file.new_session(Session {
-        user_id: login_result.user_id.to_string(),
-        device_id: login_result.device_id.to_string(),
+    save_new_session(client, session_store, Session {
+        user_id: login_result.user_id,
+        profile,
+        alias: None,
+        is_default: false,
+        device_id: login_result.device_id,
         access_token: login_result.access_token.to_string(),
         refresh_token: login_result.refresh_token,
-    }).unwrap();
+        secrets_in_store: false,
+        store_encrypted: false,
+        connection_options,
+    }).await
+}
 
-    client.encryption().wait_for_e2ee_initialization_tasks().await;
-    client.sync_once(SyncSettings::new().set_presence(PresenceState::Offline)).await?;
+// Registers a new account through the homeserver's user-interactive auth, for homeservers whose registration flows are made up of the dummy and registration token stages, then saves a session for it as with first_login. Same expectations of the client as there, too.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(username = %username, profile = ?profile))]
+pub async fn register(client: &Client, session_store: &mut dyn SessionStore, username: &str, profile: Option<String>, password: &str, registration_token: Option<&str>, session_name: Option<String>, connection_options: ConnectionOptions) -> Result<()> {
+    let auth = client.matrix_auth();
+    let mut request = register::v3::Request::new();
+    request.username = Some(String::from(username));
+    request.password = Some(String::from(password));
+    request.initial_device_display_name = session_name;
+    request.refresh_token = true; // Servers which don't do refresh tokens just ignore this
+    let register_result = loop {
+        let uiaa_info = match auth.register(request.clone()).await {
+            Ok(register_result) => break register_result,
+            Err(e) => match e.as_uiaa_response() {
+                Some(uiaa_info) => uiaa_info.clone(),
+                None => return Err(e.into()),
+            },
+        };
+        if let Some(auth_error) = &uiaa_info.auth_error {
+            return Err(Error::Login(format!("Homeserver rejected registration with error '{}'.", auth_error.message)));
+        }
+        let is_supported_stage = |stage: &AuthType| match stage {
+            AuthType::Dummy => true,
+            AuthType::RegistrationToken => registration_token.is_some(),
+            _ => false,
+        };
+        let Some(flow) = uiaa_info.flows.iter().find(|flow| flow.stages.iter().all(&is_supported_stage)) else {
+            match uiaa_info.flows.iter().any(|flow| flow.stages.contains(&AuthType::RegistrationToken)) && registration_token.is_none() {
+                true => return Err(Error::Login(String::from("Homeserver requires a registration token to register."))),
+                false => return Err(Error::Login(String::from("Homeserver requires a form of authentication for registration which isn't supported yet (e.g. a CAPTCHA or email verification)."))),
+            }
+        };
+        let Some(next_stage) = flow.stages.iter().find(|stage| !uiaa_info.completed.contains(stage)) else {
+            return Err(Error::Login(String::from("Homeserver kept asking for authentication after every stage of registration was completed.")));
+        };
+        request.auth = Some(match next_stage {
+            AuthType::RegistrationToken => {
+                let mut token_auth = uiaa::RegistrationToken::new(String::from(registration_token.unwrap()));
+                token_auth.session = uiaa_info.session.clone();
+                AuthData::RegistrationToken(token_auth)
+            }
+            _ => {
+                let mut dummy_auth = uiaa::Dummy::new();
+                dummy_auth.session = uiaa_info.session.clone();
+                AuthData::Dummy(dummy_auth)
+            }
+        });
+    };
+    let (Some(access_token), Some(device_id)) = (register_result.access_token, register_result.device_id) else {
+        return Err(Error::Login(String::from("Homeserver registered the account without logging it in.")));
+    };
+
+    save_new_session(client, session_store, Session {
+        user_id: register_result.user_id,
+        profile,
+        alias: None,
+        is_default: false,
+        device_id,
+        access_token,
+        refresh_token: register_result.refresh_token,
+        secrets_in_store: false,
+        store_encrypted: false,
+        connection_options,
+    }).await
+}
+
+// Syncs just enough for exports and room listings to work from, i.e. each joined room's state, with members lazy-loaded and without presence or receipts. Events get fetched separately through /messages anyway, so only a few recent ones per room come along, for the event cache to keep for offline exports. Much quicker than a full initial sync for accounts in lots of rooms; later syncs are incremental either way.
+pub async fn light_sync(client: &Client) -> Result<()> {
+    let mut filter = FilterDefinition::default();
+    filter.presence = Filter::ignore_all();
+    filter.room.ephemeral = RoomEventFilter::ignore_all();
+    filter.room.timeline.limit = Some(UInt::from(10_u32));
+    filter.room.state.lazy_load_options = LazyLoadOptions::Enabled {
+        include_redundant_members: false,
+    };
+    client.sync_once(SyncSettings::new().filter(SyncFilter::FilterDefinition(filter)).set_presence(PresenceState::Offline)).await?;
+
+    Ok(())
+}
+
+// Makes a fresh initial sync of just the given rooms' ephemeral events and room account data of the given types (or all of it, given None), without any state or timeline. Incremental syncs only carry whatever changed since the last one, so this is the only way to get at all of it as it currently stands, e.g. every member's latest read receipt. It goes around the SDK's sync handling, leaving the SDK's own sync position alone.
+pub(crate) async fn initial_sync_of_rooms(client: &Client, room_ids: Vec<OwnedRoomId>, ephemeral_types: Vec<String>, account_data_types: Option<Vec<String>>) -> Result<BTreeMap<OwnedRoomId, JoinedRoom>> {
+    if room_ids.is_empty() {
+        return Ok(BTreeMap::new())
+    }
+    let mut filter = FilterDefinition::default();
+    filter.presence = Filter::ignore_all();
+    filter.account_data = Filter::ignore_all();
+    filter.room.rooms = Some(room_ids);
+    filter.room.timeline = RoomEventFilter::ignore_all();
+    filter.room.state = RoomEventFilter::ignore_all();
+    filter.room.ephemeral.types = Some(ephemeral_types);
+    filter.room.account_data.types = account_data_types;
+    let mut request = SyncRequest::new();
+    request.filter = Some(SyncFilter::FilterDefinition(filter));
+    request.set_presence = PresenceState::Offline;
+    let response = client.send(request).await?;
+
+    Ok(response.rooms.join)
+}
+
+// Logs a soft-logged-out session back into the same device, keeping its encryption keys and verification.
+#[instrument(skip_all, fields(user_id = %user_id, profile = ?profile))]
+pub async fn resume_session(session_store: &mut dyn SessionStore, user_id: &str, profile: Option<&str>, store_path: &Path, password: &str) -> Result<()> {
+    let Some(session) = session_store.get(user_id, profile)? else {
+        return Err(Error::SessionNotFound(session_key(user_id, profile)));
+    };
+    let client = build_client(&session.user_id, &session.connection_options, store_path, session_store_passphrase(&session, session_store)?).await?;
+    let login_result = client.matrix_auth().login_username(&session.user_id, password).device_id(session.device_id.as_str()).request_refresh_token().send().await?;
+    session_store.update_tokens(user_id, profile, login_result.access_token, login_result.refresh_token)?;
 
     Ok(())
 }
 
-pub async fn logout_full(client: &Client, sessions_file: &mut SessionsFile, store_path: &Path) -> anyhow::Result<()> {
+pub async fn logout_full(client: &Client, profile: Option<&str>, session_store: &mut dyn SessionStore, store_path: &Path) -> Result<()> {
     client.matrix_auth().logout().await?;
     remove_dir_all(store_path)?;
     let store_path_parent = store_path.parent().unwrap();
     if store_path_parent.read_dir()?.next().is_none() {
         remove_dir_all(store_path_parent)?;
     }
-    sessions_file.delete_session(client.user_id().unwrap().as_ref()).unwrap();
+    session_store.delete(client.user_id().unwrap().as_ref(), profile)?;
 
     Ok(())
 }
 
-pub fn logout_local(user_id: &str, sessions_file: &mut SessionsFile, store_path: &Path) -> anyhow::Result<()> {
+pub fn logout_local(user_id: &str, profile: Option<&str>, session_store: &mut dyn SessionStore, store_path: &Path) -> Result<()> {
     remove_dir_all(store_path)?;
     let store_path_parent = store_path.parent().unwrap();
     if store_path_parent.read_dir()?.next().is_none() {
         remove_dir_all(store_path_parent)?;
     }
-    sessions_file.delete_session(user_id).unwrap();
+    session_store.delete(user_id, profile)?;
 
     Ok(())
 }
 
-pub async fn list_sessions(sessions_file: &SessionsFile, dirs: &ProjectDirs) -> anyhow::Result<Vec<(String, String)>> {
-    let mut sessions_info = join_all(sessions_file.sessions.iter().map(|session| async {
-        let store_path = PathBuf::from(dirs.data_local_dir()).join(user_id_to_crypto_store_path(&session.user_id));
-        let client = nonfirst_login(&session.user_id, sessions_file, &store_path).await?;
-        let device_list = client.devices().await?.devices;
-        let device_name = device_list.into_iter().find(|device| device.device_id == session.device_id).unwrap().display_name.unwrap_or_else(|| String::from("[Unnamed]"));
-        anyhow::Result::<(String, String)>::Ok((session.user_id.clone(), device_name))
-    })).await.into_iter().collect::<anyhow::Result<Vec<(String, String)>, _>>()?;
-    sessions_info.sort_by(|(user_id_1, _display_name_1), (user_id_2, _display_name_2)| user_id_1.cmp(user_id_2)); // sort_by_key doesn't work here for weird lifetime reasons
+// Pairs each session with its device's display name.
+pub async fn list_sessions(session_store: &dyn SessionStore, data_dir: &Path) -> Result<Vec<(Session, String)>> {
+    let mut sessions_info = join_all(session_store.list()?.into_iter().map(|session| async move {
+        let store_path = data_dir.join(user_id_to_crypto_store_path(session.user_id.as_str(), session.profile.as_deref()));
+        let client = nonfirst_login(session.user_id.as_str(), session.profile.as_deref(), session_store, &store_path).await?;
+        let device_list = list_devices(&client).await?;
+        let device_name = device_list.into_iter().find(|device| device.device_id == session.device_id).and_then(|device| device.display_name).unwrap_or_else(|| String::from("[Unnamed]"));
+        Result::<(Session, String)>::Ok((session, device_name))
+    })).await.into_iter().collect::<Result<Vec<(Session, String)>, _>>()?;
+    sessions_info.sort_by(|(session_1, _display_name_1), (session_2, _display_name_2)| (&session_1.user_id, &session_1.profile).cmp(&(&session_2.user_id, &session_2.profile))); // sort_by_key doesn't work here for weird lifetime reasons
 
     Ok(sessions_info)
 }
 
-pub async fn rename_session(client: &Client, new_session_name: &str) -> anyhow::Result<()> {
+// Every device logged into the client's account, this one included.
+pub async fn list_devices(client: &Client) -> Result<Vec<Device>> {
+    Ok(client.devices().await?.devices)
+}
+
+// Homeservers generally want the account's password before deleting devices, so password only gets called on if the homeserver asks for it.
+pub async fn delete_devices(client: &Client, device_ids: &[OwnedDeviceId], password: impl FnOnce() -> Result<String>) -> Result<()> {
+    if let Some(own_device_id) = client.device_id() {
+        if device_ids.iter().any(|device_id| device_id == own_device_id) {
+            return Err(Error::Other(anyhow::anyhow!("Tried to delete the session's own device {}. Log out of the session instead.", own_device_id)));
+        }
+    }
+    let Err(e) = client.delete_devices(device_ids, None).await else {
+        return Ok(())
+    };
+    let Some(uiaa_info) = e.as_uiaa_response() else {
+        return Err(e.into())
+    };
+    client.delete_devices(device_ids, Some(uiaa_password_auth(client, uiaa_info, password, "delete devices")?)).await?;
+
+    Ok(())
+}
+
+// The client should come from nonfirst_login, which has already caught sessions the homeserver no longer accepts the tokens of; the whoami here is asked again so that other failures to reach the homeserver show up as errors rather than being passed over.
+pub async fn session_status(client: &Client, store_path: &Path) -> Result<SessionStatus> {
+    let whoami = client.send(whoami::v3::Request::new()).await?;
+    let encryption = client.encryption();
+    let device_verified = match encryption.get_own_device().await? {
+        Some(device) => device.is_verified(),
+        None => false,
+    };
+    let cross_signing_set_up = match encryption.cross_signing_status().await {
+        Some(status) => status.is_complete(),
+        None => false,
+    };
+
+    Ok(SessionStatus {
+        whoami_user_id: whoami.user_id.to_string(),
+        whoami_device_id: whoami.device_id.map(|device_id| device_id.to_string()),
+        device_verified,
+        cross_signing_set_up,
+        backup_enabled: encryption.backups().are_enabled().await,
+        store_path: store_path.to_path_buf(),
+        store_size: dir_size(store_path)?,
+    })
+}
+
+// Verifies the session through the account's secret storage, for when there's no other device around to verify with interactively. Takes either the recovery key or the passphrase it was set up with. Cross-signing keys come out of secret storage along with the key backup's key, after which the session's device gets signed as verified, and whatever room keys are backed up get downloaded for the account's joined rooms.
+pub async fn recover_session(client: &Client, recovery_key: &str) -> Result<()> {
+    client.encryption().recovery().recover(recovery_key).await?;
+    restore_key_backup(client).await?;
+
+    Ok(())
+}
+
+// Sets up server-side backup of room keys, along with secret storage to keep the backup's key in, and uploads the session's room keys to it. Returns the recovery key for getting at both from elsewhere (e.g. with recover_session), which needs keeping somewhere safe, since it's not kept anywhere else.
+pub async fn enable_key_backup(client: &Client) -> Result<String> {
+    let encryption = client.encryption();
+    if encryption.backups().exists_on_server().await? {
+        return Err(Error::Other(anyhow::anyhow!("Account already has a key backup on the homeserver. Restore from it with the account's recovery key instead.")));
+    }
+    let recovery_key = encryption.recovery().enable().wait_for_backups_to_upload().await?;
+
+    Ok(recovery_key)
+}
+
+// Downloads whatever room keys are backed up for the account's joined and left rooms, for decrypting history from before the session existed. Needs the backup's key already, i.e. the session should either have enabled the backup itself or have been recovered with recover_session.
+pub async fn restore_key_backup(client: &Client) -> Result<()> {
+    let backups = client.encryption().backups();
+    if !backups.are_enabled().await {
+        return Err(Error::Other(anyhow::anyhow!("Session has no key backup to restore from. Recover it with the account's recovery key first.")));
+    }
+
+    light_sync(client).await?;
+    // Left rooms' history can still be exported, so their keys are wanted too
+    for room in client.joined_rooms().into_iter().chain(client.left_rooms()) {
+        backups.download_room_keys_for_room(room.room_id()).await?;
+    }
+
+    Ok(())
+}
+
+// Writes all the session's room keys to a file in the passphrase-encrypted format Element and other clients use for key exports, for importing there or into other Trace sessions.
+pub async fn export_room_keys(client: &Client, destination: &Path, passphrase: &str) -> Result<()> {
+    client.encryption().export_room_keys(destination.to_path_buf(), passphrase, |_| true).await?;
+
+    Ok(())
+}
+
+// Takes room keys from a file exported by Element or another client, or by export_room_keys. Returns how many keys were new to the session, out of how many were in the file.
+pub async fn import_room_keys(client: &Client, source: &Path, passphrase: &str) -> Result<(usize, usize)> {
+    let result = client.encryption().import_room_keys(source.to_path_buf(), passphrase).await.map_err(anyhow::Error::from)?;
+
+    Ok((result.imported_count, result.total_count))
+}
+
+// Creates cross-signing keys for an account without any, signing the session's device with them, or with reset set, replaces the account's existing ones, which leaves every device and user verified with the old ones unverified. As with delete_devices, password only gets called on if the homeserver asks for it.
+pub async fn bootstrap_cross_signing(client: &Client, reset: bool, password: impl FnOnce() -> Result<String>) -> Result<()> {
+    let encryption = client.encryption();
+    if !reset {
+        // Asked of the homeserver rather than the local store, which won't know of keys set up from another client since the session last synced them
+        if encryption.request_user_identity(client.user_id().unwrap()).await?.is_some() {
+            return Err(Error::Other(anyhow::anyhow!("Account already has cross-signing keys. Verify the session with them instead, or reset them to replace them.")));
+        }
+        let Err(e) = encryption.bootstrap_cross_signing(None).await else {
+            return Ok(())
+        };
+        let Some(uiaa_info) = e.as_uiaa_response() else {
+            return Err(e.into())
+        };
+        encryption.bootstrap_cross_signing(Some(uiaa_password_auth(client, uiaa_info, password, "set up cross-signing")?)).await?;
+    } else if let Some(reset_handle) = encryption.reset_cross_signing().await? {
+        let CrossSigningResetAuthType::Uiaa(uiaa_info) = reset_handle.auth_type() else {
+            return Err(Error::Other(anyhow::anyhow!("Homeserver requires its account management page to be used to reset cross-signing, which isn't supported yet.")));
+        };
+        reset_handle.auth(Some(uiaa_password_auth(client, uiaa_info, password, "reset cross-signing")?)).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn rename_session(client: &Client, new_session_name: &str) -> Result<()> {
     client.rename_device(client.device_id().unwrap(), new_session_name).await?;
 
     Ok(())
 }
 
-pub async fn get_rooms_info(client: &Client) -> anyhow::Result<Vec<RoomWithCachedInfo>> {
-    let mut rooms_info = client.joined_rooms().into_iter().map(|room| RoomWithCachedInfo {
-        id: room.room_id().to_owned(),
-        name: room.name(),
-        canonical_alias: room.canonical_alias(),
-        alt_aliases: room.alt_aliases(),
-        room,
-    }).collect::<Vec<RoomWithCachedInfo>>();
+// Bundles the session (tokens included) and its local store, room keys and cross-signing state and all, into a single file encrypted with the passphrase, for moving it to another machine with import_session. The session shouldn't be used from here again afterwards, since two copies of the same device fall out of step with each other's encryption state.
+pub fn export_session(session_store: &dyn SessionStore, user_id: &str, profile: Option<&str>, store_path: &Path, destination: &Path, passphrase: &str) -> Result<()> {
+    let normalized_user_id = add_at_to_user_id_if_applicable(user_id);
+    let Some(session) = session_store.get(&normalized_user_id, profile)? else {
+        return Err(Error::SessionNotFound(session_key(&normalized_user_id, profile)));
+    };
+    let session_json = serde_json::to_vec(&session)?;
+
+    let encrypted_output = age::Encryptor::with_user_passphrase(SecretString::from(passphrase.to_owned())).wrap_output(File::create(destination)?)?;
+    let mut bundle = tar::Builder::new(encrypted_output);
+    let mut session_header = tar::Header::new_gnu();
+    session_header.set_size(session_json.len() as u64);
+    session_header.set_mode(0o600);
+    session_header.set_cksum();
+    bundle.append_data(&mut session_header, "session.json", session_json.as_slice())?;
+    bundle.append_dir_all("store", store_path)?;
+    bundle.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+// Restores a session bundled by export_session, under the profile given or else the one it was bundled from. Sessions whose stores were encrypted need the same passphrase for them here as they had before.
+pub fn import_session(session_store: &mut dyn SessionStore, data_dir: &Path, source: &Path, passphrase: &str, profile: Option<String>) -> Result<Session> {
+    let decryptor = age::Decryptor::new(File::open(source)?).map_err(anyhow::Error::from)?;
+    let identity = age::scrypt::Identity::new(SecretString::from(passphrase.to_owned()));
+    let decrypted_input = decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity)).map_err(|e| Error::Other(anyhow::anyhow!("Couldn't decrypt session bundle due to error '{}'. Is the passphrase correct?", e)))?;
+
+    // Unpacked alongside the stores first, so that a bundle which turns out to be unusable doesn't leave anything half-imported
+    let unpack_path = data_dir.join(format!("import.{}.tmp", std::process::id()));
+    let import_result = (|| {
+        tar::Archive::new(decrypted_input).unpack(&unpack_path)?;
+        let mut session: Session = serde_json::from_slice(&read(unpack_path.join("session.json"))?)?;
+        if profile.is_some() {
+            session.profile = profile;
+        }
+        let session_key = session_key(session.user_id.as_str(), session.profile.as_deref());
+        if session_store.get(session.user_id.as_str(), session.profile.as_deref())?.is_some() {
+            return Err(Error::SessionExists(session_key));
+        }
+        if session.store_encrypted && session_store.store_passphrase().is_none() {
+            return Err(Error::Other(anyhow::anyhow!("Session for {} has an encrypted store, but no passphrase for it was given.", session_key)));
+        }
+        let store_path = data_dir.join(user_id_to_crypto_store_path(session.user_id.as_str(), session.profile.as_deref()));
+        if store_path.exists() {
+            return Err(Error::Other(anyhow::anyhow!("Tried to import session for {}, but there's already a store for it at {}.", session_key, store_path.display())));
+        }
+        create_dir_all(store_path.parent().unwrap())?;
+        rename(unpack_path.join("store"), &store_path)?;
+        session.secrets_in_store = false; // Whether they end up in a secret store here is up to this session store
+        session.alias = None; // Could clash with this session store's existing aliases
+        session.is_default = false;
+        if let Err(e) = session_store.insert(session.clone()) {
+            remove_dir_all(&store_path)?;
+            return Err(e)
+        }
+
+        Ok(session)
+    })();
+    if unpack_path.exists() {
+        remove_dir_all(&unpack_path)?;
+    }
+
+    import_result
+}
+
+pub async fn get_rooms_info(client: &Client) -> Result<Vec<RoomWithCachedInfo>> {
+    let mut rooms_info = client.joined_rooms().into_iter().map(RoomWithCachedInfo::from_room).collect::<Vec<RoomWithCachedInfo>>();
     rooms_info.sort_by(|room_1, room_2| match (&room_1.name, &room_2.name) {
         (Some(name_1), Some(name_2)) => name_1.cmp(name_2),
         (Some(_name), None) => Ordering::Greater,
@@ -254,3 +1196,108 @@ pub async fn get_rooms_info(client: &Client) -> anyhow::Result<Vec<RoomWithCache
 
     Ok(rooms_info)
 }
+
+// The rooms (and subspaces) directly within one of the account's joined spaces, identified as for export. Children whose m.space.child events have no via servers have been taken out of the space, so they're left out here too.
+pub async fn get_space_child_ids(client: &Client, space_identifier: &str) -> Result<Vec<OwnedRoomId>> {
+    let rooms_info = get_rooms_info(client).await?;
+    let space_index = export::get_room_index_by_identifier(&rooms_info, space_identifier).map_err(|e| e.into_error(client, space_identifier))?;
+    let space = &rooms_info[space_index].room;
+    if !space.is_space() {
+        return Err(Error::Other(anyhow::anyhow!("{} isn't a space.", space_identifier)))
+    }
+
+    space_child_ids(space).await
+}
+
+// Every joined space that isn't itself within another joined space, along with everything beneath it. Rooms and subspaces the account hasn't joined still show up, by ID alone, but their own children can't be seen.
+// Spaces within each other in a cycle have no such top-level space, so the first of each cycle not already reached is taken as a top-level space itself.
+pub async fn get_space_hierarchy(client: &Client) -> Result<Vec<SpaceHierarchyNode>> {
+    let rooms_info = get_rooms_info(client).await?;
+    let mut child_ids_by_space = HashMap::new();
+    for room_info in rooms_info.iter().filter(|room_info| room_info.is_space) {
+        child_ids_by_space.insert(room_info.id.clone(), space_child_ids(&room_info.room).await?);
+    }
+    let nested_space_ids = child_ids_by_space.values().flatten().filter(|child_id| child_ids_by_space.contains_key(*child_id)).cloned().collect::<HashSet<OwnedRoomId>>();
+    let rooms_info_by_id = rooms_info.iter().map(|room_info| (room_info.id.clone(), room_info)).collect::<HashMap<OwnedRoomId, &RoomWithCachedInfo>>();
+
+    let mut visited_ids = HashSet::new();
+    let mut hierarchy = rooms_info.iter()
+        .filter(|room_info| room_info.is_space && !nested_space_ids.contains(&room_info.id))
+        .map(|room_info| space_hierarchy_node(&room_info.id, &rooms_info_by_id, &child_ids_by_space, &mut Vec::new(), &mut visited_ids))
+        .collect::<Vec<SpaceHierarchyNode>>();
+    for room_info in rooms_info.iter().filter(|room_info| room_info.is_space) {
+        if !visited_ids.contains(&room_info.id) {
+            hierarchy.push(space_hierarchy_node(&room_info.id, &rooms_info_by_id, &child_ids_by_space, &mut Vec::new(), &mut visited_ids));
+        }
+    }
+
+    Ok(hierarchy)
+}
+
+// When the room last saw an event, going by the newest one the homeserver hands back. That's a request per room, so this is best left to callers that actually need it.
+pub async fn get_latest_event_timestamp_millis(room: &Room) -> Result<Option<i64>> {
+    let mut messages_options = MessagesOptions::backward();
+    messages_options.limit = UInt::from(1u32);
+    let messages = retry_rate_limited(DEFAULT_MAX_RETRIES, || async { Ok(room.messages(messages_options.clone()).await?) }).await?;
+
+    Ok(messages.chunk.first().and_then(|event| event.raw().get_field::<i64>("origin_server_ts").ok().flatten()))
+}
+
+// Likewise, for each of the given rooms, in the same order, with only a few requests going at once. Rooms whose latest event couldn't be fetched come back as None, rather than failing the rest.
+pub async fn get_latest_event_timestamps_millis(rooms_info: &[RoomWithCachedInfo]) -> Vec<Option<i64>> {
+    stream::iter(rooms_info).map(|room_info| async move {
+        get_latest_event_timestamp_millis(&room_info.room).await.unwrap_or_else(|e| {
+            warn!("Couldn't fetch the latest event in room {} due to error '{}'.", room_info.id, e);
+            None
+        })
+    }).buffered(LATEST_EVENT_FETCH_JOBS).collect().await
+}
+
+pub async fn list_invites(client: &Client) -> Result<Vec<RoomInvite>> {
+    let mut invites = Vec::new();
+    for room in client.invited_rooms() {
+        let invite_details = room.invite_details().await?;
+        invites.push(RoomInvite {
+            room_id: room.room_id().to_owned(),
+            name: room.name(),
+            canonical_alias: room.canonical_alias(),
+            inviter: invite_details.inviter.map(|inviter| inviter.user_id().to_owned()),
+        });
+    }
+
+    Ok(invites)
+}
+
+// Returns the ID of the room joined.
+pub async fn accept_invite(client: &Client, identifier: &str) -> Result<OwnedRoomId> {
+    let room = find_invited_room(client, identifier)?;
+    room.join().await?;
+
+    Ok(room.room_id().to_owned())
+}
+
+// Returns the ID of the room whose invite got declined.
+pub async fn decline_invite(client: &Client, identifier: &str) -> Result<OwnedRoomId> {
+    let room = find_invited_room(client, identifier)?;
+    room.leave().await?;
+
+    Ok(room.room_id().to_owned())
+}
+
+// Works for rooms the account has been invited to as well as public ones. The via servers are for room IDs, which say nothing about how to reach the room, so the homeserver may need telling which servers to ask when it isn't in the room already; aliases resolve to servers by themselves.
+pub async fn join_room(client: &Client, identifier: &str, via: &[OwnedServerName]) -> Result<OwnedRoomId> {
+    let room_or_alias_id = OwnedRoomOrAliasId::try_from(identifier)?;
+    let room = client.join_room_by_id_or_alias(&room_or_alias_id, via).await?;
+
+    Ok(room.room_id().to_owned())
+}
+
+// Rooms are identified as for export. Returns the ID of the room left.
+pub async fn leave_room(client: &Client, identifier: &str) -> Result<OwnedRoomId> {
+    let rooms_info = get_rooms_info(client).await?;
+    let room_index = export::get_room_index_by_identifier(&rooms_info, identifier).map_err(|e| e.into_error(client, identifier))?;
+    let room = &rooms_info[room_index].room;
+    room.leave().await?;
+
+    Ok(room.room_id().to_owned())
+}
@@ -0,0 +1,359 @@
+use std::collections::{
+    hash_map::Entry,
+    HashMap,
+    HashSet,
+};
+use std::fs::{
+    create_dir_all,
+    read,
+    read_dir,
+    read_to_string,
+    rename,
+    write,
+};
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+
+use crate::{
+    retry::retry_rate_limited,
+    Error,
+    ExportProgress,
+    Result,
+    RoomWithCachedInfo,
+};
+
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    media::{
+        MediaFormat,
+        MediaRequestParameters,
+    },
+    ruma::{
+        events::{
+            room::{
+                message::MessageType,
+                MediaSource,
+            },
+            AnySyncMessageLikeEvent,
+            AnySyncTimelineEvent,
+        },
+        MxcUri,
+        OwnedUserId,
+        RoomId,
+    },
+    Client,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+pub(crate) const MEDIA_MANIFEST_FILENAME: &str = "manifest.json";
+
+///////////////
+//   Types   //
+///////////////
+
+pub enum MediaProblemKind {
+    Missing,
+    HashMismatch,
+    Unrecorded, // Present, but with no hash in the manifest to check it against
+    InvalidPath, // The media_file field points somewhere other than directly inside the media directory
+}
+
+pub struct MediaProblem {
+    pub media_file: String, // Relative to the export directory, so that problems in nested exports can be told apart
+    pub event_id: Option<String>,
+    pub kind: MediaProblemKind,
+    source: Option<MediaSource>,
+    media_path: Option<PathBuf>, // None for invalid paths, which never get written to
+}
+
+impl MediaProblem {
+    pub fn is_repairable(&self) -> bool {
+        self.source.is_some() && self.media_path.is_some()
+    }
+}
+
+pub struct MediaRepairFailure {
+    pub media_file: String,
+    pub error: Error,
+}
+
+pub struct MediaRepairReport {
+    pub repaired_count: usize,
+    pub failures: Vec<MediaRepairFailure>, // Only for problems which had a source to redownload from
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+// Server names can carry ports, and IPv6 addresses in brackets, neither of which Windows allows in filenames, so anything but letters, digits, '.', and '-' is swapped out.
+pub fn mxc_uri_to_filename(mxc_uri: &MxcUri) -> Result<String> {
+    let (server_name, media_id) = mxc_uri.parts().map_err(anyhow::Error::from)?;
+    let sanitize = |part: &str| part.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect::<String>();
+    Ok(format!("{}_{}", sanitize(server_name.as_str()), sanitize(media_id)))
+}
+
+// Exports only ever point at files directly inside their media directories, so media_file fields pointing anywhere else (at an absolute path, say, or up out of the export through '..') are taken to be doctored, and come back as None rather than getting followed. Otherwise returns the filename within the media directory.
+pub(crate) fn media_file_to_filename(media_file: &str) -> Option<&str> {
+    let media_filename = media_file.strip_prefix("media/")?;
+    let mut components = Path::new(media_filename).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Some(media_filename),
+        _ => None,
+    }
+}
+
+pub fn media_source_mxc_uri(source: &MediaSource) -> &MxcUri {
+    match source {
+        MediaSource::Plain(mxc_uri) => mxc_uri,
+        MediaSource::Encrypted(file) => &file.url,
+    }
+}
+
+pub(crate) fn sha256_hex(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+// The manifest maps filenames within the media directory to the SHA-256 hashes of their contents as downloaded, for later integrity-checking.
+fn read_media_manifest(media_dir: &Path) -> Result<HashMap<String, String>> {
+    match read_to_string(media_dir.join(MEDIA_MANIFEST_FILENAME)) {
+        Ok(file) => Ok(serde_json::from_str(&file)?),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+fn write_media_manifest(media_dir: &Path, manifest: &HashMap<String, String>) -> Result<()> {
+    write(media_dir.join(MEDIA_MANIFEST_FILENAME), serde_json::to_string_pretty(manifest)?)?;
+
+    Ok(())
+}
+
+pub fn event_media_source(event: &TimelineEvent) -> Option<MediaSource> {
+    sync_event_media_source(&event.raw().deserialize().ok()?)
+}
+
+fn sync_event_media_source(event: &AnySyncTimelineEvent) -> Option<MediaSource> {
+    match event {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(e)) => match &e.as_original()?.content.msgtype {
+            MessageType::Audio(e) => Some(e.source.clone()),
+            MessageType::File(e) => Some(e.source.clone()),
+            MessageType::Image(e) => Some(e.source.clone()),
+            MessageType::Video(e) => Some(e.source.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Returns the SHA-256 hash of the downloaded content. Rate-limited downloads are retried up to max_retries times.
+// Media directories and avatar directories hold no exports of their own, so they're skipped.
+fn verify_media_in_dir(export_dir: &Path, dir: &Path, problems: &mut Vec<MediaProblem>) -> Result<()> {
+    let media_dir = dir.join("media");
+    let manifest = read_media_manifest(&media_dir)?;
+    let mut checked_media_files = HashSet::new();
+
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|dir_name| dir_name != "media" && dir_name != "avatars") {
+                verify_media_in_dir(export_dir, &path, problems)?;
+            }
+            continue
+        }
+        if !path.is_file() || path.extension().is_none_or(|extension| extension != "json") {
+            continue
+        }
+        let export: serde_json::Value = serde_json::from_str(&read_to_string(&path)?)?;
+        let events = match export {
+            serde_json::Value::Array(events) => events, // Exports from before the room header was added
+            mut export => match export.get_mut("events").map(serde_json::Value::take) {
+                Some(serde_json::Value::Array(events)) => events,
+                _ => continue,
+            },
+        };
+        for event in events {
+            let Some(media_file) = event.get("media_file").and_then(|media_file| media_file.as_str()) else {
+                continue
+            };
+            if !checked_media_files.insert(media_file.to_owned()) {
+                continue
+            }
+
+            let media_filename = media_file_to_filename(media_file);
+            let media_path = media_filename.map(|media_filename| media_dir.join(media_filename));
+            let kind = match (media_filename, &media_path) {
+                (Some(media_filename), Some(media_path)) if media_path.exists() => match manifest.get(media_filename) {
+                    Some(expected_hash) if *expected_hash != sha256_hex(&read(media_path)?) => MediaProblemKind::HashMismatch,
+                    Some(_) => continue,
+                    None => MediaProblemKind::Unrecorded,
+                },
+                (Some(_), _) => MediaProblemKind::Missing,
+                (None, _) => MediaProblemKind::InvalidPath,
+            };
+            problems.push(MediaProblem {
+                media_file: dir.strip_prefix(export_dir).unwrap_or(dir).join(media_file).display().to_string(),
+                event_id: event.get("event_id").and_then(|event_id| event_id.as_str()).map(String::from),
+                kind,
+                source: serde_json::from_value::<AnySyncTimelineEvent>(event.clone()).ok().and_then(|event| sync_event_media_source(&event)),
+                media_path,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn download_media_source_to_path(client: &Client, source: &MediaSource, path: &Path, max_retries: u32) -> Result<String> {
+    let request = MediaRequestParameters {
+        source: source.clone(),
+        format: MediaFormat::File,
+    };
+    let content = retry_rate_limited(max_retries, || async { Ok(client.media().get_media_content(&request, false).await?) }).await?; // Encrypted sources are decrypted here, so the stored file is always plaintext
+    // Written to a temporary file and moved into place, so that an interrupted write never leaves a partial file where later exports would take it for a complete one
+    let mut temporary_filename = path.file_name().unwrap_or_default().to_owned();
+    temporary_filename.push(".part");
+    let temporary_path = path.with_file_name(temporary_filename);
+    write(&temporary_path, &content)?;
+    rename(temporary_path, path)?;
+
+    Ok(sha256_hex(&content))
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Returns a map from sender user IDs to their avatars' paths relative to the export directory. Avatars already present in avatars_dir aren't redownloaded, so each one is only fetched once per export even when it's shared across rooms. Avatars which fail to download are reported through progress and left out.
+pub async fn download_sender_avatars(client: &Client, room_info: &RoomWithCachedInfo, events: &[TimelineEvent], avatars_dir: &Path, max_retries: u32, progress: &dyn Fn(ExportProgress)) -> Result<HashMap<String, String>> {
+    let mut sender_avatars = HashMap::new();
+    let mut seen_senders = HashSet::new();
+
+    for event in events {
+        let Ok(Some(sender)) = event.raw().get_field::<OwnedUserId>("sender") else {
+            continue
+        };
+        if !seen_senders.insert(sender.clone()) {
+            continue
+        }
+
+        let Some(room_member) = room_info.room.get_member_no_sync(&sender).await? else {
+            continue
+        };
+        let Some(avatar_url) = room_member.avatar_url() else {
+            continue
+        };
+        let avatar_filename = mxc_uri_to_filename(avatar_url)?;
+        let avatar_path = avatars_dir.join(&avatar_filename);
+        if !avatar_path.exists() {
+            match download_media_source_to_path(client, &MediaSource::Plain(avatar_url.to_owned()), &avatar_path, max_retries).await {
+                Ok(_) => progress(ExportProgress::MediaDownloaded {
+                    room_id: room_info.id.clone(),
+                }),
+                Err(e) => {
+                    progress(ExportProgress::MediaSkipped {
+                        room_id: room_info.id.clone(),
+                        description: format!("Couldn't download avatar {} for {} due to error '{}'", avatar_url, sender, e),
+                    });
+                    continue
+                }
+            }
+        }
+        sender_avatars.insert(sender.to_string(), format!("avatars/{}", avatar_filename));
+    }
+
+    Ok(sender_avatars)
+}
+
+// Returns a map from event IDs to their attachments' paths relative to the export directory. Files are keyed by MXC ID in a single store shared by every room in the export, so media reposted across rooms is only downloaded and stored once. As with avatars, failed downloads are reported through progress, under the room the events are from.
+pub async fn download_event_media(client: &Client, room_id: &RoomId, events: &[TimelineEvent], media_dir: &Path, max_retries: u32, progress: &dyn Fn(ExportProgress)) -> Result<HashMap<String, String>> {
+    let mut event_media = HashMap::new();
+    let mut manifest = read_media_manifest(media_dir)?;
+
+    for event in events {
+        let Some(event_id) = event.event_id() else {
+            continue
+        };
+        let Some(source) = event_media_source(event) else {
+            continue
+        };
+        let mxc_uri = media_source_mxc_uri(&source);
+        let media_filename = mxc_uri_to_filename(mxc_uri)?;
+        let media_path = media_dir.join(&media_filename);
+        if !media_path.exists() {
+            match download_media_source_to_path(client, &source, &media_path, max_retries).await {
+                Ok(hash) => {
+                    manifest.insert(media_filename.clone(), hash);
+                    progress(ExportProgress::MediaDownloaded {
+                        room_id: room_id.to_owned(),
+                    });
+                }
+                Err(e) => {
+                    progress(ExportProgress::MediaSkipped {
+                        room_id: room_id.to_owned(),
+                        description: format!("Couldn't download media {} from event {} due to error '{}'", mxc_uri, event_id, e),
+                    });
+                    continue
+                }
+            }
+        }
+        event_media.insert(event_id.to_string(), format!("media/{}", media_filename));
+    }
+    write_media_manifest(media_dir, &manifest)?;
+
+    Ok(event_media)
+}
+
+// Cross-references the media_file fields of an existing export's JSON files against its media directory, reporting media which is missing, whose contents no longer match the hash recorded at download time, or which has no hash recorded to check. Exports nested in subdirectories (e.g. one per job) get checked against their own media directories.
+pub fn verify_media(export_dir: &Path) -> Result<Vec<MediaProblem>> {
+    let mut problems = Vec::new();
+    verify_media_in_dir(export_dir, export_dir, &mut problems)?;
+
+    Ok(problems)
+}
+
+// Problems come from verify_media, which has already resolved where each file belongs, so nothing gets written outside the export's media directories.
+pub async fn repair_media(client: &Client, problems: &[MediaProblem], max_retries: u32) -> Result<MediaRepairReport> {
+    let mut manifests_by_media_dir = HashMap::new();
+    let mut repaired_count = 0;
+    let mut failures = Vec::new();
+
+    for problem in problems {
+        let (Some(source), Some(media_path)) = (&problem.source, &problem.media_path) else {
+            continue
+        };
+        let (Some(media_dir), Some(media_filename)) = (media_path.parent(), media_path.file_name().and_then(|media_filename| media_filename.to_str())) else {
+            continue
+        };
+        let manifest = match manifests_by_media_dir.entry(media_dir.to_owned()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                create_dir_all(media_dir)?;
+                entry.insert(read_media_manifest(media_dir)?)
+            }
+        };
+        match download_media_source_to_path(client, source, media_path, max_retries).await {
+            Ok(hash) => {
+                manifest.insert(String::from(media_filename), hash);
+                repaired_count += 1;
+            }
+            Err(error) => failures.push(MediaRepairFailure {
+                media_file: problem.media_file.clone(),
+                error,
+            }),
+        }
+    }
+    for (media_dir, manifest) in manifests_by_media_dir {
+        write_media_manifest(&media_dir, &manifest)?;
+    }
+
+    Ok(MediaRepairReport {
+        repaired_count,
+        failures,
+    })
+}
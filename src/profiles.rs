@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::fs::{
+    create_dir_all,
+    read_to_string,
+    write,
+};
+use std::path::PathBuf;
+
+use crate::Result;
+
+use chrono::{
+    Duration,
+    Utc,
+};
+use matrix_sdk::ruma::{
+    OwnedMxcUri,
+    OwnedRoomId,
+    OwnedUserId,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+///////////////
+//   Types   //
+///////////////
+
+// A sender's display name and avatar as of fetched_at_millis. These are per-room, since Matrix lets people set different ones in each room.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SenderProfile {
+    pub display_name: Option<String>,
+    pub avatar_url: Option<OwnedMxcUri>,
+    pub fetched_at_millis: i64,
+}
+
+// Keeps sender profiles between runs, so that rooms with thousands of members don't need all of them looked up again on every export. Profiles older than the max age given on opening are dropped, to be looked up afresh.
+pub struct ProfileCacheFile {
+    path: PathBuf,
+    pub rooms: HashMap<OwnedRoomId, HashMap<OwnedUserId, SenderProfile>>,
+}
+
+impl ProfileCacheFile {
+    pub fn open(path: PathBuf, max_age: Duration) -> Result<Self> {
+        let mut rooms: HashMap<OwnedRoomId, HashMap<OwnedUserId, SenderProfile>> = match read_to_string(&path) {
+            Ok(file) => serde_json::from_str(&file)?,
+            Err(_) => HashMap::new(),
+        };
+        let oldest_fresh_millis = (Utc::now() - max_age).timestamp_millis();
+        for sender_profiles in rooms.values_mut() {
+            sender_profiles.retain(|_user_id, sender_profile| sender_profile.fetched_at_millis >= oldest_fresh_millis);
+        }
+        rooms.retain(|_room_id, sender_profiles| !sender_profiles.is_empty());
+
+        Ok(Self {
+            path,
+            rooms,
+        })
+    }
+
+    pub fn write(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&self.path, serde_json::to_string(&self.rooms)?)?;
+
+        Ok(())
+    }
+}
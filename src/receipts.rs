@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use crate::initial_sync_of_rooms;
+
+use matrix_sdk::{
+    ruma::{
+        OwnedEventId,
+        OwnedRoomId,
+        OwnedUserId,
+    },
+    Client,
+};
+use serde::Deserialize;
+
+///////////////
+//   Types   //
+///////////////
+
+#[derive(Clone)]
+pub(crate) struct ReadReceipt {
+    pub(crate) user_id: OwnedUserId,
+    pub(crate) receipt_type: String, // 'm.read', or 'm.read.private' for the account's own private receipts
+    pub(crate) timestamp_millis: Option<i64>,
+    pub(crate) thread_id: Option<String>, // 'main' for the main timeline, as sent by thread-aware clients, or else a thread root's ID
+}
+
+// How far each member of a room has read, as of now. Homeservers only keep each member's latest receipt (per thread), so there's no record of how reading went along the way.
+#[derive(Clone, Default)]
+pub(crate) struct RoomReadReceipts {
+    pub(crate) receipts_by_event: HashMap<OwnedEventId, Vec<ReadReceipt>>,
+    pub(crate) read_marker: Option<OwnedEventId>, // The account's own fully-read marker
+}
+
+#[derive(Deserialize)]
+struct ReceiptEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(default)]
+    content: HashMap<OwnedEventId, HashMap<String, HashMap<OwnedUserId, ReceiptFields>>>, // Event to receipt type to user
+}
+
+#[derive(Deserialize)]
+struct ReceiptFields {
+    ts: Option<i64>,
+    thread_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FullyReadEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    content: Option<FullyReadContent>,
+}
+
+#[derive(Deserialize)]
+struct FullyReadContent {
+    event_id: OwnedEventId,
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Receipts only ever come through syncs, so this makes a fresh one of its own for just the given rooms' receipts and read markers.
+pub(crate) async fn get_read_receipts(client: &Client, room_ids: Vec<OwnedRoomId>) -> anyhow::Result<HashMap<OwnedRoomId, RoomReadReceipts>> {
+    let joined_rooms = initial_sync_of_rooms(client, room_ids, vec![String::from("m.receipt")], Some(vec![String::from("m.fully_read")])).await?;
+    let mut read_receipts = HashMap::new();
+    for (room_id, joined_room) in joined_rooms {
+        let mut room_read_receipts = RoomReadReceipts::default();
+        for event in joined_room.ephemeral.events {
+            let Ok(event) = serde_json::from_str::<ReceiptEvent>(event.json().get()) else {
+                continue
+            };
+            if event.event_type != "m.receipt" {
+                continue
+            }
+            for (event_id, receipts_by_type) in event.content {
+                for (receipt_type, receipts_by_user) in receipts_by_type {
+                    for (user_id, receipt) in receipts_by_user {
+                        room_read_receipts.receipts_by_event.entry(event_id.clone()).or_default().push(ReadReceipt {
+                            user_id,
+                            receipt_type: receipt_type.clone(),
+                            timestamp_millis: receipt.ts,
+                            thread_id: receipt.thread_id,
+                        });
+                    }
+                }
+            }
+        }
+        for event in joined_room.account_data.events {
+            if let Ok(FullyReadEvent { event_type, content: Some(content) }) = serde_json::from_str(event.json().get()) {
+                if event_type == "m.fully_read" {
+                    room_read_receipts.read_marker = Some(content.event_id);
+                }
+            }
+        }
+        // Earliest first, so that they read in the order people got there
+        for receipts in room_read_receipts.receipts_by_event.values_mut() {
+            receipts.sort_by_key(|receipt| receipt.timestamp_millis);
+        }
+        read_receipts.insert(room_id, room_read_receipts);
+    }
+
+    Ok(read_receipts)
+}
@@ -0,0 +1,174 @@
+use crate::{
+    export::{
+        get_room_index_by_identifier,
+        EventPager,
+        EventSource,
+        ExportEventRange,
+        PaginationOptions,
+    },
+    get_rooms_info,
+    retry::retry_rate_limited,
+    Error,
+    Result,
+};
+
+use matrix_sdk::{
+    ruma::{
+        OwnedEventId,
+        OwnedRoomId,
+        OwnedUserId,
+        RoomId,
+    },
+    Client,
+};
+use regex::Regex;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+///////////////
+//   Types   //
+///////////////
+
+// Which events to redact. Everything given has to match, and leaving something out matches anything.
+#[derive(Default)]
+pub struct RedactionFilter {
+    pub sender: Option<OwnedUserId>, // Redacting anyone else's events needs the power to
+    pub since_millis: Option<i64>, // Inclusive at both ends
+    pub until_millis: Option<i64>,
+    pub pattern: Option<Regex>, // Matched against message bodies, so events without them (or which couldn't be decrypted) never match one
+}
+
+#[derive(Serialize)]
+pub struct RedactableEvent {
+    pub event_id: OwnedEventId,
+    pub event_type: String,
+    pub sender: OwnedUserId,
+    pub timestamp_millis: i64,
+    pub body: Option<String>,
+}
+
+pub struct RedactionCandidates {
+    pub room_id: OwnedRoomId,
+    pub room_name: Option<String>,
+    pub events: Vec<RedactableEvent>, // Newest first
+}
+
+pub struct RedactionFailure {
+    pub event_id: OwnedEventId,
+    pub error: Error,
+}
+
+pub struct RedactionReport {
+    pub redacted_count: usize,
+    pub failures: Vec<RedactionFailure>,
+}
+
+// Just the parts of an event that deciding whether to redact it needs.
+#[derive(Deserialize)]
+struct EventFields {
+    #[serde(rename = "type")]
+    event_type: String,
+    event_id: OwnedEventId,
+    sender: OwnedUserId,
+    origin_server_ts: i64,
+    state_key: Option<serde_json::Value>,
+    #[serde(default)]
+    content: serde_json::Map<String, serde_json::Value>,
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Paginates back through a joined room from the present, collecting the events which match the filter, for redact_events to redact once they've been looked over. State events are never included, since redacting them can break the room (e.g. by stripping its join rules), and nor are redactions or events that are already redacted. Pagination stops at the first page entirely older than since_millis.
+pub async fn find_redactable_events(client: &Client, room: &str, filter: &RedactionFilter, pagination_options: PaginationOptions) -> Result<RedactionCandidates> {
+    let accessible_rooms_info = get_rooms_info(client).await?;
+    let room_info = match get_room_index_by_identifier(&accessible_rooms_info, room) {
+        Ok(room_index) => &accessible_rooms_info[room_index],
+        Err(e) => return Err(e.into_error(client, room)),
+    };
+    let own_user_id = client.user_id().map(|user_id| user_id.to_owned());
+    if let (true, Some(own_user_id)) = (filter.sender != own_user_id, &own_user_id) {
+        if !room_info.room.can_user_redact_other(own_user_id).await? {
+            return Err(Error::InvalidRedaction(format!("{} doesn't have the power to redact other people's events in {}.", own_user_id, room_info.id)));
+        }
+    }
+
+    let pagination_options = PaginationOptions {
+        newest_first: true,
+        ..pagination_options
+    };
+    let mut event_pager = EventPager::new(EventSource::Joined(&room_info.room), &ExportEventRange::default(), &pagination_options);
+    let mut events = Vec::new();
+    while let Some(page) = event_pager.next_page().await? {
+        let mut page_is_older = true;
+        for event in page {
+            let Ok(event) = serde_json::from_str::<EventFields>(event.raw().json().get()) else {
+                continue
+            };
+            if filter.since_millis.is_none_or(|since_millis| event.origin_server_ts >= since_millis) {
+                page_is_older = false;
+            }
+            if event.state_key.is_some() || event.event_type == "m.room.redaction" || event.content.is_empty() {
+                continue
+            }
+            let body = event.content.get("body").and_then(|body| body.as_str()).map(String::from);
+            let matches = filter.sender.as_ref().is_none_or(|sender| *sender == event.sender)
+                && filter.since_millis.is_none_or(|since_millis| event.origin_server_ts >= since_millis)
+                && filter.until_millis.is_none_or(|until_millis| event.origin_server_ts <= until_millis)
+                && filter.pattern.as_ref().is_none_or(|pattern| body.as_deref().is_some_and(|body| pattern.is_match(body)));
+            if matches {
+                events.push(RedactableEvent {
+                    event_id: event.event_id,
+                    event_type: event.event_type,
+                    sender: event.sender,
+                    timestamp_millis: event.origin_server_ts,
+                    body,
+                });
+            }
+        }
+        if page_is_older {
+            break
+        }
+    }
+    // Missing part of the room here would mean leaving matching events unredacted while reporting success
+    event_pager.ensure_no_fetch_failures()?;
+
+    Ok(RedactionCandidates {
+        room_id: room_info.id.clone(),
+        room_name: room_info.name.clone(),
+        events,
+    })
+}
+
+// Redacts each event in turn, carrying on past any that fail. Redactions are rate-limited harder than most requests on most homeservers, so big batches take a while.
+pub async fn redact_events(client: &Client, room_id: &RoomId, event_ids: Vec<OwnedEventId>, reason: Option<&str>, max_retries: u32, progress: &dyn Fn(usize)) -> Result<RedactionReport> {
+    let Some(room) = client.get_room(room_id) else {
+        return Err(Error::RoomNotFound {
+            user_id: client.user_id().map(|user_id| user_id.to_string()).unwrap_or_default(),
+            identifier: room_id.to_string(),
+        })
+    };
+    let mut redacted_count = 0;
+    let mut failures = Vec::new();
+    for event_id in event_ids {
+        let redaction = retry_rate_limited(max_retries, || async { Ok(room.redact(&event_id, reason, None).await?) }).await;
+        match redaction {
+            Ok(_) => {
+                redacted_count += 1;
+                progress(redacted_count);
+            }
+            Err(e) => failures.push(RedactionFailure {
+                event_id,
+                error: e.into(),
+            }),
+        }
+    }
+
+    Ok(RedactionReport {
+        redacted_count,
+        failures,
+    })
+}
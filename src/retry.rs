@@ -0,0 +1,90 @@
+use std::future::Future;
+use std::time::{
+    Duration,
+    SystemTime,
+};
+
+use matrix_sdk::{
+    ruma::api::client::error::{
+        ErrorKind,
+        RetryAfter,
+    },
+    HttpError,
+};
+use tracing::warn;
+
+// Used when exports aren't told otherwise. Big exports from matrix.org tend to get rate-limited a handful of times over, so this errs on the generous side.
+pub const DEFAULT_MAX_RETRIES: u32 = 8;
+
+// Backoff doubles from here with each retry, for responses which don't say how long to wait.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(120);
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+// Returns None if the error isn't a rate-limit, or Some with however long the homeserver asked to be given before the next request, if it said.
+fn rate_limit_retry_after(error: &anyhow::Error) -> Option<Option<Duration>> {
+    let api_error = match (error.downcast_ref::<matrix_sdk::Error>(), error.downcast_ref::<HttpError>()) {
+        (Some(e), _) => e.as_client_api_error(),
+        (_, Some(e)) => e.as_client_api_error(),
+        _ => None,
+    }?;
+    match api_error.error_kind() {
+        Some(ErrorKind::LimitExceeded { retry_after }) => Some(retry_after.as_ref().map(|retry_after| match retry_after {
+            RetryAfter::Delay(delay) => *delay,
+            RetryAfter::DateTime(time) => time.duration_since(SystemTime::now()).unwrap_or_default(),
+        })),
+        _ if api_error.status_code.as_u16() == 429 => Some(None), // Some servers and proxies rate-limit without an M_LIMIT_EXCEEDED body
+        _ => None,
+    }
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Whether the error's likely to go away by itself, like a dropped connection, a timeout, or the homeserver struggling, as opposed to one which would just come back again, like a forbidden room, a bad event ID, or a rejected token.
+pub(crate) fn is_transient(error: &anyhow::Error) -> bool {
+    if rate_limit_retry_after(error).is_some() || error.downcast_ref::<reqwest::Error>().is_some() {
+        return true
+    }
+    let http_error: &HttpError = match (error.downcast_ref::<matrix_sdk::Error>(), error.downcast_ref::<HttpError>()) {
+        (Some(matrix_sdk::Error::Http(e)), _) => e,
+        (_, Some(e)) => e,
+        _ => return false,
+    };
+    match http_error.as_client_api_error() {
+        Some(api_error) => api_error.status_code.is_server_error(),
+        None => true, // No response came back to go by
+    }
+}
+
+// Reruns the request whenever it fails due to rate-limiting, up to max_retries times, waiting as long as the homeserver asks or else backing off exponentially. Other errors are returned immediately.
+pub(crate) async fn retry_rate_limited<T, F, Fut>(max_retries: u32, mut request: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retry_count = 0;
+    loop {
+        let e = match request().await {
+            Ok(response) => return Ok(response),
+            Err(e) => e,
+        };
+        let Some(retry_after) = rate_limit_retry_after(&e) else {
+            return Err(e)
+        };
+        if retry_count >= max_retries {
+            return Err(e.context(format!("Still rate-limited after {} retries.", max_retries)))
+        }
+
+        let delay = retry_after.unwrap_or(backoff).min(MAX_BACKOFF);
+        warn!("Rate-limited by homeserver. Retrying in {} seconds ({} of {}).", delay.as_secs_f32().ceil(), retry_count + 1, max_retries);
+        tokio::time::sleep(delay).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        retry_count += 1;
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use crate::{
+    initial_sync_of_rooms,
+    retry::retry_rate_limited,
+};
+
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    ruma::{
+        OwnedEventId,
+        OwnedRoomId,
+        UInt,
+    },
+    Client,
+    Room,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+///////////////
+//   Types   //
+///////////////
+
+// The parts of a room which live outside its timeline: its pinned events, and the account's own account data for it (e.g. its tags and read marker).
+#[derive(Clone, Default)]
+pub(crate) struct RoomData {
+    pub(crate) pinned_events: Vec<PinnedEvent>, // In the order the room lists them
+    pub(crate) account_data: serde_json::Map<String, serde_json::Value>, // Content by event type
+}
+
+#[derive(Clone)]
+pub(crate) struct PinnedEvent {
+    pub(crate) event_id: OwnedEventId,
+    pub(crate) event: Option<TimelineEvent>, // None if it couldn't be fetched, e.g. for having been purged from the homeserver
+}
+
+#[derive(Deserialize)]
+struct AccountDataEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    content: serde_json::Value,
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Pins only hold event IDs, so each gets fetched in turn. Ones which can't be get left unresolved rather than failing the export, since pins often outlive what they point at.
+pub(crate) async fn get_pinned_events(room: &Room, max_retries: u32) -> Vec<PinnedEvent> {
+    let mut pinned_events = Vec::new();
+    for event_id in room.pinned_event_ids().unwrap_or_default() {
+        let event = match retry_rate_limited(max_retries, || async { Ok(room.event_with_context(&event_id, true, UInt::MIN, None).await?) }).await {
+            Ok(event_context) => event_context.event,
+            Err(e) => {
+                warn!("Couldn't fetch pinned event {} in room {} due to error '{}'.", event_id, room.room_id(), e);
+                None
+            }
+        };
+        pinned_events.push(PinnedEvent {
+            event_id,
+            event,
+        });
+    }
+    pinned_events
+}
+
+// Account data comes through syncs too, so this makes a fresh one for just the given rooms' account data, of every type.
+pub(crate) async fn get_room_account_data(client: &Client, room_ids: Vec<OwnedRoomId>) -> anyhow::Result<HashMap<OwnedRoomId, serde_json::Map<String, serde_json::Value>>> {
+    let joined_rooms = initial_sync_of_rooms(client, room_ids, Vec::new(), None).await?;
+    Ok(joined_rooms.into_iter().map(|(room_id, joined_room)| {
+        let account_data = joined_room.account_data.events.iter().filter_map(|event| serde_json::from_str::<AccountDataEvent>(event.json().get()).ok()).map(|event| (event.event_type, event.content)).collect();
+        (room_id, account_data)
+    }).collect())
+}
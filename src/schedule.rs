@@ -0,0 +1,228 @@
+use chrono::{
+    DateTime,
+    Datelike,
+    NaiveDate,
+    TimeDelta,
+    TimeZone,
+    Timelike,
+};
+
+use crate::{
+    Error,
+    Result,
+};
+
+const SEARCHED_YEARS: i32 = 8; // Long enough for even the rarest schedules to come round again, since the 29th of February can go 8 years without doing so
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const DAY_OF_WEEK_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+///////////////
+//   Types   //
+///////////////
+
+// When to run something, as in a crontab: minute, hour, day of month, month, and day of week, each of which can be '*', a value, a range like '1-5', a step like '*/15' or '0-30/10', or a comma-separated list of those. Months and days of the week can also be given by their three-letter English names. The usual shorthands (@hourly, @daily, @weekly, @monthly, and @yearly) work too.
+pub struct Schedule {
+    minutes: u64, // Bitmasks of the values each field matches
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64, // Sunday is 0 (or 7, as given)
+    days_of_month_restricted: bool, // As in cron, days matching either day field count when both are restricted, rather than only days matching both
+    days_of_week_restricted: bool,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields = expression.split_whitespace().collect::<Vec<&str>>();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(Error::InvalidSchedule(format!("Schedule '{}' has {} fields, rather than the 5 expected (minute, hour, day of month, month, and day of week).", expression, fields.len())))
+        };
+        let field_error = |field_name: &str, reason: String| Error::InvalidSchedule(format!("Schedule '{}' has an invalid {} field: {}.", expression, field_name, reason));
+        let mut days_of_week_mask = parse_field(days_of_week, 0, 7, &DAY_OF_WEEK_NAMES).map_err(|reason| field_error("day of week", reason))?;
+        if days_of_week_mask & (1 << 7) != 0 {
+            days_of_week_mask |= 1; // Sunday, either way
+        }
+
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59, &[]).map_err(|reason| field_error("minute", reason))?,
+            hours: parse_field(hours, 0, 23, &[]).map_err(|reason| field_error("hour", reason))?,
+            days_of_month: parse_field(days_of_month, 1, 31, &[]).map_err(|reason| field_error("day of month", reason))?,
+            months: parse_field(months, 1, 12, &MONTH_NAMES).map_err(|reason| field_error("month", reason))?,
+            days_of_week: days_of_week_mask,
+            days_of_month_restricted: !days_of_month.starts_with('*'),
+            days_of_week_restricted: !days_of_week.starts_with('*'),
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let day_of_month_matches = self.days_of_month & (1 << date.day()) != 0;
+        let day_of_week_matches = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+        let day_matches = match (self.days_of_month_restricted, self.days_of_week_restricted) {
+            (true, true) => day_of_month_matches || day_of_week_matches,
+            _ => day_of_month_matches && day_of_week_matches,
+        };
+        self.months & (1 << date.month()) != 0 && day_matches
+    }
+
+    // The first time the schedule matches strictly after the given one, in the given one's time zone. Times skipped over by daylight saving changes are passed over, and times repeated by them only count the first time round. None if nothing matches, as with '0 0 31 2 *'.
+    pub fn next_after<Z: TimeZone>(&self, after: &DateTime<Z>) -> Option<DateTime<Z>> {
+        let timezone = after.timezone();
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + TimeDelta::minutes(1);
+        let mut date = start.date();
+        while date.year() <= start.year() + SEARCHED_YEARS {
+            if self.matches_date(date) {
+                let first_minute_of_day = match date == start.date() {
+                    true => start.hour() * 60 + start.minute(),
+                    false => 0,
+                };
+                for minute_of_day in first_minute_of_day..24 * 60 {
+                    let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                    if self.hours & (1 << hour) == 0 || self.minutes & (1 << minute) == 0 {
+                        continue
+                    }
+                    if let Some(datetime) = timezone.from_local_datetime(&date.and_hms_opt(hour, minute, 0)?).earliest() {
+                        if datetime > *after {
+                            return Some(datetime)
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn parse_value(value: &str, min: u32, max: u32, names: &[&str]) -> std::result::Result<u32, String> {
+    let value = match names.iter().position(|name| name.eq_ignore_ascii_case(value)) {
+        Some(name_index) => min + name_index as u32,
+        None => value.parse::<u32>().map_err(|_| format!("'{}' isn't a number", value))?,
+    };
+    match (min..=max).contains(&value) {
+        true => Ok(value),
+        false => Err(format!("{} is outside of {}-{}", value, min, max)),
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> std::result::Result<u64, String> {
+    let mut mask = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(|| format!("'{}' isn't a valid step", step))?)),
+            None => (item, None),
+        };
+        let (start, end) = match (range, range.split_once('-'), step) {
+            ("*", _, _) => (min, max),
+            (_, Some((start, end)), _) => (parse_value(start, min, max, names)?, parse_value(end, min, max, names)?),
+            (_, None, Some(_)) => (parse_value(range, min, max, names)?, max), // As in '5/15', meaning every 15 from 5 on
+            (_, None, None) => {
+                let value = parse_value(range, min, max, names)?;
+                (value, value)
+            }
+        };
+        if start > end {
+            return Err(format!("range {} runs backwards", range))
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+///////////////
+//   Tests   //
+///////////////
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use chrono_tz::Europe::London;
+
+    use super::*;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_ranges_steps_lists_and_names() {
+        let schedule = Schedule::parse("0-30/10,45 9-17 * jan,mar-apr mon-fri").unwrap();
+        assert_eq!(schedule.minutes, 1 | (1 << 10) | (1 << 20) | (1 << 30) | (1 << 45));
+        assert_eq!(schedule.hours, (9..=17).fold(0, |mask, hour| mask | (1 << hour)));
+        assert_eq!(schedule.months, (1 << 1) | (1 << 3) | (1 << 4));
+        assert_eq!(schedule.days_of_week, (1..=5).fold(0, |mask, day| mask | (1 << day)));
+        assert!(!schedule.days_of_month_restricted);
+        assert!(schedule.days_of_week_restricted);
+
+        assert_eq!(Schedule::parse("5/20 * * * *").unwrap().minutes, (1 << 5) | (1 << 25) | (1 << 45));
+        assert_eq!(Schedule::parse("0 0 * * 7").unwrap().days_of_week & 1, 1); // Sunday as 7
+    }
+
+    #[test]
+    fn expands_shorthands() {
+        let schedule = Schedule::parse("@weekly").unwrap();
+        assert_eq!(schedule.minutes, 1);
+        assert_eq!(schedule.hours, 1);
+        assert_eq!(schedule.days_of_week, 1);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for expression in ["* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "5-1 * * * *", "*/0 * * * *", "* * * foo *", "a * * * *"] {
+            assert!(matches!(Schedule::parse(expression), Err(Error::InvalidSchedule(_))), "{} should be rejected", expression);
+        }
+    }
+
+    #[test]
+    fn next_after_is_strictly_after() {
+        let schedule = Schedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(schedule.next_after(&Utc.with_ymd_and_hms(2026, 1, 1, 12, 7, 30).unwrap()), Some(utc(2026, 1, 1, 12, 15)));
+        assert_eq!(schedule.next_after(&utc(2026, 1, 1, 12, 15)), Some(utc(2026, 1, 1, 12, 30)));
+        assert_eq!(schedule.next_after(&utc(2026, 12, 31, 23, 45)), Some(utc(2027, 1, 1, 0, 0)));
+    }
+
+    #[test]
+    fn matches_either_day_field_when_both_are_restricted() {
+        // The 1st of January 2026 is a Thursday, so the next Monday (the 5th) comes before the next 1st of the month
+        assert_eq!(Schedule::parse("0 0 1 * mon").unwrap().next_after(&utc(2026, 1, 1, 0, 0)), Some(utc(2026, 1, 5, 0, 0)));
+        // With only one day field restricted, that one alone decides, and the 1st of February 2026 is a Sunday
+        assert_eq!(Schedule::parse("0 0 * 2 mon").unwrap().next_after(&utc(2026, 1, 1, 0, 0)), Some(utc(2026, 2, 2, 0, 0)));
+        assert_eq!(Schedule::parse("0 0 13 * *").unwrap().next_after(&utc(2026, 1, 1, 0, 0)), Some(utc(2026, 1, 13, 0, 0)));
+    }
+
+    #[test]
+    fn finds_rare_and_impossible_dates() {
+        assert_eq!(Schedule::parse("0 0 29 2 *").unwrap().next_after(&utc(2026, 3, 1, 0, 0)), Some(utc(2028, 2, 29, 0, 0)));
+        assert_eq!(Schedule::parse("0 0 31 2 *").unwrap().next_after(&utc(2026, 3, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn passes_over_skipped_times_and_repeated_ones_after_the_first() {
+        let schedule = Schedule::parse("30 1 * * *").unwrap();
+
+        // 01:00 to 01:59 doesn't happen in London on the 29th of March 2026, when clocks go forward
+        let next = schedule.next_after(&London.with_ymd_and_hms(2026, 3, 28, 12, 0, 0).unwrap()).unwrap();
+        assert_eq!(next.with_timezone(&Utc), utc(2026, 3, 30, 0, 30));
+
+        // And happens twice on the 25th of October 2026, when they go back, first in BST and then in GMT
+        let next = schedule.next_after(&London.with_ymd_and_hms(2026, 10, 25, 0, 0, 0).unwrap()).unwrap();
+        assert_eq!(next.with_timezone(&Utc), utc(2026, 10, 25, 0, 30));
+        let next = schedule.next_after(&next).unwrap();
+        assert_eq!(next.with_timezone(&Utc), utc(2026, 10, 26, 1, 30));
+    }
+}
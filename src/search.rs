@@ -0,0 +1,187 @@
+use crate::{
+    analyze::resolve_joined_rooms,
+    export::{
+        EventPager,
+        EventSource,
+        ExportEventRange,
+        PaginationOptions,
+        RoomResolution,
+    },
+    get_rooms_info,
+    retry::retry_rate_limited,
+    Result,
+    RoomWithCachedInfo,
+};
+
+use matrix_sdk::{
+    ruma::{
+        api::client::search::search_events::v3::{
+            Categories,
+            Criteria,
+            Request as SearchRequest,
+            SearchKeys,
+        },
+        OwnedEventId,
+        OwnedRoomId,
+        OwnedUserId,
+    },
+    Client,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tracing::{
+    debug,
+    warn,
+};
+
+///////////////
+//   Types   //
+///////////////
+
+#[derive(Serialize)]
+pub struct SearchMatch {
+    pub room_id: OwnedRoomId,
+    pub room_name: Option<String>,
+    pub event_id: OwnedEventId,
+    pub sender: OwnedUserId,
+    pub timestamp_millis: i64,
+    pub body: String,
+    pub permalink: String, // matrix.to link to the event
+}
+
+pub struct SearchReport {
+    pub room_resolutions: Vec<RoomResolution>, // As for export, minus the DM partners and regexes
+    pub matches: Vec<SearchMatch>, // Oldest first
+}
+
+// Just the parts of an m.room.message event that searching needs, whether it came from /search or from pagination. (Events from /search carry their room IDs; paginated ones don't, but their rooms are known anyway.)
+#[derive(Deserialize)]
+struct MessageFields {
+    #[serde(rename = "type")]
+    event_type: String,
+    room_id: Option<OwnedRoomId>,
+    event_id: OwnedEventId,
+    sender: OwnedUserId,
+    origin_server_ts: i64,
+    content: MessageContentFields,
+}
+
+#[derive(Deserialize)]
+struct MessageContentFields {
+    body: Option<String>, // Missing from redacted messages
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn message_to_match(room_info: &RoomWithCachedInfo, message: MessageFields) -> Option<SearchMatch> {
+    if message.event_type != "m.room.message" {
+        return None
+    }
+
+    Some(SearchMatch {
+        permalink: room_info.id.matrix_to_event_uri(message.event_id.clone()).to_string(),
+        room_id: room_info.id.clone(),
+        room_name: room_info.name.clone(),
+        event_id: message.event_id,
+        sender: message.sender,
+        timestamp_millis: message.origin_server_ts,
+        body: message.content.body?,
+    })
+}
+
+// Homeservers can't see into encrypted rooms, so this only gets used for unencrypted ones. Matching is up to the homeserver, which generally means full-text search rather than plain substrings.
+async fn server_side_search(client: &Client, query: &str, rooms_info: &[&RoomWithCachedInfo], max_retries: u32) -> Result<Vec<SearchMatch>> {
+    let mut criteria = Criteria::new(query.to_owned());
+    criteria.filter.rooms = Some(rooms_info.iter().map(|room_info| room_info.id.clone()).collect());
+    criteria.keys = Some(vec![SearchKeys::ContentBody]);
+    let mut categories = Categories::new();
+    categories.room_events = Some(criteria);
+
+    let mut matches = Vec::new();
+    let mut next_batch = None;
+    loop {
+        let mut request = SearchRequest::new(categories.clone());
+        request.next_batch = next_batch;
+        let response = retry_rate_limited(max_retries, || async { Ok(client.send(request.clone()).await?) }).await?;
+        for result in response.search_categories.room_events.results {
+            let Some(event) = result.result else {
+                continue
+            };
+            let Ok(message) = serde_json::from_str::<MessageFields>(event.json().get()) else {
+                continue
+            };
+            let Some(room_info) = message.room_id.as_ref().and_then(|room_id| rooms_info.iter().find(|room_info| &room_info.id == room_id)) else {
+                continue
+            };
+            matches.extend(message_to_match(room_info, message));
+        }
+        next_batch = response.search_categories.room_events.next_batch;
+        if next_batch.is_none() {
+            break
+        }
+    }
+
+    Ok(matches)
+}
+
+// Case-insensitive substring matching, over whatever pagination turns up, decrypted events included.
+async fn local_search(query: &str, room_info: &RoomWithCachedInfo, pagination_options: &PaginationOptions) -> Result<Vec<SearchMatch>> {
+    let query = query.to_lowercase();
+    let mut matches = Vec::new();
+    let mut event_pager = EventPager::new(EventSource::Joined(&room_info.room), &ExportEventRange::default(), pagination_options);
+    while let Some(page) = event_pager.next_page().await? {
+        for event in page {
+            let Ok(message) = serde_json::from_str::<MessageFields>(event.raw().json().get()) else {
+                continue
+            };
+            if message.content.body.as_ref().is_some_and(|body| body.to_lowercase().contains(&query)) {
+                matches.extend(message_to_match(room_info, message));
+            }
+        }
+    }
+    event_pager.ensure_no_fetch_failures()?;
+
+    Ok(matches)
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Searches message bodies across the given rooms, identified as for export, or across every joined room if none are given. Unencrypted rooms go through the homeserver's /search where it's supported; encrypted ones, and unencrypted ones on homeservers without working search, get paginated through and matched locally, which takes as long as exporting them would.
+pub async fn search(client: &Client, query: &str, rooms: Vec<String>, pagination_options: PaginationOptions) -> Result<SearchReport> {
+    let accessible_rooms_info = get_rooms_info(client).await?;
+    let (room_indices, room_resolutions) = match rooms.is_empty() {
+        true => ((0..accessible_rooms_info.len()).collect(), Vec::new()),
+        false => resolve_joined_rooms(client, &accessible_rooms_info, rooms),
+    };
+
+    let (encrypted_rooms_info, unencrypted_rooms_info) = room_indices.into_iter()
+        .map(|room_index| &accessible_rooms_info[room_index])
+        .partition::<Vec<&RoomWithCachedInfo>, _>(|room_info| room_info.is_encrypted);
+    let mut locally_searched_rooms_info = encrypted_rooms_info;
+    let mut matches = Vec::new();
+    if !unencrypted_rooms_info.is_empty() {
+        match server_side_search(client, query, &unencrypted_rooms_info, pagination_options.max_retries).await {
+            Ok(server_matches) => matches.extend(server_matches),
+            Err(e) => {
+                warn!("Couldn't search on the homeserver due to error '{}'. Searching unencrypted rooms locally instead.", e);
+                locally_searched_rooms_info.extend(unencrypted_rooms_info);
+            }
+        }
+    }
+    for room_info in locally_searched_rooms_info {
+        debug!(room_id = %room_info.id, "Searching room locally");
+        matches.extend(local_search(query, room_info, &pagination_options).await?);
+    }
+    matches.sort_by_key(|search_match| search_match.timestamp_millis);
+
+    Ok(SearchReport {
+        room_resolutions,
+        matches,
+    })
+}
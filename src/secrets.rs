@@ -0,0 +1,63 @@
+use crate::Result;
+
+use keyring::Entry;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+// Keyring entries are filed under this service name, with the session key (see crate::session_key) as the account name.
+const KEYRING_SERVICE: &str = "trace";
+
+///////////////
+//   Types   //
+///////////////
+
+#[derive(Deserialize, Serialize)]
+pub struct SessionSecrets {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+}
+
+// Somewhere to keep sessions' tokens other than the plaintext sessions file, for the sessions file to defer to. Implement this to keep them in a secret manager of your own.
+pub trait SecretStore: Send + Sync {
+    fn load(&self, session_key: &str) -> Result<Option<SessionSecrets>>;
+    fn save(&self, session_key: &str, secrets: &SessionSecrets) -> Result<()>;
+    fn delete(&self, session_key: &str) -> Result<()>;
+}
+
+// The platform's own keyring, i.e. Secret Service on Linux, Keychain on macOS, and Credential Manager on Windows.
+pub struct KeyringSecretStore;
+
+impl KeyringSecretStore {
+    // Some systems (e.g. headless Linux ones without a Secret Service provider running) have no keyring to use.
+    pub fn is_available() -> bool {
+        match Entry::new(KEYRING_SERVICE, "availability-check").and_then(|entry| entry.get_password()) {
+            Ok(_) | Err(keyring::Error::NoEntry) => true,
+            Err(_) => false,
+        }
+    }
+}
+
+impl SecretStore for KeyringSecretStore {
+    fn load(&self, session_key: &str) -> Result<Option<SessionSecrets>> {
+        match Entry::new(KEYRING_SERVICE, session_key)?.get_password() {
+            Ok(secrets) => Ok(Some(serde_json::from_str(&secrets)?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, session_key: &str, secrets: &SessionSecrets) -> Result<()> {
+        Entry::new(KEYRING_SERVICE, session_key)?.set_password(&serde_json::to_string(secrets)?)?;
+
+        Ok(())
+    }
+
+    fn delete(&self, session_key: &str) -> Result<()> {
+        match Entry::new(KEYRING_SERVICE, session_key)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use matrix_sdk::{
+    ruma::{
+        api::Direction,
+        events::AnyTimelineEvent,
+        serde::Raw,
+        EventId,
+        OwnedRoomAliasId,
+        RoomId,
+    },
+    Client,
+};
+use reqwest::StatusCode;
+use serde::{
+    de::DeserializeOwned,
+    Deserialize,
+};
+
+///////////////
+//   Types   //
+///////////////
+
+// What Synapse knows about a room, whether or not anyone on the server is still in it.
+#[derive(Clone, Deserialize)]
+pub(crate) struct AdminRoomDetails {
+    pub(crate) name: Option<String>,
+    pub(crate) canonical_alias: Option<OwnedRoomAliasId>,
+    pub(crate) topic: Option<String>,
+    pub(crate) joined_members: Option<u64>,
+    pub(crate) encryption: Option<String>, // The algorithm, if the room's encrypted
+}
+
+// A page of events, as from the client-server API's /messages.
+#[derive(Deserialize)]
+pub(crate) struct AdminMessages {
+    pub(crate) chunk: Vec<Raw<AnyTimelineEvent>>,
+    pub(crate) end: Option<String>, // None once there's nothing further to paginate to
+}
+
+// An event along with pagination tokens either side of it, as from the client-server API's /context.
+#[derive(Deserialize)]
+pub(crate) struct AdminEventContext {
+    pub(crate) event: Raw<AnyTimelineEvent>,
+    pub(crate) start: Option<String>, // Toward the past
+    pub(crate) end: Option<String>, // Toward the present
+}
+
+#[derive(Deserialize)]
+struct AdminStatus {
+    admin: bool,
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+// The admin API lives outside the client-server API, so its requests go through the SDK's HTTP client (to keep any proxy and certificate settings) by hand, rather than through Client::send. That also means rate-limiting goes unretried, but Synapse doesn't rate-limit the admin API anyway.
+async fn send_admin_request(client: &Client, path_segments: &[&str], query: &[(&str, String)]) -> anyhow::Result<reqwest::Response> {
+    let Some(access_token) = client.access_token() else {
+        anyhow::bail!("Can't use the Synapse admin API without being logged in.");
+    };
+    let mut url = client.homeserver();
+    url.path_segments_mut().map_err(|_| anyhow::anyhow!("Homeserver URL {} can't have a path appended to it.", client.homeserver()))?.pop_if_empty().extend(["_synapse", "admin", "v1"]).extend(path_segments);
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    Ok(client.http_client().get(url).bearer_auth(access_token).send().await?)
+}
+
+async fn admin_request<T: DeserializeOwned>(client: &Client, path_segments: &[&str], query: &[(&str, String)]) -> anyhow::Result<T> {
+    let response = send_admin_request(client, path_segments, query).await?;
+    if !response.status().is_success() {
+        let (url, status) = (response.url().clone(), response.status());
+        anyhow::bail!("Synapse admin API request to {} failed with status {}: {}", url, status, response.text().await.unwrap_or_default());
+    }
+    Ok(response.json().await?)
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Whether the logged-in account is a server admin, which the rest of the admin API needs it to be. Synapse forbids non-admins from asking, so that counts as a no.
+pub(crate) async fn is_server_admin(client: &Client) -> anyhow::Result<bool> {
+    let Some(user_id) = client.user_id() else {
+        return Ok(false)
+    };
+    let response = send_admin_request(client, &["users", user_id.as_str(), "admin"], &[]).await?;
+    match response.status() {
+        StatusCode::FORBIDDEN => Ok(false),
+        status if status.is_success() => Ok(response.json::<AdminStatus>().await?.admin),
+        status => anyhow::bail!("Couldn't check whether {} is a server admin, since the Synapse admin API responded with status {}: {}", user_id, status, response.text().await.unwrap_or_default()),
+    }
+}
+
+pub(crate) async fn get_admin_room_details(client: &Client, room_id: &RoomId) -> anyhow::Result<AdminRoomDetails> {
+    admin_request(client, &["rooms", room_id.as_str()], &[]).await
+}
+
+// Unlike /messages, this ignores history visibility and membership altogether, so it covers everything the server has of the room.
+pub(crate) async fn get_admin_room_messages(client: &Client, room_id: &RoomId, from: Option<&str>, direction: Direction, limit: u16) -> anyhow::Result<AdminMessages> {
+    let mut query = vec![
+        ("dir", String::from(match direction {
+            Direction::Backward => "b",
+            Direction::Forward => "f",
+        })),
+        ("limit", limit.to_string()),
+    ];
+    if let Some(from) = from {
+        query.push(("from", String::from(from)));
+    }
+    admin_request(client, &["rooms", room_id.as_str(), "messages"], &query).await
+}
+
+pub(crate) async fn get_admin_event_context(client: &Client, room_id: &RoomId, event_id: &EventId) -> anyhow::Result<AdminEventContext> {
+    admin_request(client, &["rooms", room_id.as_str(), "context", event_id.as_str()], &[("limit", String::from("0"))]).await
+}
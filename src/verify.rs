@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::fs::{
+    read,
+    read_dir,
+    read_to_string,
+    rename,
+    write,
+};
+use std::path::Path;
+
+use crate::{
+    media::sha256_hex,
+    Result,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+pub(crate) const EXPORT_MANIFEST_FILENAME: &str = "trace-manifest.json";
+const EXPORT_MANIFEST_VERSION: u64 = 1;
+
+///////////////
+//   Types   //
+///////////////
+
+// Kept alongside the files of each directory exported to, with an entry per file as it gets written, for verify_export to check against later. Rewriting a file (e.g. rerunning the same export) replaces its entry.
+#[derive(Serialize, Deserialize)]
+struct ExportManifest {
+    version: u64,
+    files: BTreeMap<String, ExportManifestEntry>, // Keyed by filename within the directory
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportManifestEntry {
+    room_id: String,
+    sha256: String,
+    event_count: usize,
+    written_at: i64, // In milliseconds since the Unix epoch
+}
+
+pub enum ExportProblemKind {
+    Missing, // In the manifest, but not on disk
+    Unrecorded, // On disk, but not in the manifest, e.g. from before manifests were kept
+    ChecksumMismatch,
+    Unparseable(String), // Holds the parsing error
+    EventCountMismatch {
+        expected: usize,
+        found: usize,
+    },
+    OutOfOrder {
+        out_of_order_event_count: usize, // Events with timestamps going against the rest of the file's order
+    },
+}
+
+pub struct ExportProblem {
+    pub file: String,
+    pub kind: ExportProblemKind,
+}
+
+// A stretch of history the export itself recorded as missing, as listed in its room header.
+pub struct RecordedGap {
+    pub file: String,
+    pub preceding_event_id: Option<String>, // None if the gap comes before everything in the file
+    pub kind: String, // 'history_unavailable' or 'fetch_failed'
+    pub error: Option<String>, // Only for failed fetches
+}
+
+pub struct ExportVerification {
+    pub has_manifest: bool, // Without one, only parsing, ordering, and gaps get checked
+    pub checked_file_count: usize,
+    pub problems: Vec<ExportProblem>,
+    pub gaps: Vec<RecordedGap>,
+}
+
+/////////////////
+//   Helpers   //
+/////////////////
+
+fn read_export_manifest(export_dir: &Path) -> Result<Option<ExportManifest>> {
+    match read_to_string(export_dir.join(EXPORT_MANIFEST_FILENAME)) {
+        Ok(manifest) => Ok(Some(serde_json::from_str(&manifest)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Takes the file's SHA-256 hash, rather than its contents, so that streamed files can be hashed as they're written. The manifest gets written to a temporary file and moved into place, so that a crash partway through never leaves it half-written.
+pub(crate) fn record_in_export_manifest(export_dir: &Path, filename: &str, room_id: &str, event_count: usize, sha256: String) -> Result<()> {
+    let mut manifest = read_export_manifest(export_dir)?.unwrap_or_else(|| ExportManifest {
+        version: EXPORT_MANIFEST_VERSION,
+        files: BTreeMap::new(),
+    });
+    manifest.files.insert(String::from(filename), ExportManifestEntry {
+        room_id: String::from(room_id),
+        sha256,
+        event_count,
+        written_at: chrono::Utc::now().timestamp_millis(),
+    });
+    let manifest_path = export_dir.join(EXPORT_MANIFEST_FILENAME);
+    let temporary_manifest_path = manifest_path.with_extension("json.tmp");
+    write(&temporary_manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    rename(temporary_manifest_path, manifest_path)?;
+
+    Ok(())
+}
+
+// For files appended to since they were recorded, as by following an export; the appended events are added onto however many were recorded before.
+pub(crate) fn record_appended_in_export_manifest(export_dir: &Path, filename: &str, room_id: &str, appended_event_count: usize, sha256: String) -> Result<()> {
+    let recorded_event_count = read_export_manifest(export_dir)?.and_then(|manifest| manifest.files.get(filename).map(|manifest_entry| manifest_entry.event_count)).unwrap_or_default();
+    record_in_export_manifest(export_dir, filename, room_id, recorded_event_count + appended_event_count, sha256)
+}
+
+// JSON exports hold their events in an events list (or are one, from before the room header was added), and JSON lines exports (as written to stdout) have an event per line. Returns the events along with the rest of the export (i.e. the room header and senders), which is Null for the latter two.
+pub(crate) fn parse_export_events(filename: &str, contents: &str) -> std::result::Result<(Vec<serde_json::Value>, serde_json::Value), String> {
+    if filename.ends_with(".jsonl") || filename.ends_with(".ndjson") {
+        let events = contents.lines().filter(|line| !line.trim().is_empty()).map(serde_json::from_str).collect::<serde_json::Result<Vec<serde_json::Value>>>().map_err(|e| e.to_string())?;
+        return Ok((events, serde_json::Value::Null))
+    }
+    match serde_json::from_str(contents).map_err(|e| e.to_string())? {
+        serde_json::Value::Array(events) => Ok((events, serde_json::Value::Null)),
+        mut export => match export.get_mut("events").map(serde_json::Value::take) {
+            Some(serde_json::Value::Array(events)) => Ok((events, export)),
+            _ => Err(String::from("it has no events list")),
+        },
+    }
+}
+
+// Exports run oldest-first or newest-first, so whichever way the file's ends point counts as its order.
+fn out_of_order_event_count(events: &[serde_json::Value]) -> usize {
+    let timestamps_millis = events.iter().filter_map(|event| event.get("origin_server_ts").and_then(|timestamp| timestamp.as_i64())).collect::<Vec<i64>>();
+    let newest_first = timestamps_millis.first() > timestamps_millis.last();
+    timestamps_millis.windows(2).filter(|pair| match newest_first {
+        true => pair[1] > pair[0],
+        false => pair[1] < pair[0],
+    }).count()
+}
+
+//////////////
+//   Main   //
+//////////////
+
+// Checks an export directory's files against the manifest written alongside them: that nothing's gone missing or changed since, that JSON still parses and holds as many events as were written, and that events are still in order. Also gathers up the gaps each export recorded in its history. Media is checked separately, by media::verify_media.
+pub fn verify_export(export_dir: &Path) -> Result<ExportVerification> {
+    let manifest = read_export_manifest(export_dir)?;
+    let mut verification = ExportVerification {
+        has_manifest: manifest.is_some(),
+        checked_file_count: 0,
+        problems: Vec::new(),
+        gaps: Vec::new(),
+    };
+
+    let mut filenames = Vec::new();
+    for entry in read_dir(export_dir)? {
+        let path = entry?.path();
+        let Some(filename) = path.file_name().and_then(|filename| filename.to_str()) else {
+            continue
+        };
+        if path.is_file() && filename != EXPORT_MANIFEST_FILENAME && [".json", ".jsonl", ".ndjson", ".txt"].iter().any(|extension| filename.ends_with(extension)) {
+            filenames.push(String::from(filename));
+        }
+    }
+    filenames.sort();
+    if let Some(manifest) = &manifest {
+        for filename in manifest.files.keys().filter(|filename| !filenames.contains(filename)) {
+            verification.problems.push(ExportProblem {
+                file: filename.clone(),
+                kind: ExportProblemKind::Missing,
+            });
+        }
+    }
+
+    for filename in filenames {
+        verification.checked_file_count += 1;
+        let contents = read(export_dir.join(&filename))?;
+        let manifest_entry = manifest.as_ref().and_then(|manifest| manifest.files.get(&filename));
+        match (&manifest, manifest_entry) {
+            (Some(_), None) => verification.problems.push(ExportProblem {
+                file: filename.clone(),
+                kind: ExportProblemKind::Unrecorded,
+            }),
+            (_, Some(manifest_entry)) if manifest_entry.sha256 != sha256_hex(&contents) => verification.problems.push(ExportProblem {
+                file: filename.clone(),
+                kind: ExportProblemKind::ChecksumMismatch,
+            }),
+            _ => (),
+        }
+        if filename.ends_with(".txt") {
+            continue // Nothing to parse
+        }
+
+        let (events, export) = match String::from_utf8(contents).map_err(|e| e.to_string()).and_then(|contents| parse_export_events(&filename, &contents)) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                verification.problems.push(ExportProblem {
+                    file: filename,
+                    kind: ExportProblemKind::Unparseable(e),
+                });
+                continue
+            }
+        };
+        if let Some(manifest_entry) = manifest_entry.filter(|manifest_entry| manifest_entry.event_count != events.len()) {
+            verification.problems.push(ExportProblem {
+                file: filename.clone(),
+                kind: ExportProblemKind::EventCountMismatch {
+                    expected: manifest_entry.event_count,
+                    found: events.len(),
+                },
+            });
+        }
+        let out_of_order_event_count = out_of_order_event_count(&events);
+        if out_of_order_event_count > 0 {
+            verification.problems.push(ExportProblem {
+                file: filename.clone(),
+                kind: ExportProblemKind::OutOfOrder {
+                    out_of_order_event_count,
+                },
+            });
+        }
+        for gap in export.pointer("/room/gaps").and_then(|gaps| gaps.as_array()).into_iter().flatten() {
+            verification.gaps.push(RecordedGap {
+                file: filename.clone(),
+                preceding_event_id: gap.get("preceding_event_id").and_then(|event_id| event_id.as_str()).map(String::from),
+                kind: gap.get("kind").and_then(|kind| kind.as_str()).map(String::from).unwrap_or_default(),
+                error: gap.get("error").and_then(|error| error.as_str()).map(String::from),
+            });
+        }
+    }
+
+    Ok(verification)
+}